@@ -0,0 +1,102 @@
+use std::{env, fs, path::PathBuf};
+
+/// Export the versions of the direct dependencies locked in `Cargo.lock` as
+/// `WEBINFO_LOCKED_DEPENDENCIES`, a comma separated list of `name=version`,
+/// empty when the lock file is not found
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    // the lock file of a workspace member is at the root of the workspace
+    let lock = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.exists());
+    let dependencies = match &lock {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.display());
+            fs::read_to_string(path)
+                .map(|content| locked_dependencies(&content, &env::var("CARGO_PKG_NAME").unwrap()))
+                .unwrap_or_default()
+        }
+        None => Vec::new(),
+    };
+    println!("cargo:rerun-if-changed=build.rs");
+    println!(
+        "cargo:rustc-env=WEBINFO_LOCKED_DEPENDENCIES={}",
+        dependencies
+            .iter()
+            .map(|(name, version)| format!("{}={}", name, version))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+}
+
+/// Name, version and dependencies of a `[[package]]` entry of the lock file
+struct Package {
+    name: String,
+    version: String,
+    dependencies: Vec<String>,
+}
+
+/// Parse the `[[package]]` entries of the lock file, only the keys needed
+/// to resolve the versions of the dependencies are read
+fn packages(content: &str) -> Vec<Package> {
+    let unquote = |value: &str| {
+        value
+            .trim()
+            .trim_end_matches(',')
+            .trim_matches('"')
+            .to_string()
+    };
+    let mut packages = Vec::new();
+    let mut in_dependencies = false;
+    for line in content.lines() {
+        if line == "[[package]]" {
+            packages.push(Package {
+                name: String::new(),
+                version: String::new(),
+                dependencies: Vec::new(),
+            });
+            in_dependencies = false;
+        } else if let Some(package) = packages.last_mut() {
+            if in_dependencies {
+                if line.starts_with(']') {
+                    in_dependencies = false;
+                } else {
+                    package.dependencies.push(unquote(line));
+                }
+            } else if let Some(name) = line.strip_prefix("name = ") {
+                package.name = unquote(name);
+            } else if let Some(version) = line.strip_prefix("version = ") {
+                package.version = unquote(version);
+            } else if line.starts_with("dependencies = [") {
+                in_dependencies = true;
+            }
+        }
+    }
+    packages
+}
+
+/// Locked versions of the dependencies of the package `name`. A dependency
+/// is given as `name version` when several versions are locked, else the
+/// version is the one of the single package with that name.
+fn locked_dependencies(content: &str, name: &str) -> Vec<(String, String)> {
+    let packages = packages(content);
+    let Some(package) = packages.iter().find(|package| package.name == name) else {
+        return Vec::new();
+    };
+    package
+        .dependencies
+        .iter()
+        .filter_map(|dependency| {
+            let mut fields = dependency.split_whitespace();
+            let name = fields.next()?;
+            match fields.next() {
+                Some(version) => Some((name.to_string(), version.to_string())),
+                None => packages
+                    .iter()
+                    .find(|package| package.name == name)
+                    .map(|package| (package.name.clone(), package.version.clone())),
+            }
+        })
+        .collect()
+}
@@ -1,20 +1,44 @@
 use ip_network::IpNetwork;
 use ip2asn::IpAsnMap;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use std::{
     collections::{HashMap, hash_map::Entry::Vacant},
+    hash::Hash,
     net::IpAddr,
     sync::Arc,
 };
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Asn {
+    #[serde(
+        serialize_with = "serialize_cidrs",
+        deserialize_with = "deserialize_cidrs"
+    )]
     pub network: Vec<IpNetwork>,
     pub asn: u32,
     pub organization: String,
     pub country_code: String,
 }
 
+/// Serialize the networks as CIDR strings like "192.0.2.0/24", whatever the
+/// serde representation of `IpNetwork`
+fn serialize_cidrs<S: Serializer>(
+    networks: &[IpNetwork],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(networks.iter().map(|network| network.to_string()))
+}
+
+/// Deserialize the CIDR strings written by `serialize_cidrs`
+fn deserialize_cidrs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<IpNetwork>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|network| network.parse().map_err(de::Error::custom))
+        .collect()
+}
+
 impl Asn {
     pub fn from_ip(ip: &IpAddr, ip2asn_map: &IpAsnMap) -> Option<Asn> {
         if let Some(asn_info) = ip2asn_map.lookup_owned(*ip) {
@@ -39,10 +63,10 @@ fn update_asn_network(asn: &mut Asn, new_network: IpNetwork) {
 /// Update the ASN information in the hash map
 /// If the ASN already exists, update its networks; otherwise, insert it.
 /// This function ensures that each ASN entry in the hash map has a unique set of networks.
-fn update_asn(hash: &mut HashMap<u32, Asn>, new_asn: Asn) {
-    if let Vacant(e) = hash.entry(new_asn.asn) {
+fn update_asn<K: Hash + Eq + Copy>(hash: &mut HashMap<K, Asn>, key: K, new_asn: Asn) {
+    if let Vacant(e) = hash.entry(key) {
         e.insert(new_asn);
-    } else if let Some(existing_asn) = hash.get_mut(&new_asn.asn) {
+    } else if let Some(existing_asn) = hash.get_mut(&key) {
         for network in new_asn.network {
             update_asn_network(existing_asn, network);
         }
@@ -51,21 +75,52 @@ fn update_asn(hash: &mut HashMap<u32, Asn>, new_asn: Asn) {
 
 /// Find ASN information for a list of IP addresses
 /// This function looks up each IP address in the provided ASN map and collects unique ASN information.
-pub fn lookup_ip(ips: &Vec<IpAddr>, ip2asn_map: &Arc<IpAsnMap>) -> Option<Vec<Asn>> {
+/// The ASNs are sorted by number and their networks in address order, so
+/// that the output does not depend on the order of the IP addresses.
+pub fn lookup_ip(ips: &[IpAddr], ip2asn_map: &Arc<IpAsnMap>) -> Option<Vec<Asn>> {
+    group_asns(ips, ip2asn_map, false)
+}
+
+/// Find ASN information for a list of IP addresses like `lookup_ip`, with an
+/// entry per ASN and address family, so that the IPv4 and IPv6 networks of
+/// a dual-stack host in one ASN are not mixed. The IPv4 entry comes first.
+pub fn lookup_ip_by_family(ips: &[IpAddr], ip2asn_map: &Arc<IpAsnMap>) -> Option<Vec<Asn>> {
+    group_asns(ips, ip2asn_map, true)
+}
+
+/// Merge the ASNs of the IP addresses by ASN, or by ASN and address family
+fn group_asns(ips: &[IpAddr], ip2asn_map: &Arc<IpAsnMap>, by_family: bool) -> Option<Vec<Asn>> {
     // Find the ASN for the given IP address
-    let mut asn_hash: HashMap<u32, Asn> = HashMap::new();
+    let mut asn_hash: HashMap<(u32, bool), Asn> = HashMap::new();
     for ip in ips {
         if let Some(asn) = Asn::from_ip(ip, ip2asn_map) {
-            update_asn(&mut asn_hash, asn);
+            update_asn(&mut asn_hash, (asn.asn, by_family && ip.is_ipv6()), asn);
         }
     }
     if asn_hash.is_empty() {
         None
     } else {
-        Some(asn_hash.into_values().collect())
+        let mut asns: Vec<Asn> = asn_hash.into_values().collect();
+        for asn in asns.iter_mut() {
+            asn.network.sort();
+        }
+        // the IPv4 networks sort before the IPv6 ones
+        asns.sort_by(|a, b| a.asn.cmp(&b.asn).then(a.network.cmp(&b.network)));
+        Some(asns)
     }
 }
 
+/// Find the ASN of each IP address, keeping the link between an address and
+/// its ASN instead of merging the addresses of an ASN. The addresses without
+/// ASN are left out.
+pub fn lookup_ip_per_ip(ips: &[IpAddr], ip2asn_map: &Arc<IpAsnMap>) -> Option<Vec<(IpAddr, Asn)>> {
+    let pairs: Vec<_> = ips
+        .iter()
+        .filter_map(|ip| Asn::from_ip(ip, ip2asn_map).map(|asn| (*ip, asn)))
+        .collect();
+    if pairs.is_empty() { None } else { Some(pairs) }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,7 +144,7 @@ mod tests {
         let ip2asn_map = Arc::new(ip2asn_map);
 
         let ip = IpAddr::V4(Ipv4Addr::new(129, 134, 0, 1));
-        let result = lookup_ip(&vec![ip], &ip2asn_map);
+        let result = lookup_ip(&[ip], &ip2asn_map);
         assert!(result.is_some());
         let asns = result.unwrap();
         assert_eq!(asns.len(), 1);
@@ -112,7 +167,7 @@ mod tests {
 
         let ip1 = IpAddr::V4(Ipv4Addr::new(129, 134, 0, 1));
         let ip2 = IpAddr::V4(Ipv4Addr::new(129, 134, 0, 2));
-        let result = lookup_ip(&vec![ip1, ip2], &ip2asn_map);
+        let result = lookup_ip(&[ip1, ip2], &ip2asn_map);
         assert!(result.is_some());
         let asns = result.unwrap();
         assert_eq!(asns.len(), 1);
@@ -139,12 +194,126 @@ mod tests {
 
         let ip1 = IpAddr::V4(Ipv4Addr::new(129, 134, 0, 1));
         let ip2 = IpAddr::V4(Ipv4Addr::new(129, 135, 0, 2));
-        let result = lookup_ip(&vec![ip1, ip2], &ip2asn_map);
+        let result = lookup_ip(&[ip1, ip2], &ip2asn_map);
         assert!(result.is_some());
         let asns = result.unwrap();
         assert_eq!(asns.len(), 2);
     }
 
+    #[test]
+    fn test_lookup_ip_sorted() {
+        let data = [
+            "10.0.0.0\t10.0.0.255\t64502\tUS\tAS-C",
+            "10.0.1.0\t10.0.1.255\t64500\tUS\tAS-A",
+            "10.0.2.0\t10.0.2.255\t64501\tUS\tAS-B",
+            "10.0.3.0\t10.0.3.255\t64500\tUS\tAS-A",
+        ]
+        .join("\n");
+        let ip2asn_map = Builder::new()
+            .with_source(data.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let ip2asn_map = Arc::new(ip2asn_map);
+
+        let ips: Vec<IpAddr> = ["10.0.3.1", "10.0.0.1", "10.0.2.1", "10.0.1.1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let mut reversed = ips.clone();
+        reversed.reverse();
+        for ips in [ips, reversed] {
+            let asns = lookup_ip(&ips, &ip2asn_map).unwrap();
+            assert_eq!(
+                asns.iter().map(|asn| asn.asn).collect::<Vec<_>>(),
+                [64500, 64501, 64502]
+            );
+            assert_eq!(
+                asns[0]
+                    .network
+                    .iter()
+                    .map(|network| network.to_string())
+                    .collect::<Vec<_>>(),
+                ["10.0.1.0/24", "10.0.3.0/24"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_lookup_ip_per_ip() {
+        let data = [
+            "192.0.2.0\t192.0.2.255\t64500\tFR\tTEST-NET-1",
+            "198.51.100.0\t198.51.100.255\t64501\tUS\tTEST-NET-2",
+        ]
+        .join("\n");
+        let ip2asn_map = Builder::new()
+            .with_source(data.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let ip2asn_map = Arc::new(ip2asn_map);
+
+        let ips: Vec<IpAddr> = ["192.0.2.1", "198.51.100.1", "192.0.2.2", "203.0.113.1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let pairs = lookup_ip_per_ip(&ips, &ip2asn_map).unwrap();
+        // each address keeps its own ASN, in the order of the addresses
+        assert_eq!(
+            pairs
+                .iter()
+                .map(|(ip, asn)| (ip.to_string(), asn.asn))
+                .collect::<Vec<_>>(),
+            vec![
+                ("192.0.2.1".to_string(), 64500),
+                ("198.51.100.1".to_string(), 64501),
+                ("192.0.2.2".to_string(), 64500),
+            ]
+        );
+        assert_eq!(pairs[1].1.organization, "TEST-NET-2");
+        // while the aggregated form merges them by ASN
+        assert_eq!(lookup_ip(&ips, &ip2asn_map).unwrap().len(), 2);
+        assert!(lookup_ip_per_ip(&ips[3..], &ip2asn_map).is_none());
+    }
+
+    #[test]
+    fn test_lookup_ip_by_family() {
+        let data = [
+            "157.240.0.0\t157.240.255.255\t32934\tUS\tFACEBOOK-AS",
+            "2a03:2880::\t2a03:2880:ffff:ffff:ffff:ffff:ffff:ffff\t32934\tUS\tFACEBOOK-AS",
+        ]
+        .join("\n");
+        let ip2asn_map = Builder::new()
+            .with_source(data.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let ip2asn_map = Arc::new(ip2asn_map);
+
+        // a dual-stack host in one ASN
+        let ips: Vec<IpAddr> = ["2a03:2880:f10c:83:face:b00c:0:25de", "157.240.3.35"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let networks = |asn: &Asn| {
+            asn.network
+                .iter()
+                .map(|network| network.to_string())
+                .collect::<Vec<_>>()
+        };
+        // merged by default, mixing the families
+        let merged = lookup_ip(&ips, &ip2asn_map).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(networks(&merged[0]), ["157.240.0.0/16", "2a03:2880::/32"]);
+        // split into an IPv4 and an IPv6 entry
+        let split = lookup_ip_by_family(&ips, &ip2asn_map).unwrap();
+        assert_eq!(split.len(), 2);
+        assert!(split.iter().all(|asn| asn.asn == 32934));
+        assert_eq!(networks(&split[0]), ["157.240.0.0/16"]);
+        assert_eq!(networks(&split[1]), ["2a03:2880::/32"]);
+        assert!(lookup_ip_by_family(&[], &ip2asn_map).is_none());
+    }
+
     #[test]
     fn test_from_ip() {
         // A small, in-memory TSV data source for the example.
@@ -165,4 +334,25 @@ mod tests {
         assert_eq!(asn.asn, 32934);
         assert_eq!(asn.organization, "FACEBOOK-AS");
     }
+
+    #[test]
+    fn test_serialize_network_cidr() {
+        let asn = Asn {
+            network: vec![
+                "192.0.2.0/24".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            asn: 64500,
+            organization: "EXAMPLE".to_string(),
+            country_code: "ZZ".to_string(),
+        };
+        let value = serde_json::to_value(&asn).unwrap();
+        assert_eq!(
+            value["network"],
+            serde_json::json!(["192.0.2.0/24", "2001:db8::/32"])
+        );
+        // the output of a previous run reads back
+        let read: Asn = serde_json::from_value(value).unwrap();
+        assert_eq!(read.network, asn.network);
+    }
 }
@@ -1,13 +1,26 @@
 use anyhow::Result;
-use clap::Parser;
-use futures::future::try_join_all;
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser, ValueEnum, parser::ValueSource};
+use flate2::{Compression, write::GzEncoder};
+use futures::{
+    StreamExt,
+    future::try_join_all,
+    stream::{FuturesUnordered, TryStreamExt},
+};
 use hickory_resolver::{Resolver, name_server::TokioConnectionProvider};
-use indicatif::{HumanCount, ProgressBar, ProgressStyle};
+use indicatif::{HumanCount, MultiProgress, ProgressBar, ProgressStyle};
 use ip2asn::IpAsnMap;
 use itertools::izip;
-use std::{fs::File, iter::repeat_with, path::PathBuf, sync::Arc, time::SystemTime};
+use publicsuffix2::{List, MatchOpts, TypeFilter};
+use serde::Deserialize;
+use std::{
+    cell::Cell, collections::BTreeSet, ffi::OsString, fs::File, io::Write, iter::repeat_with,
+    net::IpAddr, path::PathBuf, sync::Arc, sync::Mutex, sync::atomic::AtomicUsize,
+    sync::atomic::Ordering, time::Duration, time::Instant, time::SystemTime,
+};
 use tokio::{sync::mpsc, task::spawn};
+use tokio_util::sync::CancellationToken;
 use tracing::{Level, event};
+use url::Url;
 
 // Look at best pratices
 // 1. https://youtu.be/XCrZleaIUO4?si=hDRLbn3wgZ2TqRuW
@@ -15,12 +28,64 @@ use tracing::{Level, event};
 // 3. https://youtu.be/93SS3VGsKx4?si=hFAIx02eNzx_Qm7D
 use webinfo::{
     IpInfo,
-    ipinfo::OriginRecord,
-    utils::{chunked, count_lines, get_resolver, open_asn_db},
+    diff::{Baseline, Change},
+    dns::{RetryBudget, query_ipv4},
+    input::{
+        JsonArrayRecords, csv_records, dedup_records, flexible_csv_records, ip_records,
+        sample_records,
+    },
+    ipinfo::{OriginRecord, RankBands, default_psl},
+    output::{
+        AsnSummary, DomainGroups, OnePerDomain, SharedHosting, SortKey, SortOrder, ZoneFile,
+        project, to_events, unknown_fields,
+    },
+    reputation::{IpBlocklist, load_blocklist},
+    sqlite::SqliteOutput,
+    tls::StartTls,
+    utils::{
+        AdaptiveLimiter, ApexLimiter, AsnDbLoader, AsnDbPrefetch, DEFAULT_ASN_DOWNLOAD_TIMEOUT,
+        IptoasnLoader, Pacer, ProgressHandle, PyasnLoader, chunked, count_lines, get_resolver,
+        http_client, load_asn_db, load_psl, open_asn_db_with_progress, open_input,
+    },
+    webhook::Webhook,
 };
 
-fn get_writer(output: Option<PathBuf>) -> Box<dyn std::io::Write + Send> {
-    match output {
+type Records = Box<dyn Iterator<Item = Result<OriginRecord>>>;
+
+/// Output of the results, gzip-compressed with `--compress`
+enum Output {
+    Plain(Box<dyn Write + Send>),
+    Gzip(GzEncoder<Box<dyn Write + Send>>),
+}
+
+impl Output {
+    /// Flush the output, writing the gzip trailer of a compressed one
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(mut writer) => writer.flush(),
+            Output::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Plain(writer) => writer.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Plain(writer) => writer.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn get_writer(output: Option<PathBuf>, compress: bool) -> Output {
+    let writer: Box<dyn Write + Send> = match output {
         Some(path) => {
             let file = File::create(path);
             match file {
@@ -32,23 +97,106 @@ fn get_writer(output: Option<PathBuf>) -> Box<dyn std::io::Write + Send> {
             }
         }
         None => Box::new(std::io::stdout()),
+    };
+    if compress {
+        Output::Gzip(GzEncoder::new(writer, Compression::default()))
+    } else {
+        Output::Plain(writer)
     }
 }
 
+/// Parse a size in bytes with an optional unit: B, KB, MB, GB (powers of
+/// 1000) or KiB, MiB, GiB (powers of 1024), like `100MB`
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", size))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        unit => return Err(format!("unknown size unit {:?}", unit)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size {:?} is too large", size))
+}
+
+/// Records whose lookups failed with a timeout or a SERVFAIL, processed once
+/// more when all the others are done
+type RetryQueue = Arc<Mutex<Vec<OriginRecord>>>;
+
+/// Options of the processing shared by all the batches of records
+#[derive(Clone, Debug, Default)]
+struct BatchOptions {
+    /// Concurrency limit per apex domain
+    apex_limiter: Option<Arc<ApexLimiter>>,
+    /// Popularity rank bands the results are annotated with
+    rank_bands: Option<RankBands>,
+    /// Delay between the starts of the tasks
+    pacer: Option<Arc<Pacer>>,
+    /// Retries of the failed lookups of a host
+    retries: u32,
+    /// Retries shared by all the hosts
+    retry_budget: Option<Arc<RetryBudget>>,
+    /// Hosts whose raw DNS answers are attached to the results
+    debug_hosts: Option<Vec<String>>,
+    /// Concurrency adapting to the timeouts and SERVFAILs of the lookups
+    adaptive_limiter: Option<Arc<AdaptiveLimiter>>,
+    /// Counts of the processed, in flight and failed records
+    progress: ProgressHandle,
+    /// Queue of the records failed with a transient error, instead of
+    /// outputting their result
+    retry_queue: Option<RetryQueue>,
+    /// Accept the hostnames under a TLD missing from the public suffix list
+    accept_unknown_tld: bool,
+    /// Mail protocol upgraded with STARTTLS to probe the certificate of the hosts
+    starttls: Option<StartTls>,
+    /// Record the latency of the DNS lookups in the histogram of `progress`
+    latency: bool,
+    /// Flag the certificates expiring within this number of days
+    expiry_alert_days: Option<u32>,
+    /// Source address of the TLS connections
+    bind: Option<IpAddr>,
+    /// ASNs whose hosts are not probed with TLS
+    no_tls_asns: Option<Vec<u32>>,
+    /// Number of resolved IPs of a host probed by the TLS modes probing several
+    max_tls_ips: Option<usize>,
+    /// Blocklist the resolved IPs are checked against
+    blocklist: Option<Arc<IpBlocklist>>,
+    /// Process the records without their ASN once the ASN database failed
+    /// to open, instead of failing the run
+    continue_on_asn_db_failure: bool,
+}
+
 fn process_batch_of_records(
-    chunk: Vec<Result<OriginRecord, csv::Error>>,
+    chunk: Vec<Result<OriginRecord>>,
     resolver: &Resolver<TokioConnectionProvider>,
-    ip2asn_map: &Arc<IpAsnMap>,
+    asn_db: &AsnDbPrefetch,
+    psl: &Arc<List>,
+    options: &BatchOptions,
     tx: &mpsc::Sender<Result<IpInfo>>,
 ) -> Vec<tokio::task::JoinHandle<()>> {
     // store all task handles
     let mut handles = Vec::new();
-    // Create iterators that repeat the resolver, ip2asn_map, and tx for each record in the chunk
+    // Create iterators that repeat the resolver, asn_db, and tx for each record in the chunk
     let resolver_iter = repeat_with(|| resolver.clone()).take(chunk.len());
-    let ip2asn_iter = repeat_with(|| ip2asn_map.clone()).take(chunk.len());
+    let ip2asn_iter = repeat_with(|| asn_db.clone()).take(chunk.len());
+    let psl_iter = repeat_with(|| psl.clone()).take(chunk.len());
     let tx_iter = repeat_with(|| tx.clone()).take(chunk.len());
     // Process each record in the chunk
-    for (record, r, ip2asn, sender) in izip!(chunk, resolver_iter, ip2asn_iter, tx_iter) {
+    for (record, r, ip2asn, psl, sender) in
+        izip!(chunk, resolver_iter, ip2asn_iter, psl_iter, tx_iter)
+    {
         let record = match record {
             Ok(record) => record,
             Err(e) => {
@@ -56,14 +204,105 @@ fn process_batch_of_records(
                 continue;
             }
         };
+        // apex domain of the record when the concurrency is limited per apex
+        let apex = options.apex_limiter.as_ref().and_then(|limiter| {
+            let apex = apex_of(&record.origin, &psl)?;
+            Some((limiter.clone(), apex))
+        });
+        let retry = options
+            .retry_queue
+            .as_ref()
+            .map(|queue| (queue.clone(), record.clone()));
+        // IP address inputs go through the reverse pipeline
+        let runner = match record.origin.parse::<IpAddr>() {
+            Ok(ip) => IpInfo::runner_for_ip(ip),
+            Err(_) => IpInfo::runner(record),
+        };
+        let runner = match &options.rank_bands {
+            Some(rank_bands) => runner.with_rank_bands(rank_bands.clone()),
+            None => runner,
+        };
+        let runner = match &options.retry_budget {
+            Some(budget) => runner.with_retry_budget(budget.clone()),
+            None => runner,
+        }
+        .with_retries(options.retries);
+        let runner = match &options.debug_hosts {
+            Some(hosts) => runner.with_raw_dns(hosts.clone()),
+            None => runner,
+        };
+        let runner = match options.accept_unknown_tld {
+            true => runner.with_accept_unknown_tld(),
+            false => runner,
+        };
+        let runner = match options.starttls {
+            Some(protocol) => runner.with_starttls(protocol),
+            None => runner,
+        };
+        let runner = match options.latency {
+            true => runner.with_latency_histogram(options.progress.latency().clone()),
+            false => runner,
+        };
+        let runner = match options.expiry_alert_days {
+            Some(days) => runner.with_expiry_alert_days(days.into()),
+            None => runner,
+        };
+        let runner = match options.bind {
+            Some(ip) => runner.with_bind(ip),
+            None => runner,
+        };
+        let runner = match &options.no_tls_asns {
+            Some(asns) => runner.with_no_tls_asns(asns.clone()),
+            None => runner,
+        };
+        let runner = match options.max_tls_ips {
+            Some(max) => runner.with_max_tls_ips(max),
+            None => runner,
+        };
+        let runner = match &options.blocklist {
+            Some(blocklist) => runner.with_reputation(blocklist.clone()),
+            None => runner,
+        };
+        let pacer = options.pacer.clone();
+        let adaptive = options.adaptive_limiter.clone();
+        let progress = options.progress.clone();
         // Spawn a task
         let handle = spawn(async move {
+            if let Some(pacer) = pacer {
+                pacer.wait().await;
+            }
+            let _permit = match &apex {
+                Some((limiter, apex)) => Some(limiter.acquire(apex).await),
+                None => None,
+            };
+            let _adaptive_permit = match &adaptive {
+                Some(limiter) => Some(limiter.acquire().await),
+                None => None,
+            };
+            let in_flight = progress.start();
+            let transient_errors = Arc::new(AtomicUsize::new(0));
+            let start = Instant::now();
             // Perform the query
-            let ip_info = IpInfo::runner(record)
+            let ip_info = runner
                 .with_resolver(r)
-                .with_ip2asn_map(ip2asn)
+                .with_asn_prefetch(ip2asn)
+                .with_psl(psl)
+                .with_transient_errors(transient_errors.clone())
                 .run()
                 .await;
+            if let Some(limiter) = &adaptive {
+                let failed = transient_errors.load(Ordering::SeqCst) > 0;
+                limiter.record(failed, start.elapsed());
+            }
+            in_flight.finish(ip_info.is_ok());
+            // a host left unresolved by timeouts or SERVFAILs, not by NXDOMAIN, is retried
+            if let (Some((queue, record)), Ok(info)) = (retry, &ip_info)
+                && info.records.ip.is_none()
+                && transient_errors.load(Ordering::SeqCst) > 0
+            {
+                queue.lock().unwrap().push(record);
+                return;
+            }
             let _ = sender.send(ip_info).await;
         });
         handles.push(handle);
@@ -71,96 +310,1816 @@ fn process_batch_of_records(
     handles
 }
 
+/// Process once more the records queued by `--retry-failed`, by chunks. Their
+/// results are output as any other, a record failing again is not queued twice.
+async fn retry_failed_records(
+    queue: RetryQueue,
+    chunk_size: usize,
+    resolver: &Resolver<TokioConnectionProvider>,
+    asn_db: &AsnDbPrefetch,
+    psl: &Arc<List>,
+    options: &BatchOptions,
+    tx: &mpsc::Sender<Result<IpInfo>>,
+) -> Result<()> {
+    let failed = std::mem::take(&mut *queue.lock().unwrap());
+    if failed.is_empty() {
+        return Ok(());
+    }
+    event!(
+        Level::INFO,
+        "Retrying {} records failed with a transient error",
+        failed.len()
+    );
+    let options = BatchOptions {
+        retry_queue: None,
+        ..options.clone()
+    };
+    for chunk in chunked(failed.into_iter().map(Ok), chunk_size) {
+        let handles = process_batch_of_records(chunk, resolver, asn_db, psl, &options, tx);
+        try_join_all(handles).await?;
+    }
+    Ok(())
+}
+
+/// Resolve the A records of the registrable domains of `records` once, at
+/// most `concurrency` at a time, so that the resolver and upstream caches
+/// are primed before the timed run. Return the number of domains resolved.
+async fn warmup(
+    records: impl Iterator<Item = Result<OriginRecord>>,
+    resolver: &Resolver<TokioConnectionProvider>,
+    psl: &List,
+    concurrency: usize,
+) -> usize {
+    let apexes = records
+        .filter_map(Result::ok)
+        .filter_map(|record| apex_of(&record.origin, psl))
+        .collect::<BTreeSet<_>>();
+    futures::stream::iter(&apexes)
+        .for_each_concurrent(concurrency, |apex| async move {
+            query_ipv4(apex, resolver).await;
+        })
+        .await;
+    apexes.len()
+}
+
+/// Process the records as they are read instead of by batches: the input is
+/// read into a bounded channel while a pool of at most `concurrency` tasks
+/// processes them, so that the reading goes on while the slowest records of
+/// a batch are resolved. No record is started once the ASN database failed
+/// to open, see `check_asn_db`.
+async fn stream_records(
+    records: impl Iterator<Item = Result<OriginRecord>>,
+    concurrency: usize,
+    resolver: &Resolver<TokioConnectionProvider>,
+    asn_db: &AsnDbPrefetch,
+    psl: &Arc<List>,
+    options: &BatchOptions,
+    tx: &mpsc::Sender<Result<IpInfo>>,
+) -> Result<()> {
+    let concurrency = concurrency.max(1);
+    let (record_tx, mut record_rx) = mpsc::channel(concurrency);
+    // the producer runs in the current task, the input iterators not being Send
+    let producer = async move {
+        for record in records {
+            if record_tx.send(record).await.is_err() {
+                break;
+            }
+        }
+    };
+    // the receiver is dropped with the consumer, so that the producer stops
+    // reading once the consumer fails
+    let consumer = async move {
+        let mut in_flight = FuturesUnordered::new();
+        while let Some(record) = record_rx.recv().await {
+            // wait for a free worker before starting the next record
+            while in_flight.len() >= concurrency {
+                in_flight.try_next().await?;
+            }
+            check_asn_db(asn_db, options.continue_on_asn_db_failure)?;
+            in_flight.extend(process_batch_of_records(
+                vec![record],
+                resolver,
+                asn_db,
+                psl,
+                options,
+                tx,
+            ));
+        }
+        while in_flight.next().await.transpose()?.is_some() {}
+        Ok::<_, anyhow::Error>(())
+    };
+    let ((), processed) = tokio::join!(producer, consumer);
+    processed
+}
+
+/// Fail the run once the ASN database failed to open, unless `continue_on_failure`:
+/// the records are then processed without their ASN
+fn check_asn_db(asn_db: &AsnDbPrefetch, continue_on_failure: bool) -> Result<()> {
+    match asn_db.error() {
+        Some(e) if !continue_on_failure => {
+            Err(anyhow::anyhow!("Failed to open ASN database: {}", e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Percentiles of the latencies of the DNS lookups of the run, none when no
+/// lookup was recorded
+fn latency_summary(progress: &ProgressHandle) -> Option<String> {
+    let latency = progress.latency();
+    let ms = |percentile| {
+        latency
+            .percentile(percentile)
+            .map(|latency: Duration| latency.as_secs_f64() * 1000.0)
+    };
+    Some(format!(
+        "DNS lookup latency over {} queries: p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms",
+        latency.len(),
+        ms(50.0)?,
+        ms(90.0)?,
+        ms(99.0)?
+    ))
+}
+
+/// Pacer spreading the queries with `--delay` and `--jitter`, none without them
+fn pacer(cli: &Cli) -> Option<Arc<Pacer>> {
+    if cli.delay.is_none() && cli.jitter.is_none() {
+        return None;
+    }
+    Some(Arc::new(Pacer::new(
+        Duration::from_millis(cli.delay.unwrap_or(0)),
+        Duration::from_millis(cli.jitter.unwrap_or(0)),
+        cli.seed.unwrap_or_default(),
+    )))
+}
+
+/// Registrable domain of the host of an origin
+fn apex_of(origin: &str, psl: &List) -> Option<String> {
+    let url = Url::parse(origin).ok()?;
+    let opts = MatchOpts {
+        types: TypeFilter::Icann,
+        ..Default::default()
+    };
+    psl.sld(url.host_str()?, opts).map(|apex| apex.to_string())
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum InputFormat {
+    /// CSV file with a header line
+    Csv,
+    /// JSON array of records
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// Pretty-printed JSON objects
+    #[default]
+    Json,
+    /// One compact JSON object per line (newline delimited JSON)
+    Ndjson,
+    /// BIND zone file of the discovered records, written once all records are processed
+    Zonefile,
+    /// Groups of the hosts resolving to the same set of IPs, one JSON object
+    /// per line, written once all records are processed
+    #[serde(rename = "shared-hosting")]
+    SharedHosting,
+    /// RFC 6902 JSON Patch of the changes of each host since --baseline, one
+    /// per line, on a document holding the results by origin
+    #[serde(rename = "json-patch")]
+    JsonPatch,
+    /// Number of hosts of each ASN, one JSON object per line, written once
+    /// all records are processed. Streamed, unlike the zonefile and
+    /// shared-hosting aggregations, its memory does not grow with the input.
+    #[serde(rename = "asn-summary")]
+    AsnSummary,
+    /// Pretty-printed JSON object mapping each registrable domain to its
+    /// results, written once all records are processed
+    Grouped,
+    /// Small typed events, one compact JSON object per line: HostResolved,
+    /// IpDiscovered, AsnMapped and CertObserved, with the time and origin
+    Events,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum InputKind {
+    /// Records of website origins
+    Origin,
+    /// IP addresses, one per line, looked up in reverse (PTR, ASN)
+    Ip,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum AsnFormat {
+    /// iptoasn.com TSV database, gzipped or not
+    Iptoasn,
+    /// pyasn IPASN database of prefix and ASN lines, without organization nor country
+    Pyasn,
+}
+
+impl AsnFormat {
+    fn loader(self) -> Box<dyn AsnDbLoader> {
+        match self {
+            AsnFormat::Iptoasn => Box::new(IptoasnLoader),
+            AsnFormat::Pyasn => Box::new(PyasnLoader),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum SortBy {
+    /// Number of hosts
+    Count,
+    /// Sum of the popularity of the hosts, with --weight-by-popularity
+    Popularity,
+    /// ASN number
+    Asn,
+    /// ASN organization, or first hostname of a shared hosting group
+    Name,
+}
+
+impl SortBy {
+    fn key(self) -> SortKey {
+        match self {
+            SortBy::Count => SortKey::Count,
+            SortBy::Popularity => SortKey::Popularity,
+            SortBy::Asn => SortKey::Asn,
+            SortBy::Name => SortKey::Name,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum StartTlsProtocol {
+    /// SMTP on port 25
+    Smtp,
+    /// IMAP on port 143
+    Imap,
+    /// POP3 on port 110
+    Pop3,
+}
+
+impl StartTlsProtocol {
+    fn protocol(self) -> StartTls {
+        match self {
+            StartTlsProtocol::Smtp => StartTls::Smtp,
+            StartTlsProtocol::Imap => StartTls::Imap,
+            StartTlsProtocol::Pop3 => StartTls::Pop3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn level(self) -> Level {
+        match self {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// One line per event
+    #[default]
+    Compact,
+    /// One JSON object per event, for the log platforms
+    Json,
+    /// Multi-line human readable events
+    Pretty,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum TrailingDots {
+    /// Strip the trailing dot of the DNS names, like the hostname
+    #[default]
+    Strip,
+    /// Write all the DNS names as absolute names ending with a dot
+    Add,
+    /// Keep the names as returned by the resolver
+    Keep,
+}
+
+impl TrailingDots {
+    fn normalize(self, info: &mut IpInfo) {
+        match self {
+            TrailingDots::Strip => info.records.normalize_names(false),
+            TrailingDots::Add => info.records.normalize_names(true),
+            TrailingDots::Keep => {}
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Require {
+    /// A TLS certificate was retrieved
+    Tls,
+    /// The addresses belong to a known AS
+    Asn,
+}
+
+/// Whether a result resolved to at least one address, along with the
+/// required records
+fn is_resolved(info: &IpInfo, require: &[Require]) -> bool {
+    info.records.ip.as_ref().is_some_and(|ip| !ip.is_empty())
+        && require.iter().all(|require| match require {
+            Require::Tls => info.records.tls.is_some(),
+            Require::Asn => info.records.asn.is_some(),
+        })
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None, author = "Vincent Gauthier <vg@luxbulb.org>")]
+#[command(group(ArgGroup::new("input").required(true).args(["csv", "print_features"])))]
 struct Cli {
-    /// Input CSV file path
-    #[arg(short, long)]
-    csv: PathBuf,
+    /// Input CSV file path, gzipped files are decompressed on the fly
+    #[arg(short, long, visible_alias = "input")]
+    csv: Option<PathBuf>,
+    /// Format of the input file
+    #[arg(long = "input-format", value_enum, default_value_t = InputFormat::Csv)]
+    input_format: InputFormat,
+    /// Kind of the input records
+    #[arg(long = "input-kind", value_enum, default_value_t = InputKind::Origin)]
+    input_kind: InputKind,
     /// Number of concurrent tasks to run
     #[arg(short = 's', long = "size", default_value_t = 5)]
     chunk_size: usize,
-    /// Custom DNS server IP addresses (comma-separated)
+    /// Read the input while the records are processed, instead of batch by
+    /// batch, with at most --size records in flight
+    #[arg(long = "streaming")]
+    streaming: bool,
+    /// Resolve the registrable domains of the records once before the run,
+    /// to measure the throughput on primed caches rather than on cold DNS
+    #[arg(long = "warmup")]
+    warmup: bool,
+    /// Adapt the number of concurrent tasks to the timeouts and SERVFAILs of
+    /// the lookups, between --min-concurrency and --max-concurrency, instead of --size
+    #[arg(long = "auto-concurrency")]
+    auto_concurrency: bool,
+    /// Number of concurrent tasks --auto-concurrency starts with and backs off to
+    #[arg(long = "min-concurrency", default_value_t = 5)]
+    min_concurrency: usize,
+    /// Maximum number of concurrent tasks with --auto-concurrency
+    #[arg(long = "max-concurrency", default_value_t = 100)]
+    max_concurrency: usize,
+    /// Custom DNS server IP addresses (comma or whitespace separated)
     #[arg(short = 'd', long = "dns")]
     dns: Option<String>,
     /// Log file path
     #[arg(short = 'l', long = "logfile", default_value = "./webinfo.log")]
     logfile: PathBuf,
+    /// Most verbose level of the logged events
+    #[arg(long = "log-level", value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+    /// Format of the logged events
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Compact)]
+    log_format: LogFormat,
+    /// Log to stderr instead of the log file
+    #[arg(long = "log-stderr")]
+    log_stderr: bool,
     /// Optional output file path (if not provided, output to stdout)
     #[arg(short = 'o', long = "output")]
     output: Option<PathBuf>,
+    /// Format of the output
+    #[arg(long = "output-format", value_enum, default_value_t = OutputFormat::Json)]
+    output_format: OutputFormat,
+    /// Accept the CSV rows with extra or missing columns, dropping the extra
+    /// columns and leaving the missing ones empty
+    #[arg(long = "flexible-csv")]
+    flexible_csv: bool,
+    /// Compress the output (file or stdout) with gzip
+    #[arg(long = "compress")]
+    compress: bool,
+    /// Stop the scan once this much output is written, like 100MB or 512KiB,
+    /// counted before compression. The results are written whole, so the
+    /// output stays valid.
+    #[arg(long = "max-output", value_parser = parse_size)]
+    max_output: Option<u64>,
+    /// Only process records with a popularity of at least this value
+    #[arg(long = "min-popularity")]
+    min_popularity: Option<u32>,
+    /// Only read the first N records of the input
+    #[arg(long = "top")]
+    top: Option<usize>,
+    /// Only process a random sample of N records of the input
+    #[arg(long = "sample")]
+    sample: Option<usize>,
+    /// Seed of the random sample and jitter, a random one is drawn and logged when not given
+    #[arg(long = "seed")]
+    seed: Option<u64>,
+    /// Delay in milliseconds between the starts of the queries of two records
+    #[arg(long = "delay")]
+    delay: Option<u64>,
+    /// Random extra delay in milliseconds, up to this value, added to --delay
+    #[arg(long = "jitter")]
+    jitter: Option<u64>,
+    /// Only process the most popular record of each hostname
+    #[arg(long = "dedup")]
+    dedup: bool,
+    /// Treat www.example.com and example.com as the same hostname (implies --dedup)
+    #[arg(long = "dedup-www")]
+    dedup_www: bool,
+    /// Only output the most popular result of each registrable domain, once
+    /// all records are processed
+    #[arg(long = "one-per-domain")]
+    one_per_domain: bool,
+    /// Maximum number of records of the same apex domain processed concurrently
+    #[arg(long = "per-apex-limit")]
+    per_apex_limit: Option<usize>,
+    /// Annotate the results with the band of their popularity rank, the
+    /// optional comma-separated limits default to 1000,10000,100000,1000000
+    #[arg(long = "rank-bands", value_delimiter = ',', num_args = 0..)]
+    rank_bands: Option<Vec<u32>>,
+    /// Drop the results which did not resolve to any address, the optional
+    /// comma-separated values also require the tls or asn records
+    #[arg(long = "only-resolved", value_enum, value_delimiter = ',', num_args = 0..)]
+    only_resolved: Option<Vec<Require>>,
+    /// Output of a previous run (JSON or NDJSON): only the results which changed
+    /// since are output, with the list of their changes
+    #[arg(long = "baseline")]
+    baseline: Option<PathBuf>,
+    /// Rank the ASNs of --output-format asn-summary by the sum of the
+    /// popularity of their hosts instead of their number of hosts
+    #[arg(long = "weight-by-popularity")]
+    weight_by_popularity: bool,
+    /// Order of the rows of --output-format asn-summary and shared-hosting,
+    /// ascending unless --sort-desc, instead of the default ranking
+    #[arg(long = "sort-output-by", value_enum)]
+    sort_output_by: Option<SortBy>,
+    /// Sort the rows given with --sort-output-by in descending order
+    #[arg(long = "sort-desc")]
+    sort_desc: bool,
+    /// Trailing dot of the DNS names (CNAME, NS, PTR...) of the JSON results
+    #[arg(long = "trailing-dots", value_enum, default_value_t = TrailingDots::Strip)]
+    trailing_dots: TrailingDots,
+    /// Only output these fields (comma-separated, dotted paths like tls.organization)
+    #[arg(long = "fields", value_delimiter = ',')]
+    fields: Option<Vec<String>>,
+    /// HTTP endpoint the results are POSTed to as JSON arrays, instead of the output
+    #[arg(long = "webhook")]
+    webhook: Option<Url>,
+    /// Number of results sent in a single POST to the webhook
+    #[arg(long = "webhook-batch-size", default_value_t = 100)]
+    webhook_batch_size: usize,
+    /// SQLite database the results are inserted into, instead of the output,
+    /// with a table per host, IP, ASN, name server and certificate
+    #[arg(long = "sqlite")]
+    sqlite: Option<PathBuf>,
+    /// Number of retries of a DNS lookup failing with a timeout or a SERVFAIL
+    #[arg(long = "retries", default_value_t = 0)]
+    retries: u32,
+    /// Total number of retries of the whole run, the failed lookups are no
+    /// longer retried once they are spent
+    #[arg(long = "retry-budget")]
+    retry_budget: Option<usize>,
+    /// Process once more, at the end of the run, the records left unresolved
+    /// by timeouts or SERVFAILs
+    #[arg(long = "retry-failed")]
+    retry_failed: bool,
+    /// Attach the raw DNS answers of the hosts given with --debug-host to their results
+    #[arg(long = "debug-dns")]
+    debug_dns: bool,
+    /// Hosts debugged with --debug-dns (comma-separated)
+    #[arg(long = "debug-host", value_delimiter = ',')]
+    debug_host: Option<Vec<String>>,
+    /// Public suffix list file to use instead of the bundled one
+    #[arg(long = "psl-file")]
+    psl_file: Option<PathBuf>,
+    /// Accept the hostnames whose TLD is missing from the public suffix list
+    /// but looks like a new gTLD, with known_tld set to false
+    #[arg(long = "accept-unknown-tld")]
+    accept_unknown_tld: bool,
+    /// Probe the certificate of the hosts as mail servers, upgrading this
+    /// protocol to TLS with STARTTLS
+    #[arg(long = "starttls", value_enum)]
+    starttls: Option<StartTlsProtocol>,
+    /// Report the p50, p90 and p99 latencies of the DNS lookups at the end of the run
+    #[arg(long = "latency-percentiles")]
+    latency_percentiles: bool,
+    /// Flag the certificates expiring within this number of days, or expired,
+    /// and exit with an error when any is found. Probes the TLS certificates.
+    #[arg(long = "expiry-alert-days")]
+    expiry_alert_days: Option<u32>,
+    /// Source address of the DNS queries and of the TLS connections, to scan
+    /// from a dedicated interface of a multi-homed host. Of the family of the
+    /// DNS servers.
+    #[arg(long = "bind")]
+    bind: Option<IpAddr>,
+    /// Do not probe with TLS the IPs announced by these ASNs
+    /// (comma-separated), like honeypots or sensitive networks, nor the IPs
+    /// whose ASN is unknown
+    #[arg(long = "no-tls-asn", value_delimiter = ',')]
+    no_tls_asn: Option<Vec<u32>>,
+    /// Maximum number of resolved IPs of a host probed when the certificate
+    /// of several IPs is retrieved, to bound the connections to the
+    /// round-robin hosts with dozens of IPs
+    #[arg(long = "max-tls-ips", default_value_t = 3)]
+    max_tls_ips: usize,
+    /// Local blocklist file, a CIDR per line optionally followed by a label,
+    /// the resolved IPs it contains are flagged with their reputation
+    #[arg(long = "blocklist")]
+    blocklist: Option<PathBuf>,
+    /// Local ASN database file to use instead of the downloaded iptoasn.com one
+    #[arg(long = "asn-db")]
+    asn_db: Option<PathBuf>,
+    /// Format of the ASN database given with --asn-db
+    #[arg(long = "asn-format", value_enum, default_value_t = AsnFormat::Iptoasn)]
+    asn_format: AsnFormat,
+    /// Timeout in seconds of the download of the iptoasn.com ASN database
+    #[arg(long = "asn-download-timeout", default_value_t = DEFAULT_ASN_DOWNLOAD_TIMEOUT.as_secs())]
+    asn_download_timeout: u64,
+    /// Go on without the ASN records when the ASN database cannot be opened,
+    /// instead of aborting the run
+    #[arg(long = "continue-on-asn-db-failure")]
+    continue_on_asn_db_failure: bool,
+    /// TOML configuration file, command line flags override its values
+    #[arg(long = "config")]
+    config: Option<PathBuf>,
+    /// Print the compiled Cargo features and the versions of the key
+    /// dependencies, then exit
+    #[arg(long = "print-features")]
+    print_features: bool,
+}
+
+/// Cargo features of the crate and whether they are compiled in
+const FEATURES: &[(&str, bool)] = &[("network-tests", cfg!(feature = "network-tests"))];
+
+/// Key dependencies whose versions are reported by `--print-features`
+const KEY_DEPENDENCIES: &[&str] = &["hickory-resolver", "rustls", "ip2asn", "reqwest"];
+
+/// List the compiled Cargo features and the versions of the key dependencies
+/// locked in `Cargo.lock` when the binary was built
+fn features_report() -> String {
+    use std::fmt::Write as _;
+    let locked = env!("WEBINFO_LOCKED_DEPENDENCIES");
+    let mut report = String::new();
+    // writing to a String cannot fail
+    let _ = writeln!(report, "webinfo {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "features:");
+    for (feature, enabled) in FEATURES {
+        let state = if *enabled { "enabled" } else { "disabled" };
+        let _ = writeln!(report, "  {}: {}", feature, state);
+    }
+    let _ = writeln!(report, "dependencies:");
+    for name in KEY_DEPENDENCIES {
+        let version = locked
+            .split(',')
+            .filter_map(|dependency| dependency.split_once('='))
+            .find(|(dependency, _)| dependency == name)
+            .map_or("unknown", |(_, version)| version);
+        let _ = writeln!(report, "  {} {}", name, version);
+    }
+    report
+}
+
+/// Options of a TOML configuration file, the keys are the long names of the
+/// command line flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    input_format: Option<InputFormat>,
+    input_kind: Option<InputKind>,
+    size: Option<usize>,
+    streaming: Option<bool>,
+    warmup: Option<bool>,
+    auto_concurrency: Option<bool>,
+    min_concurrency: Option<usize>,
+    max_concurrency: Option<usize>,
+    dns: Option<String>,
+    logfile: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    log_stderr: Option<bool>,
+    output: Option<PathBuf>,
+    output_format: Option<OutputFormat>,
+    min_popularity: Option<u32>,
+    top: Option<usize>,
+    dedup: Option<bool>,
+    dedup_www: Option<bool>,
+    one_per_domain: Option<bool>,
+    psl_file: Option<PathBuf>,
+    accept_unknown_tld: Option<bool>,
+    starttls: Option<StartTlsProtocol>,
+    latency_percentiles: Option<bool>,
+    expiry_alert_days: Option<u32>,
+    bind: Option<IpAddr>,
+    no_tls_asn: Option<Vec<u32>>,
+    max_tls_ips: Option<usize>,
+    blocklist: Option<PathBuf>,
+    fields: Option<Vec<String>>,
+    per_apex_limit: Option<usize>,
+    asn_db: Option<PathBuf>,
+    asn_format: Option<AsnFormat>,
+    asn_download_timeout: Option<u64>,
+    continue_on_asn_db_failure: Option<bool>,
+    rank_bands: Option<Vec<u32>>,
+    only_resolved: Option<Vec<Require>>,
+    baseline: Option<PathBuf>,
+    trailing_dots: Option<TrailingDots>,
+    weight_by_popularity: Option<bool>,
+    sort_output_by: Option<SortBy>,
+    sort_desc: Option<bool>,
+    flexible_csv: Option<bool>,
+    compress: Option<bool>,
+    max_output: Option<String>,
+    sample: Option<usize>,
+    seed: Option<u64>,
+    delay: Option<u64>,
+    jitter: Option<u64>,
+    webhook: Option<Url>,
+    webhook_batch_size: Option<usize>,
+    sqlite: Option<PathBuf>,
+    retries: Option<u32>,
+    retry_budget: Option<usize>,
+    retry_failed: Option<bool>,
+    debug_dns: Option<bool>,
+    debug_host: Option<Vec<String>>,
+}
+
+impl Config {
+    fn from_file(path: &PathBuf) -> Result<Config> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read config file {:?}: {}", path, e))?;
+        toml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse config file {:?}: {}", path, e))
+    }
+}
+
+impl Cli {
+    /// Path of the input file, required unless `--print-features` is given
+    fn input(&self) -> Result<&PathBuf> {
+        self.csv
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The input file is required, use --csv"))
+    }
+
+    ///
+    /// Parse the command line and merge the configuration file given with `--config`
+    /// Precedence is command line flags, then configuration file, then defaults.
+    ///
+    fn parse_with_config<I, T>(args: I) -> Result<Cli>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Cli::command().get_matches_from(args);
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        if let Some(path) = &cli.config {
+            let config = Config::from_file(path)?;
+            // only the options not given on the command line are taken from the file
+            let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+            if let Some(input_format) = config.input_format.filter(|_| unset("input_format")) {
+                cli.input_format = input_format;
+            }
+            if let Some(input_kind) = config.input_kind.filter(|_| unset("input_kind")) {
+                cli.input_kind = input_kind;
+            }
+            if let Some(size) = config.size.filter(|_| unset("chunk_size")) {
+                cli.chunk_size = size;
+            }
+            if let Some(streaming) = config.streaming.filter(|_| unset("streaming")) {
+                cli.streaming = streaming;
+            }
+            if let Some(warmup) = config.warmup.filter(|_| unset("warmup")) {
+                cli.warmup = warmup;
+            }
+            if let Some(auto) = config
+                .auto_concurrency
+                .filter(|_| unset("auto_concurrency"))
+            {
+                cli.auto_concurrency = auto;
+            }
+            if let Some(min) = config.min_concurrency.filter(|_| unset("min_concurrency")) {
+                cli.min_concurrency = min;
+            }
+            if let Some(max) = config.max_concurrency.filter(|_| unset("max_concurrency")) {
+                cli.max_concurrency = max;
+            }
+            if let Some(dns) = config.dns.filter(|_| unset("dns")) {
+                cli.dns = Some(dns);
+            }
+            if let Some(logfile) = config.logfile.filter(|_| unset("logfile")) {
+                cli.logfile = logfile;
+            }
+            if let Some(log_level) = config.log_level.filter(|_| unset("log_level")) {
+                cli.log_level = log_level;
+            }
+            if let Some(log_format) = config.log_format.filter(|_| unset("log_format")) {
+                cli.log_format = log_format;
+            }
+            if let Some(log_stderr) = config.log_stderr.filter(|_| unset("log_stderr")) {
+                cli.log_stderr = log_stderr;
+            }
+            if let Some(output) = config.output.filter(|_| unset("output")) {
+                cli.output = Some(output);
+            }
+            if let Some(output_format) = config.output_format.filter(|_| unset("output_format")) {
+                cli.output_format = output_format;
+            }
+            if let Some(min_popularity) = config.min_popularity.filter(|_| unset("min_popularity"))
+            {
+                cli.min_popularity = Some(min_popularity);
+            }
+            if let Some(top) = config.top.filter(|_| unset("top")) {
+                cli.top = Some(top);
+            }
+            if let Some(dedup) = config.dedup.filter(|_| unset("dedup")) {
+                cli.dedup = dedup;
+            }
+            if let Some(dedup_www) = config.dedup_www.filter(|_| unset("dedup_www")) {
+                cli.dedup_www = dedup_www;
+            }
+            if let Some(one_per_domain) = config.one_per_domain.filter(|_| unset("one_per_domain"))
+            {
+                cli.one_per_domain = one_per_domain;
+            }
+            if let Some(psl_file) = config.psl_file.filter(|_| unset("psl_file")) {
+                cli.psl_file = Some(psl_file);
+            }
+            if let Some(accept) = config
+                .accept_unknown_tld
+                .filter(|_| unset("accept_unknown_tld"))
+            {
+                cli.accept_unknown_tld = accept;
+            }
+            if let Some(starttls) = config.starttls.filter(|_| unset("starttls")) {
+                cli.starttls = Some(starttls);
+            }
+            if let Some(latency) = config
+                .latency_percentiles
+                .filter(|_| unset("latency_percentiles"))
+            {
+                cli.latency_percentiles = latency;
+            }
+            if let Some(days) = config
+                .expiry_alert_days
+                .filter(|_| unset("expiry_alert_days"))
+            {
+                cli.expiry_alert_days = Some(days);
+            }
+            if let Some(bind) = config.bind.filter(|_| unset("bind")) {
+                cli.bind = Some(bind);
+            }
+            if let Some(asns) = config.no_tls_asn.filter(|_| unset("no_tls_asn")) {
+                cli.no_tls_asn = Some(asns);
+            }
+            if let Some(max) = config.max_tls_ips.filter(|_| unset("max_tls_ips")) {
+                cli.max_tls_ips = max;
+            }
+            if let Some(blocklist) = config.blocklist.filter(|_| unset("blocklist")) {
+                cli.blocklist = Some(blocklist);
+            }
+            if let Some(fields) = config.fields.filter(|_| unset("fields")) {
+                cli.fields = Some(fields);
+            }
+            if let Some(limit) = config.per_apex_limit.filter(|_| unset("per_apex_limit")) {
+                cli.per_apex_limit = Some(limit);
+            }
+            if let Some(asn_db) = config.asn_db.filter(|_| unset("asn_db")) {
+                cli.asn_db = Some(asn_db);
+            }
+            if let Some(asn_format) = config.asn_format.filter(|_| unset("asn_format")) {
+                cli.asn_format = asn_format;
+            }
+            if let Some(timeout) = config
+                .asn_download_timeout
+                .filter(|_| unset("asn_download_timeout"))
+            {
+                cli.asn_download_timeout = timeout;
+            }
+            if let Some(continue_on_failure) = config
+                .continue_on_asn_db_failure
+                .filter(|_| unset("continue_on_asn_db_failure"))
+            {
+                cli.continue_on_asn_db_failure = continue_on_failure;
+            }
+            if let Some(rank_bands) = config.rank_bands.filter(|_| unset("rank_bands")) {
+                cli.rank_bands = Some(rank_bands);
+            }
+            if let Some(only_resolved) = config.only_resolved.filter(|_| unset("only_resolved")) {
+                cli.only_resolved = Some(only_resolved);
+            }
+            if let Some(baseline) = config.baseline.filter(|_| unset("baseline")) {
+                cli.baseline = Some(baseline);
+            }
+            if let Some(trailing_dots) = config.trailing_dots.filter(|_| unset("trailing_dots")) {
+                cli.trailing_dots = trailing_dots;
+            }
+            if let Some(weight) = config
+                .weight_by_popularity
+                .filter(|_| unset("weight_by_popularity"))
+            {
+                cli.weight_by_popularity = weight;
+            }
+            if let Some(sort_by) = config.sort_output_by.filter(|_| unset("sort_output_by")) {
+                cli.sort_output_by = Some(sort_by);
+            }
+            if let Some(sort_desc) = config.sort_desc.filter(|_| unset("sort_desc")) {
+                cli.sort_desc = sort_desc;
+            }
+            if let Some(flexible_csv) = config.flexible_csv.filter(|_| unset("flexible_csv")) {
+                cli.flexible_csv = flexible_csv;
+            }
+            if let Some(compress) = config.compress.filter(|_| unset("compress")) {
+                cli.compress = compress;
+            }
+            if let Some(sample) = config.sample.filter(|_| unset("sample")) {
+                cli.sample = Some(sample);
+            }
+            if let Some(seed) = config.seed.filter(|_| unset("seed")) {
+                cli.seed = Some(seed);
+            }
+            if let Some(delay) = config.delay.filter(|_| unset("delay")) {
+                cli.delay = Some(delay);
+            }
+            if let Some(jitter) = config.jitter.filter(|_| unset("jitter")) {
+                cli.jitter = Some(jitter);
+            }
+            if let Some(size) = config.max_output.filter(|_| unset("max_output")) {
+                cli.max_output = Some(parse_size(&size).map_err(|e| {
+                    anyhow::anyhow!("Invalid max_output in {}: {}", path.display(), e)
+                })?);
+            }
+            if let Some(webhook) = config.webhook.filter(|_| unset("webhook")) {
+                cli.webhook = Some(webhook);
+            }
+            if let Some(batch_size) = config
+                .webhook_batch_size
+                .filter(|_| unset("webhook_batch_size"))
+            {
+                cli.webhook_batch_size = batch_size;
+            }
+            if let Some(sqlite) = config.sqlite.filter(|_| unset("sqlite")) {
+                cli.sqlite = Some(sqlite);
+            }
+            if let Some(retries) = config.retries.filter(|_| unset("retries")) {
+                cli.retries = retries;
+            }
+            if let Some(budget) = config.retry_budget.filter(|_| unset("retry_budget")) {
+                cli.retry_budget = Some(budget);
+            }
+            if let Some(retry_failed) = config.retry_failed.filter(|_| unset("retry_failed")) {
+                cli.retry_failed = retry_failed;
+            }
+            if let Some(debug_dns) = config.debug_dns.filter(|_| unset("debug_dns")) {
+                cli.debug_dns = debug_dns;
+            }
+            if let Some(debug_host) = config.debug_host.filter(|_| unset("debug_host")) {
+                cli.debug_host = Some(debug_host);
+            }
+        }
+        // probing no IP would silently skip the certificates
+        if cli.max_tls_ips == 0 {
+            return Err(anyhow::anyhow!("--max-tls-ips must be at least 1"));
+        }
+        Ok(cli)
+    }
+}
+
+/// Keep the records selected by the command line filters: `--top` stops
+/// reading after N rows, `--min-popularity` drops the rows below the threshold.
+/// Malformed rows are kept so that they are still reported.
+fn filter_records<I>(
+    records: I,
+    min_popularity: Option<u32>,
+    top: Option<usize>,
+) -> impl Iterator<Item = Result<OriginRecord>>
+where
+    I: Iterator<Item = Result<OriginRecord>>,
+{
+    records
+        .take(top.unwrap_or(usize::MAX))
+        .filter(move |record| match (record, min_popularity) {
+            (Ok(record), Some(min_popularity)) => record.popularity >= min_popularity,
+            _ => true,
+        })
+}
+
+/// Apply the filters, the deduplication and the sampling selected on the
+/// command line, the sites being deduplicated with the public suffix list `psl`
+fn select_records(records: Records, cli: &Cli, psl: &List) -> Records {
+    let records = filter_records(records, cli.min_popularity, cli.top);
+    let records: Records = if cli.dedup || cli.dedup_www {
+        Box::new(dedup_records(records, cli.dedup_www, psl).into_iter())
+    } else {
+        Box::new(records)
+    };
+    match cli.sample {
+        Some(n) => {
+            let seed = cli.seed.unwrap_or_default();
+            Box::new(sample_records(records, n, seed).into_iter())
+        }
+        None => records,
+    }
+}
+
+/// Open the input file and deserialize its records according to its format
+fn open_records(cli: &Cli) -> Result<Records> {
+    // gzipped inputs are decompressed on the fly
+    let file = open_input(cli.input()?);
+    if cli.input_kind == InputKind::Ip {
+        let file = file.map_err(|e| anyhow::anyhow!("Failed to open IP list file: {}", e))?;
+        return Ok(Box::new(ip_records(file)));
+    }
+    let records: Records = match cli.input_format {
+        InputFormat::Csv => {
+            let file = file.map_err(|e| anyhow::anyhow!("Failed to open CSV file: {}", e))?;
+            if cli.flexible_csv {
+                Box::new(flexible_csv_records(file))
+            } else {
+                Box::new(csv_records(file))
+            }
+        }
+        InputFormat::Json => {
+            let file = file.map_err(|e| anyhow::anyhow!("Failed to open JSON file: {}", e))?;
+            Box::new(JsonArrayRecords::new(file))
+        }
+    };
+    Ok(records)
+}
+
+/// Count the number of records to process, taking the filters into account
+fn count_selected_records(cli: &Cli, csv_path: &str, psl: &List) -> Result<usize> {
+    let dedup = cli.dedup || cli.dedup_www;
+    let count = match (cli.input_kind, cli.input_format, cli.min_popularity, dedup) {
+        (InputKind::Origin, InputFormat::Csv, None, false) if cli.sample.is_none() => {
+            count_lines(csv_path)?
+        }
+        _ => select_records(open_records(cli)?, cli, psl).count(),
+    };
+    Ok(count.min(cli.top.unwrap_or(usize::MAX)))
+}
+
+/// Listen for Ctrl-C: the first one cancels the returned token so that the
+/// processing stops and the output is finished, a second one exits at once.
+/// Listening for the signal replaces its default handler.
+fn shutdown_on_ctrl_c() -> CancellationToken {
+    let shutdown = CancellationToken::new();
+    let on_ctrl_c = shutdown.clone();
+    spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if on_ctrl_c.is_cancelled() {
+                std::process::exit(130);
+            }
+            on_ctrl_c.cancel();
+        }
+    });
+    shutdown
+}
+
+/// Why the processing was stopped early: Ctrl-C, or else the output cap
+fn stop_reason(shutdown: &CancellationToken) -> &'static str {
+    match shutdown.is_cancelled() {
+        true => "Interrupted",
+        false => "Output cap reached",
+    }
+}
+
+/// Open the ASN database, showing the download progress in `bars` and
+/// cancelling the download when `cancel` is cancelled
+async fn open_asn_db_with_bar(
+    client: reqwest::Client,
+    timeout: Duration,
+    cancel: CancellationToken,
+    bars: MultiProgress,
+) -> Result<IpAsnMap> {
+    let bar = bars.add(ProgressBar::no_length());
+    bar.set_style(
+        ProgressStyle::with_template(
+            "ASN database [{bar:50.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec})",
+        )?
+        .progress_chars("= "),
+    );
+    let ip2asn_map = open_asn_db_with_progress(&client, timeout, &cancel, |downloaded, total| {
+        if let Some(total) = total {
+            bar.set_length(total);
+        }
+        bar.set_position(downloaded);
+    })
+    .await;
+    bar.finish_and_clear();
+    ip2asn_map
 }
 
 async fn process_all_records(
-    mut rdr: csv::Reader<File>,
-    chunk_size: usize,
+    records: Records,
     total_lines: usize,
-    custom_dns: Option<String>,
-    output: Option<PathBuf>,
+    cli: &Cli,
+    psl: Arc<List>,
 ) -> Result<()> {
+    // with --auto-concurrency, the adaptive limiter bounds the tasks of a batch
+    let chunk_size = if cli.auto_concurrency {
+        cli.max_concurrency.max(cli.min_concurrency).max(1)
+    } else {
+        cli.chunk_size
+    };
     // create a channel to communicate results
     let (tx, rx) = mpsc::channel::<Result<webinfo::IpInfo>>(chunk_size);
+    let rx = match cli.one_per_domain {
+        true => one_per_domain(rx, chunk_size),
+        false => rx,
+    };
 
     // spawn a task to handle results
-    handle_result(rx, output);
+    if let Some(fields) = &cli.fields {
+        for field in unknown_fields(fields) {
+            event!(Level::WARN, "Unknown output field: {}", field);
+        }
+    }
+    // a single HTTP client for the ASN database download and the webhook
+    let http_client = http_client()?;
+    let webhook = match &cli.webhook {
+        Some(_) if cli.output_format == OutputFormat::Zonefile => {
+            return Err(anyhow::anyhow!(
+                "--webhook sends JSON, it cannot be used with --output-format zonefile"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::SharedHosting => {
+            return Err(anyhow::anyhow!(
+                "--webhook sends the results, it cannot be used with --output-format shared-hosting"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::AsnSummary => {
+            return Err(anyhow::anyhow!(
+                "--webhook sends the results, it cannot be used with --output-format asn-summary"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::Grouped => {
+            return Err(anyhow::anyhow!(
+                "--webhook sends the results, it cannot be used with --output-format grouped"
+            ));
+        }
+        Some(url) => Some(
+            Webhook::new(url.clone())
+                .with_client(http_client.clone())
+                .with_batch_size(cli.webhook_batch_size),
+        ),
+        None => None,
+    };
+    let baseline = match &cli.baseline {
+        Some(_) if cli.output_format == OutputFormat::Zonefile => {
+            return Err(anyhow::anyhow!(
+                "--baseline outputs the changed results, it cannot be used with --output-format zonefile"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::SharedHosting => {
+            return Err(anyhow::anyhow!(
+                "--baseline outputs the changed results, it cannot be used with --output-format shared-hosting"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::AsnSummary => {
+            return Err(anyhow::anyhow!(
+                "--baseline outputs the changed results, it cannot be used with --output-format asn-summary"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::Events => {
+            return Err(anyhow::anyhow!(
+                "--baseline outputs the changed results, it cannot be used with --output-format events"
+            ));
+        }
+        Some(_) if cli.output_format == OutputFormat::JsonPatch && cli.fields.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--output-format json-patch patches whole results, it cannot be used with --fields"
+            ));
+        }
+        Some(path) => Some(Baseline::load(path)?),
+        None if cli.output_format == OutputFormat::JsonPatch => {
+            return Err(anyhow::anyhow!(
+                "--output-format json-patch requires --baseline"
+            ));
+        }
+        None => None,
+    };
+    let sqlite = match &cli.sqlite {
+        Some(_) if !matches!(cli.output_format, OutputFormat::Json | OutputFormat::Ndjson) => {
+            return Err(anyhow::anyhow!(
+                "--sqlite stores the results, it requires --output-format json or ndjson"
+            ));
+        }
+        Some(_) if cli.webhook.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--sqlite stores the results, it cannot be used with --webhook"
+            ));
+        }
+        Some(_) if cli.fields.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--sqlite stores whole results, it cannot be used with --fields"
+            ));
+        }
+        Some(_) if cli.baseline.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--sqlite stores all the results, it cannot be used with --baseline"
+            ));
+        }
+        Some(path) => Some(SqliteOutput::open(path)?.with_chunk_size(cli.chunk_size)),
+        None => None,
+    };
+    if cli.fields.is_some() && cli.output_format == OutputFormat::Events {
+        return Err(anyhow::anyhow!(
+            "--output-format events emits fixed events, it cannot be used with --fields"
+        ));
+    }
+    if cli.weight_by_popularity && cli.output_format != OutputFormat::AsnSummary {
+        return Err(anyhow::anyhow!(
+            "--weight-by-popularity weights the ASN summary, it requires --output-format asn-summary"
+        ));
+    }
+    if cli.sort_desc && cli.sort_output_by.is_none() {
+        return Err(anyhow::anyhow!("--sort-desc requires --sort-output-by"));
+    }
+    if let Some(sort_by) = cli.sort_output_by {
+        match (cli.output_format, sort_by) {
+            (OutputFormat::AsnSummary, SortBy::Popularity) if !cli.weight_by_popularity => {
+                return Err(anyhow::anyhow!(
+                    "--sort-output-by popularity requires --weight-by-popularity"
+                ));
+            }
+            (OutputFormat::AsnSummary, _)
+            | (OutputFormat::SharedHosting, SortBy::Count | SortBy::Name) => {}
+            (OutputFormat::SharedHosting, _) => {
+                return Err(anyhow::anyhow!(
+                    "the shared hosting groups can only be sorted by count or name"
+                ));
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "--sort-output-by requires --output-format asn-summary or shared-hosting"
+                ));
+            }
+        }
+    }
+    match cli.output_format {
+        _ if cli.max_output.is_none() => {}
+        OutputFormat::Zonefile
+        | OutputFormat::SharedHosting
+        | OutputFormat::AsnSummary
+        | OutputFormat::Grouped => {
+            return Err(anyhow::anyhow!(
+                "--max-output caps the streamed results, it cannot be used with an aggregated --output-format"
+            ));
+        }
+        _ if cli.webhook.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--max-output caps the written output, it cannot be used with --webhook"
+            ));
+        }
+        _ if cli.sqlite.is_some() => {
+            return Err(anyhow::anyhow!(
+                "--max-output caps the written output, it cannot be used with --sqlite"
+            ));
+        }
+        _ => {}
+    }
+    // on Ctrl-C, stop after the current batch so that the output is complete
+    let shutdown = shutdown_on_ctrl_c();
+    // the output cap stops the processing in the same way, without the Ctrl-C exit
+    let stop = shutdown.child_token();
+    let options = ResultOptions {
+        format: cli.output_format,
+        fields: cli.fields.clone(),
+        only_resolved: cli.only_resolved.clone(),
+        baseline,
+        trailing_dots: cli.trailing_dots,
+        weight_by_popularity: cli.weight_by_popularity,
+        sort: cli.sort_output_by.map(|sort_by| SortOrder {
+            key: sort_by.key(),
+            descending: cli.sort_desc,
+        }),
+        max_output: cli.max_output.map(|limit| OutputCap {
+            limit,
+            stop: stop.clone(),
+        }),
+        sqlite,
+    };
+    let writer = handle_result(rx, cli.output.clone(), cli.compress, options, webhook);
 
     // Initialize dns resolver
-    let resolver = get_resolver(custom_dns)
-        .map_err(|_| anyhow::anyhow!("Failed to create DNS resolver with default configuration"))?;
-    // Wrap the ASN map in an Arc for shared ownership
-    let ip2asn_map = open_asn_db()
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to open ASN database: {}", e))?;
-    let ip2asn_map = Arc::new(ip2asn_map);
+    let resolver = get_resolver(cli.dns.clone(), cli.bind)
+        .map_err(|e| anyhow::anyhow!("Failed to create DNS resolver: {}", e))?;
+    let options = BatchOptions {
+        // Concurrency limit per apex domain, shared by all the batches
+        apex_limiter: cli
+            .per_apex_limit
+            .map(|limit| Arc::new(ApexLimiter::new(limit))),
+        // Popularity rank bands, the default ones when no limit is given
+        rank_bands: cli.rank_bands.as_ref().map(|limits| {
+            if limits.is_empty() {
+                RankBands::default()
+            } else {
+                RankBands::new(limits.clone())
+            }
+        }),
+        pacer: pacer(cli),
+        retries: cli.retries,
+        // Retries shared by all the batches
+        retry_budget: cli
+            .retry_budget
+            .map(|budget| Arc::new(RetryBudget::new(budget))),
+        debug_hosts: match (cli.debug_dns, &cli.debug_host) {
+            (true, Some(hosts)) => Some(hosts.clone()),
+            (true, None) => return Err(anyhow::anyhow!("--debug-dns requires --debug-host")),
+            (false, _) => None,
+        },
+        adaptive_limiter: cli.auto_concurrency.then(|| {
+            Arc::new(AdaptiveLimiter::new(
+                cli.min_concurrency,
+                cli.max_concurrency,
+            ))
+        }),
+        progress: ProgressHandle::default(),
+        retry_queue: cli.retry_failed.then(RetryQueue::default),
+        accept_unknown_tld: cli.accept_unknown_tld,
+        starttls: cli.starttls.map(StartTlsProtocol::protocol),
+        latency: cli.latency_percentiles,
+        expiry_alert_days: cli.expiry_alert_days,
+        bind: cli.bind,
+        no_tls_asns: cli.no_tls_asn.clone(),
+        max_tls_ips: Some(cli.max_tls_ips),
+        blocklist: match &cli.blocklist {
+            Some(path) => Some(Arc::new(load_blocklist(path)?)),
+            None => None,
+        },
+        continue_on_asn_db_failure: cli.continue_on_asn_db_failure,
+    };
+    // the ASN database loads in the background while the first records are resolved
+    let bars = MultiProgress::new();
+    let asn_db = match &cli.asn_db {
+        Some(path) => {
+            let (path, format) = (path.clone(), cli.asn_format);
+            AsnDbPrefetch::spawn(async move {
+                tokio::task::spawn_blocking(move || load_asn_db(&path, format.loader().as_ref()))
+                    .await?
+            })
+        }
+        None if cli.asn_format != AsnFormat::Iptoasn => {
+            return Err(anyhow::anyhow!("--asn-format requires --asn-db"));
+        }
+        None => AsnDbPrefetch::spawn(open_asn_db_with_bar(
+            http_client.clone(),
+            Duration::from_secs(cli.asn_download_timeout),
+            shutdown.clone(),
+            bars.clone(),
+        )),
+    };
+
+    // prime the caches with the registrable domains before the timed run
+    if cli.warmup {
+        let now = Instant::now();
+        let records = select_records(open_records(cli)?, cli, &psl);
+        let apexes = warmup(records, &resolver, &psl, chunk_size).await;
+        event!(
+            Level::INFO,
+            "Warmup resolved {} registrable domains in {:.2} seconds",
+            apexes,
+            now.elapsed().as_secs_f64()
+        );
+    }
 
     // Create a progress bar
-    let bar = ProgressBar::new(total_lines as u64);
+    let bar = bars.add(ProgressBar::new(total_lines as u64));
     bar.set_style(ProgressStyle::with_template("[{bar:50.cyan/blue}] {msg}")?.progress_chars("= "));
     let mut progress = 0;
 
     // Implement chunking to limit the number of concurrent tasks
-    for chunk in chunked(rdr.deserialize::<OriginRecord>(), chunk_size) {
-        // Process each record in the chunk
+    // count the rows which could not be read, they are logged and skipped
+    let malformed = Cell::new(0);
+    let records = select_records(records, cli, &psl).inspect(|record| {
+        if record.is_err() {
+            malformed.set(malformed.get() + 1);
+        }
+    });
+    if cli.streaming {
         let now = SystemTime::now();
-        // process the current batch of records and get their task handles
-        let handles = process_batch_of_records(chunk, &resolver, &ip2asn_map, &tx);
-        // Wait for the current batch of tasks to complete
-        let _ = try_join_all(handles).await?;
-        // Update progress bar
-        bar.inc(chunk_size as u64);
-        progress += chunk_size;
-        bar.set_message(format!(
-            "{}/{}, {} records processed in {:.2} seconds",
-            HumanCount(progress.try_into()?),
-            HumanCount(total_lines.try_into()?),
-            chunk_size,
-            now.elapsed().unwrap().as_secs_f64()
-        ));
+        // the progress counts the records read, a few ahead of those processed
+        let records = records.take_while(|_| !stop.is_cancelled()).inspect(|_| {
+            bar.inc(1);
+            progress += 1;
+            bar.set_message(format!(
+                "{}/{} records read in {:.2} seconds",
+                HumanCount(progress as u64),
+                HumanCount(total_lines as u64),
+                now.elapsed().unwrap_or_default().as_secs_f64()
+            ));
+        });
+        stream_records(records, chunk_size, &resolver, &asn_db, &psl, &options, &tx).await?;
+        if stop.is_cancelled() {
+            event!(
+                Level::WARN,
+                "{}, stopping after {} records",
+                stop_reason(&shutdown),
+                progress
+            );
+        }
+        check_asn_db(&asn_db, options.continue_on_asn_db_failure)?;
+    } else {
+        for chunk in chunked(records, chunk_size) {
+            if stop.is_cancelled() {
+                event!(
+                    Level::WARN,
+                    "{}, stopping after {} records",
+                    stop_reason(&shutdown),
+                    progress
+                );
+                break;
+            }
+            // Process each record in the chunk
+            let now = SystemTime::now();
+            let processed = chunk.len();
+            // process the current batch of records and get their task handles
+            let handles = process_batch_of_records(chunk, &resolver, &asn_db, &psl, &options, &tx);
+            // Wait for the current batch of tasks to complete
+            let _ = try_join_all(handles).await?;
+            check_asn_db(&asn_db, options.continue_on_asn_db_failure)?;
+            // Update progress bar
+            bar.inc(processed as u64);
+            progress += processed;
+            bar.set_message(format!(
+                "{}/{}, {} records processed in {:.2} seconds",
+                HumanCount(progress.try_into()?),
+                HumanCount(total_lines.try_into()?),
+                processed,
+                now.elapsed().unwrap().as_secs_f64()
+            ));
+        }
+    }
+    if let Some(queue) = options.retry_queue.clone()
+        && !stop.is_cancelled()
+    {
+        retry_failed_records(queue, chunk_size, &resolver, &asn_db, &psl, &options, &tx).await?;
     }
     bar.finish();
-    Ok(())
+    options.progress.complete();
+    event!(
+        Level::INFO,
+        "{} records processed, {} failed",
+        options.progress.processed(),
+        options.progress.errors()
+    );
+    if let (true, Some(summary)) = (cli.latency_percentiles, latency_summary(&options.progress)) {
+        event!(Level::INFO, "{}", summary);
+        eprintln!("{}", summary);
+    }
+    if let Some(e) = asn_db.error() {
+        event!(Level::WARN, "Run completed without the ASN records: {}", e);
+        eprintln!("ASN database unavailable, the results have no ASN records");
+    }
+    if malformed.get() > 0 {
+        event!(Level::WARN, "{} malformed records skipped", malformed.get());
+        eprintln!(
+            "{} malformed records skipped, see the log file",
+            malformed.get()
+        );
+    }
+    // wait for the remaining results to be written
+    drop(tx);
+    let stats = writer.await?;
+    if shutdown.is_cancelled() {
+        std::process::exit(130);
+    }
+    match cli.expiry_alert_days {
+        Some(days) => expiry_alert(&stats.expiring, days),
+        None => Ok(()),
+    }
+}
+
+/// Print the hosts whose certificate expires within `days` to stderr, and
+/// fail the run when there is any so that a cron job notices
+fn expiry_alert(expiring: &[(String, Option<i64>)], days: u32) -> Result<()> {
+    if expiring.is_empty() {
+        return Ok(());
+    }
+    eprintln!(
+        "{} certificates expire within {} days:",
+        expiring.len(),
+        days
+    );
+    for (hostname, left) in expiring {
+        match left {
+            Some(left) if *left < 0 => eprintln!("  {} expired {} days ago", hostname, -left),
+            Some(left) => eprintln!("  {} expires in {} days", hostname, left),
+            None => eprintln!("  {}", hostname),
+        }
+    }
+    Err(anyhow::anyhow!(
+        "{} certificates expire within {} days",
+        expiring.len(),
+        days
+    ))
+}
+
+/// Relay the results of `rx` keeping the most popular one of each registrable
+/// domain, sent once all the results are received. The errors and the results
+/// without domain are relayed at once.
+fn one_per_domain(
+    mut rx: mpsc::Receiver<Result<IpInfo>>,
+    capacity: usize,
+) -> mpsc::Receiver<Result<IpInfo>> {
+    let (tx, selected) = mpsc::channel(capacity);
+    spawn(async move {
+        let mut selection = OnePerDomain::default();
+        while let Some(result) = rx.recv().await {
+            let result = match result {
+                Ok(info) => match selection.add(info) {
+                    Some(info) => Ok(info),
+                    None => continue,
+                },
+                Err(e) => Err(e),
+            };
+            let _ = tx.send(result).await;
+        }
+        for info in selection.results() {
+            let _ = tx.send(Ok(info)).await;
+        }
+    });
+    selected
+}
+
+/// Occupancy of the result channel observed by the writer
+#[derive(Debug, Default)]
+struct ChannelStats {
+    /// Highest number of results waiting in the channel
+    peak_occupancy: usize,
+    /// Number of times the writer found the channel full
+    full_count: usize,
+    /// Number of results dropped by --only-resolved
+    unresolved: usize,
+    /// Number of results unchanged since the baseline
+    unchanged: usize,
+    /// Hostnames flagged by --expiry-alert-days, with the days left
+    expiring: Vec<(String, Option<i64>)>,
+    /// Number of results dropped once the output cap was reached
+    over_cap: usize,
+}
+
+/// Size limit of the output: once it is reached, `stop` is cancelled so that
+/// no more records are processed, and the results still coming are dropped
+#[derive(Debug, Clone)]
+struct OutputCap {
+    limit: u64,
+    stop: CancellationToken,
+}
+
+/// Options of the results written by `handle_result`
+#[derive(Debug, Default)]
+struct ResultOptions {
+    /// Pretty-printed JSON, one compact JSON object per line or a zone file
+    format: OutputFormat,
+    /// Only output these fields
+    fields: Option<Vec<String>>,
+    /// Drop the results without address, or without the required records
+    only_resolved: Option<Vec<Require>>,
+    /// Only output the results which changed since a previous run
+    baseline: Option<Baseline>,
+    /// Trailing dot of the DNS names of the JSON results
+    trailing_dots: TrailingDots,
+    /// Rank the ASNs of the summary by the popularity of their hosts
+    weight_by_popularity: bool,
+    /// Order of the rows of the aggregations, instead of their default one
+    sort: Option<SortOrder>,
+    /// Size limit of the output
+    max_output: Option<OutputCap>,
+    /// SQLite database the results are inserted into instead of the output
+    sqlite: Option<SqliteOutput>,
+}
+
+/// Write a result as pretty-printed JSON or as a line of compact JSON,
+/// returning the number of bytes written
+fn write_value(writer: &mut Output, format: OutputFormat, value: &serde_json::Value) -> u64 {
+    let json = match format {
+        OutputFormat::Json | OutputFormat::Grouped => serde_json::to_string_pretty(value),
+        // compact JSON never contains a newline
+        OutputFormat::Ndjson
+        | OutputFormat::Zonefile
+        | OutputFormat::SharedHosting
+        | OutputFormat::JsonPatch
+        | OutputFormat::AsnSummary
+        | OutputFormat::Events => serde_json::to_string(value),
+    };
+    let json = json.unwrap();
+    writeln!(writer, "{}", json).expect("Failed to write to output");
+    json.len() as u64 + 1
 }
 
 ///
 /// Handle results received from the channel and print json to stdout
 /// @param rx Receiver channel
 /// @param output Optional output file path
+/// @param compress Whether the output is gzip-compressed
+/// @param options Format, fields, filters and baseline of the results
+/// @param webhook Optional HTTP collector the results are POSTed to instead
 ///
-fn handle_result(mut rx: mpsc::Receiver<Result<webinfo::IpInfo>>, output: Option<PathBuf>) {
-    let mut writer = get_writer(output);
+/// Writing is blocking I/O, so it runs on a dedicated blocking thread rather
+/// than on the async workers. When the output cannot keep up, the bounded
+/// channel fills and the tasks sending results wait, which applies
+/// backpressure to the whole pipeline. The channel occupancy is tracked and
+/// logged when all results are written.
+///
+fn handle_result(
+    mut rx: mpsc::Receiver<Result<webinfo::IpInfo>>,
+    output: Option<PathBuf>,
+    compress: bool,
+    options: ResultOptions,
+    webhook: Option<Webhook>,
+) -> tokio::task::JoinHandle<ChannelStats> {
+    let ResultOptions {
+        format,
+        fields,
+        only_resolved,
+        mut baseline,
+        trailing_dots,
+        weight_by_popularity,
+        sort,
+        max_output,
+        mut sqlite,
+    } = options;
+    let mut writer = get_writer(output, compress);
+    // the webhook requests are sent from the async runtime
+    let runtime = tokio::runtime::Handle::current();
+    let mut batcher = webhook.map(Webhook::batcher);
     // Handle results received from the channel
-    tokio::spawn(async move {
-        while let Some(result) = rx.recv().await {
+    tokio::task::spawn_blocking(move || {
+        let mut stats = ChannelStats::default();
+        // the aggregations are updated as the results arrive, the zone file
+        // groups the records of all the results by apex
+        let mut zonefile = ZoneFile::default();
+        // the shared hosting groups only keep the hostnames of the results
+        let mut shared_hosting = SharedHosting::default();
+        // the ASN summary only keeps a count per ASN
+        let mut asn_summary = match weight_by_popularity {
+            true => AsnSummary::weighted_by_popularity(),
+            false => AsnSummary::default(),
+        };
+        // the grouped output keeps the whole results until the end
+        let mut domain_groups = DomainGroups::default();
+        // bytes of the results written, checked against the output cap
+        let mut written = 0;
+        loop {
+            let occupancy = rx.len();
+            stats.peak_occupancy = stats.peak_occupancy.max(occupancy);
+            if occupancy == rx.max_capacity() {
+                stats.full_count += 1;
+            }
+            let Some(result) = rx.blocking_recv() else {
+                break;
+            };
+            // the results of the records in flight are drained and dropped
+            if let Some(cap) = &max_output
+                && written >= cap.limit
+            {
+                if !cap.stop.is_cancelled() {
+                    event!(
+                        Level::WARN,
+                        "Output cap of {} bytes reached, stopping the scan",
+                        cap.limit
+                    );
+                    cap.stop.cancel();
+                }
+                stats.over_cap += 1;
+                continue;
+            }
+            if let Ok(info) = &result
+                && info.records.expiring_soon == Some(true)
+            {
+                let left = info
+                    .records
+                    .tls
+                    .as_ref()
+                    .and_then(|tls| tls.days_until_expiry());
+                stats.expiring.push((info.records.hostname.clone(), left));
+            }
+            // the unresolved hosts are dropped, the errors are still logged
+            if let (Ok(info), Some(require)) = (&result, &only_resolved)
+                && !is_resolved(info, require)
+            {
+                stats.unresolved += 1;
+                continue;
+            }
             match result {
-                Ok(info) => {
-                    writeln!(writer, "{}", serde_json::to_string_pretty(&info).unwrap())
-                        .expect("Failed to write to output");
+                Ok(info) if format == OutputFormat::Zonefile => zonefile.add(&info),
+                Ok(info) if format == OutputFormat::SharedHosting => shared_hosting.add(&info),
+                Ok(info) if format == OutputFormat::AsnSummary => asn_summary.add(&info),
+                Ok(mut info) if format == OutputFormat::Events => {
+                    trailing_dots.normalize(&mut info);
+                    for event in to_events(&info) {
+                        let value = serde_json::to_value(event).unwrap();
+                        match &mut batcher {
+                            Some(batcher) => runtime.block_on(batcher.push(value)),
+                            None => written += write_value(&mut writer, format, &value),
+                        }
+                    }
+                }
+                Ok(mut info) if format == OutputFormat::JsonPatch => {
+                    trailing_dots.normalize(&mut info);
+                    let Some(baseline) = &mut baseline else {
+                        continue;
+                    };
+                    let patch = baseline.patch(&info);
+                    if patch.is_empty() {
+                        stats.unchanged += 1;
+                        continue;
+                    }
+                    let value = serde_json::to_value(patch).unwrap();
+                    match &mut batcher {
+                        Some(batcher) => runtime.block_on(batcher.push(value)),
+                        None => written += write_value(&mut writer, format, &value),
+                    }
+                }
+                Ok(mut info) => {
+                    trailing_dots.normalize(&mut info);
+                    // with a baseline, only the changed results are output
+                    let changes = baseline.as_mut().map(|baseline| baseline.changes(&info));
+                    if changes.as_ref().is_some_and(Vec::is_empty) {
+                        stats.unchanged += 1;
+                        continue;
+                    }
+                    let mut value = match &fields {
+                        Some(fields) => project(&serde_json::to_value(&info).unwrap(), fields),
+                        None => serde_json::to_value(&info).unwrap(),
+                    };
+                    if let Some(changes) = changes {
+                        value["changes"] = serde_json::to_value(changes).unwrap();
+                    }
+                    if format == OutputFormat::Grouped {
+                        domain_groups.add(&info, value);
+                        continue;
+                    }
+                    if let Some(sqlite) = &mut sqlite {
+                        if let Err(e) = sqlite.add(info) {
+                            event!(Level::ERROR, "{}", e);
+                        }
+                        continue;
+                    }
+                    if let Some(batcher) = &mut batcher {
+                        runtime.block_on(batcher.push(value));
+                        continue;
+                    }
+                    written += write_value(&mut writer, format, &value);
                 }
                 Err(e) => event!(Level::ERROR, "{}", e),
             }
         }
-    });
+        // the hosts of the baseline missing from the results were removed,
+        // unless the scan was cut short by the output cap
+        let cut_short = max_output.is_some_and(|cap| cap.stop.is_cancelled());
+        let removed = baseline.filter(|_| !cut_short).map(Baseline::removed);
+        for mut info in removed.unwrap_or_default() {
+            if format == OutputFormat::JsonPatch {
+                let value = serde_json::to_value([Baseline::remove_op(&info)]).unwrap();
+                match &mut batcher {
+                    Some(batcher) => runtime.block_on(batcher.push(value)),
+                    None => {
+                        write_value(&mut writer, format, &value);
+                    }
+                }
+                continue;
+            }
+            trailing_dots.normalize(&mut info);
+            let mut value = match &fields {
+                Some(fields) => project(&serde_json::to_value(&info).unwrap(), fields),
+                None => serde_json::to_value(&info).unwrap(),
+            };
+            value["changes"] = serde_json::to_value([Change::RemovedHost]).unwrap();
+            if format == OutputFormat::Grouped {
+                domain_groups.add(&info, value);
+                continue;
+            }
+            match &mut batcher {
+                Some(batcher) => runtime.block_on(batcher.push(value)),
+                None => {
+                    write_value(&mut writer, format, &value);
+                }
+            }
+        }
+        if format == OutputFormat::Zonefile {
+            write!(writer, "{}", zonefile.render()).expect("Failed to write to output");
+        }
+        if format == OutputFormat::SharedHosting {
+            let groups = match sort {
+                Some(order) => shared_hosting.groups_sorted_by(order),
+                None => shared_hosting.groups(),
+            };
+            for group in groups {
+                let json = serde_json::to_string(&group).unwrap();
+                writeln!(writer, "{}", json).expect("Failed to write to output");
+            }
+        }
+        if format == OutputFormat::Grouped {
+            write_value(&mut writer, format, &domain_groups.render());
+        }
+        if format == OutputFormat::AsnSummary {
+            let rows = match sort {
+                Some(order) => asn_summary.rows_sorted_by(order),
+                None => asn_summary.rows(),
+            };
+            for row in rows {
+                let json = serde_json::to_string(&row).unwrap();
+                writeln!(writer, "{}", json).expect("Failed to write to output");
+            }
+        }
+        writer.finish().expect("Failed to finish output");
+        if let Some(Err(e)) = sqlite.map(SqliteOutput::finish) {
+            event!(Level::ERROR, "{}", e);
+        }
+        if let Some(batcher) = batcher {
+            let failed = runtime.block_on(batcher.finish());
+            if failed > 0 {
+                event!(
+                    Level::ERROR,
+                    "{} batches could not be sent to the webhook",
+                    failed
+                );
+            }
+        }
+        if only_resolved.is_some() {
+            event!(
+                Level::INFO,
+                "{} unresolved results dropped",
+                stats.unresolved
+            );
+        }
+        if stats.unchanged > 0 {
+            event!(
+                Level::INFO,
+                "{} results unchanged since the baseline",
+                stats.unchanged
+            );
+        }
+        if stats.over_cap > 0 {
+            event!(
+                Level::WARN,
+                "{} results dropped over the output cap",
+                stats.over_cap
+            );
+        }
+        event!(
+            Level::INFO,
+            "Result channel peak occupancy {}/{}, full {} times",
+            stats.peak_occupancy,
+            rx.max_capacity(),
+            stats.full_count
+        );
+        stats
+    })
 }
+
+/// Install the subscriber of `--log-level`, `--log-format` and `--log-stderr`.
+/// The logs are written by a background thread until the returned guard is dropped.
+fn init_logging(cli: &Cli) -> Result<tracing_appender::non_blocking::WorkerGuard> {
+    let (writer, guard) = if cli.log_stderr {
+        tracing_appender::non_blocking(std::io::stderr())
+    } else {
+        let file_appender = tracing_appender::rolling::daily(
+            cli.logfile.parent().unwrap(),
+            cli.logfile.file_name().unwrap(),
+        );
+        tracing_appender::non_blocking(file_appender)
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(cli.log_level.level())
+        .with_timer(tracing_subscriber::fmt::time::SystemTime)
+        .with_writer(writer)
+        .with_ansi(false);
+    let subscriber: Box<dyn tracing::Subscriber + Send + Sync> = match cli.log_format {
+        LogFormat::Compact => Box::new(builder.compact().finish()),
+        // one JSON object per line, the fields beside the timestamp, level and target
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .finish(),
+        ),
+        LogFormat::Pretty => Box::new(builder.pretty().finish()),
+    };
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|_| anyhow::anyhow!("Failed to set global default subscriber"))?;
+    Ok(guard)
+}
+
 //******************************************************************************
 //
 // Main function
@@ -168,29 +2127,35 @@ fn handle_result(mut rx: mpsc::Receiver<Result<webinfo::IpInfo>>, output: Option
 //******************************************************************************
 #[tokio::main]
 async fn main() -> Result<()> {
-    let timer = tracing_subscriber::fmt::time::SystemTime;
-    let cli = Cli::parse();
+    let mut cli = Cli::parse_with_config(std::env::args_os())?;
+    // like --version, the input file is not needed
+    if cli.print_features {
+        print!("{}", features_report());
+        return Ok(());
+    }
 
-    // Initialize logging
-    let file_appender = tracing_appender::rolling::daily(
-        cli.logfile.parent().unwrap(),
-        cli.logfile.file_name().unwrap(),
-    );
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .compact()
-        .with_timer(timer)
-        .with_writer(non_blocking)
-        .with_ansi(false)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
-        .map_err(|_| anyhow::anyhow!("Failed to set global default subscriber"))?;
+    // Initialize logging, the guard flushes the logs when dropped at the end of main
+    let _guard = init_logging(&cli)?;
 
-    let csv_path = cli.csv;
+    // the sample is drawn twice, to count and to process the records, with the same seed
+    if cli.sample.is_some() || cli.jitter.is_some() {
+        let seed = *cli.seed.get_or_insert_with(rand::random);
+        event!(
+            Level::INFO,
+            "Sampling the records and jitter with seed {}",
+            seed
+        );
+    }
+    let csv_path = cli.input()?;
     let csv_path_str = csv_path
         .to_str()
         .ok_or_else(|| anyhow::anyhow!("Failed to convert CSV path to string"))?;
-    let line_count = count_lines(csv_path_str)?;
+    // Public suffix list shared by the selection and the processing of the records
+    let psl = match &cli.psl_file {
+        Some(path) => Arc::new(load_psl(path)?),
+        None => default_psl(),
+    };
+    let line_count = count_selected_records(&cli, csv_path_str, &psl)?;
 
     event!(
         Level::INFO,
@@ -199,11 +2164,11 @@ async fn main() -> Result<()> {
         line_count
     );
 
-    // open the CSV file
-    let rdr = csv::Reader::from_path(&csv_path)?;
+    // open the input file
+    let records = open_records(&cli)?;
 
     // process chunk_size records concurrently
-    process_all_records(rdr, cli.chunk_size, line_count, cli.dns, cli.output).await?;
+    process_all_records(records, line_count, &cli, psl).await?;
     Ok(())
 }
 
@@ -222,18 +2187,1270 @@ mod tests {
         // Initialize dns resolver using the host OS'es `/etc/resolv.conf`
         let resolver = Resolver::builder_tokio().unwrap().build();
         // Wrap the ASN map in an Arc for shared ownership
-        let ip2asn_map = open_asn_db().await.unwrap();
-        let ip2asn_map = Arc::new(ip2asn_map);
+        let ip2asn_map = webinfo::utils::open_asn_db().await.unwrap();
+        let asn_db = AsnDbPrefetch::ready(Arc::new(ip2asn_map));
 
         let file = assert_fs::NamedTempFile::new("sample.txt").unwrap();
         file.write_str(
             "origin,popularity,date,country\nhttps://www.google.fr,1000,2025-08-28,FR\n",
         )
         .unwrap();
-        let mut rdr = csv::Reader::from_path(file.path()).unwrap();
-        let records = rdr.deserialize::<OriginRecord>().collect::<Vec<_>>();
-        let handles =
-            process_batch_of_records(records, &resolver, &ip2asn_map, &mpsc::channel(1).0);
+        let records = csv_records(File::open(file.path()).unwrap()).collect::<Vec<_>>();
+        let handles = process_batch_of_records(
+            records,
+            &resolver,
+            &asn_db,
+            &default_psl(),
+            &BatchOptions::default(),
+            &mpsc::channel(1).0,
+        );
         assert_eq!(handles.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_process_batch_of_records_delay() {
+        let resolver = Resolver::builder_tokio().unwrap().build();
+        let ip2asn_map = ip2asn::Builder::new()
+            .with_source("".as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        // the origins are rejected before any query, only the delay takes time
+        let data = "origin,popularity,date,country\n".to_string()
+            + &"not an origin,1,2025-08-28,FR\n".repeat(4);
+        let records = csv_records(data.as_bytes()).collect::<Vec<_>>();
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv", "--delay", "30"]);
+        let options = BatchOptions {
+            pacer: pacer(&cli),
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(4);
+        let now = std::time::Instant::now();
+        let handles = process_batch_of_records(
+            records,
+            &resolver,
+            &AsnDbPrefetch::ready(Arc::new(ip2asn_map)),
+            &default_psl(),
+            &options,
+            &tx,
+        );
+        try_join_all(handles).await.unwrap();
+        // the 4 tasks start 30 ms apart
+        assert!(now.elapsed() >= std::time::Duration::from_millis(90));
+        for _ in 0..4 {
+            assert!(rx.recv().await.unwrap().is_err());
+        }
+    }
+
+    /// DNS server answering the A queries with 192.0.2.1, or every query
+    /// with SERVFAIL while `failing` is set, and a resolver querying it
+    async fn flaky_dns(
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    ) -> Resolver<TokioConnectionProvider> {
+        logged_flaky_dns(failing).await.0
+    }
+
+    /// Name and type of the queries received by a DNS server
+    type QueryLog = Arc<Mutex<Vec<(String, hickory_proto::rr::RecordType)>>>;
+
+    /// `flaky_dns` logging the queries it receives
+    async fn logged_flaky_dns(
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    ) -> (Resolver<TokioConnectionProvider>, QueryLog) {
+        use hickory_proto::{
+            op::{Message, MessageType, ResponseCode},
+            rr::{RData, Record, RecordType, rdata::A},
+            xfer::Protocol,
+        };
+        use hickory_resolver::config::{NameServerConfig, ResolverConfig};
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let log = QueryLog::default();
+        let queries = log.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(request) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                if let Some(query) = request.query() {
+                    let name = query.name().to_lowercase().to_string();
+                    queries.lock().unwrap().push((name, query.query_type()));
+                }
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_queries(request.queries().to_vec());
+                if failing.load(Ordering::SeqCst) {
+                    response.set_response_code(ResponseCode::ServFail);
+                } else if let Some(query) = request.query()
+                    && query.query_type() == RecordType::A
+                {
+                    response.add_answer(Record::from_rdata(
+                        query.name().clone(),
+                        300,
+                        RData::A(A::new(192, 0, 2, 1)),
+                    ));
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        let name_server = NameServerConfig::new(addr, Protocol::Udp);
+        let config = ResolverConfig::from_parts(None, vec![], vec![name_server]);
+        let mut builder = Resolver::builder_with_config(config, TokioConnectionProvider::default());
+        builder.options_mut().attempts = 0;
+        builder.options_mut().cache_size = 0;
+        (builder.build(), log)
+    }
+
+    #[tokio::test]
+    async fn test_warmup() {
+        use hickory_proto::rr::RecordType;
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let (resolver, queries) = logged_flaky_dns(failing).await;
+        let data = "origin,popularity,date,country\n\
+            https://www.example.com,1000,2025-08-28,FR\n\
+            https://api.example.com,500,2025-08-28,FR\n\
+            https://www.example.org,100,2025-08-28,FR\n";
+        let apexes = warmup(csv_records(data.as_bytes()), &resolver, &default_psl(), 2).await;
+        // a single A query per registrable domain
+        assert_eq!(apexes, 2);
+        let mut warmup_queries = std::mem::take(&mut *queries.lock().unwrap());
+        warmup_queries.sort();
+        assert_eq!(
+            warmup_queries,
+            [
+                ("example.com.".to_string(), RecordType::A),
+                ("example.org.".to_string(), RecordType::A)
+            ]
+        );
+
+        // the main pass proceeds afterward on the hosts
+        let asn_db = AsnDbPrefetch::ready(Arc::new(
+            ip2asn::Builder::new()
+                .with_source("".as_bytes())
+                .unwrap()
+                .build()
+                .unwrap(),
+        ));
+        let records = csv_records(data.as_bytes()).take(1).collect::<Vec<_>>();
+        let options = BatchOptions::default();
+        let (tx, mut rx) = mpsc::channel(4);
+        let handles =
+            process_batch_of_records(records, &resolver, &asn_db, &default_psl(), &options, &tx);
+        try_join_all(handles).await.unwrap();
+        let info = rx.recv().await.unwrap().unwrap();
+        assert_eq!(info.records.ip, Some(vec![IpAddr::from([192, 0, 2, 1])]));
+        assert!(
+            queries
+                .lock()
+                .unwrap()
+                .contains(&("www.example.com.".to_string(), RecordType::A))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_records() {
+        let resolver = flaky_dns(Arc::new(std::sync::atomic::AtomicBool::new(false))).await;
+        // the tasks wait for the ASN database until it is released
+        let (release, loaded) = tokio::sync::oneshot::channel::<()>();
+        let asn_db = AsnDbPrefetch::spawn(async move {
+            let _ = loaded.await;
+            ip2asn::Builder::new()
+                .with_source("".as_bytes())?
+                .build()
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        });
+        let read = Cell::new(0);
+        let records = (1..=6)
+            .map(|i| Ok(OriginRecord::new(format!("192.0.2.{}", i), 100)))
+            .inspect(|_| read.set(read.get() + 1));
+        let options = BatchOptions::default();
+        let psl = default_psl();
+        let (tx, mut rx) = mpsc::channel(8);
+        let stream = stream_records(records, 2, &resolver, &asn_db, &psl, &options, &tx);
+        let check = async {
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+            while options.progress.in_flight() < 2 {
+                assert!(tokio::time::Instant::now() < deadline);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // the input is read ahead while the 2 workers are busy, a batch
+            // would only have read its 2 records
+            assert_eq!(options.progress.in_flight(), 2);
+            assert_eq!(options.progress.processed(), 0);
+            assert!(read.get() > 2, "{} records read", read.get());
+            release.send(()).unwrap();
+        };
+        let (processed, ()) = tokio::join!(stream, check);
+        processed.unwrap();
+        drop(tx);
+        let mut results = 0;
+        while let Some(result) = rx.recv().await {
+            result.unwrap();
+            results += 1;
+        }
+        assert_eq!(results, 6);
+        assert_eq!(options.progress.processed(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_asn_db_failure() {
+        let resolver = flaky_dns(Arc::new(std::sync::atomic::AtomicBool::new(false))).await;
+        let asn_db = AsnDbPrefetch::spawn(async {
+            Err::<IpAsnMap, _>(anyhow::anyhow!("iptoasn.com is down"))
+        });
+        asn_db.get().await;
+        let read = Cell::new(0);
+        let records = (1..=100)
+            .map(|i| Ok(OriginRecord::new(format!("192.0.2.{}", i), 100)))
+            .inspect(|_| read.set(read.get() + 1));
+        let options = BatchOptions::default();
+        let (tx, _rx) = mpsc::channel(128);
+        let error = stream_records(
+            records,
+            2,
+            &resolver,
+            &asn_db,
+            &default_psl(),
+            &options,
+            &tx,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to open ASN database: iptoasn.com is down"
+        );
+        // the scan stops early instead of reading the whole input
+        assert!(read.get() < 100, "{} records read", read.get());
+        assert_eq!(options.progress.processed(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_continue_on_asn_db_failure() {
+        let resolver = flaky_dns(Arc::new(std::sync::atomic::AtomicBool::new(false))).await;
+        let asn_db = AsnDbPrefetch::spawn(async {
+            Err::<IpAsnMap, _>(anyhow::anyhow!("iptoasn.com is down"))
+        });
+        let data = "origin,popularity,date,country\nhttps://www.example.com,1000,2025-08-28,FR\n";
+        let records = csv_records(data.as_bytes()).collect::<Vec<_>>();
+        let options = BatchOptions::default();
+        let (tx, mut rx) = mpsc::channel(4);
+        let handles =
+            process_batch_of_records(records, &resolver, &asn_db, &default_psl(), &options, &tx);
+        try_join_all(handles).await.unwrap();
+        // the DNS records are there, without ASN
+        let info = rx.recv().await.unwrap().unwrap();
+        assert_eq!(info.records.ip, Some(vec![IpAddr::from([192, 0, 2, 1])]));
+        assert!(info.records.asn.is_none());
+        let error = check_asn_db(&asn_db, false).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to open ASN database: iptoasn.com is down"
+        );
+        check_asn_db(&asn_db, true).unwrap();
+    }
+
+    #[test]
+    fn test_latency_summary() {
+        let progress = ProgressHandle::default();
+        assert_eq!(latency_summary(&progress), None);
+        for ms in [4, 8, 16, 32] {
+            progress.latency().record(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            latency_summary(&progress).unwrap(),
+            "DNS lookup latency over 4 queries: p50 7.9 ms, p90 31.7 ms, p99 31.7 ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_records() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let resolver = flaky_dns(failing.clone()).await;
+        let ip2asn_map = ip2asn::Builder::new()
+            .with_source("".as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let asn_db = AsnDbPrefetch::ready(Arc::new(ip2asn_map));
+        let data = "origin,popularity,date,country\nhttps://www.example.com,1000,2025-08-28,FR\n";
+        let records = csv_records(data.as_bytes()).collect::<Vec<_>>();
+        let queue = RetryQueue::default();
+        let options = BatchOptions {
+            retry_queue: Some(queue.clone()),
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(4);
+        let handles =
+            process_batch_of_records(records, &resolver, &asn_db, &default_psl(), &options, &tx);
+        try_join_all(handles).await.unwrap();
+        // the record failed with SERVFAIL, it is queued instead of output
+        assert!(rx.try_recv().is_err());
+        assert_eq!(queue.lock().unwrap().len(), 1);
+
+        failing.store(false, Ordering::SeqCst);
+        retry_failed_records(
+            queue.clone(),
+            5,
+            &resolver,
+            &asn_db,
+            &default_psl(),
+            &options,
+            &tx,
+        )
+        .await
+        .unwrap();
+        let info = rx.recv().await.unwrap().unwrap();
+        assert_eq!(info.origin.origin, "https://www.example.com");
+        assert_eq!(info.records.ip, Some(vec![IpAddr::from([192, 0, 2, 1])]));
+        assert!(queue.lock().unwrap().is_empty());
+    }
+
+    fn sample_records() -> Vec<Result<OriginRecord>> {
+        let data = "origin,popularity,date,country\n\
+            https://www.google.fr,1000,2025-08-28,FR\n\
+            https://www.free.fr,5000,2025-08-28,FR\n\
+            https://www.lefigaro.fr,10000,2025-08-28,FR\n\
+            https://www.example.com,1000,2025-08-28,FR\n";
+        csv_records(data.as_bytes()).collect()
+    }
+
+    fn origins(records: impl Iterator<Item = Result<OriginRecord>>) -> Vec<String> {
+        records.map(|r| r.unwrap().origin).collect()
+    }
+
+    #[test]
+    fn test_filter_records_min_popularity() {
+        let records = filter_records(sample_records().into_iter(), Some(5000), None);
+        assert_eq!(
+            origins(records),
+            vec!["https://www.free.fr", "https://www.lefigaro.fr"]
+        );
+    }
+
+    #[test]
+    fn test_filter_records_top() {
+        let records = filter_records(sample_records().into_iter(), None, Some(2));
+        assert_eq!(
+            origins(records),
+            vec!["https://www.google.fr", "https://www.free.fr"]
+        );
+    }
+
+    #[test]
+    fn test_filter_records_top_and_min_popularity() {
+        let records = filter_records(sample_records().into_iter(), Some(5000), Some(2));
+        assert_eq!(origins(records), vec!["https://www.free.fr"]);
+    }
+
+    #[test]
+    fn test_count_selected_records() {
+        let file = assert_fs::NamedTempFile::new("sample.csv").unwrap();
+        file.write_str(
+            "origin,popularity,date,country\n\
+            https://www.google.fr,1000,2025-08-28,FR\n\
+            https://www.free.fr,5000,2025-08-28,FR\n\
+            https://www.lefigaro.fr,10000,2025-08-28,FR\n",
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+        let cli = Cli::parse_from(["webinfo", "--csv", path, "--min-popularity", "5000"]);
+        assert_eq!(
+            count_selected_records(&cli, path, &default_psl()).unwrap(),
+            2
+        );
+        let cli = Cli::parse_from(["webinfo", "--csv", path, "--top", "1"]);
+        assert_eq!(
+            count_selected_records(&cli, path, &default_psl()).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_open_records_json() {
+        let file = assert_fs::NamedTempFile::new("sample.json").unwrap();
+        file.write_str(
+            r#"[{"origin": "https://www.free.fr", "popularity": 5000, "date": "2025-08-28", "country": "FR"},
+            {"origin": "https://www.google.fr", "popularity": 1000, "date": "2025-08-28", "country": "FR"}]"#,
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+        let cli = Cli::parse_from(["webinfo", "--input", path, "--input-format", "json"]);
+        let records = open_records(&cli).unwrap();
+        assert_eq!(
+            origins(records),
+            vec!["https://www.free.fr", "https://www.google.fr"]
+        );
+        assert_eq!(
+            count_selected_records(&cli, path, &default_psl()).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_select_records_dedup_www() {
+        let file = assert_fs::NamedTempFile::new("sample.csv").unwrap();
+        file.write_str(
+            "origin,popularity,date,country\n\
+            https://www.free.fr,1000,2025-08-28,FR\n\
+            https://free.fr,5000,2025-08-28,FR\n\
+            https://www.free.fr,2000,2025-08-28,FR\n\
+            https://www.google.fr,1000,2025-08-28,FR\n",
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+        let cli = Cli::parse_from(["webinfo", "--csv", path, "--dedup"]);
+        let records = select_records(open_records(&cli).unwrap(), &cli, &default_psl());
+        assert_eq!(
+            origins(records),
+            vec![
+                "https://www.free.fr",
+                "https://free.fr",
+                "https://www.google.fr"
+            ]
+        );
+        let cli = Cli::parse_from(["webinfo", "--csv", path, "--dedup-www"]);
+        let records = select_records(open_records(&cli).unwrap(), &cli, &default_psl());
+        assert_eq!(
+            origins(records),
+            vec!["https://free.fr", "https://www.google.fr"]
+        );
+        assert_eq!(
+            count_selected_records(&cli, path, &default_psl()).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_parse_with_config() {
+        let config = assert_fs::NamedTempFile::new("webinfo.toml").unwrap();
+        config
+            .write_str(
+                "input-format = \"json\"\n\
+                size = 50\n\
+                dns = \"1.1.1.1\"\n\
+                output = \"results.json\"\n\
+                top = 100\n\
+                dedup-www = true\n\
+                asn-db = \"ipasn.dat\"\n\
+                asn-format = \"pyasn\"\n",
+            )
+            .unwrap();
+        let path = config.path().to_str().unwrap();
+        let cli =
+            Cli::parse_with_config(["webinfo", "--csv", "in.json", "--config", path, "-s", "10"])
+                .unwrap();
+        // the command line overrides the file, which overrides the defaults
+        assert_eq!(cli.chunk_size, 10);
+        assert_eq!(cli.input_format, InputFormat::Json);
+        assert_eq!(cli.dns.as_deref(), Some("1.1.1.1"));
+        assert_eq!(cli.output, Some(PathBuf::from("results.json")));
+        assert_eq!(cli.top, Some(100));
+        assert!(cli.dedup_www);
+        assert!(!cli.dedup);
+        assert_eq!(cli.min_popularity, None);
+        assert_eq!(cli.logfile, PathBuf::from("./webinfo.log"));
+        assert_eq!(cli.asn_db, Some(PathBuf::from("ipasn.dat")));
+        assert_eq!(cli.asn_format, AsnFormat::Pyasn);
+
+        config.write_str("sizes = 50\n").unwrap();
+        assert!(Cli::parse_with_config(["webinfo", "--csv", "in.json", "--config", path]).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_tls_ips() {
+        let cli = Cli::parse_with_config(["webinfo", "--csv", "in.csv"]).unwrap();
+        assert_eq!(cli.max_tls_ips, 3);
+        let config = assert_fs::NamedTempFile::new("scan.toml").unwrap();
+        config.write_str("max-tls-ips = 8\n").unwrap();
+        let path = config.path().to_str().unwrap();
+        let cli = Cli::parse_with_config(["webinfo", "--csv", "in.csv", "--config", path]).unwrap();
+        assert_eq!(cli.max_tls_ips, 8);
+        // no IP would be probed
+        assert!(
+            Cli::parse_with_config(["webinfo", "--csv", "in.csv", "--max-tls-ips", "0"]).is_err()
+        );
+        config.write_str("max-tls-ips = 0\n").unwrap();
+        assert!(Cli::parse_with_config(["webinfo", "--csv", "in.csv", "--config", path]).is_err());
+    }
+
+    #[test]
+    fn test_open_records_ip() {
+        let file = assert_fs::NamedTempFile::new("ips.txt").unwrap();
+        file.write_str("192.0.2.1\n\n2001:db8::1\n").unwrap();
+        let path = file.path().to_str().unwrap();
+        let cli = Cli::parse_from(["webinfo", "--input", path, "--input-kind", "ip"]);
+        let records = open_records(&cli).unwrap();
+        assert_eq!(origins(records), vec!["192.0.2.1", "2001:db8::1"]);
+        assert_eq!(
+            count_selected_records(&cli, path, &default_psl()).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_open_records_flexible_csv() {
+        let file = assert_fs::NamedTempFile::new("ragged.csv").unwrap();
+        file.write_str(
+            "origin,popularity,date,country\n\
+            https://www.google.fr,1000\n\
+            https://www.free.fr,5000,2025-08-28,FR,extra\n",
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+        let cli = Cli::parse_from(["webinfo", "--csv", path]);
+        let records = open_records(&cli).unwrap().collect::<Vec<_>>();
+        assert!(records.iter().all(|r| r.is_err()));
+        let cli = Cli::parse_from(["webinfo", "--csv", path, "--flexible-csv"]);
+        let records = open_records(&cli).unwrap();
+        assert_eq!(
+            origins(records),
+            vec!["https://www.google.fr", "https://www.free.fr"]
+        );
+    }
+
+    #[test]
+    fn test_open_records_gzip() {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+        let content = "origin,popularity,date,country\n\
+            https://www.google.fr,1000,2025-08-28,FR\n\
+            https://www.free.fr,5000,2025-08-28,FR\n";
+        let plain = assert_fs::NamedTempFile::new("origins.csv").unwrap();
+        plain.write_str(content).unwrap();
+        let gzipped = assert_fs::NamedTempFile::new("origins.csv.gz").unwrap();
+        let mut encoder =
+            GzEncoder::new(File::create(gzipped.path()).unwrap(), Compression::best());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let open = |path: &str| {
+            let cli = Cli::parse_from(["webinfo", "--csv", path]);
+            let records = open_records(&cli).unwrap();
+            (
+                origins(records),
+                count_selected_records(&cli, path, &default_psl()).unwrap(),
+            )
+        };
+        let from_plain = open(plain.path().to_str().unwrap());
+        let from_gzip = open(gzipped.path().to_str().unwrap());
+        assert_eq!(from_gzip.0.len(), 2);
+        assert_eq!(from_gzip, from_plain);
+    }
+
+    #[test]
+    fn test_select_records_sample() {
+        let file = assert_fs::NamedTempFile::new("sample.csv").unwrap();
+        let mut content = String::from("origin,popularity,date,country\n");
+        for i in 0..100 {
+            content.push_str(&format!("https://www.site{}.fr,{},2025-08-28,FR\n", i, i));
+        }
+        file.write_str(&content).unwrap();
+        let path = file.path().to_str().unwrap();
+        let sample = |args: &[&str]| {
+            let cli = Cli::parse_from(["webinfo", "--csv", path].iter().chain(args));
+            let count = count_selected_records(&cli, path, &default_psl()).unwrap();
+            (
+                origins(select_records(
+                    open_records(&cli).unwrap(),
+                    &cli,
+                    &default_psl(),
+                )),
+                count,
+            )
+        };
+        let (records, count) = sample(&["--sample", "10", "--seed", "42"]);
+        assert_eq!(records.len(), 10);
+        assert_eq!(count, 10);
+        assert_eq!(sample(&["--sample", "10", "--seed", "42"]).0, records);
+        // fewer records than the sample size
+        let (records, count) = sample(&["--sample", "10", "--seed", "42", "--top", "4"]);
+        assert_eq!(records.len(), 4);
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_rank_bands_flag() {
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv", "--rank-bands"]);
+        assert_eq!(cli.rank_bands, Some(vec![]));
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv", "--rank-bands", "100,5000"]);
+        assert_eq!(cli.rank_bands, Some(vec![100, 5000]));
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv"]);
+        assert_eq!(cli.rank_bands, None);
+    }
+
+    #[test]
+    fn test_apex_of() {
+        let psl = default_psl();
+        assert_eq!(
+            apex_of("https://www.example.co.uk", &psl).unwrap(),
+            "example.co.uk"
+        );
+        assert_eq!(
+            apex_of("https://a.b.example.com/path", &psl).unwrap(),
+            "example.com"
+        );
+        assert!(apex_of("192.0.2.1", &psl).is_none());
+    }
+
+    fn sample_ip_info(origin: &str) -> IpInfo {
+        IpInfo {
+            origin: OriginRecord::new(origin, 1000),
+            records: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_fields() {
+        let file = assert_fs::NamedTempFile::new("output.json").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+        let cli = Cli::parse_from([
+            "webinfo",
+            "--csv",
+            "in.csv",
+            "--fields",
+            "hostname,origin.origin",
+        ]);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: cli.output_format,
+                fields: cli.fields,
+                ..Default::default()
+            },
+            None,
+        );
+        let mut info = sample_ip_info("https://www.free.fr");
+        info.records.hostname = "www.free.fr".to_string();
+        info.records.domain = Some("free.fr".to_string());
+        tx.send(Ok(info)).await.unwrap();
+        drop(tx);
+        writer.await.unwrap();
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "hostname": "www.free.fr",
+                "origin": {"origin": "https://www.free.fr"}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_one_per_domain() {
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+        let mut selected = one_per_domain(rx, 4);
+        for (hostname, popularity) in [
+            ("www.free.fr", 100),
+            ("mail.free.fr", 300),
+            ("portail.free.fr", 200),
+        ] {
+            let mut info = sample_ip_info(&format!("https://{}", hostname));
+            info.origin.popularity = popularity;
+            info.records.hostname = hostname.to_string();
+            info.records.domain = Some("free.fr".to_string());
+            tx.send(Ok(info)).await.unwrap();
+        }
+        tx.send(Err(anyhow::anyhow!("lookup failed")))
+            .await
+            .unwrap();
+        drop(tx);
+        // the error is relayed first, the selection once all are received
+        assert!(selected.recv().await.unwrap().is_err());
+        let info = selected.recv().await.unwrap().unwrap();
+        assert_eq!(info.records.hostname, "mail.free.fr");
+        assert!(selected.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_grouped() {
+        let file = assert_fs::NamedTempFile::new("output.json").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Grouped,
+                fields: Some(vec!["hostname".to_string()]),
+                ..Default::default()
+            },
+            None,
+        );
+        for (hostname, domain) in [
+            ("www.free.fr", Some("free.fr")),
+            ("www.google.fr", Some("google.fr")),
+            ("mail.free.fr", Some("free.fr")),
+            ("intranet", None),
+        ] {
+            let mut info = sample_ip_info(&format!("https://{}", hostname));
+            info.records.hostname = hostname.to_string();
+            info.records.domain = domain.map(str::to_string);
+            tx.send(Ok(info)).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap();
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "(unknown)": [{"hostname": "intranet"}],
+                "free.fr": [{"hostname": "mail.free.fr"}, {"hostname": "www.free.fr"}],
+                "google.fr": [{"hostname": "www.google.fr"}],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_events() {
+        let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Events,
+                ..Default::default()
+            },
+            None,
+        );
+        let mut info = sample_ip_info("https://www.free.fr");
+        info.records.hostname = "www.free.fr".to_string();
+        info.records.ip = Some(vec!["212.27.48.10".parse().unwrap()]);
+        tx.send(Ok(info)).await.unwrap();
+        // no event for a host which did not resolve
+        tx.send(Ok(sample_ip_info("https://www.google.fr")))
+            .await
+            .unwrap();
+        drop(tx);
+        writer.await.unwrap();
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        let event_types = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .map(|event| event["event_type"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(event_types, ["HostResolved", "IpDiscovered"]);
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_max_output() {
+        let file = assert_fs::NamedTempFile::new("output.json").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(16);
+        let stop = CancellationToken::new();
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Json,
+                max_output: Some(OutputCap {
+                    limit: 300,
+                    stop: stop.clone(),
+                }),
+                ..Default::default()
+            },
+            None,
+        );
+        for n in 0..10 {
+            tx.send(Ok(sample_ip_info(&format!("https://www{}.free.fr", n))))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        let stats = writer.await.unwrap();
+        assert!(stop.is_cancelled());
+        // the output holds whole results up to the first one crossing the cap
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        let results = serde_json::Deserializer::from_str(&output)
+            .into_iter::<serde_json::Value>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!results.is_empty() && results.len() < 10);
+        assert_eq!(results.len() + stats.over_cap, 10);
+        let last = serde_json::to_string_pretty(results.last().unwrap()).unwrap();
+        assert!(output.len() >= 300);
+        assert!(output.len() - (last.len() + 1) < 300);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("100MB"), Ok(100_000_000));
+        assert_eq!(parse_size("2 KiB"), Ok(2048));
+        assert_eq!(parse_size("1gib"), Ok(1 << 30));
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("10PB").is_err());
+        assert!(parse_size("99999999999GB").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_alert() {
+        let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Ndjson,
+                ..Default::default()
+            },
+            None,
+        );
+        for (origin, expiring) in [
+            ("https://www.free.fr", Some(true)),
+            ("https://www.google.fr", Some(false)),
+            ("https://www.orange.fr", None),
+        ] {
+            let mut info = sample_ip_info(origin);
+            info.records.hostname = origin.trim_start_matches("https://").to_string();
+            info.records.expiring_soon = expiring;
+            tx.send(Ok(info)).await.unwrap();
+        }
+        drop(tx);
+        let stats = writer.await.unwrap();
+        assert_eq!(stats.expiring, [("www.free.fr".to_string(), None)]);
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        assert!(output.contains(r#""expiring_soon":true"#));
+
+        // a single expiring certificate fails the run
+        let error = expiry_alert(&stats.expiring, 30).unwrap_err();
+        assert_eq!(error.to_string(), "1 certificates expire within 30 days");
+        assert!(expiry_alert(&[], 30).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_ndjson() {
+        let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Ndjson,
+                ..Default::default()
+            },
+            None,
+        );
+        for origin in ["https://www.free.fr", "https://www.google.fr"] {
+            tx.send(Ok(sample_ip_info(origin))).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap();
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        assert!(output.ends_with("}\n"));
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        for (line, origin) in lines
+            .iter()
+            .zip(["https://www.free.fr", "https://www.google.fr"])
+        {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["origin"]["origin"], origin);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_sqlite() {
+        let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+        let db = assert_fs::NamedTempFile::new("results.db").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Ndjson,
+                sqlite: Some(SqliteOutput::open(db.path()).unwrap().with_chunk_size(1)),
+                ..Default::default()
+            },
+            None,
+        );
+        for origin in ["https://www.free.fr", "https://www.google.fr"] {
+            tx.send(Ok(sample_ip_info(origin))).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap();
+        // the results are inserted into the database instead of the output
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), "");
+        let conn = rusqlite::Connection::open(db.path()).unwrap();
+        let hosts: i64 = conn
+            .query_row("SELECT COUNT(*) FROM hosts", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(hosts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_only_resolved() {
+        let output = |only_resolved: Vec<Require>| async move {
+            let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+            let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+            let writer = handle_result(
+                rx,
+                Some(file.path().to_path_buf()),
+                false,
+                ResultOptions {
+                    format: OutputFormat::Ndjson,
+                    fields: Some(vec!["origin.origin".to_string()]),
+                    only_resolved: Some(only_resolved),
+                    ..Default::default()
+                },
+                None,
+            );
+            let mut resolved = sample_ip_info("https://www.free.fr");
+            resolved.records.ip = Some(vec!["212.27.48.10".parse().unwrap()]);
+            tx.send(Ok(resolved)).await.unwrap();
+            tx.send(Ok(sample_ip_info("https://dead.example.com")))
+                .await
+                .unwrap();
+            tx.send(Err(anyhow::anyhow!("Failed"))).await.unwrap();
+            drop(tx);
+            let stats = writer.await.unwrap();
+            (
+                std::fs::read_to_string(file.path()).unwrap(),
+                stats.unresolved,
+            )
+        };
+        let (kept, dropped) = output(vec![]).await;
+        assert_eq!(kept, "{\"origin\":{\"origin\":\"https://www.free.fr\"}}\n");
+        assert_eq!(dropped, 1);
+        // the resolved host has no certificate
+        let (kept, dropped) = output(vec![Require::Tls]).await;
+        assert_eq!(kept, "");
+        assert_eq!(dropped, 2);
+
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv", "--only-resolved", "tls,asn"]);
+        assert_eq!(cli.only_resolved, Some(vec![Require::Tls, Require::Asn]));
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv", "--only-resolved"]);
+        assert_eq!(cli.only_resolved, Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_baseline() {
+        let with_ip = |origin: &str, ip: &str| {
+            let mut info = sample_ip_info(origin);
+            info.records.ip = Some(vec![ip.parse().unwrap()]);
+            info
+        };
+        // the pretty JSON output of the previous run
+        let previous = assert_fs::NamedTempFile::new("previous.json").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+        let writer = handle_result(
+            rx,
+            Some(previous.path().to_path_buf()),
+            false,
+            ResultOptions::default(),
+            None,
+        );
+        tx.send(Ok(with_ip("https://same.example", "192.0.2.1")))
+            .await
+            .unwrap();
+        tx.send(Ok(with_ip("https://moved.example", "192.0.2.2")))
+            .await
+            .unwrap();
+        tx.send(Ok(with_ip("https://gone.example", "192.0.2.3")))
+            .await
+            .unwrap();
+        drop(tx);
+        writer.await.unwrap();
+
+        let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Ndjson,
+                fields: Some(vec!["origin.origin".to_string()]),
+                baseline: Some(Baseline::load(previous.path()).unwrap()),
+                ..Default::default()
+            },
+            None,
+        );
+        tx.send(Ok(with_ip("https://same.example", "192.0.2.1")))
+            .await
+            .unwrap();
+        tx.send(Ok(with_ip("https://moved.example", "192.0.2.4")))
+            .await
+            .unwrap();
+        tx.send(Ok(with_ip("https://new.example", "192.0.2.5")))
+            .await
+            .unwrap();
+        drop(tx);
+        let stats = writer.await.unwrap();
+        assert_eq!(stats.unchanged, 1);
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        let values: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                serde_json::json!({
+                    "origin": {"origin": "https://moved.example"},
+                    "changes": [
+                        {"change": "ips_added", "ips": ["192.0.2.4"]},
+                        {"change": "ips_removed", "ips": ["192.0.2.2"]},
+                    ]
+                }),
+                serde_json::json!({
+                    "origin": {"origin": "https://new.example"},
+                    "changes": [{"change": "new_host"}]
+                }),
+                serde_json::json!({
+                    "origin": {"origin": "https://gone.example"},
+                    "changes": [{"change": "removed_host"}]
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_json_patch() {
+        let with_ip = |origin: &str, ip: &str| {
+            let mut info = sample_ip_info(origin);
+            info.records.ip = Some(vec![ip.parse().unwrap()]);
+            info
+        };
+        let mut baseline = Baseline::default();
+        baseline.insert(with_ip("same.example", "192.0.2.1"));
+        baseline.insert(with_ip("moved.example", "192.0.2.2"));
+        baseline.insert(with_ip("gone.example", "192.0.2.3"));
+        let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(4);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::JsonPatch,
+                baseline: Some(baseline),
+                ..Default::default()
+            },
+            None,
+        );
+        tx.send(Ok(with_ip("same.example", "192.0.2.1")))
+            .await
+            .unwrap();
+        tx.send(Ok(with_ip("moved.example", "192.0.2.4")))
+            .await
+            .unwrap();
+        drop(tx);
+        let stats = writer.await.unwrap();
+        assert_eq!(stats.unchanged, 1);
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        let values: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                serde_json::json!([
+                    {"op": "replace", "path": "/moved.example/records/ip", "value": ["192.0.2.4"]}
+                ]),
+                serde_json::json!([{"op": "remove", "path": "/gone.example"}]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_trailing_dots() {
+        let output = |trailing_dots: TrailingDots| async move {
+            let file = assert_fs::NamedTempFile::new("output.ndjson").unwrap();
+            let (tx, rx) = mpsc::channel::<Result<IpInfo>>(1);
+            let writer = handle_result(
+                rx,
+                Some(file.path().to_path_buf()),
+                false,
+                ResultOptions {
+                    format: OutputFormat::Ndjson,
+                    fields: Some(vec!["cname".to_string(), "ns.names".to_string()]),
+                    trailing_dots,
+                    ..Default::default()
+                },
+                None,
+            );
+            let mut info = sample_ip_info("https://www.free.fr");
+            info.records.cname = Some(vec!["edge.free.fr.".to_string()]);
+            info.records.ns = Some(webinfo::dns::NameServer {
+                names: vec!["freens1-g20.free.fr.".to_string()],
+                ips: None,
+                asn: None,
+                open_resolver: None,
+                edns_cookie_supported: None,
+            });
+            tx.send(Ok(info)).await.unwrap();
+            drop(tx);
+            writer.await.unwrap();
+            let output = std::fs::read_to_string(file.path()).unwrap();
+            serde_json::from_str::<serde_json::Value>(&output).unwrap()
+        };
+        // stripped by default, like the hostname
+        assert_eq!(
+            output(TrailingDots::default()).await,
+            serde_json::json!({
+                "cname": ["edge.free.fr"],
+                "ns": {"names": ["freens1-g20.free.fr"]}
+            })
+        );
+        assert_eq!(
+            output(TrailingDots::Keep).await,
+            serde_json::json!({
+                "cname": ["edge.free.fr."],
+                "ns": {"names": ["freens1-g20.free.fr."]}
+            })
+        );
+        let cli = Cli::parse_from(["webinfo", "--csv", "in.csv", "--trailing-dots", "add"]);
+        assert_eq!(cli.trailing_dots, TrailingDots::Add);
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_compress() {
+        use std::io::Read;
+        let origins = ["https://www.free.fr", "https://www.google.fr"];
+        let mut outputs = Vec::new();
+        for (name, compress) in [("output.ndjson", false), ("output.ndjson.gz", true)] {
+            let file = assert_fs::NamedTempFile::new(name).unwrap();
+            let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+            let writer = handle_result(
+                rx,
+                Some(file.path().to_path_buf()),
+                compress,
+                ResultOptions {
+                    format: OutputFormat::Ndjson,
+                    ..Default::default()
+                },
+                None,
+            );
+            for origin in origins {
+                tx.send(Ok(sample_ip_info(origin))).await.unwrap();
+            }
+            drop(tx);
+            writer.await.unwrap();
+            outputs.push(std::fs::read(file.path()).unwrap());
+        }
+        // the compressed output is a complete gzip stream of the same content
+        assert_eq!(&outputs[1][..2], &[0x1f, 0x8b]);
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&outputs[1][..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed.as_bytes(), &outputs[0][..]);
+        assert_eq!(decompressed.lines().count(), origins.len());
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_webhook() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        // stub collector accepting a single POST
+        let collector = spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let body = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap();
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                }
+            };
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            body
+        });
+        let file = assert_fs::NamedTempFile::new("output.json").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+        let webhook = Webhook::new(url).with_batch_size(10);
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Json,
+                fields: Some(vec!["origin.origin".to_string()]),
+                ..Default::default()
+            },
+            Some(webhook),
+        );
+        for origin in ["https://www.free.fr", "https://www.google.fr"] {
+            tx.send(Ok(sample_ip_info(origin))).await.unwrap();
+        }
+        drop(tx);
+        writer.await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&collector.await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {"origin": {"origin": "https://www.free.fr"}},
+                {"origin": {"origin": "https://www.google.fr"}}
+            ])
+        );
+        // the results are sent instead of written
+        assert_eq!(std::fs::read_to_string(file.path()).unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_handle_result_backpressure() {
+        let file = assert_fs::NamedTempFile::new("output.json").unwrap();
+        let (tx, rx) = mpsc::channel::<Result<IpInfo>>(2);
+        // fill the channel before the writer starts
+        tx.send(Ok(sample_ip_info("https://www.free.fr")))
+            .await
+            .unwrap();
+        tx.send(Ok(sample_ip_info("https://www.google.fr")))
+            .await
+            .unwrap();
+        let writer = handle_result(
+            rx,
+            Some(file.path().to_path_buf()),
+            false,
+            ResultOptions {
+                format: OutputFormat::Json,
+                ..Default::default()
+            },
+            None,
+        );
+        // the sender waits for the writer to drain the channel
+        for _ in 0..10 {
+            tx.send(Ok(sample_ip_info("https://www.lefigaro.fr")))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        let stats = writer.await.unwrap();
+        assert_eq!(stats.peak_occupancy, 2);
+        assert!(stats.full_count >= 1);
+        let output = std::fs::read_to_string(file.path()).unwrap();
+        assert_eq!(output.matches("\"popularity\":").count(), 12);
+    }
 }
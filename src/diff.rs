@@ -0,0 +1,465 @@
+use super::{IpInfo, tls::CertificateIssuerInfo, utils::open_input};
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    path::Path,
+};
+
+/// Change of a host between a baseline run and the current one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// The host is not in the baseline
+    NewHost,
+    /// The host of the baseline is not in the current results
+    RemovedHost,
+    /// The host resolved in the baseline and no longer resolves
+    WentDark,
+    /// The host did not resolve in the baseline and now resolves
+    Resolved,
+    IpsAdded {
+        ips: Vec<IpAddr>,
+    },
+    IpsRemoved {
+        ips: Vec<IpAddr>,
+    },
+    AsnsAdded {
+        asns: Vec<u32>,
+    },
+    AsnsRemoved {
+        asns: Vec<u32>,
+    },
+    Cname {
+        before: Option<Vec<String>>,
+        after: Option<Vec<String>>,
+    },
+    CertOrganization {
+        before: Option<String>,
+        after: Option<String>,
+    },
+    /// The host serves another leaf certificate, or no longer serves one
+    CertFingerprint {
+        before: Option<String>,
+        after: Option<String>,
+    },
+}
+
+/// Changes of the key fields of a host between two runs, empty when nothing
+/// changed. The order of the IPs, ASNs and CNAME targets does not matter.
+pub fn diff(prev: &IpInfo, curr: &IpInfo) -> Vec<Change> {
+    let (prev, curr) = (&prev.records, &curr.records);
+    let mut changes = Vec::new();
+
+    let prev_ips: BTreeSet<IpAddr> = prev.ip.iter().flatten().copied().collect();
+    let curr_ips: BTreeSet<IpAddr> = curr.ip.iter().flatten().copied().collect();
+    match (prev_ips.is_empty(), curr_ips.is_empty()) {
+        (false, true) => changes.push(Change::WentDark),
+        (true, false) => changes.push(Change::Resolved),
+        _ => {
+            let added: Vec<IpAddr> = curr_ips.difference(&prev_ips).copied().collect();
+            let removed: Vec<IpAddr> = prev_ips.difference(&curr_ips).copied().collect();
+            if !added.is_empty() {
+                changes.push(Change::IpsAdded { ips: added });
+            }
+            if !removed.is_empty() {
+                changes.push(Change::IpsRemoved { ips: removed });
+            }
+        }
+    }
+
+    let prev_asns: BTreeSet<u32> = prev.asn.iter().flatten().map(|asn| asn.asn).collect();
+    let curr_asns: BTreeSet<u32> = curr.asn.iter().flatten().map(|asn| asn.asn).collect();
+    let added: Vec<u32> = curr_asns.difference(&prev_asns).copied().collect();
+    let removed: Vec<u32> = prev_asns.difference(&curr_asns).copied().collect();
+    if !added.is_empty() {
+        changes.push(Change::AsnsAdded { asns: added });
+    }
+    if !removed.is_empty() {
+        changes.push(Change::AsnsRemoved { asns: removed });
+    }
+
+    let sorted = |cname: &Option<Vec<String>>| {
+        cname.clone().map(|mut names| {
+            names.sort();
+            names
+        })
+    };
+    if sorted(&prev.cname) != sorted(&curr.cname) {
+        changes.push(Change::Cname {
+            before: prev.cname.clone(),
+            after: curr.cname.clone(),
+        });
+    }
+
+    let (prev_tls, curr_tls) = (prev.tls.as_ref(), curr.tls.as_ref());
+    let organization =
+        |tls: Option<&CertificateIssuerInfo>| tls.map(|tls| tls.organization().to_string());
+    if organization(prev_tls) != organization(curr_tls) {
+        changes.push(Change::CertOrganization {
+            before: organization(prev_tls),
+            after: organization(curr_tls),
+        });
+    }
+    let fingerprint =
+        |tls: Option<&CertificateIssuerInfo>| tls.map(|tls| tls.sha256_fingerprint().to_string());
+    if fingerprint(prev_tls) != fingerprint(curr_tls) {
+        changes.push(Change::CertFingerprint {
+            before: fingerprint(prev_tls),
+            after: fingerprint(curr_tls),
+        });
+    }
+    changes
+}
+
+/// Fields of a record changing from one run to the next while the host does
+/// not, left out of the JSON Patches
+const VOLATILE_FIELDS: &[&[&str]] = &[
+    &["ttl"],
+    &["tls", "days_until_expiry"],
+    &["tls", "handshake_ms"],
+];
+
+/// JSON of a result without its volatile fields, nor those of its sibling
+fn stable_value(info: &IpInfo) -> Value {
+    fn strip(records: &mut Value) {
+        for path in VOLATILE_FIELDS {
+            let (field, parents) = path.split_last().unwrap();
+            let parent = parents
+                .iter()
+                .try_fold(&mut *records, |value, key| value.get_mut(*key));
+            if let Some(Value::Object(parent)) = parent {
+                parent.remove(*field);
+            }
+        }
+        if let Some(related) = records.get_mut("related") {
+            strip(related);
+        }
+    }
+    let mut value = serde_json::to_value(info).unwrap();
+    if let Some(records) = value.get_mut("records") {
+        strip(records);
+    }
+    value
+}
+
+/// Operation of an RFC 6902 JSON Patch
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+/// Escape a key as a JSON Pointer reference token (RFC 6901)
+fn pointer_token(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Operations turning `prev` into `curr` under `path`: the objects are
+/// compared key by key, the arrays and the other values are replaced whole
+fn diff_values(path: &str, prev: &Value, curr: &Value, ops: &mut Vec<PatchOp>) {
+    match (prev, curr) {
+        (Value::Object(prev), Value::Object(curr)) => {
+            for (key, value) in prev {
+                let path = format!("{}/{}", path, pointer_token(key));
+                match curr.get(key) {
+                    Some(curr) => diff_values(&path, value, curr, ops),
+                    None => ops.push(PatchOp::Remove { path }),
+                }
+            }
+            for (key, value) in curr.iter().filter(|(key, _)| !prev.contains_key(*key)) {
+                ops.push(PatchOp::Add {
+                    path: format!("{}/{}", path, pointer_token(key)),
+                    value: value.clone(),
+                });
+            }
+        }
+        (prev, curr) if prev != curr => ops.push(PatchOp::Replace {
+            path: path.to_string(),
+            value: curr.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// JSON Patch turning the previous result of a host into the current one,
+/// empty when nothing changed. Unlike `diff`, every field but the volatile
+/// ones is compared and the order of the arrays matters.
+pub fn to_json_patch(prev: &IpInfo, curr: &IpInfo) -> Vec<PatchOp> {
+    let mut ops = Vec::new();
+    diff_values("", &stable_value(prev), &stable_value(curr), &mut ops);
+    ops
+}
+
+/// Results of a previous run, by origin, the current results are compared to
+#[derive(Debug, Default)]
+pub struct Baseline {
+    results: HashMap<String, IpInfo>,
+}
+
+impl Baseline {
+    /// Load the JSON or NDJSON output of a previous run, gzipped or not
+    pub fn load(path: &Path) -> Result<Baseline> {
+        let reader = open_input(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open baseline {:?}: {}", path, e))?;
+        let mut baseline = Baseline::default();
+        for info in serde_json::Deserializer::from_reader(reader).into_iter::<IpInfo>() {
+            let info =
+                info.map_err(|e| anyhow::anyhow!("Failed to parse baseline {:?}: {}", path, e))?;
+            baseline.insert(info);
+        }
+        Ok(baseline)
+    }
+
+    pub fn insert(&mut self, info: IpInfo) {
+        self.results.insert(info.origin.origin.clone(), info);
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Changes of a current result against its baseline, which is taken out
+    /// so that the hosts left at the end are the removed ones
+    pub fn changes(&mut self, curr: &IpInfo) -> Vec<Change> {
+        match self.results.remove(&curr.origin.origin) {
+            Some(prev) => diff(&prev, curr),
+            None => vec![Change::NewHost],
+        }
+    }
+
+    /// JSON Patch of a current result against its baseline, on a document
+    /// holding the results by origin, the new hosts being added whole. The
+    /// baseline result is taken out as with `changes`.
+    pub fn patch(&mut self, curr: &IpInfo) -> Vec<PatchOp> {
+        let path = format!("/{}", pointer_token(&curr.origin.origin));
+        match self.results.remove(&curr.origin.origin) {
+            Some(prev) => {
+                let mut ops = Vec::new();
+                diff_values(&path, &stable_value(&prev), &stable_value(curr), &mut ops);
+                ops
+            }
+            None => vec![PatchOp::Add {
+                path,
+                value: stable_value(curr),
+            }],
+        }
+    }
+
+    /// Operation removing a host missing from the current results from the
+    /// document of `patch`
+    pub fn remove_op(info: &IpInfo) -> PatchOp {
+        PatchOp::Remove {
+            path: format!("/{}", pointer_token(&info.origin.origin)),
+        }
+    }
+
+    /// Baseline results of the hosts missing from the current results, by origin
+    pub fn removed(self) -> Vec<IpInfo> {
+        let mut removed: Vec<IpInfo> = self.results.into_values().collect();
+        removed.sort_by(|a, b| a.origin.origin.cmp(&b.origin.origin));
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipinfo::{IpInfoRecord, OriginRecord};
+    use serde_json::json;
+
+    fn info(origin: &str, ips: &[&str], organization: Option<&str>) -> IpInfo {
+        let mut value = json!({
+            "origin": { "origin": origin },
+            "records": {
+                "hostname": origin,
+                "ip": ips,
+                "asn": [{
+                    "network": ["192.0.2.0/24"],
+                    "asn": 64500,
+                    "organization": "EXAMPLE",
+                    "country_code": "FR",
+                }],
+            },
+        });
+        if let Some(organization) = organization {
+            value["records"]["tls"] = json!({
+                "organization": organization,
+                "name_mismatch": false,
+                "sha256_fingerprint": format!("{}-fingerprint", organization),
+            });
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_diff_ips() {
+        let prev = info("a.example", &["192.0.2.1", "192.0.2.2"], None);
+        let curr = info("a.example", &["192.0.2.2", "192.0.2.1"], None);
+        assert_eq!(diff(&prev, &curr), vec![]);
+
+        let curr = info("a.example", &["192.0.2.2", "192.0.2.3"], None);
+        assert_eq!(
+            diff(&prev, &curr),
+            vec![
+                Change::IpsAdded {
+                    ips: vec!["192.0.2.3".parse().unwrap()]
+                },
+                Change::IpsRemoved {
+                    ips: vec!["192.0.2.1".parse().unwrap()]
+                },
+            ]
+        );
+
+        let mut dark = info("a.example", &[], None);
+        dark.records.asn = None;
+        assert_eq!(
+            diff(&prev, &dark),
+            vec![Change::WentDark, Change::AsnsRemoved { asns: vec![64500] }]
+        );
+        assert_eq!(
+            diff(&dark, &prev),
+            vec![Change::Resolved, Change::AsnsAdded { asns: vec![64500] }]
+        );
+    }
+
+    #[test]
+    fn test_diff_cert_organization() {
+        let prev = info("a.example", &["192.0.2.1"], Some("Let's Encrypt"));
+        let curr = info("a.example", &["192.0.2.1"], Some("DigiCert Inc"));
+        assert_eq!(
+            diff(&prev, &curr),
+            vec![
+                Change::CertOrganization {
+                    before: Some("Let's Encrypt".to_string()),
+                    after: Some("DigiCert Inc".to_string()),
+                },
+                Change::CertFingerprint {
+                    before: Some("Let's Encrypt-fingerprint".to_string()),
+                    after: Some("DigiCert Inc-fingerprint".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_json_patch() {
+        let prev = info("a.example", &["192.0.2.1"], Some("Let's Encrypt"));
+        assert_eq!(to_json_patch(&prev, &prev), vec![]);
+
+        let mut curr = info("a.example", &["192.0.2.2"], Some("DigiCert Inc"));
+        curr.records.ptr = Some(vec![Some("host.example.".to_string())]);
+        curr.records.asn = None;
+        assert_eq!(
+            to_json_patch(&prev, &curr),
+            vec![
+                PatchOp::Remove {
+                    path: "/records/asn".to_string()
+                },
+                PatchOp::Replace {
+                    path: "/records/ip".to_string(),
+                    value: json!(["192.0.2.2"]),
+                },
+                PatchOp::Replace {
+                    path: "/records/tls/organization".to_string(),
+                    value: json!("DigiCert Inc"),
+                },
+                PatchOp::Replace {
+                    path: "/records/tls/sha256_fingerprint".to_string(),
+                    value: json!("DigiCert Inc-fingerprint"),
+                },
+                PatchOp::Add {
+                    path: "/records/ptr".to_string(),
+                    value: json!(["host.example."]),
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&to_json_patch(&prev, &curr)[1]).unwrap(),
+            json!({"op": "replace", "path": "/records/ip", "value": ["192.0.2.2"]})
+        );
+    }
+
+    #[test]
+    fn test_json_patch_ignores_volatile_fields() {
+        let with = |ttl: u32, handshake_ms: u64, days: i64| {
+            let mut value =
+                serde_json::to_value(info("a.example", &["192.0.2.1"], Some("Let's Encrypt")))
+                    .unwrap();
+            value["records"]["ttl"] = json!(ttl);
+            value["records"]["tls"]["handshake_ms"] = json!(handshake_ms);
+            value["records"]["tls"]["days_until_expiry"] = json!(days);
+            serde_json::from_value::<IpInfo>(value).unwrap()
+        };
+        let (prev, curr) = (with(300, 42, 60), with(120, 17, 59));
+        assert_eq!(to_json_patch(&prev, &curr), vec![]);
+
+        let mut baseline = Baseline::default();
+        baseline.insert(prev);
+        assert_eq!(baseline.patch(&curr), vec![]);
+    }
+
+    #[test]
+    fn test_baseline_patch() {
+        let mut baseline = Baseline::default();
+        baseline.insert(info("https://a.example/x", &["192.0.2.1"], None));
+        // the origins are escaped in the paths
+        assert_eq!(
+            baseline.patch(&info("https://a.example/x", &["192.0.2.2"], None)),
+            vec![PatchOp::Replace {
+                path: "/https:~1~1a.example~1x/records/ip".to_string(),
+                value: json!(["192.0.2.2"]),
+            }]
+        );
+        let new = info("b.example", &["192.0.2.3"], None);
+        assert_eq!(
+            baseline.patch(&new),
+            vec![PatchOp::Add {
+                path: "/b.example".to_string(),
+                value: serde_json::to_value(&new).unwrap(),
+            }]
+        );
+        assert_eq!(
+            Baseline::remove_op(&new),
+            PatchOp::Remove {
+                path: "/b.example".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_baseline_new_and_removed_hosts() {
+        let file = assert_fs::NamedTempFile::new("baseline.json").unwrap();
+        // the pretty JSON output of a previous run
+        let output: Vec<String> = ["a.example", "b.example"]
+            .iter()
+            .map(|origin| {
+                serde_json::to_string_pretty(&info(origin, &["192.0.2.1"], None)).unwrap()
+            })
+            .collect();
+        std::fs::write(file.path(), output.join("\n")).unwrap();
+        let mut baseline = Baseline::load(file.path()).unwrap();
+        assert_eq!(baseline.len(), 2);
+
+        assert_eq!(
+            baseline.changes(&info("a.example", &["192.0.2.1"], None)),
+            vec![]
+        );
+        let new = IpInfo {
+            origin: OriginRecord::from_ip("192.0.2.9".parse().unwrap()),
+            records: IpInfoRecord::default(),
+        };
+        assert_eq!(baseline.changes(&new), vec![Change::NewHost]);
+        let removed = baseline.removed();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].origin.origin, "b.example");
+    }
+}
@@ -1,18 +1,135 @@
 use super::{asn::Asn, asn::lookup_ip};
+use anyhow::Result;
+use base64::{Engine, prelude::BASE64_STANDARD};
 use futures::future::join_all;
-use hickory_resolver::{Resolver, name_server::ConnectionProvider, proto::rr::RecordType};
+use hickory_proto::xfer::Protocol;
+use hickory_proto::{
+    ProtoErrorKind,
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        Name, RData,
+        rdata::opt::ClientSubnet,
+        rdata::opt::EdnsCode,
+        rdata::opt::EdnsOption,
+        rdata::svcb::{SVCB, SvcParamValue},
+    },
+    serialize::binary::BinDecodable,
+};
+use hickory_resolver::{
+    ResolveError, Resolver,
+    config::{NameServerConfig, ResolverConfig, ResolverOpts},
+    lookup::Lookup,
+    name_server::ConnectionProvider,
+    proto::rr::RecordType,
+};
+use indexmap::IndexSet;
+use ip_network::IpNetwork;
 use ip2asn::IpAsnMap;
-use serde::Serialize;
-use std::net::IpAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{Level, event};
 
-#[derive(Debug, Serialize, Clone)]
+/// Timeout for the queries sent directly to a name server
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NameServer {
     pub names: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ips: Option<Vec<IpAddr>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub asn: Option<Vec<Asn>>,
+    /// Whether each name server IP, in the same order as `ips`, answers
+    /// recursive queries for other zones, only set with the open resolver check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_resolver: Option<Vec<bool>>,
+    /// Whether each name server IP, in the same order as `ips`, answers with
+    /// a DNS cookie, only set with the EDNS cookie check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edns_cookie_supported: Option<Vec<bool>>,
+}
+
+/// An IP address together with its reverse name and origin AS
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ResolvedIp {
+    pub addr: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ptr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// Label of the blocklist entry containing the address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reputation: Option<String>,
+}
+
+/// Mode of an HTTPS or SVCB record
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SvcMode {
+    /// Priority 0, the name is an alias of the target name
+    Alias,
+    /// The record carries the parameters of a service endpoint
+    Service,
+}
+
+/// HTTPS (type 65) or SVCB (type 64) record with its service parameters
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HttpsRecord {
+    pub mode: SvcMode,
+    pub priority: u16,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpn: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ipv4hint: Vec<IpAddr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ipv6hint: Vec<IpAddr>,
+    /// Encrypted Client Hello configuration list advertised by the record, in base64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ech: Option<String>,
+}
+
+impl From<&SVCB> for HttpsRecord {
+    fn from(svcb: &SVCB) -> Self {
+        let mut record = HttpsRecord {
+            mode: match svcb.svc_priority() {
+                0 => SvcMode::Alias,
+                _ => SvcMode::Service,
+            },
+            priority: svcb.svc_priority(),
+            target: svcb.target_name().to_string(),
+            alpn: vec![],
+            port: None,
+            ipv4hint: vec![],
+            ipv6hint: vec![],
+            ech: None,
+        };
+        for (_, value) in svcb.svc_params() {
+            match value {
+                SvcParamValue::Alpn(alpn) => record.alpn = alpn.0.clone(),
+                SvcParamValue::Port(port) => record.port = Some(*port),
+                SvcParamValue::Ipv4Hint(hint) => {
+                    record.ipv4hint = hint.0.iter().map(|a| IpAddr::from(a.0)).collect()
+                }
+                SvcParamValue::Ipv6Hint(hint) => {
+                    record.ipv6hint = hint.0.iter().map(|aaaa| IpAddr::from(aaaa.0)).collect()
+                }
+                SvcParamValue::EchConfigList(ech) => {
+                    record.ech = Some(BASE64_STANDARD.encode(&ech.0))
+                }
+                _ => {}
+            }
+        }
+        record
+    }
 }
 
 pub async fn query_ns<T: ConnectionProvider>(
@@ -20,48 +137,449 @@ pub async fn query_ns<T: ConnectionProvider>(
     resolver: &Resolver<T>,
     ip2asn_map: &Arc<IpAsnMap>,
 ) -> Option<NameServer> {
+    let (ns_records, ns_ips) = query_ns_ips(target, resolver).await?;
+    // fetch ns asn
+    let asn = lookup_ip(&ns_ips, ip2asn_map);
+
+    let ip_records = match ns_ips.is_empty() {
+        true => None,
+        false => Some(ns_ips),
+    };
+
+    Some(NameServer {
+        names: ns_records,
+        ips: ip_records,
+        asn,
+        open_resolver: None,
+        edns_cookie_supported: None,
+    })
+}
+
+/// Names of the name servers of `target` and their addresses
+pub async fn query_ns_ips<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<(Vec<String>, Vec<IpAddr>)> {
     let lookup_ns_future = resolver.lookup(target, RecordType::NS);
     match lookup_ns_future.await {
         Ok(response_ns) => {
             // fetch ns records
             let ns_records = response_ns
-                .into_iter()
-                .filter_map(|r| r.into_ns().ok())
+                .iter()
+                .filter_map(|r| r.as_ns())
                 .map(|name| name.to_string())
                 .collect::<Vec<_>>();
-            // fetch ns ips
-            let futures = ns_records.iter().map(|ns| query_ipv4_ipv6(ns, resolver));
+            // fetch ns ips, only resolving the name servers without glue
+            let futures = ns_records.iter().map(|ns| {
+                let glue = glue_ips(&response_ns, ns);
+                async move {
+                    match glue.is_empty() {
+                        true => query_ipv4_ipv6(ns, resolver).await,
+                        false => Some(glue),
+                    }
+                }
+            });
             let parallel_results = join_all(futures).await;
-            let ns_ips = parallel_results
+            // several nameservers often share the same addresses
+            let ns_ips = canonical_ips(parallel_results.into_iter().flatten().flatten());
+            Some((ns_records, ns_ips))
+        }
+        Err(_) => None,
+    }
+}
+
+/// Addresses of the name server `ns` given as glue by the additional section
+/// of an NS answer, kept by the resolver along with the NS records
+fn glue_ips(response_ns: &Lookup, ns: &str) -> Vec<IpAddr> {
+    response_ns
+        .records()
+        .iter()
+        .filter(|r| r.name().to_string().eq_ignore_ascii_case(ns))
+        .filter_map(|r| r.data().ip_addr())
+        .collect()
+}
+
+/// NS sets of a domain as delegated by the parent zone and as served by the
+/// name servers of the domain itself
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Delegation {
+    /// Zone delegating the domain
+    pub parent_zone: String,
+    /// Name servers of the referral of the parent zone
+    pub parent_ns: Vec<String>,
+    /// Name servers in the authoritative NS answer of the delegated name
+    /// servers, none when none of them answers authoritatively
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub child_ns: Option<Vec<String>>,
+    /// Whether the two sets differ, only set when the child answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mismatch: Option<bool>,
+}
+
+/// Names of the NS records of `domain` in the answer or authority section of
+/// `response`, lowercased and sorted, with the glue of the additional section
+fn ns_set(response: &Message, domain: &Name) -> (Vec<String>, Vec<IpAddr>) {
+    let records = response.answers().iter().chain(response.name_servers());
+    let mut names = records
+        .filter(|r| r.name().to_lowercase() == domain.to_lowercase())
+        .filter_map(|r| r.data().as_ns())
+        .map(|ns| ns.0.to_lowercase())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    let glue = response
+        .additionals()
+        .iter()
+        .filter(|r| names.contains(&r.name().to_lowercase()))
+        .filter_map(|r| r.data().ip_addr())
+        .collect();
+    (names.iter().map(Name::to_string).collect(), glue)
+}
+
+/// Non-recursive NS query for `domain` sent to each of `ips` in turn, the
+/// NS set of the first NOERROR answer holding one, which must also be
+/// authoritative when `authoritative`, unlike the referral of a parent zone
+async fn query_ns_direct(
+    domain: &Name,
+    ips: &[IpAddr],
+    port: u16,
+    authoritative: bool,
+) -> Option<(Vec<String>, Vec<IpAddr>)> {
+    let query = build_query(&domain.to_string(), RecordType::NS, false).ok()?;
+    for ip in ips {
+        match exchange(SocketAddr::new(*ip, port), &query).await {
+            Ok(response) if response.response_code() != ResponseCode::NoError => event!(
+                Level::DEBUG,
+                "NS query for {} to {} answered {}",
+                domain,
+                ip,
+                response.response_code()
+            ),
+            Ok(response) if authoritative && !response.authoritative() => event!(
+                Level::DEBUG,
+                "NS query for {} to {} answered without authority",
+                domain,
+                ip
+            ),
+            Ok(response) => {
+                let (names, glue) = ns_set(&response, domain);
+                if !names.is_empty() {
+                    return Some((names, glue));
+                }
+                event!(
+                    Level::DEBUG,
+                    "NS query for {} to {} answered no NS record",
+                    domain,
+                    ip
+                );
+            }
+            Err(e) => event!(
+                Level::DEBUG,
+                "NS query for {} to {} failed: {}",
+                domain,
+                ip,
+                e
+            ),
+        }
+    }
+    None
+}
+
+/// Compare the NS set of `domain` delegated by the name servers of its
+/// parent zone, found through the resolver, with the one served by the
+/// delegated name servers, queried directly on `port`. None when no name
+/// server of the parent zone answers with the delegation.
+pub async fn trace_delegation<T: ConnectionProvider>(
+    domain: &str,
+    resolver: &Resolver<T>,
+    port: u16,
+) -> Option<Delegation> {
+    let mut domain = Name::from_str(domain).ok()?;
+    domain.set_fqdn(true);
+    let parent = domain.base_name();
+    let (_, parent_ips) = query_ns_ips(&parent.to_string(), resolver).await?;
+    let (parent_ns, glue) = query_ns_direct(&domain, &parent_ips, port, false).await?;
+    // the delegated name servers without glue are resolved
+    let child_ips = match glue.is_empty() {
+        true => canonical_ips(
+            join_all(parent_ns.iter().map(|ns| query_ipv4_ipv6(ns, resolver)))
+                .await
                 .into_iter()
                 .flatten()
-                .flatten()
-                .collect::<Vec<_>>();
-            // fetch ns asn
-            let asn = lookup_ip(&ns_ips, ip2asn_map);
-
-            let ip_records = match ns_ips.is_empty() {
-                true => None,
-                false => Some(ns_ips),
-            };
-
-            Some(NameServer {
-                names: ns_records,
-                ips: ip_records,
-                asn,
-            })
+                .flatten(),
+        ),
+        false => canonical_ips(glue),
+    };
+    let child_ns = query_ns_direct(&domain, &child_ips, port, true)
+        .await
+        .map(|(names, _)| names);
+    Some(Delegation {
+        parent_zone: parent.to_string(),
+        mismatch: child_ns.as_ref().map(|child_ns| *child_ns != parent_ns),
+        parent_ns,
+        child_ns,
+    })
+}
+
+/// Configuration of a resolver sending non-recursive queries (RD=0) to the
+/// authoritative name servers at `ips`, to see their own answers instead of
+/// those of a recursive cache
+pub fn authoritative_config(ips: &[IpAddr], port: u16) -> (ResolverConfig, ResolverOpts) {
+    let name_servers = ips
+        .iter()
+        .map(|ip| NameServerConfig::new(SocketAddr::new(*ip, port), Protocol::Udp))
+        .collect::<Vec<_>>();
+    let config = ResolverConfig::from_parts(None, vec![], name_servers);
+    let mut options = ResolverOpts::default();
+    options.recursion_desired = false;
+    // the resolver only lives for one record
+    options.cache_size = 0;
+    (config, options)
+}
+
+/// Name outside of the zones of the probed name servers, which only an open
+/// resolver answers
+const OPEN_RESOLVER_PROBE: &str = "www.iana.org.";
+
+/// Configuration of a resolver sending a single recursive query (RD=1) to
+/// the name server at `ip`
+pub fn open_resolver_config(ip: IpAddr, port: u16) -> (ResolverConfig, ResolverOpts) {
+    let name_server = NameServerConfig::new(SocketAddr::new(ip, port), Protocol::Udp);
+    let config = ResolverConfig::from_parts(None, vec![], vec![name_server]);
+    let mut options = ResolverOpts::default();
+    options.recursion_desired = true;
+    options.cache_size = 0;
+    options.attempts = 1;
+    options.timeout = Duration::from_secs(2);
+    (config, options)
+}
+
+/// Whether the name server behind `resolver`, built with
+/// `open_resolver_config`, answers a recursive query for a name it is not
+/// authoritative for. A refusal, an empty referral or a timeout is closed.
+pub async fn is_open_resolver<T: ConnectionProvider>(resolver: &Resolver<T>) -> bool {
+    resolver
+        .lookup(OPEN_RESOLVER_PROBE, RecordType::A)
+        .await
+        .is_ok_and(|lookup| lookup.iter().next().is_some())
+}
+
+/// Number of retries shared by all the queries of a run: once it is spent,
+/// the failed queries are no longer retried, so that a failing resolver does
+/// not multiply the number of queries and the duration of the run
+#[derive(Debug)]
+pub struct RetryBudget {
+    remaining: AtomicUsize,
+    exhausted: AtomicBool,
+}
+
+impl RetryBudget {
+    pub fn new(retries: usize) -> Self {
+        RetryBudget {
+            remaining: AtomicUsize::new(retries),
+            exhausted: AtomicBool::new(false),
         }
-        Err(_) => None,
     }
+
+    /// Take a retry from the budget, false once the budget is spent
+    pub fn try_take(&self) -> bool {
+        let taken = self
+            .remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if !taken && !self.exhausted.swap(true, Ordering::SeqCst) {
+            event!(
+                Level::WARN,
+                "Retry budget exhausted, the failed queries are no longer retried"
+            );
+        }
+        taken
+    }
+
+    /// Number of retries left
+    pub fn remaining(&self) -> usize {
+        self.remaining.load(Ordering::SeqCst)
+    }
+}
+
+/// Sub-buckets per power of two of the latency histogram, the recorded
+/// latencies are rounded down by at most 1/32
+const LATENCY_SUB_BUCKETS: u64 = 64;
+/// Latencies are recorded in microseconds, up to 2^40 (about 12 days)
+const LATENCY_MAX_BITS: u32 = 40;
+
+/// Histogram of the latencies of the DNS queries of a run, shared by all the
+/// hosts. The buckets are logarithmic, like an HDR histogram: the latencies
+/// below 64 µs are exact, the others are rounded down to their 6 most
+/// significant bits, so that the memory does not grow with the run.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        let len = Self::bucket(u64::MAX) + 1;
+        LatencyHistogram {
+            buckets: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Bucket of a latency in microseconds: the latency itself below 64,
+    /// then 32 buckets per power of two
+    fn bucket(micros: u64) -> usize {
+        let micros = micros.min((1 << LATENCY_MAX_BITS) - 1);
+        if micros < LATENCY_SUB_BUCKETS {
+            return micros as usize;
+        }
+        let shift = u64::from(63 - micros.leading_zeros()) - 5;
+        (shift * LATENCY_SUB_BUCKETS / 2 + (micros >> shift)) as usize
+    }
+
+    /// Smallest latency in microseconds of a bucket
+    fn lowest(bucket: usize) -> u64 {
+        let bucket = bucket as u64;
+        if bucket < LATENCY_SUB_BUCKETS {
+            return bucket;
+        }
+        let shift = bucket / (LATENCY_SUB_BUCKETS / 2) - 1;
+        (bucket - shift * LATENCY_SUB_BUCKETS / 2) << shift
+    }
+
+    /// Record the latency of a query
+    pub fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of latencies recorded
+    pub fn len(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Latency below which `percentile` percents of the queries completed,
+    /// by the nearest rank, none when nothing was recorded
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let total = self.len();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Some(Duration::from_micros(Self::lowest(bucket)));
+            }
+        }
+        None
+    }
+}
+
+/// Retries of the failed lookups of a host, bounded by an optional budget
+/// shared with the other hosts
+#[derive(Debug, Clone, Default)]
+pub struct Retry {
+    pub retries: u32,
+    pub budget: Option<Arc<RetryBudget>>,
+    /// Counter of the attempts failing with a transient error
+    pub transient_errors: Option<Arc<AtomicUsize>>,
+    /// Histogram the latency of each attempt is recorded in
+    pub latency: Option<Arc<LatencyHistogram>>,
+}
+
+impl Retry {
+    /// Run `lookup`, retrying it while it fails with a transient error and
+    /// neither the retries of the query nor the budget are spent
+    pub async fn lookup<F, Fut, R>(&self, mut lookup: F) -> Result<R, ResolveError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, ResolveError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let start = std::time::Instant::now();
+            let result = lookup().await;
+            if let Some(latency) = &self.latency {
+                latency.record(start.elapsed());
+            }
+            if let (Err(e), Some(errors)) = (&result, &self.transient_errors)
+                && is_transient(e)
+            {
+                errors.fetch_add(1, Ordering::SeqCst);
+            }
+            match result {
+                Err(e)
+                    if attempt < self.retries
+                        && is_transient(&e)
+                        && self.budget.as_ref().is_none_or(|budget| budget.try_take()) =>
+                {
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether a lookup failed because of the resolver, a timeout or a SERVFAIL,
+/// rather than because the records do not exist
+fn is_transient(error: &ResolveError) -> bool {
+    match error.proto().map(|e| e.kind()) {
+        Some(ProtoErrorKind::NoRecordsFound { response_code, .. }) => !matches!(
+            *response_code,
+            ResponseCode::NoError | ResponseCode::NXDomain
+        ),
+        _ => true,
+    }
+}
+
+/// Smallest TTL of the records of a lookup, including the CNAME records
+/// followed to reach the answers
+fn min_ttl(lookup: &Lookup) -> u32 {
+    lookup
+        .record_iter()
+        .map(|record| record.ttl())
+        .min()
+        .unwrap_or_default()
 }
 
 pub async fn query_cname<T: ConnectionProvider>(
     target: &str,
     resolver: &Resolver<T>,
 ) -> Option<Vec<String>> {
-    let lookup_cname_future = resolver.lookup(target, RecordType::CNAME);
+    query_cname_with_ttl(target, resolver)
+        .await
+        .map(|(cnames, _)| cnames)
+}
+
+/// CNAME lookup returning the smallest TTL of the answers along with them
+pub async fn query_cname_with_ttl<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<(Vec<String>, u32)> {
+    query_cname_with_retry(target, resolver, &Retry::default()).await
+}
+
+/// CNAME lookup with its TTL, retried on failure
+pub async fn query_cname_with_retry<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+    retry: &Retry,
+) -> Option<(Vec<String>, u32)> {
+    let lookup_cname_future = retry.lookup(|| resolver.lookup(target, RecordType::CNAME));
     match lookup_cname_future.await {
         Ok(response_cname) => {
+            let ttl = min_ttl(&response_cname);
             let cnames = response_cname
                 .into_iter()
                 .filter_map(|r| r.into_cname().ok())
@@ -70,25 +588,121 @@ pub async fn query_cname<T: ConnectionProvider>(
             if cnames.is_empty() {
                 None
             } else {
-                Some(cnames)
+                Some((cnames, ttl))
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+/// Type code of the DNAME record (RFC 6672), which hickory has no variant for
+const DNAME: RecordType = RecordType::Unknown(39);
+
+/// DNAME lookup, returning the target names the subtree of `target` is
+/// redirected to. The record is read from its raw RDATA, an uncompressed name.
+pub async fn query_dname<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<Vec<String>> {
+    let lookup_dname_future = resolver.lookup(target, DNAME);
+    match lookup_dname_future.await {
+        Ok(response_dname) => {
+            let dnames = response_dname
+                .iter()
+                .filter_map(|rdata| match rdata {
+                    RData::Unknown { code, rdata } if *code == DNAME => {
+                        Name::from_bytes(rdata.anything()).ok()
+                    }
+                    _ => None,
+                })
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>();
+            if dnames.is_empty() {
+                None
+            } else {
+                Some(dnames)
             }
         }
         Err(_) => None,
     }
 }
 
+/// Whether `target` does not exist, its lookup being answered with NXDOMAIN.
+/// None when the lookup failed for another reason, like a timeout.
+pub async fn is_nx_domain<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<bool> {
+    match resolver.lookup(target, RecordType::A).await {
+        Ok(_) => Some(false),
+        Err(e) => match e.proto().map(|e| e.kind()) {
+            Some(ProtoErrorKind::NoRecordsFound { response_code, .. }) => {
+                Some(*response_code == ResponseCode::NXDomain)
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Maximum number of CNAME hops followed, to stop on a loop
+const MAX_CNAME_DEPTH: usize = 16;
+
+/// Follow the CNAME chain of `target` hop by hop, returning every name of
+/// the chain up to the final target
+pub async fn resolve_cname_chain<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<Vec<String>> {
+    let mut chain: Vec<String> = Vec::new();
+    let mut name = target.to_string();
+    while chain.len() < MAX_CNAME_DEPTH {
+        let Some(next) = query_cname(&name, resolver)
+            .await
+            .and_then(|cnames| cnames.into_iter().next())
+        else {
+            break;
+        };
+        if chain.contains(&next) {
+            break;
+        }
+        name = next.clone();
+        chain.push(next);
+    }
+    if chain.is_empty() { None } else { Some(chain) }
+}
+
 pub async fn query_ipv6<T: ConnectionProvider>(
     target: &str,
     resolver: &Resolver<T>,
 ) -> Option<Vec<IpAddr>> {
-    let lookup_aaaa_future = resolver.ipv6_lookup(target);
+    query_ipv6_with_ttl(target, resolver)
+        .await
+        .map(|(ips, _)| ips)
+}
+
+/// AAAA lookup returning the smallest TTL of the answers along with them
+pub async fn query_ipv6_with_ttl<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<(Vec<IpAddr>, u32)> {
+    query_ipv6_with_retry(target, resolver, &Retry::default()).await
+}
+
+/// AAAA lookup with its TTL, retried on failure
+pub async fn query_ipv6_with_retry<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+    retry: &Retry,
+) -> Option<(Vec<IpAddr>, u32)> {
+    let lookup_aaaa_future = retry.lookup(|| resolver.ipv6_lookup(target));
     match lookup_aaaa_future.await {
         Ok(response_aaaa) => {
+            let ttl = min_ttl(response_aaaa.as_lookup());
             let ipv6_addrs = response_aaaa
                 .into_iter()
                 .map(|addr| IpAddr::from(addr.0))
                 .collect::<Vec<_>>();
-            Some(ipv6_addrs)
+            Some((ipv6_addrs, ttl))
         }
         Err(_) => None,
     }
@@ -98,43 +712,434 @@ pub async fn query_ipv4<T: ConnectionProvider>(
     target: &str,
     resolver: &Resolver<T>,
 ) -> Option<Vec<IpAddr>> {
-    let lookup_a_future = resolver.ipv4_lookup(target);
+    query_ipv4_with_ttl(target, resolver)
+        .await
+        .map(|(ips, _)| ips)
+}
+
+/// A lookup returning the smallest TTL of the answers along with them
+pub async fn query_ipv4_with_ttl<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<(Vec<IpAddr>, u32)> {
+    query_ipv4_with_retry(target, resolver, &Retry::default()).await
+}
+
+/// A lookup with its TTL, retried on failure
+pub async fn query_ipv4_with_retry<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+    retry: &Retry,
+) -> Option<(Vec<IpAddr>, u32)> {
+    let lookup_a_future = retry.lookup(|| resolver.ipv4_lookup(target));
     match lookup_a_future.await {
         Ok(response_a) => {
+            let ttl = min_ttl(response_a.as_lookup());
             let ipv4_addrs = response_a
                 .into_iter()
                 .map(|addr| IpAddr::from(addr.0))
                 .collect::<Vec<_>>();
-            Some(ipv4_addrs)
+            Some((ipv4_addrs, ttl))
         }
         Err(_) => None,
     }
 }
 
-// need to refactor to make the two queries run in parallel
+/// HTTPS records of `target`, in AliasMode or ServiceMode, along with the
+/// SVCB records of the answer
+pub async fn query_https<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<Vec<HttpsRecord>> {
+    let lookup_https_future = resolver.lookup(target, RecordType::HTTPS);
+    match lookup_https_future.await {
+        Ok(response_https) => {
+            let records = response_https
+                .iter()
+                .filter_map(|rdata| match rdata {
+                    RData::HTTPS(https) => Some(HttpsRecord::from(&https.0)),
+                    RData::SVCB(svcb) => Some(HttpsRecord::from(svcb)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if records.is_empty() {
+                None
+            } else {
+                Some(records)
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+/// MX record, a mail exchanger of the domain
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MxRecord {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+/// SOA record, the start of authority of the zone
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SoaRecord {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    pub minimum: u32,
+}
+
+/// CAA record, a certificate authority allowed to issue for the domain
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CaaRecord {
+    pub critical: bool,
+    pub tag: String,
+    pub value: String,
+}
+
+/// Records of `lookup` converted by `convert`, none when the lookup failed
+/// or has no such record
+fn convert_records<R, F>(lookup: Option<&Lookup>, convert: F) -> Option<Vec<R>>
+where
+    F: Fn(&RData) -> Option<R>,
+{
+    let records = lookup?.iter().filter_map(convert).collect::<Vec<_>>();
+    if records.is_empty() {
+        None
+    } else {
+        Some(records)
+    }
+}
+
+/// Lookups of `target` for each of `record_types`, all sent at once and
+/// awaited together; the failed lookups are left out
+pub async fn query_multi<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+    record_types: &[RecordType],
+) -> HashMap<RecordType, Lookup> {
+    let lookups = record_types
+        .iter()
+        .map(|record_type| resolver.lookup(target, *record_type));
+    record_types
+        .iter()
+        .zip(join_all(lookups).await)
+        .filter_map(|(record_type, lookup)| Some((*record_type, lookup.ok()?)))
+        .collect()
+}
+
+/// MX records of `lookup`, by order of preference
+pub fn mx_records(lookup: Option<&Lookup>) -> Option<Vec<MxRecord>> {
+    let mut records = convert_records(lookup, |rdata| match rdata {
+        RData::MX(mx) => Some(MxRecord {
+            preference: mx.preference(),
+            exchange: mx.exchange().to_string(),
+        }),
+        _ => None,
+    })?;
+    records.sort_by_key(|record| record.preference);
+    Some(records)
+}
+
+/// TXT records of `lookup`, the strings of a record concatenated
+pub fn txt_records(lookup: Option<&Lookup>) -> Option<Vec<String>> {
+    convert_records(lookup, |rdata| match rdata {
+        RData::TXT(txt) => Some(
+            txt.iter()
+                .map(|data| String::from_utf8_lossy(data))
+                .collect::<String>(),
+        ),
+        _ => None,
+    })
+}
+
+/// SOA record of `lookup`
+pub fn soa_record(lookup: Option<&Lookup>) -> Option<SoaRecord> {
+    convert_records(lookup, |rdata| match rdata {
+        RData::SOA(soa) => Some(SoaRecord {
+            mname: soa.mname().to_string(),
+            rname: soa.rname().to_string(),
+            serial: soa.serial(),
+            refresh: soa.refresh(),
+            retry: soa.retry(),
+            expire: soa.expire(),
+            minimum: soa.minimum(),
+        }),
+        _ => None,
+    })?
+    .into_iter()
+    .next()
+}
+
+/// CAA records of `lookup`
+pub fn caa_records(lookup: Option<&Lookup>) -> Option<Vec<CaaRecord>> {
+    convert_records(lookup, |rdata| match rdata {
+        RData::CAA(caa) => Some(CaaRecord {
+            critical: caa.issuer_critical(),
+            tag: caa.tag().as_str().to_string(),
+            value: String::from_utf8_lossy(caa.raw_value()).into_owned(),
+        }),
+        _ => None,
+    })
+}
+
+/// MX records of `target`, by order of preference
+pub async fn query_mx<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<Vec<MxRecord>> {
+    mx_records(resolver.lookup(target, RecordType::MX).await.ok().as_ref())
+}
+
+/// TXT records of `target`, the strings of a record concatenated
+pub async fn query_txt<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<Vec<String>> {
+    txt_records(resolver.lookup(target, RecordType::TXT).await.ok().as_ref())
+}
+
+/// SOA record of `target`, only found at the apex of a zone
+pub async fn query_soa<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<SoaRecord> {
+    soa_record(resolver.lookup(target, RecordType::SOA).await.ok().as_ref())
+}
+
+/// CAA records of `target`
+pub async fn query_caa<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<Vec<CaaRecord>> {
+    caa_records(resolver.lookup(target, RecordType::CAA).await.ok().as_ref())
+}
+
+/// Record types of the host whose raw answers are dumped for debugging
+const RAW_RECORD_TYPES: &[RecordType] = &[
+    RecordType::A,
+    RecordType::AAAA,
+    RecordType::CNAME,
+    RecordType::HTTPS,
+];
+
+/// Raw answers of the lookups of `target`, for debugging: the records in
+/// zone file syntax or the error of the lookup, by record type
+pub async fn raw_lookups<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> serde_json::Value {
+    let lookups = RAW_RECORD_TYPES
+        .iter()
+        .map(|record_type| resolver.lookup(target, *record_type));
+    let mut raw = serde_json::Map::new();
+    for (record_type, lookup) in RAW_RECORD_TYPES.iter().zip(join_all(lookups).await) {
+        let answer = match lookup {
+            Ok(lookup) => serde_json::json!({
+                "records": lookup.record_iter().map(|record| record.to_string()).collect::<Vec<_>>()
+            }),
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        };
+        raw.insert(record_type.to_string(), answer);
+    }
+    serde_json::Value::Object(raw)
+}
+
+pub async fn query_ptr<T: ConnectionProvider>(
+    ip: IpAddr,
+    resolver: &Resolver<T>,
+) -> Option<String> {
+    let lookup_ptr_future = resolver.reverse_lookup(ip);
+    match lookup_ptr_future.await {
+        Ok(response_ptr) => response_ptr.into_iter().next().map(|name| name.to_string()),
+        Err(_) => None,
+    }
+}
+
+/// Remove duplicate IP addresses, keeping the first-seen order
+pub fn dedup_ips(ips: impl IntoIterator<Item = IpAddr>) -> Vec<IpAddr> {
+    ips.into_iter()
+        .collect::<IndexSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Remove duplicate IP addresses and sort them in canonical order: IPv4
+/// before IPv6, then in numeric order. The order of the answers varies from
+/// one query to the next, sorting them makes the output reproducible.
+pub fn canonical_ips(ips: impl IntoIterator<Item = IpAddr>) -> Vec<IpAddr> {
+    let mut ips = ips.into_iter().collect::<Vec<_>>();
+    // the `Ord` of `IpAddr` puts every IPv4 before IPv6
+    ips.sort_unstable();
+    ips.dedup();
+    ips
+}
+
 pub async fn query_ipv4_ipv6<T: ConnectionProvider>(
     target: &str,
     resolver: &Resolver<T>,
 ) -> Option<Vec<IpAddr>> {
     let ipv4 = query_ipv4(target, resolver);
     let ipv6 = query_ipv6(target, resolver);
-    let mut ip: Vec<IpAddr> = Vec::new();
     let (ipv4, ipv6) = tokio::join!(ipv4, ipv6);
-    if let Some(v4) = ipv4 {
-        ip.extend(v4);
+    let ip = canonical_ips(ipv4.into_iter().chain(ipv6).flatten());
+    if ip.is_empty() { None } else { Some(ip) }
+}
+
+/// A and AAAA lookups returning the smallest TTL of both answers along with
+/// the addresses
+pub async fn query_ipv4_ipv6_with_ttl<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+) -> Option<(Vec<IpAddr>, u32)> {
+    query_ipv4_ipv6_with_retry(target, resolver, &Retry::default()).await
+}
+
+/// A and AAAA lookups with their smallest TTL, retried on failure
+pub async fn query_ipv4_ipv6_with_retry<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+    retry: &Retry,
+) -> Option<(Vec<IpAddr>, u32)> {
+    let ipv4 = query_ipv4_with_retry(target, resolver, retry);
+    let ipv6 = query_ipv6_with_retry(target, resolver, retry);
+    let (ipv4, ipv6) = tokio::join!(ipv4, ipv6);
+    let ttl = ipv4.iter().chain(&ipv6).map(|(_, ttl)| *ttl).min()?;
+    let ip = canonical_ips(ipv4.into_iter().chain(ipv6).flat_map(|(ips, _)| ips));
+    if ip.is_empty() { None } else { Some((ip, ttl)) }
+}
+
+/// Check whether `domain` has a wildcard record, by resolving a random
+/// subdomain which cannot exist otherwise
+pub async fn has_wildcard<T: ConnectionProvider>(domain: &str, resolver: &Resolver<T>) -> bool {
+    let label = format!("webinfo-{:016x}", rand::random::<u64>());
+    // fully qualified so that the search domains are never appended
+    let target = format!("{}.{}.", label, domain.trim_end_matches('.'));
+    query_ipv4_ipv6(&target, resolver).await.is_some()
+}
+
+/// Build a query message for `target`, with EDNS enabled
+pub fn build_query(
+    target: &str,
+    record_type: RecordType,
+    recursion_desired: bool,
+) -> Result<Message> {
+    let name = Name::from_str(target)?;
+    let mut message = Message::new();
+    let mut edns = Edns::new();
+    edns.set_max_payload(1232);
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(recursion_desired)
+        .add_query(Query::query(name, record_type))
+        .set_edns(edns);
+    Ok(message)
+}
+
+/// Send a query directly to `server` over UDP, bypassing the resolver and its cache
+pub async fn exchange(server: SocketAddr, query: &Message) -> Result<Message> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.send_to(&query.to_vec()?, server).await?;
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, peer) = timeout(QUERY_TIMEOUT, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("DNS query to {} timed out", server))??;
+        if peer != server {
+            continue;
+        }
+        let response = Message::from_vec(&buf[..len])?;
+        if response.id() == query.id() {
+            return Ok(response);
+        }
     }
-    if let Some(v6) = ipv6 {
-        ip.extend(v6);
+}
+
+/// Whether the name server at `server` supports DNS cookies (RFC 7873): the
+/// non-recursive query for the SOA of `zone` carries a client cookie, which
+/// a supporting server echoes followed by a server cookie of 8 to 32 bytes.
+/// No answer, or an answer without a valid cookie, is unsupported.
+pub async fn supports_edns_cookie(server: SocketAddr, zone: &str) -> bool {
+    let Ok(mut query) = build_query(zone, RecordType::SOA, false) else {
+        return false;
+    };
+    let client_cookie: [u8; 8] = rand::random();
+    query
+        .extensions_mut()
+        .get_or_insert_with(Edns::new)
+        .options_mut()
+        .insert(EdnsOption::Unknown(
+            u16::from(EdnsCode::Cookie),
+            client_cookie.to_vec(),
+        ));
+    let Ok(response) = exchange(server, &query).await else {
+        return false;
+    };
+    match response
+        .extensions()
+        .as_ref()
+        .and_then(|edns| edns.option(EdnsCode::Cookie))
+    {
+        Some(EdnsOption::Unknown(_, cookie)) => {
+            (16..=40).contains(&cookie.len()) && cookie.starts_with(&client_cookie)
+        }
+        _ => false,
     }
+}
+
+/// Resolve A and AAAA records sending an EDNS Client Subnet option, so that
+/// geo-aware name servers answer as if the query came from `subnet`.
+/// Queries go directly to the first name server of the resolver.
+pub async fn query_ipv4_ipv6_ecs<T: ConnectionProvider>(
+    target: &str,
+    resolver: &Resolver<T>,
+    subnet: &IpNetwork,
+) -> Option<Vec<IpAddr>> {
+    let server = resolver.config().name_servers().first()?.socket_addr;
+    let queries = [RecordType::A, RecordType::AAAA].map(|record_type| async move {
+        let mut query = build_query(target, record_type, true).ok()?;
+        let ecs = ClientSubnet::new(subnet.network_address(), subnet.netmask(), 0);
+        query
+            .extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .options_mut()
+            .insert(EdnsOption::Subnet(ecs));
+        let response = exchange(server, &query).await.ok()?;
+        let ips = response
+            .answers()
+            .iter()
+            .filter_map(|r| match r.data() {
+                RData::A(a) => Some(IpAddr::V4(a.0)),
+                RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        Some(ips)
+    });
+    let ip = canonical_ips(join_all(queries).await.into_iter().flatten().flatten());
     if ip.is_empty() { None } else { Some(ip) }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_dns::{MockDns, record, resolver_for};
+    use hickory_proto::rr::{
+        Name, RData,
+        rdata::{A, AAAA, CNAME, NS, PTR, opt::EdnsCode},
+    };
     use hickory_resolver::Resolver;
     use ip2asn::Builder;
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
 
     #[tokio::test]
     async fn test_query_ipv4_some() {
@@ -208,6 +1213,485 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_resolve_cname_chain() {
+        let cname = |name: &str| RData::CNAME(CNAME(Name::from_str(name).unwrap()));
+        let mock = MockDns::start(vec![
+            record(
+                "www.example.com.",
+                300,
+                cname("www.example.com.cdn.example.net."),
+            ),
+            record(
+                "www.example.com.cdn.example.net.",
+                300,
+                cname("edge.example.org."),
+            ),
+            record("edge.example.org.", 300, cname("a1.edge.example.org.")),
+            record("a1.edge.example.org.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("loop1.example.com.", 300, cname("loop2.example.com.")),
+            record("loop2.example.com.", 300, cname("loop1.example.com.")),
+        ])
+        .await;
+        let resolver = mock.resolver();
+        let chain = resolve_cname_chain("www.example.com", &resolver)
+            .await
+            .unwrap();
+        assert_eq!(
+            chain,
+            [
+                "www.example.com.cdn.example.net.",
+                "edge.example.org.",
+                "a1.edge.example.org."
+            ]
+        );
+        assert!(
+            resolve_cname_chain("a1.edge.example.org", &resolver)
+                .await
+                .is_none()
+        );
+        let chain = resolve_cname_chain("loop1.example.com", &resolver)
+            .await
+            .unwrap();
+        assert_eq!(chain, ["loop2.example.com.", "loop1.example.com."]);
+    }
+
+    #[tokio::test]
+    async fn test_query_dname() {
+        use hickory_proto::{rr::rdata::NULL, serialize::binary::BinEncodable};
+        let target = Name::from_str("example.net.").unwrap().to_bytes().unwrap();
+        let mock = MockDns::start(vec![
+            record(
+                "example.com.",
+                300,
+                RData::Unknown {
+                    code: DNAME,
+                    rdata: NULL::with(target),
+                },
+            ),
+            record("example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+        ])
+        .await;
+        let resolver = mock.resolver();
+        assert_eq!(
+            query_dname("example.com.", &resolver).await,
+            Some(vec!["example.net.".to_string()])
+        );
+        assert_eq!(query_dname("www.example.org.", &resolver).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_query_mx_txt_soa_caa() {
+        use hickory_proto::rr::rdata::{CAA, MX, SOA, TXT};
+        let mock = MockDns::start(vec![
+            record(
+                "example.com.",
+                300,
+                RData::MX(MX::new(20, Name::from_str("mx2.example.com.").unwrap())),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::MX(MX::new(10, Name::from_str("mx1.example.com.").unwrap())),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::TXT(TXT::new(vec!["v=spf1 ".to_string(), "-all".to_string()])),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::SOA(SOA::new(
+                    Name::from_str("ns1.example.com.").unwrap(),
+                    Name::from_str("hostmaster.example.com.").unwrap(),
+                    2024010101,
+                    7200,
+                    3600,
+                    1209600,
+                    300,
+                )),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::CAA(CAA::new_issue(
+                    false,
+                    Some(Name::from_str("letsencrypt.org").unwrap()),
+                    vec![],
+                )),
+            ),
+        ])
+        .await;
+        let resolver = mock.resolver();
+        assert_eq!(
+            query_mx("example.com.", &resolver).await,
+            Some(vec![
+                MxRecord {
+                    preference: 10,
+                    exchange: "mx1.example.com.".to_string()
+                },
+                MxRecord {
+                    preference: 20,
+                    exchange: "mx2.example.com.".to_string()
+                },
+            ])
+        );
+        assert_eq!(
+            query_txt("example.com.", &resolver).await,
+            Some(vec!["v=spf1 -all".to_string()])
+        );
+        let soa = query_soa("example.com.", &resolver).await.unwrap();
+        assert_eq!(soa.mname, "ns1.example.com.");
+        assert_eq!(soa.rname, "hostmaster.example.com.");
+        assert_eq!(soa.serial, 2024010101);
+        assert_eq!(soa.minimum, 300);
+        assert_eq!(
+            query_caa("example.com.", &resolver).await,
+            Some(vec![CaaRecord {
+                critical: false,
+                tag: "issue".to_string(),
+                value: "letsencrypt.org".to_string(),
+            }])
+        );
+        assert_eq!(query_mx("www.example.org.", &resolver).await, None);
+        assert_eq!(query_txt("www.example.org.", &resolver).await, None);
+        assert_eq!(query_soa("www.example.org.", &resolver).await, None);
+        assert_eq!(query_caa("www.example.org.", &resolver).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_query_multi() {
+        use hickory_proto::rr::rdata::{MX, TXT};
+        let mock = MockDns::start(vec![
+            record(
+                "example.com.",
+                300,
+                RData::MX(MX::new(10, Name::from_str("mx1.example.com.").unwrap())),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::TXT(TXT::new(vec!["v=spf1 -all".to_string()])),
+            ),
+        ])
+        .await;
+        let resolver = mock.resolver();
+        let types = [
+            RecordType::MX,
+            RecordType::TXT,
+            RecordType::SOA,
+            RecordType::CAA,
+        ];
+        let lookups = query_multi("example.com.", &resolver, &types).await;
+        // every type is queried, only the answered ones are returned
+        let queried = mock
+            .queries()
+            .into_iter()
+            .map(|(_, record_type)| record_type)
+            .collect::<Vec<_>>();
+        for record_type in types {
+            assert!(queried.contains(&record_type), "{record_type} not queried");
+        }
+        assert_eq!(lookups.len(), 2);
+        assert_eq!(
+            mx_records(lookups.get(&RecordType::MX)).unwrap()[0].exchange,
+            "mx1.example.com."
+        );
+        assert_eq!(
+            txt_records(lookups.get(&RecordType::TXT)),
+            Some(vec!["v=spf1 -all".to_string()])
+        );
+        assert_eq!(soa_record(lookups.get(&RecordType::SOA)), None);
+        assert_eq!(caa_records(lookups.get(&RecordType::CAA)), None);
+    }
+
+    #[tokio::test]
+    async fn test_query_https() {
+        use hickory_proto::rr::rdata::{
+            HTTPS,
+            svcb::{Alpn, EchConfigList, IpHint, SvcParamKey},
+        };
+        let service = SVCB::new(
+            1,
+            Name::from_str(".").unwrap(),
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h3".to_string(), "h2".to_string()])),
+                ),
+                (SvcParamKey::Port, SvcParamValue::Port(8443)),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![A::new(192, 0, 2, 1)])),
+                ),
+                (
+                    SvcParamKey::EchConfigList,
+                    SvcParamValue::EchConfigList(EchConfigList(vec![0xfe, 0x0d, 0, 0])),
+                ),
+                (
+                    SvcParamKey::Ipv6Hint,
+                    SvcParamValue::Ipv6Hint(IpHint(vec![AAAA::new(
+                        0x2001, 0xdb8, 0, 0, 0, 0, 0, 1,
+                    )])),
+                ),
+            ],
+        );
+        let alias = SVCB::new(0, Name::from_str("cdn.example.net.").unwrap(), vec![]);
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::HTTPS(HTTPS(service))),
+            record("alias.example.com.", 300, RData::HTTPS(HTTPS(alias))),
+            record("plain.example.com.", 300, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        let resolver = mock.resolver();
+
+        let records = query_https("www.example.com.", &resolver).await.unwrap();
+        assert_eq!(
+            records,
+            vec![HttpsRecord {
+                mode: SvcMode::Service,
+                priority: 1,
+                target: ".".to_string(),
+                alpn: vec!["h3".to_string(), "h2".to_string()],
+                port: Some(8443),
+                ipv4hint: vec!["192.0.2.1".parse().unwrap()],
+                ipv6hint: vec!["2001:db8::1".parse().unwrap()],
+                ech: Some("/g0AAA==".to_string()),
+            }]
+        );
+        let records = query_https("alias.example.com.", &resolver).await.unwrap();
+        assert_eq!(records[0].mode, SvcMode::Alias);
+        assert_eq!(records[0].target, "cdn.example.net.");
+        assert_eq!(
+            serde_json::to_value(&records[0]).unwrap(),
+            serde_json::json!({"mode": "alias", "priority": 0, "target": "cdn.example.net."})
+        );
+        assert!(query_https("plain.example.com.", &resolver).await.is_none());
+    }
+
+    #[test]
+    fn test_https_record_ech_wire() {
+        use hickory_proto::{rr::Record, serialize::binary::BinDecodable};
+        // HTTPS record of crypto.cloudflare.com in wire format, with a short ECH config
+        let wire = [
+            0x06, 0x63, 0x72, 0x79, 0x70, 0x74, 0x6f, 0x0a, 0x63, 0x6c, 0x6f, 0x75, 0x64, 0x66,
+            0x6c, 0x61, 0x72, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x41, 0x00, 0x01, 0x00,
+            0x00, 0x01, 0x2c, 0x00, 0x3d, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x06, 0x02, 0x68,
+            0x33, 0x02, 0x68, 0x32, 0x00, 0x04, 0x00, 0x08, 0xa2, 0x9f, 0x89, 0x55, 0xa2, 0x9f,
+            0x8a, 0x55, 0x00, 0x05, 0x00, 0x0c, 0x00, 0x0a, 0xfe, 0x0d, 0x00, 0x06, 0x01, 0x02,
+            0x03, 0x04, 0x05, 0x06, 0x00, 0x06, 0x00, 0x10, 0x26, 0x06, 0x47, 0x00, 0x00, 0x07,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa2, 0x9f, 0x89, 0x55,
+        ];
+        let record = Record::from_bytes(&wire).unwrap();
+        let RData::HTTPS(https) = record.data() else {
+            panic!("not an HTTPS record: {:?}", record);
+        };
+        let https = HttpsRecord::from(&https.0);
+        assert_eq!(https.mode, SvcMode::Service);
+        assert_eq!(https.alpn, vec!["h3", "h2"]);
+        assert_eq!(
+            https.ipv4hint,
+            vec![
+                "162.159.137.85".parse::<IpAddr>().unwrap(),
+                "162.159.138.85".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            https.ipv6hint,
+            vec!["2606:4700:7::a29f:8955".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(https.ech.as_deref(), Some("AAr+DQAGAQIDBAUG"));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(not(feature = "network-tests"), ignore)]
+    async fn test_query_https_ech_cloudflare() {
+        let resolver = Resolver::builder_tokio().unwrap().build();
+        let records = query_https("crypto.cloudflare.com", &resolver)
+            .await
+            .unwrap();
+        assert!(records.iter().any(|record| record.ech.is_some()));
+    }
+
+    #[tokio::test]
+    #[cfg_attr(not(feature = "network-tests"), ignore)]
+    async fn test_is_open_resolver_closed() {
+        // a.iana-servers.net is authoritative only
+        let (config, options) = open_resolver_config("199.43.135.53".parse().unwrap(), 53);
+        let resolver = Resolver::builder_with_config(
+            config,
+            hickory_resolver::name_server::TokioConnectionProvider::default(),
+        )
+        .with_options(options)
+        .build();
+        assert!(!is_open_resolver(&resolver).await);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert!(histogram.is_empty());
+        assert_eq!(histogram.percentile(50.0), None);
+        // 1 to 100 ms
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.len(), 100);
+        for (percentile, ms) in [(50.0, 50), (90.0, 90), (99.0, 99), (100.0, 100)] {
+            let latency = histogram.percentile(percentile).unwrap();
+            // rounded down by at most 1/32
+            let expected = Duration::from_millis(ms);
+            assert!(
+                latency <= expected && latency >= expected - expected / 32,
+                "p{}: {:?}",
+                percentile,
+                latency
+            );
+        }
+        // 1 ms, rounded down to its 6 most significant bits
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_micros(992)));
+        // the small latencies are exact, the huge ones are clamped
+        let histogram = LatencyHistogram::default();
+        for micros in [10, 20, 30, 40] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(u64::MAX));
+        assert_eq!(histogram.percentile(40.0), Some(Duration::from_micros(20)));
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_micros(30)));
+        assert_eq!(histogram.percentile(80.0), Some(Duration::from_micros(40)));
+        assert!(histogram.percentile(100.0).unwrap() > Duration::from_secs(86_400));
+        // every bucket starts where the previous one ends
+        for bucket in 1..histogram.buckets.len() {
+            let lowest = LatencyHistogram::lowest(bucket);
+            assert!(lowest > LatencyHistogram::lowest(bucket - 1));
+            assert_eq!(LatencyHistogram::bucket(lowest), bucket);
+            assert_eq!(LatencyHistogram::bucket(lowest - 1), bucket - 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        let mock = MockDns::start_failing().await;
+        let resolver = mock.resolver();
+        let budget = Arc::new(RetryBudget::new(3));
+        let errors = Arc::new(AtomicUsize::new(0));
+        let retry = Retry {
+            retries: 2,
+            budget: Some(budget.clone()),
+            transient_errors: Some(errors.clone()),
+            latency: None,
+        };
+        for host in ["a.example.", "b.example.", "c.example."] {
+            assert!(
+                query_ipv4_with_retry(host, &resolver, &retry)
+                    .await
+                    .is_none()
+            );
+        }
+        // 3 first queries and the 3 retries of the budget, out of 6 allowed
+        assert_eq!(mock.queries().len(), 6);
+        assert_eq!(budget.remaining(), 0);
+        assert_eq!(errors.load(Ordering::SeqCst), 6);
+
+        // a missing name is an answer, it is not retried
+        let mock = MockDns::start(vec![]).await;
+        let errors = Arc::new(AtomicUsize::new(0));
+        let retry = Retry {
+            retries: 2,
+            budget: None,
+            transient_errors: Some(errors.clone()),
+            latency: None,
+        };
+        assert!(
+            query_ipv4_with_retry("missing.example.", &mock.resolver(), &retry)
+                .await
+                .is_none()
+        );
+        assert_eq!(mock.queries().len(), 1);
+        assert_eq!(errors.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_with_ttl() {
+        let mock = MockDns::start(vec![
+            record(
+                "www.example.com.",
+                600,
+                RData::CNAME(CNAME(Name::from_str("edge.example.net.").unwrap())),
+            ),
+            record("edge.example.net.", 60, RData::A(A::new(192, 0, 2, 1))),
+            record(
+                "edge.example.net.",
+                120,
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ),
+            record("static.example.com.", 86400, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        // a fresh resolver per lookup, the cached answers have a decreasing TTL
+        let resolver = || mock.resolver();
+        let (cnames, ttl) = query_cname_with_ttl("www.example.com", &resolver())
+            .await
+            .unwrap();
+        assert_eq!(cnames, ["edge.example.net."]);
+        assert_eq!(ttl, 600);
+        let (ips, ttl) = query_ipv4_with_ttl("www.example.com", &resolver())
+            .await
+            .unwrap();
+        assert_eq!(ips, [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+        assert_eq!(ttl, 60);
+        let (_, ttl) = query_ipv6_with_ttl("www.example.com", &resolver())
+            .await
+            .unwrap();
+        assert_eq!(ttl, 120);
+        let (ips, ttl) = query_ipv4_ipv6_with_ttl("www.example.com", &resolver())
+            .await
+            .unwrap();
+        assert_eq!(ips.len(), 2);
+        assert_eq!(ttl, 60);
+        let (_, ttl) = query_ipv4_ipv6_with_ttl("static.example.com", &resolver())
+            .await
+            .unwrap();
+        assert_eq!(ttl, 86400);
+        assert!(
+            query_ipv4_ipv6_with_ttl("missing.example.com", &resolver())
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_authoritative_config() {
+        let ips = [
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53)),
+        ];
+        let (config, options) = authoritative_config(&ips, 53);
+        assert!(!options.recursion_desired);
+        let servers = config
+            .name_servers()
+            .iter()
+            .map(|ns| ns.socket_addr)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            servers,
+            [SocketAddr::new(ips[0], 53), SocketAddr::new(ips[1], 53)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_has_wildcard() {
+        let mock = MockDns::start(vec![
+            record("*.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.org.", 300, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        let resolver = mock.resolver();
+        assert!(has_wildcard("example.com", &resolver).await);
+        assert!(!has_wildcard("example.org", &resolver).await);
+    }
+
     #[tokio::test]
     async fn test_query_ns_some() {
         let target = "facebook.com";
@@ -242,4 +1726,210 @@ mod tests {
         let ips = response.ips.unwrap();
         assert_eq!(ips.len(), 8);
     }
+
+    #[test]
+    fn test_canonical_ips() {
+        let ips = [
+            "2001:db8::10",
+            "192.0.2.10",
+            "2001:db8::2",
+            "198.51.100.1",
+            "192.0.2.9",
+            "::1",
+            "192.0.2.10",
+            "10.0.0.1",
+        ]
+        .map(|ip| ip.parse::<IpAddr>().unwrap());
+        let expected = [
+            "10.0.0.1",
+            "192.0.2.9",
+            "192.0.2.10",
+            "198.51.100.1",
+            "::1",
+            "2001:db8::2",
+            "2001:db8::10",
+        ]
+        .map(|ip| ip.parse::<IpAddr>().unwrap());
+        assert_eq!(canonical_ips(ips), expected);
+    }
+
+    #[test]
+    fn test_dedup_ips_keeps_order() {
+        let ips = [
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        ];
+        assert_eq!(
+            dedup_ips(ips),
+            vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_ns_dedup_overlapping_ips() {
+        // both nameservers share the same A record
+        let mock = MockDns::start(vec![
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str("ns1.example.com.").unwrap())),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str("ns2.example.com.").unwrap())),
+            ),
+            record("ns1.example.com.", 300, RData::A(A::new(192, 0, 2, 53))),
+            record("ns2.example.com.", 300, RData::A(A::new(192, 0, 2, 53))),
+        ])
+        .await;
+        let ip2asn_map = Arc::new(
+            Builder::new()
+                .with_source("".as_bytes())
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+        let response = query_ns("example.com", &mock.resolver(), &ip2asn_map).await;
+        let response = response.unwrap();
+        assert_eq!(response.names.len(), 2);
+        assert_eq!(
+            response.ips,
+            Some(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53))])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_ns_ips_glue() {
+        let ns = |name: &str| {
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str(name).unwrap())),
+            )
+        };
+        let mock = MockDns::start_with_glue(vec![
+            ns("ns1.example.com."),
+            ns("ns2.example.com."),
+            record("ns1.example.com.", 300, RData::A(A::new(192, 0, 2, 53))),
+            record(
+                "ns1.example.com.",
+                300,
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53)),
+            ),
+            record("ns2.example.com.", 300, RData::A(A::new(192, 0, 2, 54))),
+        ])
+        .await;
+        let (names, ips) = query_ns_ips("example.com.", &mock.resolver())
+            .await
+            .unwrap();
+        assert_eq!(names, ["ns1.example.com.", "ns2.example.com."]);
+        assert_eq!(
+            ips,
+            [
+                "192.0.2.53".parse::<IpAddr>().unwrap(),
+                "192.0.2.54".parse().unwrap(),
+                "2001:db8::53".parse().unwrap(),
+            ]
+        );
+        // the glue saves the lookups of the name servers
+        assert_eq!(
+            mock.queries(),
+            [("example.com.".to_string(), RecordType::NS)]
+        );
+
+        // a name server outside of the zone has no glue, it is resolved
+        let mock = MockDns::start_with_glue(vec![
+            ns("ns1.example.com."),
+            ns("ns.example.net."),
+            record("ns1.example.com.", 300, RData::A(A::new(192, 0, 2, 53))),
+            record("ns.example.net.", 300, RData::A(A::new(198, 51, 100, 53))),
+        ])
+        .await;
+        let (_, ips) = query_ns_ips("example.com.", &mock.resolver())
+            .await
+            .unwrap();
+        assert_eq!(ips.len(), 2);
+        let mut queries = mock.queries();
+        queries.sort_by_key(|(_, record_type)| u16::from(*record_type));
+        assert_eq!(
+            queries,
+            [
+                ("ns.example.net.".to_string(), RecordType::A),
+                ("example.com.".to_string(), RecordType::NS),
+                ("ns.example.net.".to_string(), RecordType::AAAA),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_ptr() {
+        let mock = MockDns::start(vec![record(
+            "53.2.0.192.in-addr.arpa.",
+            300,
+            RData::PTR(PTR(Name::from_str("ns.example.com.").unwrap())),
+        )])
+        .await;
+        let resolver = mock.resolver();
+        let ptr = query_ptr(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)), &resolver).await;
+        assert_eq!(ptr, Some("ns.example.com.".to_string()));
+        let ptr = query_ptr(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 54)), &resolver).await;
+        assert!(ptr.is_none());
+    }
+
+    /// Start a name server answering each A query with the address of the
+    /// client subnet it carries, like a geo-aware server would.
+    async fn start_ecs_echo_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.query().unwrap().clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .add_query(query.clone());
+                let subnet = request
+                    .extensions()
+                    .as_ref()
+                    .and_then(|edns| edns.option(EdnsCode::Subnet));
+                if let (Some(EdnsOption::Subnet(subnet)), RecordType::A) =
+                    (subnet, query.query_type())
+                    && let IpAddr::V4(addr) = subnet.addr()
+                {
+                    response.add_answer(record(&query.name().to_string(), 60, RData::A(A(addr))));
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_query_ipv4_ipv6_ecs() {
+        let server = start_ecs_echo_server().await;
+        let resolver = resolver_for(server);
+        let europe = IpNetwork::from_str("198.51.100.0/24").unwrap();
+        let america = IpNetwork::from_str("203.0.113.0/24").unwrap();
+        let ip_europe = query_ipv4_ipv6_ecs("geo.example.com", &resolver, &europe).await;
+        let ip_america = query_ipv4_ipv6_ecs("geo.example.com", &resolver, &america).await;
+        assert_eq!(
+            ip_europe,
+            Some(vec![IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0))])
+        );
+        assert_eq!(
+            ip_america,
+            Some(vec![IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0))])
+        );
+    }
 }
@@ -0,0 +1,187 @@
+use anyhow::Result;
+use reqwest::header::{self, HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use url::Url;
+
+/// Timeout of the HTTP request fetching the headers of an origin
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Directives of a `Strict-Transport-Security` header
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Hsts {
+    /// Seconds the browsers only connect over HTTPS, none when the
+    /// directive is missing or invalid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl Hsts {
+    /// Parse the directives of the header, case-insensitive and separated
+    /// by `;`, ignoring the unknown ones
+    pub fn parse(value: &str) -> Self {
+        let mut hsts = Hsts::default();
+        for directive in value.split(';').map(str::trim) {
+            let (name, value) = directive
+                .split_once('=')
+                .map_or((directive, None), |(name, value)| {
+                    (name.trim(), Some(value.trim().trim_matches('"')))
+                });
+            match name.to_ascii_lowercase().as_str() {
+                "max-age" => hsts.max_age = value.and_then(|value| value.parse().ok()),
+                "includesubdomains" => hsts.include_subdomains = true,
+                "preload" => hsts.preload = true,
+                _ => {}
+            }
+        }
+        hsts
+    }
+}
+
+/// Security headers of the HTTP response of an origin
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SecurityHeaders {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_transport_security: Option<Hsts>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_security_policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_frame_options: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_content_type_options: Option<String>,
+}
+
+impl SecurityHeaders {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name: header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        SecurityHeaders {
+            strict_transport_security: value(header::STRICT_TRANSPORT_SECURITY)
+                .map(|value| Hsts::parse(&value)),
+            content_security_policy: value(header::CONTENT_SECURITY_POLICY),
+            x_frame_options: value(header::X_FRAME_OPTIONS),
+            x_content_type_options: value(header::X_CONTENT_TYPE_OPTIONS),
+        }
+    }
+}
+
+/// Fetch the security headers of the origin `url` from `ip`, the address
+/// the host was resolved to, without following the redirects. The
+/// certificate is not checked, the TLS probes report it.
+pub async fn fetch_security_headers(
+    url: &str,
+    ip: IpAddr,
+    bind: Option<IpAddr>,
+) -> Result<SecurityHeaders> {
+    let url = Url::parse(url).map_err(|e| anyhow::anyhow!("Invalid origin {}: {}", url, e))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("No host in origin {}", url))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let client = reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .danger_accept_invalid_certs(true)
+        .no_proxy()
+        .resolve(host, SocketAddr::new(ip, port))
+        .local_address(bind)
+        .user_agent(concat!("webinfo/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to build the HTTP client: {}", e))?;
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to fetch {}: {}", url, e))?;
+    Ok(SecurityHeaders::from_headers(response.headers()))
+}
+
+/// Serve a single HTTP response with `headers` on a loopback port
+#[cfg(test)]
+pub(crate) async fn stub_server(headers: &'static str) -> u16 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).await;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            headers
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    });
+    port
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hsts() {
+        assert_eq!(
+            Hsts::parse("max-age=31536000; includeSubDomains; preload"),
+            Hsts {
+                max_age: Some(31536000),
+                include_subdomains: true,
+                preload: true,
+            }
+        );
+        assert_eq!(
+            Hsts::parse("Max-Age=\"600\""),
+            Hsts {
+                max_age: Some(600),
+                ..Default::default()
+            }
+        );
+        assert_eq!(Hsts::parse("max-age=soon").max_age, None);
+        assert_eq!(Hsts::parse("includesubdomains").max_age, None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_security_headers() {
+        let port = stub_server(
+            "Strict-Transport-Security: max-age=63072000; includeSubDomains\r\n\
+             Content-Security-Policy: default-src 'self'\r\n\
+             X-Frame-Options: DENY\r\n\
+             X-Content-Type-Options: nosniff\r\n",
+        )
+        .await;
+        // the host is pinned to the loopback address instead of resolved
+        let url = format!("http://stub.example:{}/", port);
+        let headers = fetch_security_headers(&url, "127.0.0.1".parse().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            headers,
+            SecurityHeaders {
+                strict_transport_security: Some(Hsts {
+                    max_age: Some(63072000),
+                    include_subdomains: true,
+                    preload: false,
+                }),
+                content_security_policy: Some("default-src 'self'".to_string()),
+                x_frame_options: Some("DENY".to_string()),
+                x_content_type_options: Some("nosniff".to_string()),
+            }
+        );
+
+        let port = stub_server("").await;
+        let url = format!("http://stub.example:{}/", port);
+        let headers = fetch_security_headers(&url, "127.0.0.1".parse().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(headers, SecurityHeaders::default());
+    }
+}
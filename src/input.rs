@@ -0,0 +1,506 @@
+use super::ipinfo::OriginRecord;
+use anyhow::Result;
+use indexmap::IndexMap;
+use publicsuffix2::{List, MatchOpts};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+use std::{
+    io::{BufRead, Read},
+    net::IpAddr,
+};
+use url::Url;
+
+/// Columns of a CSV input read into the fields of `OriginRecord`
+const ORIGIN_COLUMNS: &[&str] = &["origin", "popularity", "date", "country", "ips"];
+
+/// Origin record of a CSV row, the columns other than its fields being kept
+/// as is in its metadata
+fn origin_record(headers: &csv::StringRecord, record: &csv::StringRecord) -> Result<OriginRecord> {
+    let mut origin: OriginRecord = record.deserialize(Some(headers))?;
+    origin.metadata = headers
+        .iter()
+        .zip(record.iter())
+        .filter(|(header, _)| !ORIGIN_COLUMNS.contains(header))
+        .map(|(header, value)| (header.to_string(), value.to_string()))
+        .collect();
+    Ok(origin)
+}
+
+/// Read the origin records of a CSV input with a header line, the extra
+/// columns being kept in the metadata of the records
+pub fn csv_records<R: Read>(reader: R) -> impl Iterator<Item = Result<OriginRecord>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers().cloned().map_err(|e| e.to_string());
+    reader.into_records().map(move |record| {
+        let headers = headers
+            .as_ref()
+            .map_err(|e| anyhow::anyhow!("Failed to read CSV header: {}", e))?;
+        origin_record(headers, &record?)
+    })
+}
+
+/// Read the origin records of a CSV input with a header line, accepting the
+/// rows whose column count differs from the header: the columns past the
+/// header are dropped and missing trailing columns take their default value.
+pub fn flexible_csv_records<R: Read>(reader: R) -> impl Iterator<Item = Result<OriginRecord>> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let headers = reader.headers().cloned().map_err(|e| e.to_string());
+    reader.into_records().map(move |record| {
+        let headers = headers
+            .as_ref()
+            .map_err(|e| anyhow::anyhow!("Failed to read CSV header: {}", e))?;
+        let mut record = record?;
+        record.truncate(headers.len());
+        // the missing columns are left out of the headers to take their default
+        let headers = headers
+            .iter()
+            .take(record.len())
+            .collect::<csv::StringRecord>();
+        origin_record(&headers, &record)
+    })
+}
+
+/// Read a list of IP addresses, one per line. Blank lines and lines starting
+/// with `#` are skipped.
+pub fn ip_records<R: BufRead>(reader: R) -> impl Iterator<Item = Result<OriginRecord>> {
+    reader.lines().filter_map(|line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        Some(
+            line.parse::<IpAddr>()
+                .map(OriginRecord::from_ip)
+                .map_err(|e| anyhow::anyhow!("Invalid IP address {}: {}", line, e)),
+        )
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum JsonArrayState {
+    Start,
+    Element,
+    Done,
+}
+
+/// Stream the elements of a JSON array of origin records, deserializing one
+/// element at a time so that the whole array is never buffered in memory.
+pub struct JsonArrayRecords<R: BufRead> {
+    reader: R,
+    state: JsonArrayState,
+}
+
+impl<R: BufRead> JsonArrayRecords<R> {
+    pub fn new(reader: R) -> Self {
+        JsonArrayRecords {
+            reader,
+            state: JsonArrayState::Start,
+        }
+    }
+
+    /// Consume the whitespaces and return the next byte without consuming it
+    fn peek_byte(&mut self) -> Result<Option<u8>> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            match buf.first() {
+                None => return Ok(None),
+                Some(b) if b.is_ascii_whitespace() => self.reader.consume(1),
+                Some(b) => return Ok(Some(*b)),
+            }
+        }
+    }
+
+    fn expect_byte(&mut self, expected: &[u8]) -> Result<u8> {
+        match self.peek_byte()? {
+            Some(b) if expected.contains(&b) => {
+                self.reader.consume(1);
+                Ok(b)
+            }
+            Some(b) => Err(anyhow::anyhow!(
+                "Invalid JSON array: unexpected character '{}'",
+                b as char
+            )),
+            None => Err(anyhow::anyhow!(
+                "Invalid JSON array: unexpected end of input"
+            )),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<OriginRecord>> {
+        if self.state == JsonArrayState::Start {
+            self.expect_byte(b"[")?;
+            if self.peek_byte()? == Some(b']') {
+                self.reader.consume(1);
+                self.state = JsonArrayState::Done;
+                return Ok(None);
+            }
+            self.state = JsonArrayState::Element;
+        }
+        if self.state == JsonArrayState::Done {
+            return Ok(None);
+        }
+        let mut deserializer = serde_json::Deserializer::from_reader(&mut self.reader);
+        let record = OriginRecord::deserialize(&mut deserializer)?;
+        if self.expect_byte(b",]")? == b']' {
+            self.state = JsonArrayState::Done;
+        }
+        Ok(Some(record))
+    }
+}
+
+impl<R: BufRead> Iterator for JsonArrayRecords<R> {
+    type Item = Result<OriginRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(record) => record.map(Ok),
+            Err(e) => {
+                // the position in the array is lost, stop reading
+                self.state = JsonArrayState::Done;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Key identifying the site of an origin when deduplicating: its hostname,
+/// without a leading `www.` when `strip_www` is set. The prefix is only
+/// removed when the rest of the hostname is still a registrable domain, so
+/// that a name like `www.co.uk` is left untouched.
+pub fn dedup_key(origin: &str, strip_www: bool, list: &List) -> Option<String> {
+    let url = Url::parse(origin).ok()?;
+    let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+    if strip_www
+        && let Some(rest) = host.strip_prefix("www.")
+        && list
+            .tld(rest, MatchOpts::default())
+            .is_some_and(|tld| tld.len() < rest.len())
+    {
+        return Some(rest.to_string());
+    }
+    Some(host)
+}
+
+/// Remove the records pointing to the same site, keeping the most popular one
+/// at the position of the first occurrence. Malformed rows and origins without
+/// a hostname are kept as is. The registrable domains are those of `list`.
+pub fn dedup_records<I>(records: I, strip_www: bool, list: &List) -> Vec<Result<OriginRecord>>
+where
+    I: Iterator<Item = Result<OriginRecord>>,
+{
+    let mut selected: Vec<Result<OriginRecord>> = Vec::new();
+    let mut seen: IndexMap<String, usize> = IndexMap::new();
+    for record in records {
+        let key = match &record {
+            Ok(origin) => dedup_key(&origin.origin, strip_www, list),
+            Err(_) => None,
+        };
+        let Some(key) = key else {
+            selected.push(record);
+            continue;
+        };
+        match seen.get(&key) {
+            Some(&index) => {
+                if let (Ok(kept), Ok(origin)) = (&selected[index], &record)
+                    && origin.popularity > kept.popularity
+                {
+                    selected[index] = record;
+                }
+            }
+            None => {
+                seen.insert(key, selected.len());
+                selected.push(record);
+            }
+        }
+    }
+    selected
+}
+
+/// Reservoir sample of `n` records, drawn with a generator seeded with `seed`
+/// so that a sample can be reproduced. The sampled records keep their input
+/// order, and malformed rows are kept in front of them so that they are
+/// still reported.
+pub fn sample_records<I>(records: I, n: usize, seed: u64) -> Vec<Result<OriginRecord>>
+where
+    I: Iterator<Item = Result<OriginRecord>>,
+{
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut reservoir: Vec<(usize, OriginRecord)> = Vec::with_capacity(n);
+    let mut malformed = Vec::new();
+    let mut seen = 0;
+    for record in records {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                malformed.push(Err(e));
+                continue;
+            }
+        };
+        if reservoir.len() < n {
+            reservoir.push((seen, record));
+        } else {
+            let index = rng.random_range(0..=seen);
+            if index < n {
+                reservoir[index] = (seen, record);
+            }
+        }
+        seen += 1;
+    }
+    reservoir.sort_by_key(|(position, _)| *position);
+    malformed
+        .into_iter()
+        .chain(reservoir.into_iter().map(|(_, record)| Ok(record)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipinfo::default_psl;
+
+    fn to_json(records: impl Iterator<Item = Result<OriginRecord>>) -> Vec<serde_json::Value> {
+        records
+            .map(|r| serde_json::to_value(r.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_json_array_same_as_csv() {
+        let csv = "origin,popularity,date,country\n\
+            https://www.google.fr,1000,2025-08-28,FR\n\
+            https://www.free.fr,5000,2025-08-28,FR\n";
+        let json = r#"[
+            {"origin": "https://www.google.fr", "popularity": 1000, "date": "2025-08-28", "country": "FR"},
+            {"origin": "https://www.free.fr", "popularity": 5000, "date": "2025-08-28", "country": "FR"}
+        ]"#;
+        let from_csv = to_json(csv_records(csv.as_bytes()));
+        let from_json = to_json(JsonArrayRecords::new(json.as_bytes()));
+        assert_eq!(from_csv.len(), 2);
+        assert_eq!(from_csv, from_json);
+    }
+
+    #[test]
+    fn test_csv_records_metadata() {
+        let csv = "origin,tag,popularity,date,country,internal_id\n\
+            https://www.google.fr,search,1000,2025-08-28,FR,007\n\
+            https://www.free.fr,,5000,2025-08-28,FR,1.50\n";
+        let records = to_json(csv_records(csv.as_bytes()));
+        assert_eq!(
+            records[0],
+            serde_json::json!({
+                "origin": "https://www.google.fr",
+                "popularity": 1000,
+                "date": "2025-08-28",
+                "country": "FR",
+                "internal_id": "007",
+                "tag": "search",
+            })
+        );
+        assert_eq!(records[1]["tag"], "");
+        assert_eq!(records[1]["internal_id"], "1.50");
+
+        // the ragged rows keep the metadata of the columns they have
+        let csv = "origin,popularity,tag\nhttps://www.free.fr,5000\nhttps://www.sfr.fr,10,isp,x\n";
+        let records = flexible_csv_records(csv.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert!(records[0].metadata.is_empty());
+        assert_eq!(
+            records[1].metadata.get("tag").map(String::as_str),
+            Some("isp")
+        );
+    }
+
+    #[test]
+    fn test_flexible_csv_records() {
+        let csv = "origin,popularity,date,country\n\
+            https://www.google.fr,1000,2025-08-28,FR,extra\n\
+            https://www.free.fr,5000\n\
+            https://www.orange.fr,2000,2025-08-28,FR\n\
+            https://www.sfr.fr,many,2025-08-28,FR\n";
+        // the strict reader rejects the ragged rows
+        let strict = csv_records(csv.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(strict.iter().filter(|r| r.is_err()).count(), 3);
+
+        let records = flexible_csv_records(csv.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 4);
+        let google = records[0].as_ref().unwrap();
+        assert_eq!(google.origin, "https://www.google.fr");
+        assert_eq!(google.country, "FR");
+        let free = records[1].as_ref().unwrap();
+        assert_eq!(free.popularity, 5000);
+        assert_eq!(free.date, "");
+        assert_eq!(free.country, "");
+        assert!(records[2].is_ok());
+        // a malformed value is still an error
+        assert!(records[3].is_err());
+    }
+
+    fn numbered_records(count: usize) -> Vec<Result<OriginRecord>> {
+        (0..count)
+            .map(|i| {
+                Ok(OriginRecord::new(
+                    format!("https://www.site{}.fr", i),
+                    i as u32,
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_records() {
+        let popularity = |records: Vec<Result<OriginRecord>>| {
+            records
+                .into_iter()
+                .map(|r| r.unwrap().popularity)
+                .collect::<Vec<_>>()
+        };
+        let sample = popularity(sample_records(numbered_records(1000).into_iter(), 10, 42));
+        assert_eq!(sample.len(), 10);
+        // input order is kept
+        assert!(sample.windows(2).all(|w| w[0] < w[1]));
+        // the same seed draws the same sample, another one a different sample
+        let same = popularity(sample_records(numbered_records(1000).into_iter(), 10, 42));
+        assert_eq!(sample, same);
+        let other = popularity(sample_records(numbered_records(1000).into_iter(), 10, 7));
+        assert_ne!(sample, other);
+        // a smaller input is kept whole
+        let sample = popularity(sample_records(numbered_records(3).into_iter(), 10, 42));
+        assert_eq!(sample, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_sample_records_malformed() {
+        let mut records = numbered_records(5);
+        records.insert(2, Err(anyhow::anyhow!("malformed")));
+        let sample = sample_records(records.into_iter(), 2, 42);
+        assert_eq!(sample.len(), 3);
+        assert!(sample[0].is_err());
+        assert!(sample[1..].iter().all(|r| r.is_ok()));
+    }
+
+    #[test]
+    fn test_records_with_ips() {
+        let csv = "origin,popularity,date,country,ips\n\
+            https://www.google.fr,1000,2025-08-28,FR,192.0.2.1 2001:db8::1\n\
+            https://www.free.fr,5000,2025-08-28,FR,\n";
+        let records = csv_records(csv.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let ips = vec![
+            "192.0.2.1".parse::<IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ];
+        assert_eq!(records[0].ips, Some(ips.clone()));
+        assert_eq!(records[1].ips, None);
+        let json = r#"[{"origin": "https://www.google.fr", "popularity": 1000, "date": "", "country": "FR", "ips": ["192.0.2.1", "2001:db8::1"]}]"#;
+        let records = JsonArrayRecords::new(json.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records[0].ips, Some(ips));
+        let csv = "origin,popularity,date,country,ips\nhttps://www.free.fr,1,,FR,not-an-ip\n";
+        assert!(csv_records(csv.as_bytes()).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_json_array_empty() {
+        assert_eq!(JsonArrayRecords::new(" [ ] ".as_bytes()).count(), 0);
+    }
+
+    #[test]
+    fn test_json_array_invalid() {
+        let mut records = JsonArrayRecords::new(r#"{"origin": "https://www.free.fr"}"#.as_bytes());
+        assert!(records.next().unwrap().is_err());
+        assert!(records.next().is_none());
+
+        let json = r#"[{"origin": "https://www.free.fr", "popularity": 1, "date": "", "country": "FR"} {}]"#;
+        let mut records = JsonArrayRecords::new(json.as_bytes());
+        assert!(records.next().unwrap().is_err());
+        assert!(records.next().is_none());
+    }
+
+    #[test]
+    fn test_ip_records() {
+        let input = "# resolvers\n1.1.1.1\n\n  2606:4700:4700::1111  \nnot-an-ip\n";
+        let records = ip_records(input.as_bytes()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].as_ref().unwrap().origin, "1.1.1.1");
+        assert_eq!(records[1].as_ref().unwrap().origin, "2606:4700:4700::1111");
+        assert!(records[2].is_err());
+    }
+
+    #[test]
+    fn test_dedup_key() {
+        let list = default_psl();
+        let key = |origin, strip_www| dedup_key(origin, strip_www, &list);
+        assert_eq!(
+            key("https://www.example.com", false).unwrap(),
+            "www.example.com"
+        );
+        assert_eq!(key("https://www.example.com", true).unwrap(), "example.com");
+        assert_eq!(
+            key("https://WWW.Example.com.", true).unwrap(),
+            "example.com"
+        );
+        assert_eq!(key("https://example.com", true).unwrap(), "example.com");
+        assert_eq!(
+            key("https://mail.example.com", true).unwrap(),
+            "mail.example.com"
+        );
+        assert_eq!(key("https://www.co.uk", true).unwrap(), "www.co.uk");
+        assert_eq!(
+            key("https://www2.example.com", true).unwrap(),
+            "www2.example.com"
+        );
+        assert_eq!(
+            key("https://www.mail.example.com", true).unwrap(),
+            "mail.example.com"
+        );
+        assert!(key("not an origin", true).is_none());
+    }
+
+    #[test]
+    fn test_dedup_records_www() {
+        let csv = "origin,popularity,date,country\n\
+            https://www.example.com,1000,2025-08-28,FR\n\
+            https://www.free.fr,5000,2025-08-28,FR\n\
+            https://example.com,10000,2025-08-28,FR\n\
+            https://free.fr,1000,2025-08-28,FR\n\
+            https://www.lefigaro.fr,1000,2025-08-28,FR\n";
+        let list = default_psl();
+        let origins = |records: Vec<Result<OriginRecord>>| {
+            records
+                .into_iter()
+                .map(|r| r.unwrap().origin)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            origins(dedup_records(csv_records(csv.as_bytes()), true, &list)),
+            vec![
+                "https://example.com",
+                "https://www.free.fr",
+                "https://www.lefigaro.fr"
+            ]
+        );
+        // without www normalization the hostnames are distinct
+        assert_eq!(
+            dedup_records(csv_records(csv.as_bytes()), false, &list).len(),
+            5
+        );
+        // with example.com a public suffix, www.example.com is a registrable domain
+        let list: List = "com\nexample.com\nfr\n".parse().unwrap();
+        assert_eq!(
+            origins(dedup_records(csv_records(csv.as_bytes()), true, &list)),
+            vec![
+                "https://www.example.com",
+                "https://www.free.fr",
+                "https://example.com",
+                "https://www.lefigaro.fr"
+            ]
+        );
+    }
+}
@@ -1,58 +1,523 @@
-use super::{asn, asn::Asn, dns, tls};
+use super::{
+    asn,
+    asn::Asn,
+    dns,
+    dns::{HttpsRecord, LatencyHistogram, ResolvedIp, Retry, RetryBudget},
+    http,
+    parking::ParkingRules,
+    reputation::IpBlocklist,
+    tls,
+    utils::AsnDbPrefetch,
+};
 use anyhow::Result;
-use hickory_resolver::{Resolver, name_server::ConnectionProvider};
+use futures::future::join_all;
+use hickory_resolver::{
+    Resolver,
+    config::{ResolverConfig, ResolverOpts},
+    name_server::ConnectionProvider,
+    proto::rr::RecordType,
+};
+use ip_network::IpNetwork;
 use ip2asn::IpAsnMap;
 use publicsuffix2::{List, MatchOpts, TypeFilter};
-use serde::{Deserialize, Serialize};
-use std::{net::IpAddr, sync::Arc};
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, LazyLock, atomic::AtomicUsize},
+};
 use tracing::{Level, event};
 use url::Url;
 
+/// Public suffix list bundled at compile time, parsed once for all the records
+static DEFAULT_PSL: LazyLock<Arc<List>> = LazyLock::new(|| Arc::new(List::default()));
+
+/// Record types looked up together with all the records enabled
+const OTHER_RECORD_TYPES: &[RecordType] = &[
+    RecordType::MX,
+    RecordType::TXT,
+    RecordType::SOA,
+    RecordType::CAA,
+];
+
+/// Shared instance of the bundled public suffix list. `List::default()`
+/// deep copies the whole rule tree, so it must not be called per record.
+pub fn default_psl() -> Arc<List> {
+    DEFAULT_PSL.clone()
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[allow(dead_code)]
 pub struct OriginRecord {
     pub origin: String,
+    /// Popularity rank, 0 when the record is unranked
+    #[serde(default)]
     pub popularity: u32,
+    #[serde(default)]
     pub date: String,
+    #[serde(default)]
     pub country: String,
+    /// Addresses already known for the host, looked up in place of DNS
+    #[serde(
+        default,
+        deserialize_with = "deserialize_ips",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ips: Option<Vec<IpAddr>>,
+    /// Extra columns of a CSV input, passed through to the output untouched.
+    /// Filled by the CSV readers rather than deserialized with the record, as
+    /// the csv crate guesses the type of a flattened column, `007` becoming 7.
+    #[serde(flatten, skip_deserializing)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Deserialize a list of IPs given as an array, or as a string of addresses
+/// separated by commas or whitespaces as in a CSV column
+fn deserialize_ips<'de, D>(deserializer: D) -> Result<Option<Vec<IpAddr>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Ips {
+        List(Vec<IpAddr>),
+        Text(String),
+    }
+    let ips = match Option::<Ips>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Ips::List(ips)) => ips,
+        Some(Ips::Text(text)) => text
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| ip.parse().map_err(de::Error::custom))
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(if ips.is_empty() { None } else { Some(ips) })
+}
+
+impl OriginRecord {
+    /// Record of the `origin` URL of rank `popularity`, without date, country
+    /// nor known addresses
+    pub fn new(origin: impl Into<String>, popularity: u32) -> OriginRecord {
+        OriginRecord {
+            origin: origin.into(),
+            popularity,
+            date: String::new(),
+            country: String::new(),
+            ips: None,
+            metadata: Default::default(),
+        }
+    }
+
+    /// Record of an IP address input, which has no popularity nor date
+    pub fn from_ip(ip: IpAddr) -> OriginRecord {
+        OriginRecord::new(ip.to_string(), 0)
+    }
+}
+
+/// Upper limits of the popularity rank bands, a rank belongs to the first
+/// band whose limit it does not exceed and to `other` past the last one
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankBands {
+    limits: Vec<u32>,
+}
+
+impl Default for RankBands {
+    fn default() -> Self {
+        RankBands::new(vec![1_000, 10_000, 100_000, 1_000_000])
+    }
+}
+
+impl RankBands {
+    pub fn new(mut limits: Vec<u32>) -> Self {
+        limits.sort_unstable();
+        limits.dedup();
+        RankBands { limits }
+    }
+
+    /// Band of a rank, such as `top-10k`, none for the unranked records
+    pub fn band(&self, rank: u32) -> Option<String> {
+        if rank == 0 {
+            return None;
+        }
+        let band = match self.limits.iter().find(|limit| rank <= **limit) {
+            Some(limit) => format!("top-{}", short_count(*limit)),
+            None => "other".to_string(),
+        };
+        Some(band)
+    }
 }
 
-#[derive(Serialize, Debug, Default)]
+/// Short form of a round count, 10k for 10000 and 1M for 1000000
+fn short_count(count: u32) -> String {
+    match count {
+        n if n >= 1_000_000 && n % 1_000_000 == 0 => format!("{}M", n / 1_000_000),
+        n if n >= 1_000 && n % 1_000 == 0 => format!("{}k", n / 1_000),
+        n => n.to_string(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
 pub struct IpInfoRecord {
     pub hostname: String,
+    /// Scheme of the origin URL, like https
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub scheme: String,
+    /// Port of the origin URL, none for the default port of the scheme
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Path and query of the origin URL, none for the root path without query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Popularity band of the origin rank, only set with the rank bands
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rank_band: Option<String>,
+    /// Whether the TLD is in the public suffix list, only set when the TLDs
+    /// missing from it are accepted, leniently or when they look like one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known_tld: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub domain: Option<String>,
+    /// Why `domain` could not be extracted from the hostname, see `DomainError`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_error: Option<String>,
+    /// Whether a random subdomain of `domain` resolves, only set with the wildcard check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wildcard_dns: Option<bool>,
+    /// Whether the name servers or the ASN are those of a parking provider,
+    /// only set with the parking detection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub likely_parked: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cname: Option<Vec<String>>,
+    /// Targets of the DNAME records of the host, only set with the DNAME lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dname: Option<Vec<String>>,
+    /// MX records of the host, only set with all the records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mx: Option<Vec<dns::MxRecord>>,
+    /// TXT records of the host, only set with all the records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txt: Option<Vec<String>>,
+    /// SOA record of the host, only set with all the records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soa: Option<dns::SoaRecord>,
+    /// CAA records of the host, only set with all the records
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caa: Option<Vec<dns::CaaRecord>>,
+    /// Whether the lookups were answered by the authoritative name servers,
+    /// only set with the authoritative queries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authoritative: Option<bool>,
+    /// Number of CNAME hops to the final target, 0 for a direct address record
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cname_depth: Option<usize>,
+    /// Whether the final target of the CNAME chain does not exist, a
+    /// subdomain takeover risk, only set with the takeover check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dangling_cname: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ns: Option<dns::NameServer>,
+    /// NS sets of the domain given by the parent zone and by its own name
+    /// servers, only set with the delegation trace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delegation: Option<dns::Delegation>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip: Option<Vec<IpAddr>>,
+    /// Number of IPv4 addresses in `ip`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv4_count: Option<usize>,
+    /// Number of IPv6 addresses in `ip`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_count: Option<usize>,
+    /// Whether the host resolves to both IPv4 and IPv6 addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dual_stack: Option<bool>,
+    /// Smallest TTL of the address and CNAME answers, only set with the TTL lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    /// HTTPS records of the host, only set with the HTTPS lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub https_rr: Option<Vec<HttpsRecord>>,
+    /// Whether an HTTPS record advertises an ECH configuration, only set with the HTTPS lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ech_present: Option<bool>,
+    /// EDNS Client Subnet sent along the IP lookups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ecs: Option<IpNetwork>,
+    /// PTR names, in the same order as `ip`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ptr: Option<Vec<Option<String>>>,
+    /// Label of the blocklist entry of each address, in the same order as
+    /// `ip`, only set with the reputation lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reputation: Option<Vec<Option<String>>>,
+    /// `ip` paired with the PTR name, ASN and reputation of each address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_ips: Option<Vec<ResolvedIp>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub asn: Option<Vec<Asn>>,
+    /// ASN of each IP, unlike `asn` merging them by ASN, only set with the per-IP ASN lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_asn: Option<Vec<(IpAddr, Asn)>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tls: Option<tls::CertificateIssuerInfo>,
+    /// Whether the certificate expires within the alert threshold, only set
+    /// with an expiry alert
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiring_soon: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_all_ips: Option<tls::CertificatesByIp>,
+    /// Security headers of the HTTP response of the origin, only set with
+    /// the security headers probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security_headers: Option<http::SecurityHeaders>,
+    /// Records of the www/apex sibling of the hostname
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub related: Option<Box<IpInfoRecord>>,
+    /// Raw DNS answers of the host by record type, only set when debugging its lookups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<serde_json::Value>,
 }
 
-#[derive(Serialize, Debug)]
+impl IpInfoRecord {
+    /// Count the IPv4 and IPv6 addresses of `ip`
+    fn count_ip_families(&mut self) {
+        let Some(ips) = &self.ip else {
+            return;
+        };
+        let ipv4_count = ips.iter().filter(|ip| ip.is_ipv4()).count();
+        let ipv6_count = ips.len() - ipv4_count;
+        self.ipv4_count = Some(ipv4_count);
+        self.ipv6_count = Some(ipv6_count);
+        self.dual_stack = Some(ipv4_count > 0 && ipv6_count > 0);
+    }
+
+    /// Flag each resolved IP with the label of its blocklist entry
+    fn flag_reputation(&mut self, blocklist: &IpBlocklist) {
+        self.reputation = self.ip.as_ref().map(|ips| {
+            ips.iter()
+                .map(|addr| blocklist.reputation(*addr).map(str::to_string))
+                .collect()
+        });
+    }
+
+    /// Pair each resolved IP with its PTR name, ASN and reputation
+    fn pair_resolved_ips(&self, ip2asn_map: Option<&Arc<IpAsnMap>>) -> Option<Vec<ResolvedIp>> {
+        let ips = self.ip.as_ref()?;
+        let resolved = ips
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| ResolvedIp {
+                addr: *addr,
+                ptr: self
+                    .ptr
+                    .as_ref()
+                    .and_then(|ptr| ptr.get(i).cloned().flatten()),
+                asn: ip2asn_map
+                    .and_then(|ip2asn_map| Asn::from_ip(addr, ip2asn_map))
+                    .map(|asn| asn.asn),
+                reputation: self
+                    .reputation
+                    .as_ref()
+                    .and_then(|reputation| reputation.get(i).cloned().flatten()),
+            })
+            .collect();
+        Some(resolved)
+    }
+
+    /// Strip the trailing dot of all the DNS names of the record, or add it
+    /// to those without one when `trailing_dot`. The root name is left as is.
+    pub fn normalize_names(&mut self, trailing_dot: bool) {
+        let normalize = |name: &mut String| {
+            if name.is_empty() || name == "." {
+                return;
+            }
+            if trailing_dot && !name.ends_with('.') {
+                name.push('.');
+            } else if !trailing_dot {
+                name.truncate(name.trim_end_matches('.').len());
+            }
+        };
+        normalize(&mut self.hostname);
+        self.domain.iter_mut().for_each(normalize);
+        self.cname.iter_mut().flatten().for_each(normalize);
+        self.dname.iter_mut().flatten().for_each(normalize);
+        for mx in self.mx.iter_mut().flatten() {
+            normalize(&mut mx.exchange);
+        }
+        if let Some(soa) = &mut self.soa {
+            normalize(&mut soa.mname);
+            normalize(&mut soa.rname);
+        }
+        if let Some(ns) = &mut self.ns {
+            ns.names.iter_mut().for_each(normalize);
+        }
+        if let Some(delegation) = &mut self.delegation {
+            normalize(&mut delegation.parent_zone);
+            delegation.parent_ns.iter_mut().for_each(normalize);
+            delegation.child_ns.iter_mut().flatten().for_each(normalize);
+        }
+        self.ptr.iter_mut().flatten().flatten().for_each(normalize);
+        for resolved in self.resolved_ips.iter_mut().flatten() {
+            resolved.ptr.iter_mut().for_each(normalize);
+        }
+        for record in self.https_rr.iter_mut().flatten() {
+            normalize(&mut record.target);
+        }
+        if let Some(related) = &mut self.related {
+            related.normalize_names(trailing_dot);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct IpInfo {
     pub origin: OriginRecord,
     pub records: IpInfoRecord,
 }
 
+/// Why the registrable domain of a hostname could not be extracted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainError {
+    /// The hostname is empty or has an empty label
+    InvalidHostname,
+    /// The TLD matches no rule of the public suffix list
+    NoMatch,
+    /// The hostname is itself a public suffix, with no label left to register
+    NoSld,
+}
+
+impl DomainError {
+    /// Name of the reason, recorded as `domain_error`
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DomainError::InvalidHostname => "invalid_hostname",
+            DomainError::NoMatch => "no_psl_match",
+            DomainError::NoSld => "no_sld",
+        }
+    }
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            DomainError::InvalidHostname => "Invalid hostname",
+            DomainError::NoMatch => "No public suffix list rule matches the TLD",
+            DomainError::NoSld => "The hostname is a public suffix",
+        };
+        write!(f, "{} ({})", message, self.reason())
+    }
+}
+
+impl std::error::Error for DomainError {}
+
+/// Whether a hostname missing from the public suffix list may still be under a
+/// new TLD: it has at least two labels and its last label is alphabetic, or an
+/// IDN TLD in its `xn--` form
+fn plausible_tld(hostname: &str) -> bool {
+    let mut labels = hostname.trim_end_matches('.').rsplit('.');
+    let tld = labels.next().unwrap_or("");
+    let alphabetic =
+        |label: &str| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphabetic());
+    labels.next().is_some_and(|label| !label.is_empty())
+        && (alphabetic(tld)
+            || tld.strip_prefix("xn--").is_some_and(|idn| {
+                !idn.is_empty() && idn.chars().all(|c| c.is_ascii_alphanumeric())
+            }))
+}
+
+/// Check that a hostname follows the LDH rule (RFC 1123): labels of letters,
+/// digits and hyphens, not starting nor ending with a hyphen, of at most 63
+/// characters. The underscore, found in real world names, is tolerated.
+fn check_hostname(hostname: &str) -> std::result::Result<(), String> {
+    let name = hostname.strip_suffix('.').unwrap_or(hostname);
+    if name.is_empty() {
+        return Err("empty hostname".to_string());
+    }
+    if name.len() > 253 {
+        return Err(format!(
+            "hostname longer than 253 characters: {}",
+            name.len()
+        ));
+    }
+    for label in name.split('.') {
+        if label.is_empty() {
+            return Err("empty label".to_string());
+        }
+        if label.len() > 63 {
+            return Err(format!("label longer than 63 characters: {}", label));
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+        {
+            return Err(format!("illegal character {:?} in label {}", c, label));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(format!("label starting or ending with a hyphen: {}", label));
+        }
+    }
+    Ok(())
+}
+
 //******************************************************************************
 //
 // Builder pattern for IpInfo
 //
 //******************************************************************************
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IpInfoRunner<T: ConnectionProvider> {
     origin: OriginRecord,
     resolver: Option<Resolver<T>>,
     ip2asn_map: Option<Arc<IpAsnMap>>,
+    asn_prefetch: Option<AsnDbPrefetch>,
     tls: bool,
+    ptr: bool,
+    resolved_ips: bool,
+    per_ip_asn: bool,
+    asn_by_family: bool,
+    ecs: Option<IpNetwork>,
+    tls_first_success: bool,
+    starttls: Option<tls::StartTls>,
+    expiry_alert_days: Option<i64>,
+    tls_all_ips: bool,
+    max_tls_ips: usize,
+    no_tls_asns: Vec<u32>,
+    security_headers: bool,
+    host_header: Option<String>,
+    origin_path: bool,
+    bind: Option<IpAddr>,
+    lenient_tld: bool,
+    accept_unknown_tld: bool,
+    psl: Arc<List>,
+    reverse_ip: Option<IpAddr>,
+    wildcard_check: bool,
+    include_www: bool,
+    cname_depth: bool,
+    takeover_check: bool,
+    rank_bands: Option<RankBands>,
+    ttl: bool,
+    https_rr: bool,
+    dname: bool,
+    all_records: bool,
+    raw_dns: Option<Vec<String>>,
+    ips: Option<Vec<IpAddr>>,
+    parking_rules: Option<Arc<ParkingRules>>,
+    blocklist: Option<Arc<IpBlocklist>>,
+    authoritative: Option<BuildResolver<T>>,
+    open_resolver_check: Option<BuildResolver<T>>,
+    edns_cookie_check: bool,
+    delegation_trace: bool,
+    authoritative_port: u16,
+    retry: Retry,
 }
 
+/// Build a resolver of the same connection provider from a configuration
+type BuildResolver<T> = fn(ResolverConfig, ResolverOpts) -> Resolver<T>;
+
 impl<T: ConnectionProvider> IpInfoRunner<T> {
     pub fn with_resolver(mut self, resolver: Resolver<T>) -> Self {
         self.resolver = Some(resolver);
@@ -64,60 +529,801 @@ impl<T: ConnectionProvider> IpInfoRunner<T> {
         self
     }
 
+    /// Use the ASN database loading in the background, waiting for it only
+    /// once the addresses are resolved
+    pub fn with_asn_prefetch(mut self, prefetch: AsnDbPrefetch) -> Self {
+        self.asn_prefetch = Some(prefetch);
+        self
+    }
+
+    /// ASN database of the lookups, waiting for the prefetched one
+    async fn ip2asn_map(&self) -> Option<Arc<IpAsnMap>> {
+        match (&self.ip2asn_map, &self.asn_prefetch) {
+            (Some(ip2asn_map), _) => Some(ip2asn_map.clone()),
+            (None, Some(prefetch)) => prefetch.get().await,
+            (None, None) => None,
+        }
+    }
+
     pub fn with_tls(mut self) -> Self {
         self.tls = true;
         self
     }
 
+    /// Probe the certificate on all the resolved IPs at once, keeping the
+    /// first handshake to succeed, so that a firewalled IP does not lose the
+    /// certificate of the host. Implies `with_tls`.
+    pub fn with_tls_first_success(mut self) -> Self {
+        self.tls = true;
+        self.tls_first_success = true;
+        self
+    }
+
+    /// Probe the certificate of a mail server, upgrading the plaintext
+    /// `protocol` with STARTTLS, whatever the scheme of the origin. Implies
+    /// `with_tls`.
+    pub fn with_starttls(mut self, protocol: tls::StartTls) -> Self {
+        self.tls = true;
+        self.starttls = Some(protocol);
+        self
+    }
+
+    /// Flag with `expiring_soon` the certificates expiring within `days`, or
+    /// expired. Implies `with_tls`.
+    pub fn with_expiry_alert_days(mut self, days: i64) -> Self {
+        self.tls = true;
+        self.expiry_alert_days = Some(days);
+        self
+    }
+
+    /// Retrieve the certificate of every resolved IP instead of a single one
+    pub fn with_tls_all_ips(mut self) -> Self {
+        self.tls_all_ips = true;
+        self
+    }
+
+    /// Probe at most the first `max` resolved IPs with `with_tls_all_ips` and
+    /// `with_tls_first_success`, 3 by default, to bound the connections to
+    /// the round-robin hosts with dozens of IPs. At least one IP is probed.
+    pub fn with_max_tls_ips(mut self, max: usize) -> Self {
+        self.max_tls_ips = max.max(1);
+        self
+    }
+
+    /// Skip the TLS probes of the IPs announced by one of `asns`, like
+    /// honeypots or sensitive networks, once their ASN is looked up. The
+    /// IPs whose ASN is unknown are skipped as well.
+    pub fn with_no_tls_asns(mut self, asns: Vec<u32>) -> Self {
+        self.no_tls_asns = asns;
+        self
+    }
+
+    /// Fetch the origin from its first resolved IP and record the security
+    /// headers of the response: HSTS, CSP, X-Frame-Options and
+    /// X-Content-Type-Options
+    pub fn with_security_headers(mut self) -> Self {
+        self.security_headers = true;
+        self
+    }
+
+    /// IPs of the host the TLS probes may connect to, none when all of them
+    /// are skipped. With a skip-list, an IP is only probed once its ASN is
+    /// known and not in the list, so that a missing ASN database fails closed.
+    fn tls_allowed_ips(
+        &self,
+        hostname: &str,
+        ips: &[IpAddr],
+        ip2asn_map: Option<&Arc<IpAsnMap>>,
+    ) -> Option<Vec<IpAddr>> {
+        let allowed = ips
+            .iter()
+            .filter(|ip| {
+                if self.no_tls_asns.is_empty() {
+                    return true;
+                }
+                let asn = ip2asn_map.and_then(|ip2asn_map| Asn::from_ip(ip, ip2asn_map));
+                match asn.map(|asn| asn.asn) {
+                    Some(asn) if !self.no_tls_asns.contains(&asn) => true,
+                    Some(asn) => {
+                        event!(
+                            Level::INFO,
+                            "Skipping the TLS probes of {} on {} announced by AS{}",
+                            hostname,
+                            ip,
+                            asn
+                        );
+                        false
+                    }
+                    None => {
+                        event!(
+                            Level::INFO,
+                            "Skipping the TLS probes of {} on {} of unknown ASN",
+                            hostname,
+                            ip
+                        );
+                        false
+                    }
+                }
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        Some(allowed).filter(|allowed| !allowed.is_empty())
+    }
+
+    /// IPs of the host probed by the TLS modes probing several of them
+    fn tls_ips<'a>(&self, ips: &'a [IpAddr]) -> &'a [IpAddr] {
+        &ips[..ips.len().min(self.max_tls_ips)]
+    }
+
+    /// Send `host` as the Host header of the HTTP request of the TLS probes,
+    /// to see how a load balancer routes a virtual host. The SNI, and the
+    /// name the certificate is checked against, stay the hostname.
+    pub fn with_host_header(mut self, host: &str) -> Self {
+        self.host_header = Some(host.to_string());
+        self
+    }
+
+    /// Connect the TLS probes from the `ip` source address, on a multi-homed
+    /// host scanning from a dedicated interface
+    pub fn with_bind(mut self, ip: IpAddr) -> Self {
+        self.bind = Some(ip);
+        self
+    }
+
+    /// Request the path and query of the origin in the HTTP request of the
+    /// TLS probes instead of `/`, for the virtual hosts routing on the path
+    pub fn with_origin_path(mut self) -> Self {
+        self.origin_path = true;
+        self
+    }
+
+    /// Path of the HTTP request of the TLS probes
+    fn request_path<'a>(&self, records: &'a IpInfoRecord) -> &'a str {
+        match (self.origin_path, &records.path) {
+            (true, Some(path)) => path,
+            _ => "/",
+        }
+    }
+
+    /// Options of the TLS probes requesting `path`
+    fn probe_options(&self, path: &str) -> tls::ProbeOptions {
+        tls::ProbeOptions {
+            host_header: self.host_header.clone(),
+            path: path.to_string(),
+            bind: self.bind,
+            starttls: self.starttls,
+        }
+    }
+
+    /// Lookup the PTR name of every resolved IP
+    pub fn with_ptr(mut self) -> Self {
+        self.ptr = true;
+        self
+    }
+
+    /// Send an EDNS Client Subnet option with the IP lookups, to get the
+    /// answers geo-aware name servers give to clients in `subnet`
+    pub fn with_ecs(mut self, subnet: IpNetwork) -> Self {
+        self.ecs = Some(subnet);
+        self
+    }
+
+    /// Output the IPs as `resolved_ips` objects pairing each address with its
+    /// PTR name and ASN, instead of the parallel `ip`/`ptr` arrays
+    pub fn with_resolved_ips(mut self) -> Self {
+        self.resolved_ips = true;
+        self
+    }
+
+    /// Output the ASN of each IP as `ip_asn`, so that the IPs of a host spread
+    /// over several ASNs can be told apart. The aggregated `asn` is kept.
+    pub fn with_per_ip_asn(mut self) -> Self {
+        self.per_ip_asn = true;
+        self
+    }
+
+    /// Split the `asn` entries by address family, an entry per ASN and
+    /// family, instead of merging the IPv4 and IPv6 networks of an ASN
+    pub fn with_asn_by_family(mut self) -> Self {
+        self.asn_by_family = true;
+        self
+    }
+
+    /// ASNs of `ips`, split by address family with `with_asn_by_family`
+    fn lookup_asns(&self, ips: &[IpAddr], ip2asn_map: &Arc<IpAsnMap>) -> Option<Vec<Asn>> {
+        match self.asn_by_family {
+            true => asn::lookup_ip_by_family(ips, ip2asn_map),
+            false => asn::lookup_ip(ips, ip2asn_map),
+        }
+    }
+
+    /// Accept the hostnames whose TLD is not in the public suffix list, such
+    /// as newly delegated or private TLDs, instead of rejecting the record
+    pub fn with_lenient_tld(mut self) -> Self {
+        self.lenient_tld = true;
+        self
+    }
+
+    /// Accept the hostnames whose TLD is not in the public suffix list but
+    /// looks like one, flagged with `known_tld`, so that the TLDs newer than
+    /// the list are not rejected. See [`plausible_tld`].
+    pub fn with_accept_unknown_tld(mut self) -> Self {
+        self.accept_unknown_tld = true;
+        self
+    }
+
+    /// Check whether the domain has a wildcard record, so that the hosts
+    /// resolving only through the wildcard can be told apart
+    pub fn with_wildcard_check(mut self) -> Self {
+        self.wildcard_check = true;
+        self
+    }
+
+    /// Also lookup the www/apex sibling of the hostname, `www.example.com` for
+    /// `example.com` and the other way round, nesting its records in `related`
+    pub fn with_include_www(mut self) -> Self {
+        self.include_www = true;
+        self
+    }
+
+    /// Follow the whole CNAME chain instead of its first hop, and record its
+    /// length as `cname_depth`
+    pub fn with_cname_depth(mut self) -> Self {
+        self.cname_depth = true;
+        self
+    }
+
+    /// Flag with `dangling_cname` the hosts whose CNAME chain ends on a name
+    /// answered with NXDOMAIN, like a deprovisioned cloud resource which can
+    /// be claimed by anyone
+    pub fn with_takeover_check(mut self) -> Self {
+        self.takeover_check = true;
+        self
+    }
+
+    /// Use `ips` as the addresses of the host instead of resolving it, no DNS
+    /// query is sent. The addresses of the origin record are used otherwise.
+    pub fn with_ips(mut self, ips: Vec<IpAddr>) -> Self {
+        self.ips = Some(ips);
+        self
+    }
+
+    /// Flag the domains which look parked or for sale as `likely_parked`,
+    /// from their name servers and ASN
+    pub fn with_parking_detection(self) -> Self {
+        self.with_parking_rules(ParkingRules::default())
+    }
+
+    /// Parking detection with custom rules, to add parking providers
+    pub fn with_parking_rules(mut self, rules: ParkingRules) -> Self {
+        self.parking_rules = Some(Arc::new(rules));
+        self
+    }
+
+    /// Flag the resolved IPs found in a local blocklist with the label of
+    /// their entry, as `reputation` in the same order as `ip`, or in
+    /// `resolved_ips` with `with_resolved_ips`
+    pub fn with_reputation(mut self, blocklist: Arc<IpBlocklist>) -> Self {
+        self.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Send the address, CNAME and wildcard queries without recursion to the
+    /// name servers of the domain, found through the resolver, to see what
+    /// the authoritative servers answer rather than a recursive cache
+    pub fn with_authoritative_queries(mut self) -> Self
+    where
+        T: Default,
+    {
+        self.authoritative = Some(|config, options| {
+            Resolver::builder_with_config(config, T::default())
+                .with_options(options)
+                .build()
+        });
+        self
+    }
+
+    /// Send a recursive query for a name of another zone to each name server
+    /// IP of the domain, flagging the open resolvers in `ns.open_resolver`.
+    /// The queries go to third-party servers, the check is off by default.
+    pub fn with_open_resolver_check(mut self) -> Self
+    where
+        T: Default,
+    {
+        self.open_resolver_check = Some(|config, options| {
+            Resolver::builder_with_config(config, T::default())
+                .with_options(options)
+                .build()
+        });
+        self
+    }
+
+    /// Send a query with a DNS cookie to each name server IP of the domain,
+    /// flagging those answering with a server cookie, which resist spoofed
+    /// answers, in `ns.edns_cookie_supported`
+    pub fn with_edns_cookie_check(mut self) -> Self {
+        self.edns_cookie_check = true;
+        self
+    }
+
+    /// Query the name servers of the parent zone of the domain for its
+    /// delegation and the delegated name servers for their own NS set,
+    /// recording both in `delegation` and flagging when they differ
+    pub fn with_delegation_trace(mut self) -> Self {
+        self.delegation_trace = true;
+        self
+    }
+
+    /// Port of the name servers queried directly, for the test servers
+    #[cfg(test)]
+    fn with_authoritative_port(mut self, port: u16) -> Self {
+        self.authoritative_port = port;
+        self
+    }
+
+    /// Resolver sending the queries directly to the name servers of the
+    /// domain of the record, none when they cannot be found
+    async fn authoritative_resolver(
+        &self,
+        ipinfo: &IpInfo,
+        resolver: &Resolver<T>,
+        build: BuildResolver<T>,
+    ) -> Option<Resolver<T>> {
+        let domain = ipinfo.extract_domain(&self.psl).ok()?;
+        let (_, ips) = dns::query_ns_ips(&domain, resolver).await?;
+        if ips.is_empty() {
+            return None;
+        }
+        let (config, options) = dns::authoritative_config(&ips, self.authoritative_port);
+        Some(build(config, options))
+    }
+
+    /// Record the smallest TTL of the address and CNAME answers as `ttl`
+    pub fn with_ttl(mut self) -> Self {
+        self.ttl = true;
+        self
+    }
+
+    /// Lookup the HTTPS records of the host, with their ALPN, port and IP hints
+    pub fn with_https_rr(mut self) -> Self {
+        self.https_rr = true;
+        self
+    }
+
+    /// Lookup the DNAME records of the host, redirecting its subtree
+    pub fn with_dname(mut self) -> Self {
+        self.dname = true;
+        self
+    }
+
+    /// Lookup every record type of the host, A, AAAA, CNAME, DNAME, HTTPS,
+    /// MX, TXT, SOA, CAA and NS, as concurrent queries rather than a single
+    /// ANY query, which most resolvers refuse or answer partially (RFC 8482)
+    pub fn with_all_records(mut self) -> Self {
+        self.all_records = true;
+        self.https_rr = true;
+        self.dname = true;
+        self
+    }
+
+    /// Attach the raw DNS answers of the host as `raw` when it is one of
+    /// `hosts`, or for every host when `hosts` is empty. Verbose, for debugging.
+    pub fn with_raw_dns(mut self, hosts: Vec<String>) -> Self {
+        self.raw_dns = Some(
+            hosts
+                .iter()
+                .map(|host| host.trim_end_matches('.').to_lowercase())
+                .collect(),
+        );
+        self
+    }
+
+    /// Whether the raw DNS answers of `hostname` are attached
+    fn debug_dns(&self, hostname: &str) -> bool {
+        let hostname = hostname.trim_end_matches('.').to_lowercase();
+        self.raw_dns
+            .as_ref()
+            .is_some_and(|hosts| hosts.is_empty() || hosts.contains(&hostname))
+    }
+
+    /// Annotate the record with the band of the origin popularity rank
+    pub fn with_rank_bands(mut self, rank_bands: RankBands) -> Self {
+        self.rank_bands = Some(rank_bands);
+        self
+    }
+
+    /// Retry the address and CNAME lookups of the host failing with a
+    /// timeout or a SERVFAIL up to `retries` times
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retry.retries = retries;
+        self
+    }
+
+    /// Take the retries from a budget shared with the other runners, once it
+    /// is spent the failed lookups are no longer retried
+    pub fn with_retry_budget(mut self, budget: Arc<RetryBudget>) -> Self {
+        self.retry.budget = Some(budget);
+        self
+    }
+
+    /// Count the DNS lookups failing with a timeout or a SERVFAIL in `errors`
+    pub fn with_transient_errors(mut self, errors: Arc<AtomicUsize>) -> Self {
+        self.retry.transient_errors = Some(errors);
+        self
+    }
+
+    /// Record the latency of the address and CNAME lookups in `histogram`
+    pub fn with_latency_histogram(mut self, histogram: Arc<LatencyHistogram>) -> Self {
+        self.retry.latency = Some(histogram);
+        self
+    }
+
+    /// Use `psl` instead of the public suffix list bundled at compile time
+    pub fn with_psl(mut self, psl: Arc<List>) -> Self {
+        self.psl = psl;
+        self
+    }
+
     pub async fn run(self) -> Result<IpInfo> {
+        if let Some(ip) = self.reverse_ip {
+            return self.run_reverse(ip).await;
+        }
+        let known_ips = self.ips.clone().or_else(|| self.origin.ips.clone());
+        if known_ips.is_some() && self.resolver.is_some() {
+            // the known addresses skip every DNS lookup, sibling included
+            let runner = IpInfoRunner {
+                resolver: None,
+                include_www: false,
+                ..self
+            };
+            return Box::pin(runner.run()).await;
+        }
         let mut ipinfo = IpInfo {
             origin: self.origin.clone(),
             records: IpInfoRecord::default(),
         };
-        ipinfo.extract_hostname()?;
+        ipinfo.extract_hostname(&self.psl, self.lenient_tld, self.accept_unknown_tld)?;
+        ipinfo.records.rank_band = self
+            .rank_bands
+            .as_ref()
+            .and_then(|bands| bands.band(ipinfo.origin.popularity));
+
+        // the authoritative resolver replaces the recursive one for the lookups of the host
+        let authoritative = match (self.authoritative, &self.resolver) {
+            (Some(build), Some(resolver)) => {
+                let authoritative = self.authoritative_resolver(&ipinfo, resolver, build).await;
+                ipinfo.records.authoritative = Some(authoritative.is_some());
+                authoritative
+            }
+            _ => None,
+        };
+        let host_resolver = authoritative.as_ref().or(self.resolver.as_ref());
 
         // Perform DNS lookups with timeouts
-        if self.resolver.is_some() {
-            // IP lookup
-            let ip =
-                dns::query_ipv4_ipv6(&ipinfo.records.hostname, self.resolver.as_ref().unwrap());
+        if let Some(resolver) = host_resolver {
+            let hostname = &ipinfo.records.hostname;
+            // IP lookup, along with the TTL of the answers
+            let ip = async {
+                match &self.ecs {
+                    Some(subnet) => (
+                        dns::query_ipv4_ipv6_ecs(hostname, resolver, subnet).await,
+                        None,
+                    ),
+                    None => dns::query_ipv4_ipv6_with_retry(hostname, resolver, &self.retry)
+                        .await
+                        .map_or((None, None), |(ip, ttl)| (Some(ip), Some(ttl))),
+                }
+            };
             // CNAME lookup
-            let cname = dns::query_cname(&ipinfo.records.hostname, self.resolver.as_ref().unwrap());
-            let (ip, cname) = tokio::join!(ip, cname);
+            let cname = async {
+                if self.cname_depth {
+                    (dns::resolve_cname_chain(hostname, resolver).await, None)
+                } else {
+                    dns::query_cname_with_retry(hostname, resolver, &self.retry)
+                        .await
+                        .map_or((None, None), |(cname, ttl)| (Some(cname), Some(ttl)))
+                }
+            };
+            // HTTPS lookup
+            let https_rr = async {
+                match self.https_rr {
+                    true => dns::query_https(hostname, resolver).await,
+                    false => None,
+                }
+            };
+            // DNAME lookup
+            let dname = async {
+                match self.dname {
+                    true => dns::query_dname(hostname, resolver).await,
+                    false => None,
+                }
+            };
+            // MX, TXT, SOA and CAA lookups
+            let other = async {
+                match self.all_records {
+                    true => {
+                        let lookups =
+                            dns::query_multi(hostname, resolver, OTHER_RECORD_TYPES).await;
+                        (
+                            dns::mx_records(lookups.get(&RecordType::MX)),
+                            dns::txt_records(lookups.get(&RecordType::TXT)),
+                            dns::soa_record(lookups.get(&RecordType::SOA)),
+                            dns::caa_records(lookups.get(&RecordType::CAA)),
+                        )
+                    }
+                    false => (None, None, None, None),
+                }
+            };
+            let ((ip, ip_ttl), (cname, cname_ttl), https_rr, dname, (mx, txt, soa, caa)) =
+                tokio::join!(ip, cname, https_rr, dname, other);
+            if self.https_rr {
+                ipinfo.records.ech_present =
+                    Some(https_rr.iter().flatten().any(|record| record.ech.is_some()));
+            }
+            ipinfo.records.https_rr = https_rr;
+            if self.debug_dns(hostname) {
+                ipinfo.records.raw = Some(dns::raw_lookups(hostname, resolver).await);
+            }
+            if self.cname_depth && (ip.is_some() || cname.is_some()) {
+                ipinfo.records.cname_depth = Some(cname.as_ref().map_or(0, Vec::len));
+            }
+            if let (true, Some(cname)) = (self.takeover_check, &cname) {
+                // the whole chain is only followed with the CNAME depth
+                let chain = match self.cname_depth {
+                    true => Some(cname.clone()),
+                    false => dns::resolve_cname_chain(hostname, resolver).await,
+                };
+                if let Some(target) = chain.as_ref().and_then(|chain| chain.last()) {
+                    ipinfo.records.dangling_cname = dns::is_nx_domain(target, resolver).await;
+                }
+            }
+            if self.ttl {
+                ipinfo.records.ttl = ip_ttl.into_iter().chain(cname_ttl).min();
+            }
             ipinfo.records.ip = ip;
+            ipinfo.records.count_ip_families();
             ipinfo.records.cname = cname;
+            ipinfo.records.dname = dname;
+            ipinfo.records.mx = mx;
+            ipinfo.records.txt = txt;
+            ipinfo.records.soa = soa;
+            ipinfo.records.caa = caa;
+            ipinfo.records.ecs = self.ecs;
+        }
+        if let Some(ips) = known_ips {
+            ipinfo.records.ip = Some(dns::canonical_ips(ips));
+            ipinfo.records.count_ip_families();
         }
 
-        // ASN lookup
-        if self.ip2asn_map.is_some() && ipinfo.records.ip.is_some() {
-            ipinfo.records.asn = asn::lookup_ip(
-                ipinfo.records.ip.as_ref().unwrap(),
-                self.ip2asn_map.as_ref().unwrap(),
-            );
+        // ASN lookup, the first one needing the database
+        let ip2asn_map = self.ip2asn_map().await;
+        if let (Some(ip2asn_map), Some(ip)) = (&ip2asn_map, &ipinfo.records.ip) {
+            ipinfo.records.asn = self.lookup_asns(ip, ip2asn_map);
+            if self.per_ip_asn {
+                ipinfo.records.ip_asn = asn::lookup_ip_per_ip(ip, ip2asn_map);
+            }
+        }
+        // PTR lookup
+        if let (true, Some(resolver), Some(ip)) = (self.ptr, &self.resolver, &ipinfo.records.ip) {
+            let ptr = join_all(ip.iter().map(|ip| dns::query_ptr(*ip, resolver))).await;
+            ipinfo.records.ptr = Some(ptr);
+        }
+        if let Some(blocklist) = &self.blocklist {
+            ipinfo.records.flag_reputation(blocklist);
+        }
+        if self.resolved_ips {
+            ipinfo.records.resolved_ips = ipinfo.records.pair_resolved_ips(ip2asn_map.as_ref());
+            ipinfo.records.ptr = None;
+            ipinfo.records.reputation = None;
         }
         // extract TLD
-        ipinfo.records.domain = ipinfo.extract_domain();
-        if ipinfo.records.domain.is_some() && self.resolver.is_some() && self.ip2asn_map.is_some() {
-            // NS lookup
-            ipinfo.records.ns = dns::query_ns(
-                ipinfo.records.domain.as_ref().unwrap(),
-                self.resolver.as_ref().unwrap(),
-                self.ip2asn_map.as_ref().unwrap(),
-            )
-            .await;
+        match ipinfo.extract_domain(&self.psl) {
+            Ok(domain) => ipinfo.records.domain = Some(domain),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Warning: Could not parse domain from hostname {}: {}",
+                    &ipinfo.records.hostname,
+                    e
+                );
+                ipinfo.records.domain_error = Some(e.reason().to_string());
+            }
+        }
+        if let (Some(domain), Some(resolver)) = (&ipinfo.records.domain, &self.resolver) {
+            // NS lookup, without their ASN when there is no database
+            ipinfo.records.ns = match &ip2asn_map {
+                Some(ip2asn_map) => dns::query_ns(domain, resolver, ip2asn_map).await,
+                None if self.all_records
+                    || self.open_resolver_check.is_some()
+                    || self.edns_cookie_check
+                    || self.parking_rules.is_some() =>
+                {
+                    dns::query_ns_ips(domain, resolver)
+                        .await
+                        .map(|(names, ips)| dns::NameServer {
+                            names,
+                            ips: (!ips.is_empty()).then_some(ips),
+                            asn: None,
+                            open_resolver: None,
+                            edns_cookie_supported: None,
+                        })
+                }
+                None => None,
+            };
+        }
+        if let (Some(build), Some(ns)) = (self.open_resolver_check, &mut ipinfo.records.ns) {
+            let probes = ns.ips.iter().flatten().map(|ip| {
+                let (config, options) = dns::open_resolver_config(*ip, self.authoritative_port);
+                let resolver = build(config, options);
+                async move { dns::is_open_resolver(&resolver).await }
+            });
+            ns.open_resolver = Some(join_all(probes).await);
+        }
+        if let (true, Some(domain), Some(ns)) = (
+            self.edns_cookie_check,
+            &ipinfo.records.domain,
+            &mut ipinfo.records.ns,
+        ) {
+            let probes = ns.ips.iter().flatten().map(|ip| {
+                dns::supports_edns_cookie(SocketAddr::new(*ip, self.authoritative_port), domain)
+            });
+            ns.edns_cookie_supported = Some(join_all(probes).await);
+        }
+        if let (true, Some(domain), Some(resolver)) = (
+            self.delegation_trace,
+            &ipinfo.records.domain,
+            &self.resolver,
+        ) {
+            ipinfo.records.delegation =
+                dns::trace_delegation(domain, resolver, self.authoritative_port).await;
+        }
+        if let (true, Some(domain), Some(resolver)) =
+            (self.wildcard_check, &ipinfo.records.domain, host_resolver)
+        {
+            ipinfo.records.wildcard_dns = Some(dns::has_wildcard(domain, resolver).await);
+        }
+        if let Some(rules) = &self.parking_rules {
+            ipinfo.records.likely_parked = rules.likely_parked(&ipinfo.records);
+        }
+
+        // no TLS traffic is sent to the skipped networks
+        let tls_ips = match (self.tls || self.tls_all_ips, &ipinfo.records.ip) {
+            (true, Some(ips)) => {
+                self.tls_allowed_ips(&ipinfo.records.hostname, ips, ip2asn_map.as_ref())
+            }
+            _ => None,
+        };
+        // Retrieve TLS certificate info if the URL scheme is HTTPS, or of the mail server with STARTTLS
+        if self.tls
+            && (self.starttls.is_some() || ipinfo.origin.origin.contains("https://"))
+            && tls_ips.is_some()
+        {
+            let hostname = &ipinfo.records.hostname;
+            let options = self.probe_options(self.request_path(&ipinfo.records));
+            let tls_info = match (self.starttls, self.tls_first_success, &tls_ips) {
+                (None, true, Some(ips)) => {
+                    tls::retrive_cert_info_first(hostname, self.tls_ips(ips), &options).await
+                }
+                _ => tls::retrive_cert_info(hostname, tls_ips.as_ref(), &options),
+            };
+            match tls_info {
+                Ok(tls_info) => {
+                    ipinfo.records.expiring_soon = self
+                        .expiry_alert_days
+                        .and_then(|days| tls_info.expires_within(days));
+                    ipinfo.records.tls = Some(tls_info);
+                }
+                Err(e) => {
+                    event!(
+                        Level::ERROR,
+                        "Failed to retrieve TLS info for {}: {}",
+                        ipinfo.records.hostname,
+                        e
+                    );
+                }
+            }
+        }
+        if let (true, Some(ip)) = (self.tls_all_ips, &tls_ips)
+            && ipinfo.origin.origin.contains("https://")
+        {
+            let options = self.probe_options(self.request_path(&ipinfo.records));
+            ipinfo.records.tls_all_ips = Some(tls::retrive_cert_info_all_ips(
+                &ipinfo.records.hostname,
+                self.tls_ips(ip),
+                &options,
+            ));
+        }
+        if let (true, Some(ip)) = (
+            self.security_headers,
+            ipinfo.records.ip.as_ref().and_then(|ips| ips.first()),
+        ) {
+            match http::fetch_security_headers(&ipinfo.origin.origin, *ip, self.bind).await {
+                Ok(headers) => ipinfo.records.security_headers = Some(headers),
+                Err(e) => event!(
+                    Level::ERROR,
+                    "Failed to fetch the security headers of {}: {}",
+                    ipinfo.records.hostname,
+                    e
+                ),
+            }
+        }
+        if let (true, Some(sibling)) = (self.include_www, ipinfo.sibling_origin()) {
+            // same lookups for the sibling, without looking up its own sibling
+            let runner = IpInfoRunner {
+                origin: sibling,
+                include_www: false,
+                rank_bands: None,
+                ..self.clone()
+            };
+            match Box::pin(runner.run()).await {
+                Ok(related) => ipinfo.records.related = Some(Box::new(related.records)),
+                Err(e) => event!(
+                    Level::ERROR,
+                    "Failed to lookup the sibling of {}: {}",
+                    ipinfo.records.hostname,
+                    e
+                ),
+            }
+        }
+        Ok(ipinfo)
+    }
+
+    /// Reverse pipeline for an IP address input: PTR name and ASN of the
+    /// address, and the certificate it serves when TLS is enabled. The PTR
+    /// name, or the address itself, is used as hostname and TLS server name.
+    async fn run_reverse(self, ip: IpAddr) -> Result<IpInfo> {
+        let mut ipinfo = IpInfo {
+            origin: self.origin.clone(),
+            records: IpInfoRecord::default(),
+        };
+        let ips = vec![ip];
+        let ptr = match &self.resolver {
+            Some(resolver) => dns::query_ptr(ip, resolver).await,
+            None => None,
+        };
+        ipinfo.records.hostname = match &ptr {
+            Some(name) => name.trim_end_matches('.').to_string(),
+            None => ip.to_string(),
+        };
+        if self.resolver.is_some() {
+            ipinfo.records.ptr = Some(vec![ptr]);
+        }
+        let ip2asn_map = self.ip2asn_map().await;
+        if let Some(ip2asn_map) = &ip2asn_map {
+            ipinfo.records.asn = self.lookup_asns(&ips, ip2asn_map);
+            if self.per_ip_asn {
+                ipinfo.records.ip_asn = asn::lookup_ip_per_ip(&ips, ip2asn_map);
+            }
+        }
+        ipinfo.records.ip = Some(ips);
+        if let Some(blocklist) = &self.blocklist {
+            ipinfo.records.flag_reputation(blocklist);
         }
-
-        // Retrieve TLS certificate info if the URL scheme is HTTPS
-        if self.tls && ipinfo.origin.origin.contains("https://") && ipinfo.records.ip.is_some() {
+        if self.resolved_ips {
+            ipinfo.records.resolved_ips = ipinfo.records.pair_resolved_ips(ip2asn_map.as_ref());
+            ipinfo.records.ptr = None;
+            ipinfo.records.reputation = None;
+        }
+        let tls_ips = match self.tls {
+            true => self.tls_allowed_ips(&ipinfo.records.hostname, &[ip], ip2asn_map.as_ref()),
+            false => None,
+        };
+        if tls_ips.is_some() {
+            let options = self.probe_options("/");
             let tls_info =
-                tls::retrive_cert_info(&ipinfo.records.hostname, ipinfo.records.ip.as_ref());
+                tls::retrive_cert_info(&ipinfo.records.hostname, tls_ips.as_ref(), &options);
             match tls_info {
-                Ok(tls_info) => ipinfo.records.tls = Some(tls_info),
+                Ok(tls_info) => {
+                    ipinfo.records.expiring_soon = self
+                        .expiry_alert_days
+                        .and_then(|days| tls_info.expires_within(days));
+                    ipinfo.records.tls = Some(tls_info);
+                }
                 Err(e) => {
                     event!(
                         Level::ERROR,
                         "Failed to retrieve TLS info for {}: {}",
-                        ipinfo.records.hostname,
+                        ip,
                         e
                     );
                 }
@@ -138,63 +1344,156 @@ impl IpInfo {
             origin,
             resolver: None,
             ip2asn_map: None,
+            asn_prefetch: None,
             tls: false,
+            ptr: false,
+            resolved_ips: false,
+            per_ip_asn: false,
+            asn_by_family: false,
+            ecs: None,
+            tls_first_success: false,
+            starttls: None,
+            expiry_alert_days: None,
+            tls_all_ips: false,
+            max_tls_ips: 3,
+            no_tls_asns: Vec::new(),
+            security_headers: false,
+            host_header: None,
+            origin_path: false,
+            bind: None,
+            lenient_tld: false,
+            accept_unknown_tld: false,
+            psl: default_psl(),
+            reverse_ip: None,
+            wildcard_check: false,
+            include_www: false,
+            cname_depth: false,
+            takeover_check: false,
+            rank_bands: None,
+            ttl: false,
+            https_rr: false,
+            dname: false,
+            all_records: false,
+            raw_dns: None,
+            ips: None,
+            parking_rules: None,
+            blocklist: None,
+            authoritative: None,
+            open_resolver_check: None,
+            edns_cookie_check: false,
+            delegation_trace: false,
+            authoritative_port: 53,
+            retry: Retry::default(),
         }
     }
 
-    fn extract_hostname(&mut self) -> Result<()> {
+    /// Runner of the reverse pipeline, starting from an IP address instead
+    /// of an origin
+    pub fn runner_for_ip<T: ConnectionProvider>(ip: IpAddr) -> IpInfoRunner<T> {
+        let mut runner = IpInfo::runner(OriginRecord::from_ip(ip));
+        runner.reverse_ip = Some(ip);
+        runner
+    }
+
+    /// Hostname, scheme, port and path of the origin. The URL parser trims the
+    /// whitespace and lowercases the hostname, which must then follow the LDH
+    /// rule, so that a malformed name is rejected before any DNS query.
+    fn extract_hostname(
+        &mut self,
+        list: &List,
+        lenient_tld: bool,
+        accept_unknown_tld: bool,
+    ) -> Result<()> {
         let match_opt = MatchOpts {
             strict: true,
             ..Default::default()
         };
-        let list = List::default();
-        let tld = list.tld(&self.origin.origin, match_opt);
-        if tld.is_none() {
+        let Ok(parsed_url) = Url::parse(self.origin.origin.trim()) else {
             return Err(anyhow::anyhow!(
-                "Invalid TLD in URL: {}",
+                "Failed to parse URL: {}",
                 &self.origin.origin
             ));
+        };
+        let hostname = &parsed_url.host_str().unwrap_or("").to_lowercase();
+        if let Some(url::Host::Domain(_)) = parsed_url.host()
+            && let Err(e) = check_hostname(hostname)
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid hostname in URL: {}: {}",
+                &self.origin.origin,
+                e
+            ));
         }
-        let parsed_url = Url::parse(&self.origin.origin).ok();
-        match parsed_url {
-            Some(parsed_url) => {
-                self.records.hostname = parsed_url.host_str().unwrap_or("").to_string();
-                Ok(())
-            }
-            None => Err(anyhow::anyhow!(
-                "Failed to parse URL: {}",
+        // the TLD of the host, so that a port or a path do not get in the way
+        let tld = list.tld(hostname, match_opt);
+        if tld.is_none() && !lenient_tld && !(accept_unknown_tld && plausible_tld(hostname)) {
+            return Err(anyhow::anyhow!(
+                "Invalid TLD in URL: {}",
                 &self.origin.origin
-            )),
+            ));
+        }
+        if lenient_tld || accept_unknown_tld {
+            self.records.known_tld = Some(tld.is_some());
         }
+        self.records.hostname = hostname.to_string();
+        self.records.scheme = parsed_url.scheme().to_string();
+        self.records.port = parsed_url.port();
+        let path = match parsed_url.query() {
+            Some(query) => format!("{}?{}", parsed_url.path(), query),
+            None => parsed_url.path().to_string(),
+        };
+        self.records.path = (path != "/").then_some(path);
+        Ok(())
+    }
+
+    /// Origin of the www/apex sibling of the hostname, derived from the apex
+    /// domain: only `www.<domain>` and `<domain>` are siblings
+    fn sibling_origin(&self) -> Option<OriginRecord> {
+        let domain = self.records.domain.as_ref()?;
+        let hostname = &self.records.hostname;
+        let sibling = if hostname == domain {
+            format!("www.{}", domain)
+        } else if hostname.strip_prefix("www.") == Some(domain) {
+            domain.clone()
+        } else {
+            return None;
+        };
+        let mut url = Url::parse(&self.origin.origin).ok()?;
+        url.set_host(Some(&sibling)).ok()?;
+        // keep the origin without trailing slash when it had none
+        let origin = match url.as_str().strip_suffix('/') {
+            Some(origin) if !self.origin.origin.ends_with('/') => origin,
+            _ => url.as_str(),
+        };
+        Some(OriginRecord {
+            origin: origin.to_string(),
+            ..self.origin.clone()
+        })
     }
 
-    fn extract_domain(&mut self) -> Option<String> {
+    fn extract_domain(&self, list: &List) -> Result<String, DomainError> {
         // You can filter to only use ICANN section rules.
         let opts_icann_only = MatchOpts {
             types: TypeFilter::Icann,
             ..Default::default()
         };
-        let list = List::default();
-        let parts = list.split(&self.records.hostname, opts_icann_only);
-        if let Some(parts) = parts {
-            match parts.sll.as_deref() {
-                None => {
-                    event!(
-                        Level::WARN,
-                        "Warning: Could not parse domain from hostname: {}",
-                        &self.records.hostname
-                    );
-                    None
+        let parts = list
+            .split(&self.records.hostname, opts_icann_only)
+            .ok_or(DomainError::InvalidHostname)?;
+        match (parts.sll.as_deref(), parts.sld.as_deref()) {
+            (Some(_), Some(sld)) => Ok(sld.to_string()),
+            // without a matching rule the last label is taken as the TLD
+            _ => {
+                let strict = MatchOpts {
+                    types: TypeFilter::Icann,
+                    strict: true,
+                    ..Default::default()
+                };
+                match list.tld(&self.records.hostname, strict) {
+                    Some(_) => Err(DomainError::NoSld),
+                    None => Err(DomainError::NoMatch),
                 }
-                Some(_) => parts.sld.as_deref().map(|s| s.to_string()),
             }
-        } else {
-            event!(
-                Level::WARN,
-                "Warning: Could not parse domain from hostname: {}",
-                &self.records.hostname
-            );
-            None
         }
     }
 }
@@ -207,39 +1506,1143 @@ impl IpInfo {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_dns::{MockDns, record};
+    use hickory_proto::rr::{
+        Name, RData,
+        rdata::{A, AAAA, CNAME, NS, PTR},
+    };
+    use hickory_resolver::name_server::TokioConnectionProvider;
+    use std::str::FromStr;
+
+    /// Record of the `url` origin, of rank 100
+    fn origin(url: &str) -> OriginRecord {
+        OriginRecord::new(url, 100)
+    }
 
     #[test]
     fn test_extract_hostname() {
         let mut ipinfo = IpInfo {
-            origin: OriginRecord {
-                origin: "https://www.example.com".to_string(),
-                popularity: 100,
-                date: "2023-10-01".to_string(),
-                country: "US".to_string(),
-            },
+            origin: origin("https://www.example.com"),
             records: IpInfoRecord::default(),
         };
 
-        let _ = ipinfo.extract_hostname();
+        let _ = ipinfo.extract_hostname(&default_psl(), false, false);
         assert_eq!(ipinfo.records.hostname, "www.example.com");
+        assert_eq!(ipinfo.records.scheme, "https");
+        assert_eq!(ipinfo.records.port, None);
+    }
+
+    #[test]
+    fn test_extract_hostname_scheme_port() {
+        let origin = |url: &str| IpInfo {
+            origin: origin(url),
+            records: IpInfoRecord::default(),
+        };
+        let mut ipinfo = origin("https://www.example.com:8443/path");
+        ipinfo
+            .extract_hostname(&default_psl(), false, false)
+            .unwrap();
+        assert_eq!(ipinfo.records.hostname, "www.example.com");
+        assert_eq!(ipinfo.records.scheme, "https");
+        assert_eq!(ipinfo.records.port, Some(8443));
+        assert_eq!(ipinfo.records.path.as_deref(), Some("/path"));
+
+        let mut ipinfo = origin("https://cdn.example.com/app?v=2#top");
+        ipinfo
+            .extract_hostname(&default_psl(), false, false)
+            .unwrap();
+        assert_eq!(ipinfo.records.path.as_deref(), Some("/app?v=2"));
+
+        // the default port of the scheme is not explicit
+        let mut ipinfo = origin("http://www.example.com:80/");
+        ipinfo
+            .extract_hostname(&default_psl(), false, false)
+            .unwrap();
+        assert_eq!(ipinfo.records.scheme, "http");
+        assert_eq!(ipinfo.records.port, None);
+        assert_eq!(ipinfo.records.path, None);
+        let value = serde_json::to_value(&ipinfo.records).unwrap();
+        assert_eq!(value["scheme"], "http");
+        assert!(value.get("port").is_none());
+        assert!(value.get("path").is_none());
     }
 
     #[test]
     fn test_extract_hostname_invalid() {
         let mut ipinfo = IpInfo {
-            origin: OriginRecord {
-                origin: "https://www.example.toto".to_string(),
-                popularity: 100,
-                date: "2023-10-01".to_string(),
-                country: "US".to_string(),
-            },
+            origin: origin("https://www.example.toto"),
             records: IpInfoRecord::default(),
         };
 
-        let hostname_result = ipinfo.extract_hostname();
+        let hostname_result = ipinfo.extract_hostname(&default_psl(), false, false);
         assert!(hostname_result.is_err());
     }
 
+    #[test]
+    fn test_extract_hostname_sanitized() {
+        let extract = |url: &str| {
+            let mut ipinfo = IpInfo {
+                origin: origin(url),
+                records: IpInfoRecord::default(),
+            };
+            ipinfo
+                .extract_hostname(&default_psl(), false, false)
+                .map(|_| ipinfo.records.hostname)
+        };
+        // uppercase
+        assert_eq!(
+            extract("https://WWW.Example.COM").unwrap(),
+            "www.example.com"
+        );
+        // leading and trailing whitespace
+        assert_eq!(
+            extract("  https://www.example.com \t\n").unwrap(),
+            "www.example.com"
+        );
+        assert_eq!(
+            extract("https://a_b.example.com").unwrap(),
+            "a_b.example.com"
+        );
+        // illegal characters
+        for origin in [
+            "https://exa$mple.com",
+            "https://www.exa*mple.com",
+            "https://-www.example.com",
+            "https://www-.example.com",
+            "https://www..example.com",
+        ] {
+            let error = extract(origin).unwrap_err().to_string();
+            assert!(error.starts_with("Invalid hostname in URL"), "{}", error);
+        }
+        let error = extract("https://exa$mple.com").unwrap_err().to_string();
+        assert!(
+            error.ends_with("illegal character '$' in label exa$mple"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_extract_hostname_unknown_tld() {
+        let extract = |url: &str, accept_unknown_tld: bool| {
+            let mut ipinfo = IpInfo {
+                origin: origin(url),
+                records: IpInfoRecord::default(),
+            };
+            ipinfo
+                .extract_hostname(&default_psl(), false, accept_unknown_tld)
+                .map(|_| ipinfo.records)
+        };
+        // a TLD of the public suffix list is verified
+        let records = extract("https://www.example.com", true).unwrap();
+        assert_eq!(records.known_tld, Some(true));
+        assert_eq!(
+            extract("https://www.example.com", false).unwrap().known_tld,
+            None
+        );
+        // a brand-new gTLD, not yet in the list
+        assert!(extract("https://www.example.newgtld", false).is_err());
+        let records = extract("https://www.example.newgtld", true).unwrap();
+        assert_eq!(records.hostname, "www.example.newgtld");
+        assert_eq!(records.known_tld, Some(false));
+        let records = extract("https://example.xn--zckzah", true).unwrap();
+        assert_eq!(records.known_tld, Some(false));
+        // garbage TLDs are still rejected
+        for origin in [
+            "https://www.example.t0t0",
+            "https://www.example.123x",
+            "https://newgtld",
+            "https://newgtld.",
+        ] {
+            let error = extract(origin, true).unwrap_err().to_string();
+            assert!(
+                error.starts_with("Invalid TLD in URL"),
+                "{}: {}",
+                origin,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_names() {
+        let mut record = IpInfoRecord {
+            hostname: "www.example.com".to_string(),
+            domain: Some("example.com".to_string()),
+            cname: Some(vec!["edge.example.net.".to_string()]),
+            ns: Some(dns::NameServer {
+                names: vec![
+                    "ns1.example.net.".to_string(),
+                    "ns2.example.net".to_string(),
+                ],
+                ips: None,
+                asn: None,
+                open_resolver: None,
+                edns_cookie_supported: None,
+            }),
+            ptr: Some(vec![Some("host.example.net.".to_string()), None]),
+            related: Some(Box::new(IpInfoRecord {
+                hostname: "example.com".to_string(),
+                cname: Some(vec!["apex.example.net.".to_string()]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        record.normalize_names(false);
+        assert_eq!(record.hostname, "www.example.com");
+        assert_eq!(record.cname, Some(vec!["edge.example.net".to_string()]));
+        assert_eq!(
+            record.ns.as_ref().unwrap().names,
+            ["ns1.example.net", "ns2.example.net"]
+        );
+        assert_eq!(
+            record.ptr,
+            Some(vec![Some("host.example.net".to_string()), None])
+        );
+        assert_eq!(
+            record.related.as_ref().unwrap().cname,
+            Some(vec!["apex.example.net".to_string()])
+        );
+
+        record.normalize_names(true);
+        assert_eq!(record.hostname, "www.example.com.");
+        assert_eq!(record.domain.as_deref(), Some("example.com."));
+        assert_eq!(record.cname, Some(vec!["edge.example.net.".to_string()]));
+        assert_eq!(
+            record.ns.as_ref().unwrap().names,
+            ["ns1.example.net.", "ns2.example.net."]
+        );
+    }
+
+    #[test]
+    fn test_rank_bands() {
+        let bands = RankBands::default();
+        assert_eq!(bands.band(1).as_deref(), Some("top-1k"));
+        assert_eq!(bands.band(1000).as_deref(), Some("top-1k"));
+        assert_eq!(bands.band(1001).as_deref(), Some("top-10k"));
+        assert_eq!(bands.band(100_000).as_deref(), Some("top-100k"));
+        assert_eq!(bands.band(1_000_000).as_deref(), Some("top-1M"));
+        assert_eq!(bands.band(1_000_001).as_deref(), Some("other"));
+        assert_eq!(bands.band(0), None);
+
+        let bands = RankBands::new(vec![5000, 500]);
+        assert_eq!(bands.band(500).as_deref(), Some("top-500"));
+        assert_eq!(bands.band(501).as_deref(), Some("top-5k"));
+        assert_eq!(bands.band(5001).as_deref(), Some("other"));
+    }
+
+    #[tokio::test]
+    async fn test_builder_rank_bands() {
+        let origin = OriginRecord::new("https://www.example.com", 1001);
+        let ipinfo = IpInfo::runner::<TokioConnectionProvider>(origin.clone())
+            .with_rank_bands(RankBands::default())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ipinfo.records.rank_band.as_deref(), Some("top-10k"));
+        let ipinfo = IpInfo::runner::<TokioConnectionProvider>(origin)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ipinfo.records.rank_band, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_lenient_tld() {
+        let run = |origin, lenient: bool| {
+            let runner = IpInfo::runner::<TokioConnectionProvider>(origin);
+            let runner = if lenient {
+                runner.with_lenient_tld()
+            } else {
+                runner
+            };
+            runner.run()
+        };
+        // known TLD
+        let ip_info = run(origin("https://www.example.com"), false).await.unwrap();
+        assert_eq!(ip_info.records.hostname, "www.example.com");
+        assert_eq!(ip_info.records.known_tld, None);
+        let ip_info = run(origin("https://www.example.com"), true).await.unwrap();
+        assert_eq!(ip_info.records.hostname, "www.example.com");
+        assert_eq!(ip_info.records.known_tld, Some(true));
+        // unknown TLD
+        assert!(
+            run(origin("https://www.example.toto"), false)
+                .await
+                .is_err()
+        );
+        let ip_info = run(origin("https://www.example.toto"), true).await.unwrap();
+        assert_eq!(ip_info.records.hostname, "www.example.toto");
+        assert_eq!(ip_info.records.known_tld, Some(false));
+        // the URL must still be valid
+        assert!(run(origin("not a url"), true).await.is_err());
+    }
+
+    #[test]
+    fn test_default_psl_built_once() {
+        let first = IpInfo::runner::<TokioConnectionProvider>(origin("https://www.free.fr"));
+        let second = IpInfo::runner::<TokioConnectionProvider>(origin("https://www.google.fr"));
+        assert!(Arc::ptr_eq(&first.psl, &second.psl));
+        assert!(Arc::ptr_eq(&first.psl, &default_psl()));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_psl() {
+        let origin = origin("https://www.example.toto");
+        let psl: List = "com\ntoto\n".parse().unwrap();
+        let ip_info = IpInfo::runner::<TokioConnectionProvider>(origin)
+            .with_psl(Arc::new(psl))
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.hostname, "www.example.toto");
+        assert_eq!(ip_info.records.domain, Some("example.toto".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_wildcard_check() {
+        let mock = MockDns::start(vec![
+            record("*.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.org.", 300, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        let ip_info = IpInfo::runner(origin("https://www.example.com"))
+            .with_resolver(mock.resolver())
+            .with_wildcard_check()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.wildcard_dns, Some(true));
+        let ip_info = IpInfo::runner(origin("https://www.example.org"))
+            .with_resolver(mock.resolver())
+            .with_wildcard_check()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.wildcard_dns, Some(false));
+        // not checked by default
+        let ip_info = IpInfo::runner(origin("https://www.example.org"))
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.wildcard_dns, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_ip_counts() {
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 2))),
+            record(
+                "www.example.com.",
+                300,
+                RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            ),
+            record("v4.example.com.", 300, RData::A(A::new(192, 0, 2, 3))),
+        ])
+        .await;
+        let run = |url: &str| {
+            let origin = origin(url);
+            IpInfo::runner(origin).with_resolver(mock.resolver()).run()
+        };
+        let records = run("https://www.example.com").await.unwrap().records;
+        assert_eq!(records.ipv4_count, Some(2));
+        assert_eq!(records.ipv6_count, Some(1));
+        assert_eq!(records.dual_stack, Some(true));
+        let records = run("https://v4.example.com").await.unwrap().records;
+        assert_eq!(records.ipv4_count, Some(1));
+        assert_eq!(records.ipv6_count, Some(0));
+        assert_eq!(records.dual_stack, Some(false));
+        // nothing to count without addresses
+        let records = run("https://missing.example.com").await.unwrap().records;
+        assert_eq!(records.ipv4_count, None);
+        assert_eq!(records.dual_stack, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_security_headers() {
+        let port = http::stub_server("Strict-Transport-Security: max-age=600; preload\r\n").await;
+        let origin = OriginRecord::new(format!("http://www.example.com:{}", port), 100);
+        let ip_info = IpInfo::runner::<TokioConnectionProvider>(origin)
+            .with_ips(vec![IpAddr::from([127, 0, 0, 1])])
+            .with_security_headers()
+            .run()
+            .await
+            .unwrap();
+        let headers = ip_info.records.security_headers.unwrap();
+        assert_eq!(
+            headers.strict_transport_security,
+            Some(http::Hsts {
+                max_age: Some(600),
+                include_subdomains: false,
+                preload: true,
+            })
+        );
+        assert_eq!(headers.content_security_policy, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_ips() {
+        let mock = MockDns::start(vec![record(
+            "www.example.com.",
+            300,
+            RData::A(A::new(192, 0, 2, 1)),
+        )])
+        .await;
+        let ip2asn_map = ip2asn::Builder::new()
+            .with_source("127.0.0.0\t127.255.255.255\t64500\tZZ\tLOOPBACK".as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let origin = origin("https://www.example.com");
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_ip2asn_map(Arc::new(ip2asn_map))
+            .with_ips(vec![loopback])
+            .with_tls_all_ips()
+            .with_include_www()
+            .run()
+            .await
+            .unwrap();
+        assert!(mock.queries().is_empty());
+        assert_eq!(ip_info.records.ip, Some(vec![loopback]));
+        assert_eq!(ip_info.records.asn.unwrap()[0].asn, 64500);
+        assert!(ip_info.records.ns.is_none());
+        assert!(ip_info.records.related.is_none());
+        // the certificate was probed on the given address
+        let tls = ip_info.records.tls_all_ips.unwrap();
+        assert!(tls.errors.contains_key(&loopback));
+
+        // the addresses of the origin record are used the same way
+        let origin = OriginRecord {
+            ips: Some(vec![loopback]),
+            ..origin
+        };
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert!(mock.queries().is_empty());
+        assert_eq!(ip_info.records.ip, Some(vec![loopback]));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_no_tls_asns() {
+        let ip2asn_map = Arc::new(
+            ip2asn::Builder::new()
+                .with_source(
+                    "127.0.0.1\t127.0.0.1\t64500\tZZ\tLOOPBACK-A\n\
+                     127.0.0.2\t127.0.0.2\t64501\tZZ\tLOOPBACK-B"
+                        .as_bytes(),
+                )
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+        let origin = origin("https://www.example.com");
+        // 127.0.0.3 is in no ASN of the database
+        let ips = (1..=3)
+            .map(|i| IpAddr::from([127, 0, 0, i]))
+            .collect::<Vec<_>>();
+        let probed = |asns: Vec<u32>, ip2asn_map: Option<Arc<IpAsnMap>>| {
+            let runner = IpInfo::runner::<TokioConnectionProvider>(origin.clone())
+                .with_ips(ips.clone())
+                .with_tls_all_ips()
+                .with_no_tls_asns(asns);
+            let runner = match ip2asn_map {
+                Some(ip2asn_map) => runner.with_ip2asn_map(ip2asn_map),
+                None => runner,
+            };
+            async move {
+                let tls = runner.run().await.unwrap().records.tls_all_ips;
+                // the probes fail, nothing listening
+                tls.map(|tls| tls.errors.into_keys().collect::<Vec<_>>())
+            }
+        };
+        // only the IPs of a known ASN outside of the skip-list are probed
+        assert_eq!(
+            probed(vec![64500], Some(ip2asn_map.clone())).await,
+            Some(vec![ips[1]])
+        );
+        assert_eq!(probed(vec![64500, 64501], Some(ip2asn_map)).await, None);
+        // without the ASN database, no IP is probed
+        assert_eq!(probed(vec![64500], None).await, None);
+        assert_eq!(probed(vec![], None).await, Some(ips));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_max_tls_ips() {
+        let origin = origin("https://www.example.com");
+        // a round-robin host, nothing listening on the loopback addresses
+        let ips = (1..=10)
+            .map(|i| IpAddr::from([127, 0, 0, i]))
+            .collect::<Vec<_>>();
+        let probed = |max: Option<usize>| {
+            let runner = IpInfo::runner::<TokioConnectionProvider>(origin.clone())
+                .with_ips(ips.clone())
+                .with_tls_all_ips();
+            let runner = match max {
+                Some(max) => runner.with_max_tls_ips(max),
+                None => runner,
+            };
+            async move {
+                let tls = runner.run().await.unwrap().records.tls_all_ips.unwrap();
+                tls.errors.into_keys().collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(probed(None).await, ips[..3]);
+        assert_eq!(probed(Some(5)).await, ips[..5]);
+        assert_eq!(probed(Some(20)).await, ips);
+        // a zero limit still probes one IP
+        assert_eq!(probed(Some(0)).await, ips[..1]);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_authoritative_queries() {
+        // the authoritative server has a newer address than the recursive cache
+        let authoritative = MockDns::start(vec![record(
+            "www.example.com.",
+            300,
+            RData::A(A::new(192, 0, 2, 99)),
+        )])
+        .await;
+        let recursive = MockDns::start(vec![
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str("ns1.example.com.").unwrap())),
+            ),
+            record("ns1.example.com.", 300, RData::A(A::new(127, 0, 0, 1))),
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+        ])
+        .await;
+        let origin = origin("https://www.example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(recursive.resolver())
+            .with_authoritative_queries()
+            .with_authoritative_port(authoritative.addr().port())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(
+            ip_info.records.ip,
+            Some(vec![IpAddr::from([192, 0, 2, 99])])
+        );
+        assert_eq!(ip_info.records.authoritative, Some(true));
+        assert!(
+            authoritative
+                .queries()
+                .iter()
+                .all(|(name, _)| name == "www.example.com.")
+        );
+        // the recursive resolver is used when the name servers are unknown
+        let origin = OriginRecord {
+            origin: "https://www.example.org".to_string(),
+            ..origin
+        };
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(recursive.resolver())
+            .with_authoritative_queries()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.authoritative, Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_open_resolver_check() {
+        let zone = vec![
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str("ns1.example.com.").unwrap())),
+            ),
+            record("ns1.example.com.", 300, RData::A(A::new(127, 0, 0, 1))),
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+        ];
+        let origin = origin("https://www.example.com");
+        // the name server only answers for its own zone
+        let closed = MockDns::start(zone.clone()).await;
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(closed.resolver())
+            .with_open_resolver_check()
+            .with_authoritative_port(closed.addr().port())
+            .run()
+            .await
+            .unwrap();
+        let ns = ip_info.records.ns.unwrap();
+        assert_eq!(ns.ips, Some(vec![IpAddr::from([127, 0, 0, 1])]));
+        assert_eq!(ns.open_resolver, Some(vec![false]));
+        // the name server answers for any name
+        let mut records = zone;
+        records.push(record("www.iana.org.", 300, RData::A(A::new(192, 0, 2, 9))));
+        let open = MockDns::start(records).await;
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(open.resolver())
+            .with_open_resolver_check()
+            .with_authoritative_port(open.addr().port())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.ns.unwrap().open_resolver, Some(vec![true]));
+        // not checked by default
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(open.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert!(ip_info.records.ns.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_edns_cookie_check() {
+        let zone = vec![
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str("ns1.example.com.").unwrap())),
+            ),
+            record("ns1.example.com.", 300, RData::A(A::new(127, 0, 0, 1))),
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+        ];
+        let origin = origin("https://www.example.com");
+        let run = |mock: &MockDns| {
+            IpInfo::runner(origin.clone())
+                .with_resolver(mock.resolver())
+                .with_edns_cookie_check()
+                .with_authoritative_port(mock.addr().port())
+                .run()
+        };
+        let with_cookies = MockDns::start_with_cookies(zone.clone()).await;
+        let ns = run(&with_cookies).await.unwrap().records.ns.unwrap();
+        assert_eq!(ns.ips, Some(vec![IpAddr::from([127, 0, 0, 1])]));
+        assert_eq!(ns.edns_cookie_supported, Some(vec![true]));
+        assert!(with_cookies.queries().contains(&(
+            "example.com.".to_string(),
+            hickory_proto::rr::RecordType::SOA
+        )));
+        let without_cookies = MockDns::start(zone).await;
+        let ns = run(&without_cookies).await.unwrap().records.ns.unwrap();
+        assert_eq!(ns.edns_cookie_supported, Some(vec![false]));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_parking_detection() {
+        let zone = |ns: &str| {
+            vec![
+                record(
+                    "example.com.",
+                    300,
+                    RData::NS(NS(Name::from_str(ns).unwrap())),
+                ),
+                record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            ]
+        };
+        // the name servers are looked up without an ASN database
+        let run = |mock: MockDns| async move {
+            IpInfo::runner(origin("https://www.example.com"))
+                .with_resolver(mock.resolver())
+                .with_parking_detection()
+                .run()
+                .await
+                .unwrap()
+        };
+        let parked = run(MockDns::start(zone("ns1.sedoparking.com.")).await).await;
+        assert_eq!(parked.records.likely_parked, Some(true));
+        let active = run(MockDns::start(zone("ns1.example.com.")).await).await;
+        assert_eq!(active.records.likely_parked, Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_delegation_trace() {
+        let ns = |domain: &str, target: &str| {
+            record(domain, 300, RData::NS(NS(Name::from_str(target).unwrap())))
+        };
+        // the delegated name server serves a different NS set
+        let child = MockDns::start_authoritative(vec![
+            ns("example.com.", "ns1.example.com."),
+            ns("example.com.", "ns3.example.com."),
+        ])
+        .await;
+        let parent_addr = SocketAddr::from(([127, 0, 0, 2], child.addr().port()));
+        let _parent = MockDns::start_referral_at(
+            parent_addr,
+            vec![
+                ns("example.com.", "ns2.example.com."),
+                ns("example.com.", "ns1.example.com."),
+                record("ns1.example.com.", 300, RData::A(A::new(127, 0, 0, 1))),
+                record("ns2.example.com.", 300, RData::A(A::new(127, 0, 0, 1))),
+            ],
+        )
+        .await;
+        let recursive = MockDns::start(vec![
+            ns("com.", "a.gtld-servers.net."),
+            record("a.gtld-servers.net.", 300, RData::A(A::new(127, 0, 0, 2))),
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+        ])
+        .await;
+        let origin = origin("https://www.example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(recursive.resolver())
+            .with_delegation_trace()
+            .with_authoritative_port(child.addr().port())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(
+            ip_info.records.delegation,
+            Some(dns::Delegation {
+                parent_zone: "com.".to_string(),
+                parent_ns: vec![
+                    "ns1.example.com.".to_string(),
+                    "ns2.example.com.".to_string()
+                ],
+                child_ns: Some(vec![
+                    "ns1.example.com.".to_string(),
+                    "ns3.example.com.".to_string()
+                ]),
+                mismatch: Some(true),
+            })
+        );
+        // the child set is asked to the delegated name servers directly
+        assert!(
+            child
+                .queries()
+                .iter()
+                .any(|(name, _)| name == "example.com.")
+        );
+
+        // an answer without authority is not the NS set of the child
+        let child = MockDns::start(vec![ns("example.com.", "ns1.example.com.")]).await;
+        let parent_addr = SocketAddr::from(([127, 0, 0, 2], child.addr().port()));
+        let _parent = MockDns::start_referral_at(
+            parent_addr,
+            vec![
+                ns("example.com.", "ns1.example.com."),
+                record("ns1.example.com.", 300, RData::A(A::new(127, 0, 0, 1))),
+            ],
+        )
+        .await;
+        let delegation = IpInfo::runner(origin)
+            .with_resolver(recursive.resolver())
+            .with_delegation_trace()
+            .with_authoritative_port(child.addr().port())
+            .run()
+            .await
+            .unwrap()
+            .records
+            .delegation
+            .unwrap();
+        assert_eq!(delegation.parent_ns, vec!["ns1.example.com.".to_string()]);
+        assert_eq!(delegation.child_ns, None);
+        assert_eq!(delegation.mismatch, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_ttl() {
+        let mock = MockDns::start(vec![
+            record(
+                "www.example.com.",
+                900,
+                RData::CNAME(CNAME(Name::from_str("edge.example.net.").unwrap())),
+            ),
+            record("edge.example.net.", 30, RData::A(A::new(192, 0, 2, 1))),
+        ])
+        .await;
+        let origin = origin("https://www.example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_ttl()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.ttl, Some(30));
+        // not looked up by default
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.ttl, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_https_rr() {
+        use hickory_proto::rr::rdata::{HTTPS, SVCB};
+        let alias = SVCB::new(0, Name::from_str("cdn.example.net.").unwrap(), vec![]);
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.com.", 300, RData::HTTPS(HTTPS(alias))),
+        ])
+        .await;
+        let origin = origin("https://www.example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_https_rr()
+            .run()
+            .await
+            .unwrap();
+        let https_rr = ip_info.records.https_rr.unwrap();
+        assert_eq!(https_rr.len(), 1);
+        assert_eq!(https_rr[0].mode, dns::SvcMode::Alias);
+        assert_eq!(https_rr[0].target, "cdn.example.net.");
+        assert_eq!(ip_info.records.ech_present, Some(false));
+        // not looked up by default
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert!(ip_info.records.https_rr.is_none());
+        assert!(ip_info.records.ech_present.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_dname() {
+        use hickory_proto::{
+            rr::{RecordType, rdata::NULL},
+            serialize::binary::BinEncodable,
+        };
+        let target = Name::from_str("example.net.").unwrap().to_bytes().unwrap();
+        let mock = MockDns::start(vec![
+            record("example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record(
+                "example.com.",
+                300,
+                RData::Unknown {
+                    code: RecordType::Unknown(39),
+                    rdata: NULL::with(target),
+                },
+            ),
+        ])
+        .await;
+        let origin = origin("https://example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_dname()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(
+            ip_info.records.dname,
+            Some(vec!["example.net.".to_string()])
+        );
+        // not looked up by default
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert!(ip_info.records.dname.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_all_records() {
+        use hickory_proto::rr::{
+            RecordType,
+            rdata::{MX, NS, TXT},
+        };
+        let mock = MockDns::start(vec![
+            record("example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record(
+                "example.com.",
+                300,
+                RData::MX(MX::new(10, Name::from_str("mx.example.com.").unwrap())),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::TXT(TXT::new(vec!["v=spf1 -all".to_string()])),
+            ),
+            record(
+                "example.com.",
+                300,
+                RData::NS(NS(Name::from_str("ns1.example.com.").unwrap())),
+            ),
+        ])
+        .await;
+        let origin = origin("https://example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_all_records()
+            .run()
+            .await
+            .unwrap();
+        let records = &ip_info.records;
+        assert_eq!(records.mx.as_ref().unwrap()[0].exchange, "mx.example.com.");
+        assert_eq!(records.txt, Some(vec!["v=spf1 -all".to_string()]));
+        assert_eq!(records.ns.as_ref().unwrap().names, vec!["ns1.example.com."]);
+        assert!(records.soa.is_none() && records.caa.is_none());
+        // every supported record type is attempted
+        let queried: Vec<RecordType> = mock.queries().into_iter().map(|(_, t)| t).collect();
+        for record_type in [
+            RecordType::A,
+            RecordType::AAAA,
+            RecordType::CNAME,
+            RecordType::Unknown(39),
+            RecordType::HTTPS,
+            RecordType::MX,
+            RecordType::TXT,
+            RecordType::SOA,
+            RecordType::CAA,
+            RecordType::NS,
+        ] {
+            assert!(queried.contains(&record_type), "{:?}", record_type);
+        }
+        // not looked up by default
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert!(ip_info.records.mx.is_none() && ip_info.records.txt.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_raw_dns() {
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.org.", 300, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        let run = |url: &str| {
+            let origin = origin(url);
+            IpInfo::runner(origin)
+                .with_resolver(mock.resolver())
+                .with_raw_dns(vec!["WWW.example.com.".to_string()])
+                .run()
+        };
+        let raw = run("https://www.example.com")
+            .await
+            .unwrap()
+            .records
+            .raw
+            .unwrap();
+        // the answer comes from the cache of the resolver, its TTL may have decreased
+        let records = raw["A"]["records"].as_array().unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].as_str().unwrap().ends_with(" IN A 192.0.2.1"));
+        assert!(raw["AAAA"]["error"].is_string());
+        // the other hosts are left out
+        let ip_info = run("https://www.example.org").await.unwrap();
+        assert!(ip_info.records.ip.is_some());
+        assert!(ip_info.records.raw.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_include_www() {
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("example.com.", 300, RData::A(A::new(192, 0, 2, 2))),
+            record("mail.example.com.", 300, RData::A(A::new(192, 0, 2, 3))),
+        ])
+        .await;
+        let run = |origin| {
+            IpInfo::runner(origin)
+                .with_resolver(mock.resolver())
+                .with_include_www()
+                .run()
+        };
+        let ip_info = run(origin("https://www.example.com")).await.unwrap();
+        assert_eq!(ip_info.records.ip, Some(vec![IpAddr::from([192, 0, 2, 1])]));
+        let related = ip_info.records.related.unwrap();
+        assert_eq!(related.hostname, "example.com");
+        assert_eq!(related.ip, Some(vec![IpAddr::from([192, 0, 2, 2])]));
+        assert!(related.related.is_none());
+
+        let ip_info = run(origin("https://example.com")).await.unwrap();
+        let related = ip_info.records.related.unwrap();
+        assert_eq!(related.hostname, "www.example.com");
+        assert_eq!(related.ip, Some(vec![IpAddr::from([192, 0, 2, 1])]));
+
+        // other hosts of the domain have no sibling
+        let ip_info = run(origin("https://mail.example.com")).await.unwrap();
+        assert!(ip_info.records.related.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_cname_depth() {
+        let cname = |name: &str| RData::CNAME(CNAME(Name::from_str(name).unwrap()));
+        let mock = MockDns::start(vec![
+            record(
+                "www.example.com.",
+                300,
+                cname("www.example.com.cdn.example.net."),
+            ),
+            record(
+                "www.example.com.cdn.example.net.",
+                300,
+                cname("edge.example.org."),
+            ),
+            record("edge.example.org.", 300, cname("a1.edge.example.org.")),
+            record("a1.edge.example.org.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("example.com.", 300, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        let ip_info = IpInfo::runner(origin("https://www.example.com"))
+            .with_resolver(mock.resolver())
+            .with_cname_depth()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.cname_depth, Some(3));
+        assert_eq!(ip_info.records.cname.unwrap().len(), 3);
+        assert_eq!(ip_info.records.ip, Some(vec![IpAddr::from([192, 0, 2, 1])]));
+
+        let ip_info = IpInfo::runner(origin("https://example.com"))
+            .with_resolver(mock.resolver())
+            .with_cname_depth()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.cname_depth, Some(0));
+        assert!(ip_info.records.cname.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_takeover_check() {
+        let cname = |name: &str| RData::CNAME(CNAME(Name::from_str(name).unwrap()));
+        let mock = MockDns::start(vec![
+            record(
+                "www.example.com.",
+                300,
+                cname("www.example.com.cdn.example.net."),
+            ),
+            record(
+                "www.example.com.cdn.example.net.",
+                300,
+                cname("gone.cloudapp.example.org."),
+            ),
+            record(
+                "blog.example.com.",
+                300,
+                cname("live.cloudapp.example.org."),
+            ),
+            record(
+                "live.cloudapp.example.org.",
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ),
+            record("example.com.", 300, RData::A(A::new(192, 0, 2, 2))),
+        ])
+        .await;
+        let dangling_cname = |origin_url: &'static str, cname_depth: bool| {
+            let runner = IpInfo::runner(origin(origin_url))
+                .with_resolver(mock.resolver())
+                .with_takeover_check();
+            let runner = match cname_depth {
+                true => runner.with_cname_depth(),
+                false => runner,
+            };
+            async move { runner.run().await.unwrap().records.dangling_cname }
+        };
+        // the chain ends on a name which does not exist
+        assert_eq!(
+            dangling_cname("https://www.example.com", false).await,
+            Some(true)
+        );
+        assert_eq!(
+            dangling_cname("https://www.example.com", true).await,
+            Some(true)
+        );
+        assert_eq!(
+            dangling_cname("https://blog.example.com", false).await,
+            Some(false)
+        );
+        // no CNAME
+        assert_eq!(dangling_cname("https://example.com", false).await, None);
+        // not checked by default
+        let ip_info = IpInfo::runner(origin("https://www.example.com"))
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.dangling_cname, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_asn_prefetch() {
+        let mock = MockDns::start(vec![record(
+            "www.example.com.",
+            300,
+            RData::A(A::new(192, 0, 2, 1)),
+        )])
+        .await;
+        // the database is only loaded once the test releases it
+        let (release, loaded) = tokio::sync::oneshot::channel::<()>();
+        let prefetch = AsnDbPrefetch::spawn(async move {
+            loaded.await?;
+            let data = "192.0.2.0\t192.0.2.255\t64500\tFR\tTEST-NET";
+            Ok(ip2asn::Builder::new()
+                .with_source(data.as_bytes())?
+                .build()?)
+        });
+        let origin = origin("https://www.example.com");
+        let runner = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .with_asn_prefetch(prefetch.clone());
+        let task = tokio::spawn(runner.run());
+        // the DNS queries are sent while the database is still loading
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(5);
+        while mock.queries().is_empty() {
+            assert!(tokio::time::Instant::now() < deadline);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!prefetch.is_done());
+        release.send(()).unwrap();
+        let ip_info = task.await.unwrap().unwrap();
+        assert!(prefetch.is_done());
+        assert_eq!(ip_info.records.asn.unwrap()[0].asn, 64500);
+    }
+
+    #[tokio::test]
+    async fn test_runner_for_ip() {
+        let mock = MockDns::start(vec![record(
+            "1.2.0.192.in-addr.arpa.",
+            300,
+            RData::PTR(PTR(Name::from_str("web.example.com.").unwrap())),
+        )])
+        .await;
+        let data = "192.0.2.0\t192.0.2.255\t64500\tFR\tTEST-NET";
+        let ip2asn_map = ip2asn::Builder::new()
+            .with_source(data.as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let ip_info = IpInfo::runner_for_ip(ip)
+            .with_resolver(mock.resolver())
+            .with_ip2asn_map(Arc::new(ip2asn_map))
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.origin.origin, "192.0.2.1");
+        assert_eq!(ip_info.records.hostname, "web.example.com");
+        assert_eq!(ip_info.records.ip, Some(vec![ip]));
+        assert_eq!(
+            ip_info.records.ptr,
+            Some(vec![Some("web.example.com.".to_string())])
+        );
+        let asn = ip_info.records.asn.unwrap();
+        assert_eq!(asn.len(), 1);
+        assert_eq!(asn[0].asn, 64500);
+        assert_eq!(asn[0].organization, "TEST-NET");
+
+        // without PTR name the address is the hostname
+        let ip = IpAddr::from([192, 0, 2, 2]);
+        let ip_info = IpInfo::runner_for_ip(ip)
+            .with_resolver(mock.resolver())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.hostname, "192.0.2.2");
+        assert_eq!(ip_info.records.ptr, Some(vec![None]));
+        assert!(ip_info.records.asn.is_none());
+    }
+
     #[test]
     fn test_extract_domain() {
         let urls = [
@@ -257,53 +2660,66 @@ mod tests {
             "senpai-stream.cam",
         ];
         for (url, expected) in urls.iter().zip(expected_domains.iter()) {
-            let mut ipinfo = IpInfo {
-                origin: OriginRecord {
-                    origin: url.to_string(),
-                    popularity: 100,
-                    date: "2023-10-01".to_string(),
-                    country: "US".to_string(),
-                },
+            let ipinfo = IpInfo {
+                origin: origin(url),
                 records: IpInfoRecord {
                     hostname: url.to_string(),
                     ..Default::default()
                 },
             };
-            let domain = ipinfo.extract_domain();
-            assert!(domain.is_some());
-            assert_eq!(domain.unwrap(), expected.to_string());
+            let domain = ipinfo.extract_domain(&default_psl());
+            assert_eq!(domain, Ok(expected.to_string()));
         }
     }
 
     #[test]
     fn test_extract_domain_invalid() {
-        let bad_urls = ["invalid_domain", "https://www.example.toto"];
-        for url in bad_urls {
-            let mut ipinfo = IpInfo {
-                origin: OriginRecord {
-                    origin: url.to_string(),
-                    popularity: 100,
-                    date: "2023-10-01".to_string(),
-                    country: "US".to_string(),
-                },
+        let bad_urls = [
+            ("invalid_domain", DomainError::NoMatch),
+            ("https://www.example.toto", DomainError::NoMatch),
+            ("www.example.toto", DomainError::NoMatch),
+            ("co.uk", DomainError::NoSld),
+            ("com", DomainError::NoSld),
+            ("", DomainError::InvalidHostname),
+            ("www..example.com", DomainError::InvalidHostname),
+        ];
+        for (url, expected) in bad_urls {
+            let ipinfo = IpInfo {
+                origin: origin(url),
                 records: IpInfoRecord {
                     hostname: url.to_string(),
                     ..Default::default()
                 },
             };
-            let domain = ipinfo.extract_domain();
-            assert!(domain.is_none());
+            let domain = ipinfo.extract_domain(&default_psl());
+            assert_eq!(domain, Err(expected), "{}", url);
         }
     }
 
     #[tokio::test]
-    async fn test_builder_hostname_domaine() {
-        let origin = OriginRecord {
-            origin: "https://www.example.com".to_string(),
-            popularity: 100,
-            date: "2023-10-01".to_string(),
-            country: "US".to_string(),
+    async fn test_builder_domain_error() {
+        let records = |url: &str| {
+            let origin = origin(url);
+            async {
+                IpInfo::runner::<TokioConnectionProvider>(origin)
+                    .with_accept_unknown_tld()
+                    .run()
+                    .await
+                    .unwrap()
+                    .records
+            }
         };
+        let unknown_tld = records("https://www.example.toto").await;
+        assert_eq!(unknown_tld.domain, None);
+        assert_eq!(unknown_tld.domain_error.as_deref(), Some("no_psl_match"));
+        let known_tld = records("https://www.example.com").await;
+        assert_eq!(known_tld.domain.as_deref(), Some("example.com"));
+        assert_eq!(known_tld.domain_error, None);
+    }
+
+    #[tokio::test]
+    async fn test_builder_hostname_domaine() {
+        let origin = origin("https://www.example.com");
         // Use the host OS'es `/etc/resolv.conf`
         let resolver = Resolver::builder_tokio().unwrap().build();
         let ip_info = IpInfo::runner(origin).with_resolver(resolver).run().await;
@@ -315,12 +2731,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_builder_with_bad_hostname() {
-        let origin = OriginRecord {
-            origin: "https://www.example.toto".to_string(),
-            popularity: 100,
-            date: "2023-10-01".to_string(),
-            country: "US".to_string(),
-        };
+        let origin = origin("https://www.example.toto");
         // Use the host OS'es `/etc/resolv.conf`
         let resolver = Resolver::builder_tokio().unwrap().build();
         let ip_info_result = IpInfo::runner(origin).with_resolver(resolver).run().await;
@@ -329,12 +2740,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_builder() {
-        let origin = OriginRecord {
-            origin: "https://www.example.com".to_string(),
-            popularity: 100,
-            date: "2023-10-01".to_string(),
-            country: "US".to_string(),
-        };
+        let origin = origin("https://www.example.com");
         // Use the host OS'es `/etc/resolv.conf`
         let resolver = Resolver::builder_tokio().unwrap().build();
         let ip_info = IpInfo::runner(origin)
@@ -350,4 +2756,171 @@ mod tests {
         assert!(ip_info.records.cname.is_some());
         assert!(ip_info.records.tls.is_some());
     }
+
+    #[tokio::test]
+    async fn test_builder_with_resolved_ips() {
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record(
+                "1.2.0.192.in-addr.arpa.",
+                300,
+                RData::PTR(PTR(Name::from_str("web.example.com.").unwrap())),
+            ),
+        ])
+        .await;
+        let origin = origin("https://www.example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_ptr()
+            .run()
+            .await
+            .unwrap();
+        // flat representation by default
+        let json = serde_json::to_value(&ip_info.records).unwrap();
+        assert_eq!(json["ip"], serde_json::json!(["192.0.2.1"]));
+        assert_eq!(json["ptr"], serde_json::json!(["web.example.com."]));
+        assert!(json.get("resolved_ips").is_none());
+
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .with_ptr()
+            .with_resolved_ips()
+            .run()
+            .await
+            .unwrap();
+        let json = serde_json::to_value(&ip_info.records).unwrap();
+        assert!(json.get("ptr").is_none());
+        assert_eq!(
+            json["resolved_ips"],
+            serde_json::json!([{"addr": "192.0.2.1", "ptr": "web.example.com."}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_reputation() {
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.com.", 300, RData::A(A::new(198, 51, 100, 7))),
+        ])
+        .await;
+        let origin = origin("https://www.example.com");
+        let blocklist = IpBlocklist::default()
+            .with_network(IpNetwork::from_str("198.51.100.0/24").unwrap(), "botnet");
+        let blocklist = Arc::new(blocklist);
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_ptr()
+            .with_reputation(blocklist.clone())
+            .run()
+            .await
+            .unwrap();
+        // the default output is kept, the reputation in the same order as ip
+        let records = &ip_info.records;
+        assert!(records.resolved_ips.is_none());
+        assert_eq!(records.ptr.as_ref().map(Vec::len), Some(2));
+        let mut reputation = records
+            .ip
+            .iter()
+            .flatten()
+            .zip(records.reputation.iter().flatten())
+            .map(|(addr, reputation)| (*addr, reputation.as_deref()))
+            .collect::<Vec<_>>();
+        reputation.sort();
+        assert_eq!(
+            reputation,
+            [
+                (IpAddr::from([192, 0, 2, 1]), None),
+                (IpAddr::from([198, 51, 100, 7]), Some("botnet")),
+            ]
+        );
+
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .with_reputation(blocklist)
+            .with_resolved_ips()
+            .run()
+            .await
+            .unwrap();
+        assert!(ip_info.records.reputation.is_none());
+        let mut resolved = ip_info.records.resolved_ips.unwrap();
+        resolved.sort_by_key(|resolved| resolved.addr);
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0].reputation, None);
+        assert_eq!(resolved[1].addr.to_string(), "198.51.100.7");
+        assert_eq!(resolved[1].reputation.as_deref(), Some("botnet"));
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_per_ip_asn() {
+        let mock = MockDns::start(vec![
+            record("www.example.com.", 300, RData::A(A::new(192, 0, 2, 1))),
+            record("www.example.com.", 300, RData::A(A::new(198, 51, 100, 1))),
+        ])
+        .await;
+        let data = [
+            "192.0.2.0\t192.0.2.255\t64500\tFR\tTEST-NET-1",
+            "198.51.100.0\t198.51.100.255\t64501\tUS\tTEST-NET-2",
+        ]
+        .join("\n");
+        let ip2asn_map = Arc::new(
+            ip2asn::Builder::new()
+                .with_source(data.as_bytes())
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+        let origin = origin("https://www.example.com");
+        let ip_info = IpInfo::runner(origin.clone())
+            .with_resolver(mock.resolver())
+            .with_ip2asn_map(ip2asn_map.clone())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.asn.unwrap().len(), 2);
+        assert!(ip_info.records.ip_asn.is_none());
+
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .with_ip2asn_map(ip2asn_map)
+            .with_per_ip_asn()
+            .run()
+            .await
+            .unwrap();
+        let mut ip_asn = ip_info
+            .records
+            .ip_asn
+            .unwrap()
+            .into_iter()
+            .map(|(ip, asn)| (ip, asn.asn))
+            .collect::<Vec<_>>();
+        ip_asn.sort();
+        assert_eq!(
+            ip_asn,
+            vec![
+                (IpAddr::from([192, 0, 2, 1]), 64500),
+                (IpAddr::from([198, 51, 100, 1]), 64501),
+            ]
+        );
+        assert_eq!(ip_info.records.asn.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_ecs() {
+        let mock = MockDns::start(vec![record(
+            "www.example.com.",
+            300,
+            RData::A(A::new(192, 0, 2, 1)),
+        )])
+        .await;
+        let origin = origin("https://www.example.com");
+        let subnet = IpNetwork::from_str("198.51.100.0/24").unwrap();
+        let ip_info = IpInfo::runner(origin)
+            .with_resolver(mock.resolver())
+            .with_ecs(subnet)
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(ip_info.records.ip, Some(vec!["192.0.2.1".parse().unwrap()]));
+        assert_eq!(ip_info.records.ecs, Some(subnet));
+    }
 }
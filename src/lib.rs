@@ -1,11 +1,21 @@
 // private modules
 mod asn;
+#[cfg(test)]
+mod mock_dns;
 
 // public modules
+pub mod diff;
 pub mod dns;
+pub mod http;
+pub mod input;
 pub mod ipinfo;
+pub mod output;
+pub mod parking;
+pub mod reputation;
+pub mod sqlite;
 pub mod tls;
 pub mod utils;
+pub mod webhook;
 
 // re-export for easier access
 pub use ipinfo::IpInfo;
@@ -0,0 +1,289 @@
+//! Loopback DNS server serving canned records, so that the unit tests
+//! exercising the query helpers and the runner do not depend on the network.
+use hickory_proto::{
+    op::{Edns, Message, MessageType, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::opt::{EdnsCode, EdnsOption},
+    },
+    xfer::Protocol,
+};
+use hickory_resolver::{
+    Resolver,
+    config::{NameServerConfig, ResolverConfig},
+    name_server::TokioConnectionProvider,
+};
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+/// Build a resource record for the mock zone
+pub fn record(name: &str, ttl: u32, rdata: RData) -> Record {
+    Record::from_rdata(Name::from_str(name).unwrap(), ttl, rdata)
+}
+
+/// Behaviour of a mock server, all the options being off by default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MockOptions {
+    servfail: bool,
+    cookies: bool,
+    glue: bool,
+    authoritative: bool,
+    referral: bool,
+}
+
+impl MockOptions {
+    /// Answer every query with SERVFAIL, like a failing resolver
+    pub fn with_servfail(mut self) -> Self {
+        self.servfail = true;
+        self
+    }
+
+    /// Support DNS cookies: the client cookie of a query is echoed with a
+    /// server cookie
+    pub fn with_cookies(mut self) -> Self {
+        self.cookies = true;
+        self
+    }
+
+    /// Add to the NS answers and referrals the glue of the name servers
+    /// within the zone, in the additional section
+    pub fn with_glue(mut self) -> Self {
+        self.glue = true;
+        self
+    }
+
+    /// Answer with the authoritative (AA) flag, like the name server of the
+    /// zone of the records
+    pub fn with_authoritative(mut self) -> Self {
+        self.authoritative = true;
+        self
+    }
+
+    /// Answer the NS queries with a referral, like the name server of a
+    /// parent zone: the NS records in the authority section and an empty
+    /// answer
+    pub fn with_referral(mut self) -> Self {
+        self.referral = true;
+        self
+    }
+}
+
+pub struct MockDns {
+    addr: SocketAddr,
+    log: Arc<Mutex<Vec<(String, RecordType)>>>,
+}
+
+impl MockDns {
+    /// Start a mock server on an ephemeral loopback port answering from `records`
+    pub async fn start(records: Vec<Record>) -> MockDns {
+        MockDns::start_with(records, MockOptions::default()).await
+    }
+
+    /// Start a mock server answering with the authoritative (AA) flag, like
+    /// the name server of the zone of the records
+    pub async fn start_authoritative(records: Vec<Record>) -> MockDns {
+        MockDns::start_with(records, MockOptions::default().with_authoritative()).await
+    }
+
+    /// Start a mock server supporting DNS cookies: the client cookie of a
+    /// query is echoed with a server cookie
+    pub async fn start_with_cookies(records: Vec<Record>) -> MockDns {
+        MockDns::start_with(records, MockOptions::default().with_cookies()).await
+    }
+
+    /// Start a mock server adding to the NS answers the glue of the name
+    /// servers within the zone, in the additional section, like an
+    /// authoritative server would
+    pub async fn start_with_glue(records: Vec<Record>) -> MockDns {
+        MockDns::start_with(records, MockOptions::default().with_glue()).await
+    }
+
+    /// Start a mock server answering the NS queries with a referral, like
+    /// the name server of a parent zone: the NS records in the authority
+    /// section with their glue, and an empty answer. It listens on `addr`,
+    /// so that several servers can share a port on distinct loopback
+    /// addresses.
+    pub async fn start_referral_at(addr: SocketAddr, records: Vec<Record>) -> MockDns {
+        let options = MockOptions::default().with_glue().with_referral();
+        MockDns::start_at(addr, records, options).await
+    }
+
+    /// Start a mock server answering every query with SERVFAIL, like a
+    /// failing resolver
+    pub async fn start_failing() -> MockDns {
+        MockDns::start_with(vec![], MockOptions::default().with_servfail()).await
+    }
+
+    /// Start a mock server on an ephemeral loopback port answering from
+    /// `records` with the behaviour of `options`
+    pub async fn start_with(records: Vec<Record>, options: MockOptions) -> MockDns {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        MockDns::start_at(addr, records, options).await
+    }
+
+    /// Start a mock server listening on `addr`
+    pub async fn start_at(addr: SocketAddr, records: Vec<Record>, options: MockOptions) -> MockDns {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let queries = log.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let Ok(request) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                let mut response = answer(&request, &records, &queries);
+                response.set_authoritative(options.authoritative);
+                if options.referral
+                    && response.query().map(|q| q.query_type()) == Some(RecordType::NS)
+                {
+                    let referral = response.take_answers();
+                    response.add_name_servers(referral);
+                }
+                if options.glue {
+                    add_glue(&mut response, &records);
+                }
+                if options.servfail {
+                    response.set_response_code(ResponseCode::ServFail);
+                }
+                let client_cookie = request
+                    .extensions()
+                    .as_ref()
+                    .and_then(|edns| edns.option(EdnsCode::Cookie));
+                if let (true, Some(EdnsOption::Unknown(code, client_cookie))) =
+                    (options.cookies, client_cookie)
+                {
+                    let mut cookie = client_cookie[..8.min(client_cookie.len())].to_vec();
+                    cookie.extend_from_slice(b"\x01\x00\x00\x00server");
+                    let mut edns = Edns::new();
+                    edns.options_mut()
+                        .insert(EdnsOption::Unknown(*code, cookie));
+                    response.set_edns(edns);
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        MockDns { addr, log }
+    }
+
+    /// Name and type of the queries received so far
+    pub fn queries(&self) -> Vec<(String, RecordType)> {
+        self.log.lock().unwrap().clone()
+    }
+
+    /// Address the mock server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Create a resolver sending every query to the mock server
+    pub fn resolver(&self) -> Resolver<TokioConnectionProvider> {
+        resolver_for(self.addr)
+    }
+}
+
+/// Create a resolver sending every query to the name server at `addr`
+pub fn resolver_for(addr: SocketAddr) -> Resolver<TokioConnectionProvider> {
+    let name_server = NameServerConfig::new(addr, Protocol::Udp);
+    let config = ResolverConfig::from_parts(None, vec![], vec![name_server]);
+    let mut builder = Resolver::builder_with_config(config, TokioConnectionProvider::default());
+    builder.options_mut().timeout = Duration::from_millis(500);
+    builder.options_mut().attempts = 0;
+    builder.build()
+}
+
+/// Whether the record name `a` matches the queried name `b`, a record name
+/// starting with `*.` matches any subdomain of the rest of the name
+fn same_name(a: &Name, b: &Name) -> bool {
+    let (a, b) = (a.to_lowercase().to_string(), b.to_lowercase().to_string());
+    match a.strip_prefix("*.") {
+        Some(parent) => b.ends_with(&format!(".{}", parent)),
+        None => a == b,
+    }
+}
+
+/// Add the A and AAAA records of the name servers of an NS answer or
+/// referral which are within the queried zone, the others having no glue
+fn add_glue(response: &mut Message, records: &[Record]) {
+    let Some(zone) = response.query().map(|query| query.name().to_lowercase()) else {
+        return;
+    };
+    let targets = response
+        .answers()
+        .iter()
+        .chain(response.name_servers())
+        .filter_map(|r| r.data().as_ns())
+        .map(|ns| ns.0.to_lowercase())
+        .filter(|target| zone.zone_of(target))
+        .collect::<Vec<_>>();
+    let glue = records
+        .iter()
+        .filter(|r| r.record_type().is_ip_addr() && targets.contains(&r.name().to_lowercase()))
+        .cloned()
+        .collect::<Vec<_>>();
+    response.add_additionals(glue);
+}
+
+/// Answer a query like a recursive resolver would: matching records first,
+/// otherwise follow the CNAME chain of the name.
+fn answer(
+    request: &Message,
+    records: &[Record],
+    log: &Arc<Mutex<Vec<(String, RecordType)>>>,
+) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true);
+    let Some(query) = request.query() else {
+        response.set_response_code(ResponseCode::FormErr);
+        return response;
+    };
+    response.add_query(query.clone());
+    log.lock()
+        .unwrap()
+        .push((query.name().to_lowercase().to_string(), query.query_type()));
+
+    let mut name = query.name().clone();
+    for _ in 0..8 {
+        let matching = records
+            .iter()
+            .filter(|r| same_name(r.name(), &name) && r.record_type() == query.query_type())
+            .map(|r| {
+                // a wildcard answer carries the queried name
+                let mut r = r.clone();
+                r.set_name(name.clone());
+                r
+            })
+            .collect::<Vec<_>>();
+        if !matching.is_empty() {
+            response.add_answers(matching);
+            break;
+        }
+        let cname = records.iter().find(|r| {
+            same_name(r.name(), &name)
+                && r.record_type() == RecordType::CNAME
+                && query.query_type() != RecordType::CNAME
+        });
+        match cname {
+            Some(cname) => {
+                response.add_answer(cname.clone());
+                name = cname.data().as_cname().unwrap().0.clone();
+            }
+            None => break,
+        }
+    }
+    if response.answers().is_empty() && !records.iter().any(|r| same_name(r.name(), &name)) {
+        response.set_response_code(ResponseCode::NXDomain);
+    }
+    response
+}
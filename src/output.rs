@@ -0,0 +1,1064 @@
+use super::{
+    asn::Asn,
+    ipinfo::{IpInfo, IpInfoRecord},
+};
+use ::time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use indexmap::IndexMap;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    net::IpAddr,
+};
+
+/// TTL of the records of a generated zone file
+const ZONEFILE_TTL: u32 = 3600;
+
+/// Top level fields of a result, a projection starting with another name
+/// can never match
+const FIELDS: &[&str] = &[
+    "origin",
+    "hostname",
+    "scheme",
+    "port",
+    "path",
+    "rank_band",
+    "known_tld",
+    "domain",
+    "domain_error",
+    "wildcard_dns",
+    "likely_parked",
+    "cname",
+    "dname",
+    "mx",
+    "txt",
+    "soa",
+    "caa",
+    "authoritative",
+    "cname_depth",
+    "dangling_cname",
+    "ns",
+    "delegation",
+    "ip",
+    "ipv4_count",
+    "ipv6_count",
+    "dual_stack",
+    "ttl",
+    "https_rr",
+    "ech_present",
+    "ecs",
+    "ptr",
+    "reputation",
+    "resolved_ips",
+    "asn",
+    "ip_asn",
+    "tls",
+    "expiring_soon",
+    "tls_all_ips",
+    "security_headers",
+    "related",
+    "raw",
+];
+
+/// Return the requested fields which are not fields of a result
+pub fn unknown_fields(fields: &[String]) -> Vec<&str> {
+    fields
+        .iter()
+        .map(String::as_str)
+        .filter(|field| {
+            let name = field.split('.').next().unwrap_or_default();
+            !FIELDS.contains(&name)
+        })
+        .collect()
+}
+
+/// Lookup a dotted path in `value`, a path going through an array is
+/// applied to each of its elements
+fn get_path(value: &Value, path: &[&str]) -> Option<Value> {
+    let Some((first, rest)) = path.split_first() else {
+        return Some(value.clone());
+    };
+    match value {
+        Value::Object(map) => get_path(map.get(*first)?, rest),
+        Value::Array(items) => Some(Value::Array(
+            items
+                .iter()
+                .filter_map(|item| get_path(item, path))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// Insert `value` in `object` at the dotted `path`, creating the intermediate objects
+fn insert_path(object: &mut Map<String, Value>, path: &[&str], value: Value) {
+    match path {
+        [] => {}
+        [last] => {
+            object.insert(last.to_string(), value);
+        }
+        [first, rest @ ..] => {
+            let child = object
+                .entry(first.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(child) = child {
+                insert_path(child, rest, value);
+            }
+        }
+    }
+}
+
+///
+/// Keep only the `fields` of a serialized result. The fields are dotted paths
+/// like `tls.organization`, looked up in the DNS and TLS records first and then
+/// at the top level, so that `hostname` and `origin.popularity` both work.
+/// Fields missing from the result are left out.
+///
+pub fn project(value: &Value, fields: &[String]) -> Value {
+    let mut projected = Map::new();
+    for field in fields {
+        let path = field.split('.').collect::<Vec<_>>();
+        let found = value
+            .get("records")
+            .and_then(|records| get_path(records, &path))
+            .or_else(|| get_path(value, &path));
+        if let Some(found) = found {
+            insert_path(&mut projected, &path, found);
+        }
+    }
+    Value::Object(projected)
+}
+
+/// Fully qualified form of a DNS name
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.').to_lowercase())
+}
+
+/// Whether `name` belongs to the zone of `apex`
+fn in_zone(name: &str, apex: &str) -> bool {
+    name == apex || name.ends_with(&format!(".{}", apex))
+}
+
+/// Add the A/AAAA/CNAME/NS records of a result to the records of its zone.
+/// A name with a CNAME cannot hold other data, so its addresses are given to
+/// the last name of the CNAME chain when it is in the zone.
+fn add_zone_records(
+    records: &IpInfoRecord,
+    zones: &mut BTreeMap<String, BTreeSet<(String, &'static str, String)>>,
+) {
+    let Some(domain) = &records.domain else {
+        return;
+    };
+    let apex = fqdn(domain);
+    let zone = zones.entry(apex.clone()).or_default();
+    let mut owner = fqdn(&records.hostname);
+    if let Some(cname) = records.cname.as_ref().and_then(|cname| cname.first()) {
+        zone.insert((owner, "CNAME", fqdn(cname)));
+        owner = fqdn(records.cname.iter().flatten().last().unwrap_or(cname));
+    }
+    if in_zone(&owner, &apex) {
+        for ip in records.ip.iter().flatten() {
+            let rtype = match ip {
+                IpAddr::V4(_) => "A",
+                IpAddr::V6(_) => "AAAA",
+            };
+            zone.insert((owner.clone(), rtype, ip.to_string()));
+        }
+    }
+    for ns in records.ns.iter().flat_map(|ns| &ns.names) {
+        zone.insert((apex.clone(), "NS", fqdn(ns)));
+    }
+    if let Some(related) = &records.related {
+        add_zone_records(related, zones);
+    }
+}
+
+///
+/// Render the A/AAAA/CNAME/NS records of the results in BIND zone file
+/// syntax, one zone per apex domain. The records shared by several hosts of a
+/// zone are written once, and a placeholder SOA makes each zone loadable.
+///
+pub fn to_zonefile(results: &[IpInfo]) -> String {
+    let mut zonefile = ZoneFile::default();
+    for result in results {
+        zonefile.add(result);
+    }
+    zonefile.render()
+}
+
+//******************************************************************************
+//
+// Aggregations of the results, updated as each result arrives and rendered
+// once all are in. Only the ASN summary fully streams, its memory is bounded
+// by the number of ASNs. The zone file keeps the distinct records and the
+// shared hosting every hostname, both growing with the input.
+//
+//******************************************************************************
+
+///
+/// Records of the zones of the results, aggregated as they come: the
+/// results are dropped once their records are added, and the records shared
+/// by several hosts are only kept once
+///
+#[derive(Debug, Default)]
+pub struct ZoneFile {
+    zones: BTreeMap<String, BTreeSet<(String, &'static str, String)>>,
+}
+
+impl ZoneFile {
+    pub fn add(&mut self, result: &IpInfo) {
+        add_zone_records(&result.records, &mut self.zones);
+    }
+
+    /// Zone file of the results added so far, see `to_zonefile`
+    pub fn render(self) -> String {
+        let mut zonefile = String::new();
+        for (apex, records) in self.zones {
+            let primary = records
+                .iter()
+                .find(|(_, rtype, _)| *rtype == "NS")
+                .map(|(_, _, ns)| ns.clone())
+                .unwrap_or_else(|| format!("ns.{}", apex));
+            let _ = writeln!(zonefile, "$ORIGIN {}", apex);
+            let _ = writeln!(zonefile, "$TTL {}", ZONEFILE_TTL);
+            let _ = writeln!(
+                zonefile,
+                "@ IN SOA {} hostmaster.{} 1 7200 3600 1209600 {}",
+                primary, apex, ZONEFILE_TTL
+            );
+            for (name, rtype, rdata) in records {
+                let _ = writeln!(zonefile, "{} IN {} {}", name, rtype, rdata);
+            }
+            zonefile.push('\n');
+        }
+        zonefile
+    }
+}
+
+/// Key the rows of an aggregated output are sorted by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    /// Number of hosts of the row
+    Count,
+    /// Sum of the popularity of the hosts of the row
+    Popularity,
+    /// Number of the ASN
+    Asn,
+    /// Organization of the ASN, or first hostname of a shared hosting group
+    Name,
+}
+
+/// Ordering of the rows of an aggregated output, replacing its default one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl SortOrder {
+    /// Apply the direction to an ascending ordering
+    fn apply(self, ordering: Ordering) -> Ordering {
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+}
+
+/// Hosts resolving to exactly the same set of IP addresses
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct SharedHostingGroup {
+    pub ips: Vec<IpAddr>,
+    pub hosts: Vec<String>,
+}
+
+///
+/// Aggregate the results as they come by the sorted set of their IP
+/// addresses, keeping only the hostnames, to find the hosts sharing the same
+/// hosting. The results without any address are left out.
+///
+#[derive(Debug, Default)]
+pub struct SharedHosting {
+    groups: HashMap<Vec<IpAddr>, BTreeSet<String>>,
+}
+
+impl SharedHosting {
+    pub fn add(&mut self, result: &IpInfo) {
+        let Some(ips) = &result.records.ip else {
+            return;
+        };
+        let mut ips = ips.clone();
+        ips.sort_unstable();
+        ips.dedup();
+        self.groups
+            .entry(ips)
+            .or_default()
+            .insert(result.records.hostname.clone());
+    }
+
+    /// Groups of more than one host, the largest first
+    pub fn groups(self) -> Vec<SharedHostingGroup> {
+        let mut groups = self
+            .groups
+            .into_iter()
+            .filter(|(_, hosts)| hosts.len() > 1)
+            .map(|(ips, hosts)| SharedHostingGroup {
+                ips,
+                hosts: hosts.into_iter().collect(),
+            })
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| b.hosts.len().cmp(&a.hosts.len()).then(a.ips.cmp(&b.ips)));
+        groups
+    }
+
+    /// Groups of more than one host in the given order, ties broken by IPs.
+    /// The groups have no ASN nor popularity, these keys keep the default order.
+    pub fn groups_sorted_by(self, order: SortOrder) -> Vec<SharedHostingGroup> {
+        let mut groups = self.groups();
+        let ordering = |a: &SharedHostingGroup, b: &SharedHostingGroup| match order.key {
+            SortKey::Count => a.hosts.len().cmp(&b.hosts.len()),
+            SortKey::Name => a.hosts.first().cmp(&b.hosts.first()),
+            SortKey::Popularity | SortKey::Asn => Ordering::Equal,
+        };
+        groups.sort_by(|a, b| order.apply(ordering(a, b)).then(a.ips.cmp(&b.ips)));
+        groups
+    }
+}
+
+/// Group of the results whose registrable domain could not be extracted
+pub const UNKNOWN_DOMAIN: &str = "(unknown)";
+
+///
+/// Results nested under their registrable domain, the `domain` field, the
+/// hosts of a domain sorted by hostname. Unlike the other aggregations the
+/// whole results are kept, so the memory grows with the input.
+///
+#[derive(Debug, Default)]
+pub struct DomainGroups {
+    groups: BTreeMap<String, Vec<(String, Value)>>,
+}
+
+impl DomainGroups {
+    /// Add a result as `value`, its JSON output with the fields projected
+    pub fn add(&mut self, result: &IpInfo, value: Value) {
+        let domain = result.records.domain.as_deref().unwrap_or(UNKNOWN_DOMAIN);
+        self.groups
+            .entry(domain.to_string())
+            .or_default()
+            .push((result.records.hostname.clone(), value));
+    }
+
+    /// JSON object mapping each domain to the list of its results
+    pub fn render(self) -> Value {
+        let groups = self
+            .groups
+            .into_iter()
+            .map(|(domain, mut results)| {
+                results.sort_by(|a, b| a.0.cmp(&b.0));
+                let results = results.into_iter().map(|(_, value)| value).collect();
+                (domain, Value::Array(results))
+            })
+            .collect::<Map<_, _>>();
+        Value::Object(groups)
+    }
+}
+
+///
+/// Representative result of each registrable domain, the `domain` field: the
+/// most popular one, the first received on a tie. The results are kept until
+/// the end, in the order their domain was first seen.
+///
+#[derive(Debug, Default)]
+pub struct OnePerDomain {
+    kept: IndexMap<String, IpInfo>,
+}
+
+impl OnePerDomain {
+    /// Keep `result` when it is the most popular of its domain so far. A
+    /// result without domain cannot be compared and is given back as is.
+    pub fn add(&mut self, result: IpInfo) -> Option<IpInfo> {
+        let Some(domain) = result.records.domain.clone() else {
+            return Some(result);
+        };
+        match self.kept.get_mut(&domain) {
+            Some(kept) if result.origin.popularity > kept.origin.popularity => *kept = result,
+            Some(_) => (),
+            None => {
+                self.kept.insert(domain, result);
+            }
+        }
+        None
+    }
+
+    /// The kept results, one per domain
+    pub fn results(self) -> impl Iterator<Item = IpInfo> {
+        self.kept.into_values()
+    }
+}
+
+/// A fact discovered about an origin, with the common envelope of the events
+#[derive(Debug, Serialize, Clone)]
+pub struct Event {
+    /// RFC 3339 time the event was emitted
+    pub timestamp: String,
+    pub origin: String,
+    #[serde(flatten)]
+    pub data: EventData,
+}
+
+/// Payload of an event, tagged with its `event_type`
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event_type", content = "data")]
+pub enum EventData {
+    /// The host resolved to at least one address
+    HostResolved {
+        hostname: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        domain: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cname: Option<Vec<String>>,
+    },
+    /// An address of the host
+    IpDiscovered { hostname: String, ip: IpAddr },
+    /// An AS announcing addresses of the host
+    AsnMapped {
+        hostname: String,
+        #[serde(flatten)]
+        asn: Asn,
+    },
+    /// The certificate served for the host
+    CertObserved {
+        hostname: String,
+        issuer: String,
+        issuer_dn: String,
+        subject_dn: String,
+        sha256_fingerprint: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        not_after: Option<String>,
+    },
+}
+
+/// Flatten a result into a stream of small events, one per discovered fact:
+/// the resolution of the host, each of its addresses and ASNs, and its
+/// certificate. A host which did not resolve gives no event.
+pub fn to_events(info: &IpInfo) -> Vec<Event> {
+    let records = &info.records;
+    let Some(ips) = &records.ip else {
+        return Vec::new();
+    };
+    let hostname = &records.hostname;
+    let mut data = vec![EventData::HostResolved {
+        hostname: hostname.clone(),
+        domain: records.domain.clone(),
+        cname: records.cname.clone(),
+    }];
+    data.extend(ips.iter().map(|ip| EventData::IpDiscovered {
+        hostname: hostname.clone(),
+        ip: *ip,
+    }));
+    data.extend(
+        records
+            .asn
+            .iter()
+            .flatten()
+            .map(|asn| EventData::AsnMapped {
+                hostname: hostname.clone(),
+                asn: asn.clone(),
+            }),
+    );
+    data.extend(records.tls.iter().map(|tls| EventData::CertObserved {
+        hostname: hostname.clone(),
+        issuer: tls.organization().to_string(),
+        issuer_dn: tls.issuer_dn().to_string(),
+        subject_dn: tls.subject_dn().to_string(),
+        sha256_fingerprint: tls.sha256_fingerprint().to_string(),
+        not_after: tls.not_after().map(str::to_string),
+    }));
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    data.into_iter()
+        .map(|data| Event {
+            timestamp: timestamp.clone(),
+            origin: info.origin.origin.clone(),
+            data,
+        })
+        .collect()
+}
+
+/// Number of hosts resolving to addresses of an AS
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct AsnSummaryRow {
+    pub asn: u32,
+    pub organization: String,
+    pub country_code: String,
+    pub hosts: usize,
+    /// Sum of the popularity of the hosts, only with the weighting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub popularity: Option<u64>,
+}
+
+///
+/// Running count of the hosts of each ASN, a host resolving to several
+/// networks of the same AS counted once. Nothing of the results is kept, so
+/// the memory is bounded by the number of ASNs whatever the size of the input.
+///
+#[derive(Debug, Default)]
+pub struct AsnSummary {
+    rows: HashMap<u32, AsnSummaryRow>,
+    weight_by_popularity: bool,
+}
+
+impl AsnSummary {
+    /// Summary weighting each ASN by the popularity of its hosts: the score of
+    /// an ASN is the sum of the `popularity` of the origins of its hosts, a
+    /// host counting once per ASN, and the rows are ranked by this score
+    pub fn weighted_by_popularity() -> Self {
+        AsnSummary {
+            weight_by_popularity: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, result: &IpInfo) {
+        let asns = result.records.asn.iter().flatten();
+        let mut seen = BTreeSet::new();
+        for asn in asns.filter(|asn| seen.insert(asn.asn)) {
+            let row = self.rows.entry(asn.asn).or_insert_with(|| AsnSummaryRow {
+                asn: asn.asn,
+                organization: asn.organization.clone(),
+                country_code: asn.country_code.clone(),
+                hosts: 0,
+                popularity: self.weight_by_popularity.then_some(0),
+            });
+            row.hosts += 1;
+            if let Some(popularity) = &mut row.popularity {
+                *popularity += u64::from(result.origin.popularity);
+            }
+        }
+    }
+
+    /// Number of ASNs counted so far
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Counts of the ASNs, the most common first, or the most popular first
+    /// when weighted by popularity
+    pub fn rows(self) -> Vec<AsnSummaryRow> {
+        let mut rows = self.rows.into_values().collect::<Vec<_>>();
+        rows.sort_by(|a, b| {
+            b.popularity
+                .cmp(&a.popularity)
+                .then(b.hosts.cmp(&a.hosts))
+                .then(a.asn.cmp(&b.asn))
+        });
+        rows
+    }
+
+    /// Counts of the ASNs in the given order, ties broken by ASN. The
+    /// popularity is only known when weighted by popularity.
+    pub fn rows_sorted_by(self, order: SortOrder) -> Vec<AsnSummaryRow> {
+        let mut rows = self.rows.into_values().collect::<Vec<_>>();
+        rows.sort_by(|a, b| {
+            let ordering = match order.key {
+                SortKey::Count => a.hosts.cmp(&b.hosts),
+                SortKey::Popularity => a.popularity.cmp(&b.popularity),
+                SortKey::Asn => a.asn.cmp(&b.asn),
+                SortKey::Name => a.organization.cmp(&b.organization),
+            };
+            order.apply(ordering).then(a.asn.cmp(&b.asn))
+        });
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sample() -> Value {
+        json!({
+            "origin": {"origin": "https://www.free.fr", "popularity": 1000, "date": "2025-08-28", "country": "FR"},
+            "records": {
+                "hostname": "www.free.fr",
+                "domain": "free.fr",
+                "ip": ["212.27.48.10"],
+                "asn": [{"network": ["212.27.32.0/19"], "asn": 12322, "organization": "PROXAD", "country_code": "FR"}],
+                "tls": {"organization": "Let's Encrypt", "country": "US", "name_mismatch": false}
+            }
+        })
+    }
+
+    fn fields(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn test_project() {
+        let projected = project(
+            &sample(),
+            &fields(&[
+                "hostname",
+                "ip",
+                "asn.asn",
+                "tls.organization",
+                "origin.popularity",
+            ]),
+        );
+        assert_eq!(
+            projected,
+            json!({
+                "hostname": "www.free.fr",
+                "ip": ["212.27.48.10"],
+                "asn": {"asn": [12322]},
+                "tls": {"organization": "Let's Encrypt"},
+                "origin": {"popularity": 1000}
+            })
+        );
+    }
+
+    #[test]
+    fn test_project_missing_and_unknown() {
+        let requested = fields(&["hostname", "cname", "nope", "tls.nope"]);
+        assert_eq!(
+            project(&sample(), &requested),
+            json!({"hostname": "www.free.fr"})
+        );
+        assert_eq!(unknown_fields(&requested), vec!["nope"]);
+    }
+
+    fn ip_info(hostname: &str, domain: &str) -> IpInfo {
+        IpInfo {
+            origin: crate::ipinfo::OriginRecord::new(format!("https://{}", hostname), 1000),
+            records: IpInfoRecord {
+                hostname: hostname.to_string(),
+                domain: Some(domain.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_to_zonefile() {
+        use hickory_proto::{
+            rr::{Name, RecordType},
+            serialize::txt::Parser,
+        };
+        use std::str::FromStr;
+
+        let mut www = ip_info("www.example.com", "example.com");
+        www.records.ip = Some(vec![
+            "192.0.2.1".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ]);
+        www.records.ns = Some(crate::dns::NameServer {
+            names: vec![
+                "ns1.example.net.".to_string(),
+                "ns2.example.net.".to_string(),
+            ],
+            ips: None,
+            asn: None,
+            open_resolver: None,
+            edns_cookie_supported: None,
+        });
+        let mut apex = ip_info("example.com", "example.com");
+        apex.records.ip = Some(vec!["192.0.2.1".parse().unwrap()]);
+        apex.records.ns = www.records.ns.clone();
+        let mut cdn = ip_info("static.example.com", "example.com");
+        cdn.records.cname = Some(vec!["edge.cdn.example.org.".to_string()]);
+        cdn.records.ip = Some(vec!["198.51.100.1".parse().unwrap()]);
+        let mut other = ip_info("www.example.org", "example.org");
+        other.records.ip = Some(vec!["198.51.100.2".parse().unwrap()]);
+
+        let zonefile = to_zonefile(&[www, apex, cdn, other]);
+        let zones = zonefile.split("$ORIGIN ").skip(1).collect::<Vec<_>>();
+        assert_eq!(zones.len(), 2);
+        // the NS records shared by two hosts are written once
+        assert_eq!(zonefile.matches("IN NS ns1.example.net.").count(), 1);
+
+        let (origin, records) = Parser::new(format!("$ORIGIN {}", zones[0]), None, None)
+            .parse()
+            .unwrap();
+        assert_eq!(origin, Name::from_str("example.com.").unwrap());
+        let count = |name: &str, rtype: RecordType| {
+            records
+                .values()
+                .filter(|set| {
+                    set.name() == &Name::from_str(name).unwrap() && set.record_type() == rtype
+                })
+                .map(|set| set.records_without_rrsigs().count())
+                .sum::<usize>()
+        };
+        assert_eq!(count("www.example.com.", RecordType::A), 1);
+        assert_eq!(count("www.example.com.", RecordType::AAAA), 1);
+        assert_eq!(count("example.com.", RecordType::A), 1);
+        assert_eq!(count("example.com.", RecordType::NS), 2);
+        assert_eq!(count("example.com.", RecordType::SOA), 1);
+        assert_eq!(count("static.example.com.", RecordType::CNAME), 1);
+        // the CNAME target is out of the zone, its address is not written
+        assert!(!zones[0].contains("198.51.100.1"));
+
+        let (origin, records) = Parser::new(format!("$ORIGIN {}", zones[1]), None, None)
+            .parse()
+            .unwrap();
+        assert_eq!(origin, Name::from_str("example.org.").unwrap());
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn test_shared_hosting() {
+        let with_ips = |hostname: &str, ips: &[&str]| {
+            let mut info = ip_info(hostname, "example.com");
+            info.records.ip = Some(ips.iter().map(|ip| ip.parse().unwrap()).collect());
+            info
+        };
+        let mut aggregator = SharedHosting::default();
+        for info in [
+            with_ips("a.example.com", &["192.0.2.1", "192.0.2.2"]),
+            // the same set in another order
+            with_ips("b.example.com", &["192.0.2.2", "192.0.2.1"]),
+            with_ips("c.example.com", &["192.0.2.1", "192.0.2.2"]),
+            // overlapping but different set
+            with_ips("d.example.com", &["192.0.2.1"]),
+            with_ips("e.example.com", &["192.0.2.1"]),
+            // alone on its address
+            with_ips("f.example.com", &["198.51.100.1"]),
+            ip_info("g.example.com", "example.com"),
+        ] {
+            aggregator.add(&info);
+        }
+        let groups = aggregator.groups();
+        assert_eq!(
+            groups,
+            vec![
+                SharedHostingGroup {
+                    ips: vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+                    hosts: vec![
+                        "a.example.com".to_string(),
+                        "b.example.com".to_string(),
+                        "c.example.com".to_string()
+                    ],
+                },
+                SharedHostingGroup {
+                    ips: vec!["192.0.2.1".parse().unwrap()],
+                    hosts: vec!["d.example.com".to_string(), "e.example.com".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_domain_groups() {
+        let mut groups = DomainGroups::default();
+        for (hostname, domain) in [
+            ("www.example.com", Some("example.com")),
+            ("api.example.org", Some("example.org")),
+            ("cdn.example.com", Some("example.com")),
+            ("example.com", Some("example.com")),
+            ("localhost", None),
+        ] {
+            let mut info = ip_info(hostname, "");
+            info.records.domain = domain.map(str::to_string);
+            groups.add(&info, serde_json::json!({"hostname": hostname}));
+        }
+        assert_eq!(
+            groups.render(),
+            serde_json::json!({
+                "(unknown)": [{"hostname": "localhost"}],
+                "example.com": [
+                    {"hostname": "cdn.example.com"},
+                    {"hostname": "example.com"},
+                    {"hostname": "www.example.com"},
+                ],
+                "example.org": [{"hostname": "api.example.org"}],
+            })
+        );
+        assert_eq!(DomainGroups::default().render(), serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_to_events() {
+        let info: IpInfo = serde_json::from_value(json!({
+            "origin": {"origin": "https://www.free.fr", "popularity": 1000, "date": "2025-08-28", "country": "FR"},
+            "records": {
+                "hostname": "www.free.fr",
+                "domain": "free.fr",
+                "cname": ["free.fr."],
+                "ip": ["212.27.48.10", "2a01:e0c:1::1"],
+                "asn": [{"network": ["212.27.32.0/19"], "asn": 12322, "organization": "PROXAD", "country_code": "FR"}],
+                "tls": {
+                    "organization": "Let's Encrypt",
+                    "issuer_dn": "C=US, O=Let's Encrypt, CN=R3",
+                    "subject_dn": "CN=www.free.fr",
+                    "name_mismatch": false,
+                    "sha256_fingerprint": "ab01",
+                    "not_after": "2026-01-01T00:00:00Z"
+                }
+            }
+        }))
+        .unwrap();
+        let events = to_events(&info);
+        assert!(events.iter().all(|event| {
+            event.origin == "https://www.free.fr"
+                && OffsetDateTime::parse(&event.timestamp, &Rfc3339).is_ok()
+        }));
+        let events = events
+            .iter()
+            .map(|event| {
+                let mut value = serde_json::to_value(event).unwrap();
+                value.as_object_mut().unwrap().remove("timestamp");
+                value
+            })
+            .collect::<Vec<_>>();
+        let envelope = |event_type: &str, data: Value| json!({"origin": "https://www.free.fr", "event_type": event_type, "data": data});
+        assert_eq!(
+            events,
+            [
+                envelope(
+                    "HostResolved",
+                    json!({"hostname": "www.free.fr", "domain": "free.fr", "cname": ["free.fr."]})
+                ),
+                envelope(
+                    "IpDiscovered",
+                    json!({"hostname": "www.free.fr", "ip": "212.27.48.10"})
+                ),
+                envelope(
+                    "IpDiscovered",
+                    json!({"hostname": "www.free.fr", "ip": "2a01:e0c:1::1"})
+                ),
+                envelope(
+                    "AsnMapped",
+                    json!({
+                        "hostname": "www.free.fr",
+                        "network": ["212.27.32.0/19"],
+                        "asn": 12322,
+                        "organization": "PROXAD",
+                        "country_code": "FR"
+                    })
+                ),
+                envelope(
+                    "CertObserved",
+                    json!({
+                        "hostname": "www.free.fr",
+                        "issuer": "Let's Encrypt",
+                        "issuer_dn": "C=US, O=Let's Encrypt, CN=R3",
+                        "subject_dn": "CN=www.free.fr",
+                        "sha256_fingerprint": "ab01",
+                        "not_after": "2026-01-01T00:00:00Z"
+                    })
+                ),
+            ]
+        );
+        // a host which did not resolve gives no event
+        assert!(to_events(&ip_info("www.example.com", "example.com")).is_empty());
+    }
+
+    #[test]
+    fn test_one_per_domain() {
+        let with_popularity = |hostname: &str, domain: &str, popularity: u32| {
+            let mut info = ip_info(hostname, domain);
+            info.origin.popularity = popularity;
+            info
+        };
+        let mut selection = OnePerDomain::default();
+        for info in [
+            with_popularity("www.example.com", "example.com", 100),
+            with_popularity("api.example.com", "example.com", 500),
+            with_popularity("www.example.org", "example.org", 10),
+            with_popularity("cdn.example.com", "example.com", 500),
+            with_popularity("mail.example.org", "example.org", 5),
+        ] {
+            assert!(selection.add(info).is_none());
+        }
+        // a result without domain is output as is
+        let mut localhost = ip_info("localhost", "");
+        localhost.records.domain = None;
+        assert!(selection.add(localhost).is_some());
+        let hostnames = selection
+            .results()
+            .map(|info| info.records.hostname)
+            .collect::<Vec<_>>();
+        assert_eq!(hostnames, ["api.example.com", "www.example.org"]);
+    }
+
+    #[test]
+    fn test_asn_summary_bounded() {
+        let asn = |asn: u32| crate::asn::Asn {
+            network: vec![],
+            asn,
+            organization: format!("AS{}-ORG", asn),
+            country_code: "FR".to_string(),
+        };
+        let mut summary = AsnSummary::default();
+        // a large stream of distinct hosts spread over a few ASNs
+        for n in 0..100_000u32 {
+            let mut info = ip_info(&format!("host{}.example.com", n), "example.com");
+            info.records.asn = Some(vec![asn(64500 + n % 10), asn(64500 + n % 10)]);
+            summary.add(&info);
+            // the state only grows with the number of ASNs
+            assert!(summary.len() <= 10);
+        }
+        summary.add(&ip_info("unresolved.example.com", "example.com"));
+        assert_eq!(summary.len(), 10);
+        let rows = summary.rows();
+        assert!(rows.iter().all(|row| row.hosts == 10_000));
+        assert_eq!(
+            rows[0],
+            AsnSummaryRow {
+                asn: 64500,
+                organization: "AS64500-ORG".to_string(),
+                country_code: "FR".to_string(),
+                hosts: 10_000,
+                popularity: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_asn_summary_weighted_by_popularity() {
+        let with_asns = |hostname: &str, popularity: u32, asns: &[u32]| {
+            let mut info = ip_info(hostname, "example.com");
+            info.origin.popularity = popularity;
+            info.records.asn = Some(
+                asns.iter()
+                    .map(|asn| crate::asn::Asn {
+                        network: vec![],
+                        asn: *asn,
+                        organization: format!("AS{}-ORG", asn),
+                        country_code: "FR".to_string(),
+                    })
+                    .collect(),
+            );
+            info
+        };
+        let results = [
+            // many unpopular hosts on 64500
+            with_asns("a.example.com", 10, &[64500]),
+            with_asns("b.example.com", 20, &[64500]),
+            with_asns("c.example.com", 30, &[64500]),
+            // a single popular host on 64501
+            with_asns("d.example.com", 5000, &[64501]),
+            // a host on both, counted once per ASN
+            with_asns("e.example.com", 100, &[64500, 64501, 64501]),
+        ];
+        let mut summary = AsnSummary::weighted_by_popularity();
+        for info in &results {
+            summary.add(info);
+        }
+        let rows = summary.rows();
+        assert_eq!(
+            rows.iter()
+                .map(|row| (row.asn, row.hosts, row.popularity))
+                .collect::<Vec<_>>(),
+            vec![(64501, 2, Some(5100)), (64500, 4, Some(160))]
+        );
+        // ranked by host count without the weighting
+        let mut summary = AsnSummary::default();
+        for info in &results {
+            summary.add(info);
+        }
+        let rows = summary.rows();
+        assert_eq!(
+            rows.iter()
+                .map(|row| (row.asn, row.hosts, row.popularity))
+                .collect::<Vec<_>>(),
+            vec![(64500, 4, None), (64501, 2, None)]
+        );
+        assert_eq!(
+            serde_json::to_value(&rows[0]).unwrap(),
+            json!({"asn": 64500, "organization": "AS64500-ORG", "country_code": "FR", "hosts": 4})
+        );
+    }
+
+    #[test]
+    fn test_sort_aggregated_output() {
+        let with_asn = |hostname: &str, popularity: u32, asn: u32, organization: &str| {
+            let mut info = ip_info(hostname, "example.com");
+            info.origin.popularity = popularity;
+            info.records.asn = Some(vec![crate::asn::Asn {
+                network: vec![],
+                asn,
+                organization: organization.to_string(),
+                country_code: "FR".to_string(),
+            }]);
+            info.records.ip = Some(vec![IpAddr::from([192, 0, 2, (asn % 256) as u8])]);
+            info
+        };
+        let results = [
+            with_asn("a.example.com", 10, 64502, "Bravo"),
+            with_asn("b.example.com", 20, 64502, "Bravo"),
+            with_asn("c.example.com", 500, 64501, "Charlie"),
+            with_asn("d.example.com", 30, 64503, "Alpha"),
+            with_asn("e.example.com", 40, 64503, "Alpha"),
+            with_asn("f.example.com", 50, 64503, "Alpha"),
+        ];
+        let summary_sorted_by = |key: SortKey, descending: bool| {
+            let mut summary = AsnSummary::weighted_by_popularity();
+            for info in &results {
+                summary.add(info);
+            }
+            summary
+                .rows_sorted_by(SortOrder { key, descending })
+                .iter()
+                .map(|row| row.asn)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary_sorted_by(SortKey::Count, false),
+            [64501, 64502, 64503]
+        );
+        assert_eq!(
+            summary_sorted_by(SortKey::Count, true),
+            [64503, 64502, 64501]
+        );
+        assert_eq!(
+            summary_sorted_by(SortKey::Popularity, true),
+            [64501, 64503, 64502]
+        );
+        assert_eq!(
+            summary_sorted_by(SortKey::Asn, false),
+            [64501, 64502, 64503]
+        );
+        assert_eq!(
+            summary_sorted_by(SortKey::Name, false),
+            [64503, 64502, 64501]
+        );
+        assert_eq!(
+            summary_sorted_by(SortKey::Name, true),
+            [64501, 64502, 64503]
+        );
+
+        let groups_sorted_by = |key: SortKey, descending: bool| {
+            let mut aggregator = SharedHosting::default();
+            for info in &results {
+                aggregator.add(info);
+            }
+            aggregator
+                .groups_sorted_by(SortOrder { key, descending })
+                .into_iter()
+                .map(|group| group.hosts[0].clone())
+                .collect::<Vec<_>>()
+        };
+        // the host alone on its address is not a group
+        assert_eq!(
+            groups_sorted_by(SortKey::Count, false),
+            ["a.example.com", "d.example.com"]
+        );
+        assert_eq!(
+            groups_sorted_by(SortKey::Count, true),
+            ["d.example.com", "a.example.com"]
+        );
+        assert_eq!(
+            groups_sorted_by(SortKey::Name, true),
+            ["d.example.com", "a.example.com"]
+        );
+    }
+}
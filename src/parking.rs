@@ -0,0 +1,160 @@
+use super::ipinfo::IpInfoRecord;
+
+/// Name server domains of the parking providers
+const PARKING_NS_DOMAINS: &[&str] = &[
+    "sedoparking.com",
+    "bodis.com",
+    "parkingcrew.net",
+    "above.com",
+    "parklogic.com",
+    "afternic.com",
+    "dan.com",
+    "hugedomains.com",
+    "undeveloped.com",
+];
+
+/// Words of the ASN organizations of the parking providers
+const PARKING_ASN_ORGS: &[&str] = &["sedo", "bodis", "parkingcrew", "teaminternet", "parklogic"];
+
+/// Rules telling a parked or for sale domain apart: its name servers are
+/// those of a parking provider, or it resolves to a single IP announced by
+/// a parking provider
+#[derive(Debug, Clone)]
+pub struct ParkingRules {
+    ns_domains: Vec<String>,
+    asn_orgs: Vec<String>,
+}
+
+impl Default for ParkingRules {
+    fn default() -> Self {
+        ParkingRules {
+            ns_domains: PARKING_NS_DOMAINS.iter().map(|d| d.to_string()).collect(),
+            asn_orgs: PARKING_ASN_ORGS.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+}
+
+impl ParkingRules {
+    /// Add a parking provider name server domain, matching its subdomains
+    pub fn with_ns_domain(mut self, domain: &str) -> Self {
+        self.ns_domains
+            .push(domain.trim_end_matches('.').to_lowercase());
+        self
+    }
+
+    /// Add a word of the ASN organization of a parking provider
+    pub fn with_asn_org(mut self, org: &str) -> Self {
+        self.asn_orgs.push(org.to_lowercase());
+        self
+    }
+
+    /// Whether a name server belongs to a parking provider
+    pub fn is_parking_ns(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.ns_domains
+            .iter()
+            .any(|domain| name == *domain || name.ends_with(&format!(".{}", domain)))
+    }
+
+    /// Whether an ASN organization is a parking provider, matching whole words
+    /// so that an organization merely containing the name does not match
+    pub fn is_parking_org(&self, organization: &str) -> bool {
+        let organization = organization.to_lowercase();
+        organization
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|word| self.asn_orgs.iter().any(|org| org == word))
+    }
+
+    /// Whether the signals of a record point to a parked domain, none when
+    /// neither the name servers nor the ASN of the record are known
+    pub fn likely_parked(&self, record: &IpInfoRecord) -> Option<bool> {
+        let ns = record.ns.as_ref().map(|ns| &ns.names);
+        let single_ip_asn = match (&record.ip, &record.asn) {
+            (Some(ips), Some(asn)) if ips.len() == 1 => asn.first(),
+            _ => None,
+        };
+        if ns.is_none() && record.asn.is_none() {
+            return None;
+        }
+        let parking_ns = ns.is_some_and(|names| names.iter().any(|name| self.is_parking_ns(name)));
+        let parking_asn = single_ip_asn.is_some_and(|asn| self.is_parking_org(&asn.organization));
+        Some(parking_ns || parking_asn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asn::Asn, dns::NameServer};
+    use std::net::IpAddr;
+
+    fn record(ns: &[&str], ips: &[&str], organization: &str) -> IpInfoRecord {
+        IpInfoRecord {
+            ns: Some(NameServer {
+                names: ns.iter().map(|name| name.to_string()).collect(),
+                ips: None,
+                asn: None,
+                open_resolver: None,
+                edns_cookie_supported: None,
+            }),
+            ip: Some(ips.iter().map(|ip| ip.parse::<IpAddr>().unwrap()).collect()),
+            asn: Some(vec![Asn {
+                network: vec![],
+                asn: 64500,
+                organization: organization.to_string(),
+                country_code: "DE".to_string(),
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parking_ns() {
+        let rules = ParkingRules::default();
+        for ns in [
+            "ns1.sedoparking.com.",
+            "NS2.BODIS.COM.",
+            "ns1.parkingcrew.net",
+            "ns1.above.com.",
+        ] {
+            let parked = record(&[ns, "ns2.example.net."], &["192.0.2.1"], "EXAMPLE");
+            assert_eq!(rules.likely_parked(&parked), Some(true), "{}", ns);
+        }
+        let active = record(
+            &["ns1.example.net.", "ns2.example.net."],
+            &["192.0.2.1"],
+            "EXAMPLE",
+        );
+        assert_eq!(rules.likely_parked(&active), Some(false));
+        // a lookalike domain is not a parking provider
+        let lookalike = record(&["ns1.notbodis.com."], &["192.0.2.1"], "EXAMPLE");
+        assert_eq!(rules.likely_parked(&lookalike), Some(false));
+    }
+
+    #[test]
+    fn test_parking_asn() {
+        let rules = ParkingRules::default();
+        let parked = record(&["ns1.example.net."], &["192.0.2.1"], "SEDO-AS Sedo GmbH");
+        assert_eq!(rules.likely_parked(&parked), Some(true));
+        // several IPs are not a parking page
+        let several = record(
+            &["ns1.example.net."],
+            &["192.0.2.1", "192.0.2.2"],
+            "SEDO-AS Sedo GmbH",
+        );
+        assert_eq!(rules.likely_parked(&several), Some(false));
+        let sedona = record(&["ns1.example.net."], &["192.0.2.1"], "SEDONA-NET");
+        assert_eq!(rules.likely_parked(&sedona), Some(false));
+        assert_eq!(rules.likely_parked(&IpInfoRecord::default()), None);
+    }
+
+    #[test]
+    fn test_parking_rules_extension() {
+        let parked = record(&["ns1.parked.example."], &["192.0.2.1"], "EXAMPLE-PARKING");
+        assert_eq!(ParkingRules::default().likely_parked(&parked), Some(false));
+        let rules = ParkingRules::default().with_ns_domain("Parked.Example.");
+        assert_eq!(rules.likely_parked(&parked), Some(true));
+        let rules = ParkingRules::default().with_asn_org("PARKING");
+        assert_eq!(rules.likely_parked(&parked), Some(true));
+    }
+}
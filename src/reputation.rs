@@ -0,0 +1,136 @@
+use anyhow::Result;
+use ip_network::IpNetwork;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::{Level, event};
+
+/// Reputation of the IPs of a blocklist entry without a label
+const DEFAULT_LABEL: &str = "blocklisted";
+
+/// Local blocklist of networks on known-bad infrastructure, each with the
+/// reputation its IPs are flagged with. The networks are indexed by prefix
+/// so that an IP is looked up once per prefix length rather than against
+/// every network.
+#[derive(Debug, Clone, Default)]
+pub struct IpBlocklist {
+    networks: BTreeMap<IpNetwork, String>,
+    /// Prefix lengths of the IPv4 and IPv6 networks
+    ipv4_prefixes: BTreeSet<u8>,
+    ipv6_prefixes: BTreeSet<u8>,
+}
+
+impl IpBlocklist {
+    /// Add a network flagging its IPs with `label`, replacing the label of
+    /// the same network added before
+    pub fn with_network(mut self, network: IpNetwork, label: &str) -> Self {
+        match network {
+            IpNetwork::V4(_) => self.ipv4_prefixes.insert(network.netmask()),
+            IpNetwork::V6(_) => self.ipv6_prefixes.insert(network.netmask()),
+        };
+        self.networks.insert(network, label.to_string());
+        self
+    }
+
+    /// Parse a blocklist with a CIDR or an IP per line, optionally followed
+    /// by a label, skipping blank lines and `#` comments
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut blocklist = IpBlocklist::default();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (network, label) = line
+                .split_once(char::is_whitespace)
+                .map_or((line, DEFAULT_LABEL), |(network, label)| {
+                    (network, label.trim())
+                });
+            let network = match network.parse::<IpAddr>() {
+                Ok(ip) => IpNetwork::from(ip),
+                Err(_) => IpNetwork::from_str_truncate(network).map_err(|e| {
+                    anyhow::anyhow!("Invalid network on blocklist line {}: {}", n + 1, e)
+                })?,
+            };
+            blocklist = blocklist.with_network(network, label);
+        }
+        Ok(blocklist)
+    }
+
+    /// Reputation of an IP, the label of the most specific network
+    /// containing it, none when it is not blocklisted
+    pub fn reputation(&self, ip: IpAddr) -> Option<&str> {
+        let prefixes = match ip {
+            IpAddr::V4(_) => &self.ipv4_prefixes,
+            IpAddr::V6(_) => &self.ipv6_prefixes,
+        };
+        // the longest prefix first
+        prefixes.iter().rev().find_map(|&netmask| {
+            let network = IpNetwork::new_truncate(ip, netmask).ok()?;
+            self.networks.get(&network).map(String::as_str)
+        })
+    }
+
+    /// Number of networks in the blocklist
+    pub fn len(&self) -> usize {
+        self.networks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+}
+
+/// Load a blocklist from a local file of CIDRs
+pub fn load_blocklist(path: &Path) -> Result<IpBlocklist> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open blocklist {}: {}", path.display(), e))?;
+    let blocklist = IpBlocklist::parse(std::io::BufReader::new(file))?;
+    event!(
+        Level::INFO,
+        "Loaded {} networks from blocklist {}",
+        blocklist.len(),
+        path.display()
+    );
+    Ok(blocklist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_blocklist() {
+        let blocklist = IpBlocklist::parse(
+            "# known-bad networks\n\
+             198.51.100.0/24 botnet\n\
+             198.51.100.128/25   c2 # more specific\n\
+             \n\
+             203.0.113.7\n\
+             2001:db8::/32 spam\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(blocklist.len(), 4);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(blocklist.reputation(ip("198.51.100.1")), Some("botnet"));
+        assert_eq!(blocklist.reputation(ip("198.51.100.200")), Some("c2"));
+        assert_eq!(blocklist.reputation(ip("203.0.113.7")), Some("blocklisted"));
+        assert_eq!(blocklist.reputation(ip("203.0.113.8")), None);
+        assert_eq!(blocklist.reputation(ip("2001:db8::1")), Some("spam"));
+        assert_eq!(blocklist.reputation(ip("2001:db9::1")), None);
+        // the families do not mix, ::ffff:198.51.100.1 is not an IPv4
+        assert_eq!(blocklist.reputation(ip("::ffff:198.51.100.1")), None);
+        // a network listed again takes the last label
+        let blocklist = blocklist.with_network("203.0.113.7/32".parse().unwrap(), "spam");
+        assert_eq!(blocklist.len(), 4);
+        assert_eq!(blocklist.reputation(ip("203.0.113.7")), Some("spam"));
+        // a /0 network contains every IP of its family
+        let blocklist = blocklist.with_network("0.0.0.0/0".parse().unwrap(), "any");
+        assert_eq!(blocklist.reputation(ip("192.0.2.1")), Some("any"));
+        assert_eq!(blocklist.reputation(ip("198.51.100.200")), Some("c2"));
+        assert!(IpBlocklist::parse("not-a-network\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::IpInfo;
+use anyhow::Result;
+use rusqlite::{Connection, Transaction, params};
+use std::path::Path;
+
+/// Normalized schema of the results, created on the first run: a row per
+/// host, the IPs, name servers and certificate of a host referencing it, and
+/// a row per ASN shared by all the IPs it announces
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS hosts (
+    id INTEGER PRIMARY KEY,
+    origin TEXT NOT NULL,
+    hostname TEXT NOT NULL,
+    domain TEXT,
+    popularity INTEGER NOT NULL,
+    date TEXT NOT NULL,
+    country TEXT NOT NULL,
+    ttl INTEGER
+);
+CREATE TABLE IF NOT EXISTS asns (
+    asn INTEGER PRIMARY KEY,
+    organization TEXT NOT NULL,
+    country_code TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS ips (
+    host_id INTEGER NOT NULL REFERENCES hosts(id),
+    addr TEXT NOT NULL,
+    asn INTEGER REFERENCES asns(asn),
+    ptr TEXT,
+    reputation TEXT
+);
+CREATE TABLE IF NOT EXISTS ns (
+    host_id INTEGER NOT NULL REFERENCES hosts(id),
+    name TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tls (
+    host_id INTEGER PRIMARY KEY REFERENCES hosts(id),
+    organization TEXT NOT NULL,
+    country TEXT,
+    issuer_dn TEXT NOT NULL,
+    subject_dn TEXT NOT NULL,
+    sha256_fingerprint TEXT NOT NULL,
+    name_mismatch INTEGER NOT NULL,
+    protocol_version TEXT,
+    not_after TEXT,
+    days_until_expiry INTEGER,
+    grade TEXT
+);
+CREATE INDEX IF NOT EXISTS ips_host ON ips(host_id);
+CREATE INDEX IF NOT EXISTS ns_host ON ns(host_id);
+";
+
+/// SQLite database the results are inserted into, by chunks of
+/// `chunk_size` results each written in a single transaction
+#[derive(Debug)]
+pub struct SqliteOutput {
+    conn: Connection,
+    chunk_size: usize,
+    pending: Vec<IpInfo>,
+}
+
+impl SqliteOutput {
+    /// Open the database at `path`, creating it and its schema when missing
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path).map_err(|e| {
+            anyhow::anyhow!("Failed to open SQLite database {}: {}", path.display(), e)
+        })?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| anyhow::anyhow!("Failed to create the SQLite schema: {}", e))?;
+        Ok(SqliteOutput {
+            conn,
+            chunk_size: 100,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Number of results inserted in a single transaction
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Queue a result, inserting the queued ones once a chunk is complete
+    pub fn add(&mut self, info: IpInfo) -> Result<()> {
+        self.pending.push(info);
+        if self.pending.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Insert the queued results in a single transaction
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction()?;
+        for info in self.pending.drain(..) {
+            insert(&tx, &info)?;
+        }
+        tx.commit()
+            .map_err(|e| anyhow::anyhow!("Failed to commit to the SQLite database: {}", e))
+    }
+
+    /// Insert the results still queued and close the database
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()?;
+        self.conn
+            .close()
+            .map_err(|(_, e)| anyhow::anyhow!("Failed to close the SQLite database: {}", e))
+    }
+}
+
+/// Insert a result into the tables of the schema
+fn insert(tx: &Transaction, info: &IpInfo) -> Result<()> {
+    let records = &info.records;
+    tx.execute(
+        "INSERT INTO hosts (origin, hostname, domain, popularity, date, country, ttl)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            info.origin.origin,
+            records.hostname,
+            records.domain,
+            info.origin.popularity,
+            info.origin.date,
+            info.origin.country,
+            records.ttl,
+        ],
+    )?;
+    let host_id = tx.last_insert_rowid();
+    for asn in records.asn.iter().flatten() {
+        tx.execute(
+            "INSERT OR IGNORE INTO asns (asn, organization, country_code) VALUES (?1, ?2, ?3)",
+            params![asn.asn, asn.organization, asn.country_code],
+        )?;
+    }
+    for (i, addr) in records.ip.iter().flatten().enumerate() {
+        let resolved = records
+            .resolved_ips
+            .as_ref()
+            .and_then(|resolved| resolved.iter().find(|resolved| resolved.addr == *addr));
+        // the ASN announcing the network of the IP, or the one paired with it
+        let asn = records
+            .asn
+            .iter()
+            .flatten()
+            .find(|asn| asn.network.iter().any(|network| network.contains(*addr)))
+            .map(|asn| asn.asn)
+            .or(resolved.and_then(|resolved| resolved.asn));
+        // in the same order as the IPs, or paired with them
+        let nth = |values: &Option<Vec<Option<String>>>| {
+            values
+                .as_ref()
+                .and_then(|values| values.get(i).cloned().flatten())
+        };
+        let ptr = nth(&records.ptr).or(resolved.and_then(|resolved| resolved.ptr.clone()));
+        let reputation =
+            nth(&records.reputation).or(resolved.and_then(|resolved| resolved.reputation.clone()));
+        tx.execute(
+            "INSERT INTO ips (host_id, addr, asn, ptr, reputation) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![host_id, addr.to_string(), asn, ptr, reputation],
+        )?;
+    }
+    for name in records.ns.iter().flat_map(|ns| &ns.names) {
+        tx.execute(
+            "INSERT INTO ns (host_id, name) VALUES (?1, ?2)",
+            params![host_id, name],
+        )?;
+    }
+    if let Some(tls) = &records.tls {
+        tx.execute(
+            "INSERT INTO tls (host_id, organization, country, issuer_dn, subject_dn,
+                sha256_fingerprint, name_mismatch, protocol_version, not_after,
+                days_until_expiry, grade)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                host_id,
+                tls.organization(),
+                tls.country(),
+                tls.issuer_dn(),
+                tls.subject_dn(),
+                tls.sha256_fingerprint(),
+                tls.name_mismatch(),
+                tls.protocol_version(),
+                tls.not_after(),
+                tls.days_until_expiry(),
+                tls.grade(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Address, ASN, PTR name and ASN organization of an IP
+    type IpRow = (String, Option<u32>, Option<String>, Option<String>);
+
+    fn info(hostname: &str, ips: &[&str], tls: bool) -> IpInfo {
+        let mut value = json!({
+            "origin": { "origin": format!("https://{}", hostname), "popularity": 10 },
+            "records": {
+                "hostname": hostname,
+                "domain": "example.com",
+                "ip": ips,
+                "ptr": [format!("ptr.{}", hostname)],
+                "ns": { "names": ["ns1.example.com", "ns2.example.com"] },
+                "asn": [{
+                    "network": ["192.0.2.0/24"],
+                    "asn": 64500,
+                    "organization": "EXAMPLE",
+                    "country_code": "FR",
+                }],
+            },
+        });
+        if tls {
+            value["records"]["tls"] = json!({
+                "organization": "Let's Encrypt",
+                "issuer_dn": "C=US, O=Let's Encrypt, CN=R3",
+                "name_mismatch": false,
+                "sha256_fingerprint": "00ff",
+                "days_until_expiry": 42,
+            });
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_sqlite_round_trip() {
+        let file = assert_fs::NamedTempFile::new("results.db").unwrap();
+        // a chunk of 2 results is committed, the last one on finish
+        let mut output = SqliteOutput::open(file.path()).unwrap().with_chunk_size(2);
+        output
+            .add(info("a.example.com", &["192.0.2.1", "2001:db8::1"], true))
+            .unwrap();
+        output
+            .add(info("b.example.com", &["192.0.2.2"], false))
+            .unwrap();
+        output.add(info("c.example.com", &[], false)).unwrap();
+        output.finish().unwrap();
+
+        // the schema is reused when the database already exists
+        let output = SqliteOutput::open(file.path()).unwrap();
+        let conn = &output.conn;
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count("hosts"), 3);
+        assert_eq!(count("asns"), 1);
+        assert_eq!(count("ips"), 3);
+        assert_eq!(count("ns"), 6);
+        assert_eq!(count("tls"), 1);
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT ips.addr, ips.asn, ips.ptr, asns.organization FROM ips
+                 JOIN hosts ON hosts.id = ips.host_id
+                 LEFT JOIN asns ON asns.asn = ips.asn
+                 WHERE hosts.hostname = 'a.example.com' ORDER BY ips.addr",
+            )
+            .unwrap();
+        let rows: Vec<IpRow> = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "192.0.2.1".to_string(),
+                    Some(64500),
+                    Some("ptr.a.example.com".to_string()),
+                    Some("EXAMPLE".to_string())
+                ),
+                ("2001:db8::1".to_string(), None, None, None),
+            ]
+        );
+
+        let (hostname, issuer_dn, days): (String, String, Option<i64>) = conn
+            .query_row(
+                "SELECT hosts.hostname, tls.issuer_dn, tls.days_until_expiry FROM tls
+                 JOIN hosts ON hosts.id = tls.host_id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(hostname, "a.example.com");
+        assert_eq!(issuer_dn, "C=US, O=Let's Encrypt, CN=R3");
+        assert_eq!(days, Some(42));
+    }
+}
@@ -1,19 +1,72 @@
+use ::time::format_description::well_known::Rfc3339;
 use anyhow::Result;
-use rustls::pki_types::{CertificateDer, ServerName};
-use serde::Serialize;
+use futures::future::{FutureExt, select_ok};
+use rustls::{
+    AlertDescription, CertificateError, DigitallySignedStruct, ProtocolVersion, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Socket, Type};
 use std::{
-    io::{Read, Write},
+    collections::BTreeMap,
+    fmt,
+    io::{self, Read, Write},
     net::{IpAddr, SocketAddr, TcpStream},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
-use x509_parser::prelude::*;
+use x509_parser::{oid_registry::OID_SIG_ED25519, prelude::*, public_key::PublicKey};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CertificateIssuerInfo {
     organization: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     country: Option<String>,
+    /// Full distinguished name of the issuer of the leaf certificate, like
+    /// `C=US, O=Let's Encrypt, CN=R3`
+    #[serde(default)]
+    issuer_dn: String,
+    /// Full distinguished name of the leaf certificate subject
+    #[serde(default)]
+    subject_dn: String,
+    /// DNS names of the leaf certificate subject alternative names
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    subject_alt_names: Vec<String>,
+    /// The leaf certificate is not valid for the requested hostname (SNI)
+    name_mismatch: bool,
+    /// SHA-256 fingerprint of the leaf certificate, as plain lowercase hex
+    sha256_fingerprint: String,
+    /// SHA-256 fingerprint of the root certificate, when the server sent a chain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root_sha256_fingerprint: Option<String>,
+    /// TLS version negotiated with the server, like TLSv1.3
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol_version: Option<String>,
+    /// Duration of the TLS handshake in milliseconds, from the end of the TCP
+    /// connect to the end of the handshake
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handshake_ms: Option<u64>,
+    /// Algorithm of the leaf certificate public key: rsa, ec or ed25519
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_algorithm: Option<String>,
+    /// Size in bits of the leaf certificate public key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_bits: Option<u32>,
+    /// End of the validity of the leaf certificate, in RFC 3339
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_after: Option<String>,
+    /// Days left before the leaf certificate expires when it was retrieved,
+    /// negative once expired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_until_expiry: Option<i64>,
+    /// Grade of the TLS setup from A to F, see `compute_grade`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    grade: Option<String>,
 }
 
 impl CertificateIssuerInfo {
@@ -23,6 +76,147 @@ impl CertificateIssuerInfo {
     pub fn country(&self) -> Option<&str> {
         self.country.as_deref()
     }
+    pub fn issuer_dn(&self) -> &str {
+        &self.issuer_dn
+    }
+    pub fn subject_dn(&self) -> &str {
+        &self.subject_dn
+    }
+    pub fn subject_alt_names(&self) -> &[String] {
+        &self.subject_alt_names
+    }
+    pub fn name_mismatch(&self) -> bool {
+        self.name_mismatch
+    }
+    pub fn sha256_fingerprint(&self) -> &str {
+        &self.sha256_fingerprint
+    }
+    pub fn root_sha256_fingerprint(&self) -> Option<&str> {
+        self.root_sha256_fingerprint.as_deref()
+    }
+    pub fn protocol_version(&self) -> Option<&str> {
+        self.protocol_version.as_deref()
+    }
+    pub fn handshake_ms(&self) -> Option<u64> {
+        self.handshake_ms
+    }
+    pub fn key_algorithm(&self) -> Option<&str> {
+        self.key_algorithm.as_deref()
+    }
+    pub fn key_bits(&self) -> Option<u32> {
+        self.key_bits
+    }
+    pub fn not_after(&self) -> Option<&str> {
+        self.not_after.as_deref()
+    }
+    pub fn days_until_expiry(&self) -> Option<i64> {
+        self.days_until_expiry
+    }
+
+    /// Whether the leaf certificate expires within `days`, or has expired,
+    /// none when its validity is unknown
+    pub fn expires_within(&self, days: i64) -> Option<bool> {
+        self.days_until_expiry.map(|left| left < days)
+    }
+    pub fn grade(&self) -> Option<&str> {
+        self.grade.as_deref()
+    }
+
+    /// Grade of the TLS setup, the first matching rule applies:
+    /// - F: the certificate is not valid for the hostname, or it expired
+    /// - C: a TLS version older than 1.2, an RSA key under 2048 bits, an EC key
+    ///   under 256 bits, or an expiry within 7 days
+    /// - B: TLS 1.2, or an expiry within 30 days
+    /// - A: otherwise
+    ///
+    /// A certificate with an invalid chain fails the handshake, so that the
+    /// graded certificates all have a valid chain. The unknown inputs are not
+    /// penalized.
+    pub fn compute_grade(&self) -> &'static str {
+        let protocol = self.protocol_version.as_deref();
+        let days = self.days_until_expiry;
+        let weak_key = match (self.key_algorithm.as_deref(), self.key_bits) {
+            (Some("rsa"), Some(bits)) => bits < 2048,
+            (Some("ec"), Some(bits)) => bits < 256,
+            _ => false,
+        };
+        if self.name_mismatch || days.is_some_and(|days| days < 0) {
+            "F"
+        } else if protocol.is_some_and(|version| !matches!(version, "TLSv1.2" | "TLSv1.3"))
+            || weak_key
+            || days.is_some_and(|days| days < 7)
+        {
+            "C"
+        } else if protocol == Some("TLSv1.2") || days.is_some_and(|days| days < 30) {
+            "B"
+        } else {
+            "A"
+        }
+    }
+
+    /// Record the negotiated TLS version and grade the certificate
+    fn grade_connection(&mut self, protocol_version: Option<ProtocolVersion>) {
+        self.protocol_version = protocol_version.map(|version| match version {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            version => format!("{:?}", version),
+        });
+        self.grade = Some(self.compute_grade().to_string());
+    }
+
+    /// Check if one of the subject alternative names covers `hostname`,
+    /// a wildcard matching a single left-most label
+    pub fn matches_hostname(&self, hostname: &str) -> bool {
+        let hostname = hostname.trim_end_matches('.').to_lowercase();
+        self.subject_alt_names.iter().any(|name| {
+            let name = name.trim_end_matches('.').to_lowercase();
+            match name.strip_prefix("*.") {
+                Some(suffix) => hostname
+                    .strip_suffix(suffix)
+                    .and_then(|label| label.strip_suffix('.'))
+                    .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+                None => name == hostname,
+            }
+        })
+    }
+
+    fn check_hostname(&mut self, hostname: &str) {
+        self.name_mismatch = !self.matches_hostname(hostname);
+    }
+
+    fn parse_subject_alt_names(cert: &X509Certificate) -> Vec<String> {
+        match cert.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns_name) => Some(dns_name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Algorithm and size in bits of the public key of a certificate
+    fn parse_key(cert: &X509Certificate) -> (Option<String>, Option<u32>) {
+        let key = cert.public_key();
+        let (algorithm, bits) = match key.parsed() {
+            Ok(PublicKey::RSA(rsa)) => ("rsa", rsa.key_size()),
+            Ok(PublicKey::EC(ec)) => ("ec", ec.key_size()),
+            _ if key.algorithm.algorithm == OID_SIG_ED25519 => ("ed25519", 256),
+            _ => return (None, None),
+        };
+        (Some(algorithm.to_string()), u32::try_from(bits).ok())
+    }
+
+    /// End of the validity of a certificate and the days left until then
+    fn parse_expiry(cert: &X509Certificate) -> (Option<String>, Option<i64>) {
+        let not_after = cert.validity().not_after;
+        let days = (not_after.timestamp() - ASN1Time::now().timestamp()).div_euclid(86_400);
+        (not_after.to_datetime().format(&Rfc3339).ok(), Some(days))
+    }
 
     fn parse_country(issuer: &X509Name) -> Option<String> {
         issuer
@@ -42,6 +236,13 @@ impl CertificateIssuerInfo {
             .ok_or_else(|| anyhow::anyhow!("No organization found"))
     }
 
+    /// Distinguished name in the RFC 4514 attribute order of the certificate,
+    /// the attributes of a multi-valued RDN joined with ` + `
+    fn format_dn(name: &X509Name) -> String {
+        name.to_string_with_registry(oid_registry())
+            .unwrap_or_else(|_| name.to_string())
+    }
+
     fn get_root_cert<'a>(certs: &'a [CertificateDer<'a>]) -> Result<&'a CertificateDer<'a>> {
         certs
             .last()
@@ -51,6 +252,28 @@ impl CertificateIssuerInfo {
     pub fn from_der(certs: &[CertificateDer<'_>]) -> Result<Self> {
         // get the last cert (i.e. The root cert)
         let root_cert = Self::get_root_cert(certs)?;
+        // the leaf cert comes first
+        let (
+            issuer_dn,
+            subject_dn,
+            subject_alt_names,
+            (key_algorithm, key_bits),
+            (not_after, days_until_expiry),
+        ) = match X509Certificate::from_der(&certs[0]) {
+            Ok((_rem, leaf_cert)) => (
+                Self::format_dn(leaf_cert.issuer()),
+                Self::format_dn(leaf_cert.subject()),
+                Self::parse_subject_alt_names(&leaf_cert),
+                Self::parse_key(&leaf_cert),
+                Self::parse_expiry(&leaf_cert),
+            ),
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to parse the leaf certificate: {}",
+                    e
+                ));
+            }
+        };
 
         match X509Certificate::from_der(root_cert) {
             Ok((_rem, cert_info)) => {
@@ -60,6 +283,20 @@ impl CertificateIssuerInfo {
                 Ok(CertificateIssuerInfo {
                     organization,
                     country,
+                    issuer_dn,
+                    subject_dn,
+                    subject_alt_names,
+                    name_mismatch: false,
+                    sha256_fingerprint: sha256_fingerprint(&certs[0]),
+                    root_sha256_fingerprint: (certs.len() > 1)
+                        .then(|| sha256_fingerprint(root_cert)),
+                    protocol_version: None,
+                    handshake_ms: None,
+                    key_algorithm,
+                    key_bits,
+                    not_after,
+                    days_until_expiry,
+                    grade: None,
                 })
             }
             Err(e) => Err(anyhow::anyhow!(
@@ -70,32 +307,404 @@ impl CertificateIssuerInfo {
     }
 }
 
-fn generate_request(host: &str) -> Vec<u8> {
+/// Classification of a failed TLS probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsError {
+    /// The TCP connection was refused
+    ConnectRefused(String),
+    /// The TCP connection or the handshake timed out
+    ConnectTimeout(String),
+    /// The server aborted the handshake or sent an unexpected message
+    HandshakeFailure(String),
+    /// The server certificate chain could not be verified
+    CertVerifyFailed(String),
+    /// No protocol version or cipher suite in common with the server
+    ProtocolMismatch(String),
+    /// The server did not read the HTTP request sent after the handshake
+    WriteTimeout(String),
+    Other(String),
+}
+
+impl TlsError {
+    /// Name of the category, to bucket the failures in a report
+    pub fn category(&self) -> &'static str {
+        match self {
+            TlsError::ConnectRefused(_) => "connect_refused",
+            TlsError::ConnectTimeout(_) => "connect_timeout",
+            TlsError::HandshakeFailure(_) => "handshake_failure",
+            TlsError::CertVerifyFailed(_) => "cert_verify_failed",
+            TlsError::ProtocolMismatch(_) => "protocol_mismatch",
+            TlsError::WriteTimeout(_) => "write_timeout",
+            TlsError::Other(_) => "other",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            TlsError::ConnectRefused(message)
+            | TlsError::ConnectTimeout(message)
+            | TlsError::HandshakeFailure(message)
+            | TlsError::CertVerifyFailed(message)
+            | TlsError::ProtocolMismatch(message)
+            | TlsError::WriteTimeout(message)
+            | TlsError::Other(message) => message,
+        }
+    }
+
+    /// Classify an error of the TCP connection
+    fn from_connect(e: io::Error) -> Self {
+        let message = format!("Failed to connect: {}", e);
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => TlsError::ConnectRefused(message),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                TlsError::ConnectTimeout(message)
+            }
+            _ => TlsError::Other(message),
+        }
+    }
+
+    /// Classify an error of the handshake, rustls reporting its own errors
+    /// as `InvalidData` IO errors
+    fn from_handshake(e: io::Error) -> Self {
+        let message = format!("Failed to write request: {}", e);
+        let rustls_error = e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>());
+        match (rustls_error, e.kind()) {
+            (Some(rustls::Error::InvalidCertificate(_)), _) => TlsError::CertVerifyFailed(message),
+            (
+                Some(
+                    rustls::Error::PeerIncompatible(_)
+                    | rustls::Error::AlertReceived(AlertDescription::ProtocolVersion),
+                ),
+                _,
+            ) => TlsError::ProtocolMismatch(message),
+            (
+                Some(
+                    rustls::Error::AlertReceived(_)
+                    | rustls::Error::PeerMisbehaved(_)
+                    | rustls::Error::InappropriateMessage { .. }
+                    | rustls::Error::InappropriateHandshakeMessage { .. }
+                    | rustls::Error::InvalidMessage(_),
+                ),
+                _,
+            ) => TlsError::HandshakeFailure(message),
+            (Some(_), _) => TlsError::Other(message),
+            (None, io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => {
+                TlsError::ConnectTimeout(message)
+            }
+            (
+                None,
+                io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted,
+            ) => TlsError::HandshakeFailure(message),
+            (None, _) => TlsError::Other(message),
+        }
+    }
+
+    /// Classify an error of the HTTP request sent once the handshake is done
+    fn from_write(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                TlsError::WriteTimeout(format!("Timed out writing the request: {}", e))
+            }
+            _ => TlsError::from_handshake(e),
+        }
+    }
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message(), self.category())
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Certificates served by each resolved IP of a host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificatesByIp {
+    pub certificates: BTreeMap<IpAddr, CertificateIssuerInfo>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<IpAddr, String>,
+    /// Every IP answered with the same leaf certificate
+    pub all_identical: bool,
+}
+
+impl CertificatesByIp {
+    fn from_results(results: Vec<(IpAddr, Result<CertificateIssuerInfo>)>) -> Self {
+        let mut certificates = BTreeMap::new();
+        let mut errors = BTreeMap::new();
+        for (ip, result) in results {
+            match result {
+                Ok(cert_info) => {
+                    certificates.insert(ip, cert_info);
+                }
+                Err(e) => {
+                    errors.insert(ip, e.to_string());
+                }
+            }
+        }
+        let mut fingerprints = certificates.values().map(|c| c.sha256_fingerprint());
+        let all_identical = match fingerprints.next() {
+            Some(first) => errors.is_empty() && fingerprints.all(|f| f == first),
+            None => false,
+        };
+        CertificatesByIp {
+            certificates,
+            errors,
+            all_identical,
+        }
+    }
+}
+
+/// SHA-256 of the DER encoded certificate, as lowercase hex without separators
+fn sha256_fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// HTTP request sent once the handshake is done, for the `path` resource of
+/// the `host` virtual host
+fn generate_request(host: &str, path: &str) -> Vec<u8> {
     concat!(
-        "GET / HTTP/1.1\r\n",
-        "Host: {}\r\n",
+        "GET {path} HTTP/1.1\r\n",
+        "Host: {host}\r\n",
         "User-Agent: rustls-client\r\n",
         "Connection: close\r\n",
         "Accept: */*\r\n",
         "\r\n"
     )
-    .replace("{}", host)
+    .replace("{path}", path)
+    .replace("{host}", host)
     .as_bytes()
     .to_vec()
 }
 
-fn get_socket_addrs(dns_ips: &[IpAddr]) -> SocketAddr {
+/// Mail protocol upgraded to TLS with a STARTTLS command, instead of the
+/// implicit TLS of HTTPS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartTls {
+    Smtp,
+    Imap,
+    Pop3,
+}
+
+/// Name sent with the SMTP EHLO command
+const EHLO_NAME: &str = "localhost";
+
+impl StartTls {
+    /// Port of the plaintext protocol
+    pub fn port(self) -> u16 {
+        match self {
+            StartTls::Smtp => 25,
+            StartTls::Imap => 143,
+            StartTls::Pop3 => 110,
+        }
+    }
+
+    /// Run the plaintext exchange up to the TLS upgrade: `EHLO` and
+    /// `STARTTLS` for SMTP, `STARTTLS` for IMAP and `STLS` for POP3, each
+    /// after the greeting of the server
+    fn negotiate<S: Read + Write>(self, stream: &mut S) -> Result<(), TlsError> {
+        let failed = |reply: &str| {
+            TlsError::HandshakeFailure(format!(
+                "STARTTLS refused by the {:?} server: {}",
+                self, reply
+            ))
+        };
+        match self {
+            StartTls::Smtp => {
+                for (command, expected) in [
+                    (None, "220"),
+                    (Some(format!("EHLO {}\r\n", EHLO_NAME)), "250"),
+                    (Some("STARTTLS\r\n".to_string()), "220"),
+                ] {
+                    if let Some(command) = command {
+                        send_command(stream, &command)?;
+                    }
+                    let reply = read_smtp_reply(stream)?;
+                    if !reply.starts_with(expected) {
+                        return Err(failed(&reply));
+                    }
+                }
+            }
+            StartTls::Imap => {
+                let greeting = read_line(stream)?;
+                if !greeting.starts_with("* OK") {
+                    return Err(failed(&greeting));
+                }
+                send_command(stream, "a001 STARTTLS\r\n")?;
+                // untagged lines may come before the tagged completion
+                let reply = loop {
+                    let line = read_line(stream)?;
+                    if line.starts_with("a001 ") {
+                        break line;
+                    }
+                };
+                if !reply.starts_with("a001 OK") {
+                    return Err(failed(&reply));
+                }
+            }
+            StartTls::Pop3 => {
+                for command in [None, Some("STLS\r\n")] {
+                    if let Some(command) = command {
+                        send_command(stream, command)?;
+                    }
+                    let reply = read_line(stream)?;
+                    if !reply.starts_with("+OK") {
+                        return Err(failed(&reply));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn send_command<S: Write>(stream: &mut S, command: &str) -> Result<(), TlsError> {
+    stream
+        .write_all(command.as_bytes())
+        .map_err(|e| TlsError::HandshakeFailure(format!("Failed to send STARTTLS command: {}", e)))
+}
+
+/// Read a line of a plaintext protocol byte by byte, so that nothing of the
+/// TLS handshake following it is consumed
+fn read_line<S: Read>(stream: &mut S) -> Result<String, TlsError> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.len() < 4096 {
+        match stream.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                return Err(TlsError::ConnectTimeout(format!(
+                    "Timed out waiting for the STARTTLS reply: {}",
+                    e
+                )));
+            }
+            Err(e) => {
+                return Err(TlsError::HandshakeFailure(format!(
+                    "Failed to read the STARTTLS reply: {}",
+                    e
+                )));
+            }
+        }
+    }
+    if line.is_empty() {
+        return Err(TlsError::HandshakeFailure(
+            "Connection closed during the STARTTLS exchange".to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Read an SMTP reply, up to its last line: `250-` lines are followed by
+/// others, the `250 ` line ends the reply
+fn read_smtp_reply<S: Read>(stream: &mut S) -> Result<String, TlsError> {
+    loop {
+        let line = read_line(stream)?;
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(line);
+        }
+    }
+}
+
+/// Address of the probe among `dns_ips`, of the family of the `bind` source
+/// address when given, IPv4 being preferred otherwise
+fn get_socket_addrs(dns_ips: &[IpAddr], bind: Option<IpAddr>) -> SocketAddr {
+    let ipv4 = bind.is_none_or(|bind| bind.is_ipv4());
     for ip in dns_ips {
-        if ip.is_ipv4() {
+        if ip.is_ipv4() == ipv4 {
             return SocketAddr::new(*ip, 443);
         }
     }
-    // Fallback to the first IP if no IPv4 is found
+    // Fallback to the first IP if none of the family is found
     SocketAddr::new(dns_ips[0], 443)
 }
 
+/// Connect to `sockaddr`, from the `bind` source address when given
+fn connect(sockaddr: SocketAddr, bind: Option<IpAddr>) -> Result<TcpStream, TlsError> {
+    let timeout = Duration::from_millis(1000);
+    let Some(bind) = bind else {
+        return TcpStream::connect_timeout(&sockaddr, timeout).map_err(TlsError::from_connect);
+    };
+    let socket = Socket::new(Domain::for_address(sockaddr), Type::STREAM, None)
+        .map_err(|e| TlsError::Other(format!("Failed to create the TCP socket: {}", e)))?;
+    socket
+        .bind(&SocketAddr::new(bind, 0).into())
+        .map_err(|e| TlsError::Other(format!("Failed to bind to {}: {}", bind, e)))?;
+    socket
+        .connect_timeout(&sockaddr.into(), timeout)
+        .map_err(TlsError::from_connect)?;
+    Ok(socket.into())
+}
+
+/// Certificate verifier accepting certificates which are not valid for the
+/// requested name, the mismatch being reported in `CertificateIssuerInfo`
+/// instead of failing the handshake. Any other verification error is fatal.
+#[derive(Debug)]
+struct NameMismatchTolerantVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for NameMismatchTolerantVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
 fn config_tls() -> Arc<rustls::ClientConfig> {
-    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
     let root_store = rustls::RootCertStore {
         roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
     };
@@ -103,10 +712,19 @@ fn config_tls() -> Arc<rustls::ClientConfig> {
     // for cert in rustls_native_certs::load_native_certs().expect("could not load platform certs") {
     //     root_store.add(cert).unwrap();
     // }
+    config_tls_with_roots(root_store)
+}
+
+fn config_tls_with_roots(root_store: rustls::RootCertStore) -> Arc<rustls::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(root_store), provider.clone())
+        .build()
+        .expect("Failed to build the certificate verifier");
     let config = rustls::ClientConfig::builder_with_provider(provider)
         .with_safe_default_protocol_versions()
         .expect("Failed to set protocol versions")
-        .with_root_certificates(root_store)
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NameMismatchTolerantVerifier { inner }))
         .with_no_client_auth();
 
     Arc::new(config)
@@ -122,41 +740,201 @@ fn get_server_certs<'a, S: Write + Read>(
     Ok(certs)
 }
 
+/// Options of a TLS probe
+#[derive(Debug, Clone)]
+pub struct ProbeOptions {
+    /// Host header of the HTTP request following the handshake, usually the
+    /// same name as the SNI, which is used when none
+    pub host_header: Option<String>,
+    /// Path of the HTTP request following the handshake
+    pub path: String,
+    /// Source address the connection is made from
+    pub bind: Option<IpAddr>,
+    /// Plaintext protocol upgraded to TLS on its default port, instead of
+    /// HTTPS followed by an HTTP request
+    pub starttls: Option<StartTls>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            host_header: None,
+            path: "/".to_string(),
+            bind: None,
+            starttls: None,
+        }
+    }
+}
+
+impl ProbeOptions {
+    /// Port of the probed service
+    fn port(&self) -> u16 {
+        self.starttls.map_or(443, StartTls::port)
+    }
+}
+
+/// Retrieve the certificate served for `sni` by one of its IPs. A failed
+/// probe returns a `TlsError`.
 pub fn retrive_cert_info(
-    domain_name: &str,
+    sni: &str,
     ip: Option<&Vec<IpAddr>>,
+    options: &ProbeOptions,
+) -> Result<CertificateIssuerInfo> {
+    let mut sockaddr = get_socket_addrs(
+        ip.ok_or_else(|| anyhow::anyhow!("No IP addresses provided for TLS connection"))?,
+        options.bind,
+    );
+    sockaddr.set_port(options.port());
+    probe_cert_info(sni, sockaddr, options)
+}
+
+/// Retrieve the certificate served by every IP of the host, to check that
+/// all the backends of a load balanced host present the same certificate
+pub fn retrive_cert_info_all_ips(
+    sni: &str,
+    ips: &[IpAddr],
+    options: &ProbeOptions,
+) -> CertificatesByIp {
+    let results = std::thread::scope(|scope| {
+        let handles = ips
+            .iter()
+            .map(|ip| {
+                let sockaddr = SocketAddr::new(*ip, options.port());
+                (
+                    *ip,
+                    scope.spawn(move || probe_cert_info(sni, sockaddr, options)),
+                )
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|(ip, handle)| {
+                let result = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("TLS probe panicked")));
+                (ip, result)
+            })
+            .collect::<Vec<_>>()
+    });
+    CertificatesByIp::from_results(results)
+}
+
+/// Retrieve the certificate served for `sni` by the first IP of the host
+/// completing the handshake, all of them being probed concurrently, so that
+/// a firewalled IP does not fail the probe. The probes still running once one
+/// succeeds are abandoned, they end on their own timeouts.
+pub async fn retrive_cert_info_first(
+    sni: &str,
+    ips: &[IpAddr],
+    options: &ProbeOptions,
+) -> Result<CertificateIssuerInfo> {
+    let sockaddrs = ips
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, options.port()))
+        .collect();
+    probe_first_success(config_tls(), sni, sockaddrs, options).await
+}
+
+async fn probe_first_success(
+    tls_config: Arc<rustls::ClientConfig>,
+    sni: &str,
+    sockaddrs: Vec<SocketAddr>,
+    options: &ProbeOptions,
+) -> Result<CertificateIssuerInfo> {
+    if sockaddrs.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No IP addresses provided for TLS connection"
+        ));
+    }
+    // the probes are blocking, each one runs on a blocking thread
+    let probes = sockaddrs.into_iter().map(|sockaddr| {
+        let (tls_config, sni, options) = (tls_config.clone(), sni.to_string(), options.clone());
+        tokio::task::spawn_blocking(move || {
+            probe_cert_info_with(tls_config, &sni, sockaddr, &options, PROBE_TIMEOUT)
+        })
+        .map(|result| result.unwrap_or_else(|_| Err(anyhow::anyhow!("TLS probe panicked"))))
+        .boxed()
+    });
+    let (cert_info, _) = select_ok(probes).await?;
+    Ok(cert_info)
+}
+
+/// Timeout of the reads and writes of a TLS probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn probe_cert_info(
+    sni: &str,
+    sockaddr: SocketAddr,
+    options: &ProbeOptions,
+) -> Result<CertificateIssuerInfo> {
+    probe_cert_info_with(config_tls(), sni, sockaddr, options, PROBE_TIMEOUT)
+}
+
+/// Probe the certificate served on `sockaddr`, after the plaintext exchange
+/// of the STARTTLS protocol of the options when given, or followed by an HTTP
+/// request otherwise. The reads and writes on the connection fail after
+/// `timeout`.
+fn probe_cert_info_with(
+    tls_config: Arc<rustls::ClientConfig>,
+    sni: &str,
+    sockaddr: SocketAddr,
+    options: &ProbeOptions,
+    timeout: Duration,
 ) -> Result<CertificateIssuerInfo> {
-    // setup TLS config
-    let tls_config = config_tls();
     // parse domain name
-    let domain = ServerName::try_from(domain_name.to_string())
-        .map_err(|e| anyhow::anyhow!("Invalid URL: {}", e))?;
+    let domain = ServerName::try_from(sni.to_string())
+        .map_err(|e| TlsError::Other(format!("Invalid URL: {}", e)))?;
 
     // setup TLS connection
     let mut conn = rustls::ClientConnection::new(tls_config, domain)
-        .map_err(|e| anyhow::anyhow!("Failed to create connection: {}", e))?;
+        .map_err(|e| TlsError::Other(format!("Failed to create connection: {}", e)))?;
 
-    let sockaddr = get_socket_addrs(
-        ip.ok_or_else(|| anyhow::anyhow!("No IP addresses provided for TLS connection"))?,
-    );
     // TCP Connect to the server and perform the handshake
-    let mut stream = TcpStream::connect_timeout(&sockaddr, Duration::from_millis(1000))
-        .map_err(|e| anyhow::anyhow!("Failed to connect: {}", e))?;
-    stream
-        .set_read_timeout(Some(Duration::new(30, 0)))
-        .map_err(|e| anyhow::anyhow!("Failed to set read timeout on the TCP stream: {}", e))?;
+    let mut stream = connect(sockaddr, options.bind)?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| {
+        TlsError::Other(format!(
+            "Failed to set read timeout on the TCP stream: {}",
+            e
+        ))
+    })?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| {
+        TlsError::Other(format!(
+            "Failed to set write timeout on the TCP stream: {}",
+            e
+        ))
+    })?;
+    if let Some(protocol) = options.starttls {
+        // the handshake follows the upgrade
+        protocol.negotiate(&mut stream)?;
+    }
+    // drive the handshake explicitly to time it apart from the connect
+    let start = Instant::now();
+    while conn.is_handshaking() {
+        conn.complete_io(&mut stream)
+            .map_err(TlsError::from_handshake)?;
+    }
+    let handshake = start.elapsed();
     // Establish TLS session
     let mut tls = rustls::Stream::new(&mut conn, &mut stream);
 
-    // Send Https Get Request
-    tls.write_all(generate_request(domain_name).as_slice())
-        .map_err(|e| anyhow::anyhow!("Failed to write request: {}", e))?;
+    // Send Https Get Request, not for the mail protocols
+    if options.starttls.is_none() {
+        let host_header = options.host_header.as_deref().unwrap_or(sni);
+        tls.write_all(generate_request(host_header, &options.path).as_slice())
+            .map_err(TlsError::from_write)?;
+    }
 
+    // TLS version negotiated by the handshake
+    let protocol_version = tls.conn.protocol_version();
     // Get the TLS certificates
     let certs = get_server_certs(&mut tls)?;
 
     // Extract the root CA from the CA list and collect the organization and country
-    CertificateIssuerInfo::from_der(certs)
+    let mut cert_info = CertificateIssuerInfo::from_der(certs)?;
+    cert_info.handshake_ms = Some(handshake.as_millis() as u64);
+    cert_info.check_hostname(sni);
+    cert_info.grade_connection(protocol_version);
+    Ok(cert_info)
 }
 
 #[cfg(test)]
@@ -169,7 +947,7 @@ mod tests {
     fn test_retrive_cert_info() {
         let domain = "www.google.com";
         let google_ip = IpAddr::V4(Ipv4Addr::new(216, 58, 214, 67));
-        let cert_info = retrive_cert_info(domain, Some(&vec![google_ip]));
+        let cert_info = retrive_cert_info(domain, Some(&vec![google_ip]), &ProbeOptions::default());
         assert!(cert_info.is_ok());
         let cert_info = cert_info.unwrap();
         print!("{:?}", cert_info);
@@ -177,6 +955,622 @@ mod tests {
         assert_eq!(cert_info.country(), Some("BE"));
     }
 
+    /// Serve one connection on a loopback port with `serve`, return the address
+    fn serve_once<F>(serve: F) -> SocketAddr
+    where
+        F: FnOnce(TcpStream) + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream);
+        });
+        addr
+    }
+
+    fn probe_error(addr: SocketAddr) -> TlsError {
+        probe_cert_info("www.example.com", addr, &ProbeOptions::default())
+            .unwrap_err()
+            .downcast::<TlsError>()
+            .unwrap()
+    }
+
+    /// Answer the client hello with a fatal alert
+    fn send_alert(description: u8) -> impl FnOnce(TcpStream) + Send + 'static {
+        move |mut stream| {
+            let mut hello = [0u8; 1024];
+            let _ = stream.read(&mut hello);
+            let _ = stream.write_all(&[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, description]);
+        }
+    }
+
+    #[test]
+    fn test_probe_host_header_independent_of_sni() {
+        // capture the SNI of the client hello
+        let (tx, rx) = std::sync::mpsc::channel();
+        let addr = serve_once(move |mut stream| {
+            let mut acceptor = rustls::server::Acceptor::default();
+            let sni = loop {
+                if acceptor.read_tls(&mut stream).is_err() {
+                    break None;
+                }
+                match acceptor.accept() {
+                    Ok(Some(accepted)) => {
+                        break accepted.client_hello().server_name().map(str::to_string);
+                    }
+                    Ok(None) => continue,
+                    Err(_) => break None,
+                }
+            };
+            tx.send(sni).unwrap();
+        });
+        let options = ProbeOptions {
+            host_header: Some("vhost.example.com".to_string()),
+            ..Default::default()
+        };
+        let _ = probe_cert_info("sni.example.com", addr, &options);
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("sni.example.com"));
+
+        let request = String::from_utf8(generate_request("vhost.example.com", "/")).unwrap();
+        assert!(request.starts_with("GET / HTTP/1.1\r\nHost: vhost.example.com\r\n"));
+    }
+
+    #[test]
+    fn test_probe_bind() {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                std::fs::read("./data/certs/root.der").unwrap(),
+            ))
+            .unwrap();
+        // the server sees the connection coming from the bound address
+        let (tx, rx) = std::sync::mpsc::channel();
+        let addr = serve_once(move |stream| {
+            tx.send(stream.peer_addr().unwrap().ip()).unwrap();
+            serve_fixture_chain(stream);
+        });
+        let bind: IpAddr = "127.0.0.2".parse().unwrap();
+        let cert_info = probe_cert_info_with(
+            config_tls_with_roots(roots),
+            "www.example.com",
+            addr,
+            &ProbeOptions {
+                bind: Some(bind),
+                ..Default::default()
+            },
+            PROBE_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(cert_info.organization(), "Webinfo Test");
+        assert_eq!(rx.recv().unwrap(), bind);
+        // an address of the other family cannot be bound
+        let error = connect(addr, Some("::1".parse().unwrap())).unwrap_err();
+        assert!(matches!(error, TlsError::Other(_)), "{}", error);
+        // the probed address is of the family of the bound one
+        let ips: Vec<IpAddr> = vec!["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()];
+        assert_eq!(get_socket_addrs(&ips, None).ip(), ips[1]);
+        assert_eq!(
+            get_socket_addrs(&ips, Some("::1".parse().unwrap())).ip(),
+            ips[0]
+        );
+    }
+
+    #[test]
+    fn test_probe_origin_path() {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                std::fs::read("./data/certs/root.der").unwrap(),
+            ))
+            .unwrap();
+        // capture the request line sent once the handshake is done
+        let (tx, rx) = std::sync::mpsc::channel();
+        let addr = serve_once(move |mut stream| {
+            let key = rustls::pki_types::PrivatePkcs8KeyDer::from(
+                std::fs::read("./data/certs/leaf.key.der").unwrap(),
+            );
+            let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+                rustls::crypto::aws_lc_rs::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(load_fixture_chain(), key.into())
+            .unwrap();
+            let mut conn = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = rustls::Stream::new(&mut conn, &mut stream);
+            let mut line = String::new();
+            let _ = io::BufRead::read_line(&mut io::BufReader::new(&mut tls), &mut line);
+            tx.send(line).unwrap();
+        });
+        let cert_info = probe_cert_info_with(
+            config_tls_with_roots(roots),
+            "www.example.com",
+            addr,
+            &ProbeOptions {
+                path: "/app/index.html?lang=fr".to_string(),
+                ..Default::default()
+            },
+            PROBE_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(cert_info.organization(), "Webinfo Test");
+        assert!(cert_info.handshake_ms().is_some());
+        assert_eq!(
+            rx.recv().unwrap(),
+            "GET /app/index.html?lang=fr HTTP/1.1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_first_success() {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                std::fs::read("./data/certs/root.der").unwrap(),
+            ))
+            .unwrap();
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // a firewalled IP never answers the client hello
+        let (release, stalled) = std::sync::mpsc::channel::<()>();
+        let firewalled = serve_once(move |_stream| {
+            let _ = stalled.recv();
+        });
+        let live = serve_once(serve_fixture_chain);
+        let cert_info = probe_first_success(
+            config_tls_with_roots(roots.clone()),
+            "www.example.com",
+            vec![dead, firewalled, live],
+            &ProbeOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cert_info.organization(), "Webinfo Test");
+        assert!(!cert_info.name_mismatch());
+        release.send(()).unwrap();
+
+        // every IP failing fails the probe
+        let error = probe_first_success(
+            config_tls_with_roots(roots),
+            "www.example.com",
+            vec![dead],
+            &ProbeOptions::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.downcast_ref::<TlsError>().is_some(), "{}", error);
+    }
+
+    #[test]
+    fn test_tls_error_connect_refused() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let error = probe_error(addr);
+        assert!(matches!(error, TlsError::ConnectRefused(_)));
+        assert_eq!(error.category(), "connect_refused");
+    }
+
+    #[test]
+    fn test_tls_error_handshake_failure() {
+        // handshake_failure alert
+        let error = probe_error(serve_once(send_alert(40)));
+        assert!(matches!(error, TlsError::HandshakeFailure(_)), "{}", error);
+        // connection closed during the handshake
+        let error = probe_error(serve_once(|mut stream| {
+            let mut hello = [0u8; 1024];
+            let _ = stream.read(&mut hello);
+        }));
+        assert!(matches!(error, TlsError::HandshakeFailure(_)), "{}", error);
+    }
+
+    #[test]
+    fn test_tls_error_protocol_mismatch() {
+        // protocol_version alert
+        let error = probe_error(serve_once(send_alert(70)));
+        assert!(matches!(error, TlsError::ProtocolMismatch(_)), "{}", error);
+    }
+
+    /// Complete the handshake with the fixture chain
+    fn serve_fixture_chain(mut stream: TcpStream) {
+        accept_fixture_chain(&mut stream);
+    }
+
+    /// Complete the handshake with the fixture chain, returning the connection
+    fn accept_fixture_chain(stream: &mut TcpStream) -> rustls::ServerConnection {
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(
+            std::fs::read("./data/certs/leaf.key.der").unwrap(),
+        );
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(load_fixture_chain(), key.into())
+        .unwrap();
+        let mut conn = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+        while conn.is_handshaking() {
+            if conn.complete_io(stream).is_err() {
+                break;
+            }
+        }
+        conn
+    }
+
+    #[test]
+    fn test_tls_error_write_timeout() {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                std::fs::read("./data/certs/root.der").unwrap(),
+            ))
+            .unwrap();
+        // the server completes the handshake, then never reads
+        let (release, stalled) = std::sync::mpsc::channel::<()>();
+        let addr = serve_once(move |mut stream| {
+            let _conn = accept_fixture_chain(&mut stream);
+            let _ = stalled.recv();
+        });
+        // a request larger than the socket buffers
+        let path = format!("/{}", "a".repeat(64 << 20));
+        let start = Instant::now();
+        let error = probe_cert_info_with(
+            config_tls_with_roots(roots),
+            "www.example.com",
+            addr,
+            &ProbeOptions {
+                path: path.clone(),
+                ..Default::default()
+            },
+            Duration::from_millis(200),
+        )
+        .unwrap_err()
+        .downcast::<TlsError>()
+        .unwrap();
+        assert!(matches!(error, TlsError::WriteTimeout(_)), "{}", error);
+        assert!(error.to_string().ends_with("(write_timeout)"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_tls_error_cert_verify_failed() {
+        // the fixture root is not a trusted root
+        let addr = serve_once(serve_fixture_chain);
+        let error = probe_error(addr);
+        assert!(matches!(error, TlsError::CertVerifyFailed(_)), "{}", error);
+        assert!(error.to_string().ends_with("(cert_verify_failed)"));
+    }
+
+    /// Serve the plaintext side of `protocol` up to the upgrade, answering
+    /// `reply` to the STARTTLS command, then the fixture chain when accepted.
+    /// The commands received are sent to `commands`.
+    fn serve_starttls(
+        protocol: StartTls,
+        reply: &'static str,
+        commands: std::sync::mpsc::Sender<String>,
+    ) -> impl FnOnce(TcpStream) + Send + 'static {
+        move |mut stream| {
+            let mut exchange = |greeting: &str| {
+                stream.write_all(greeting.as_bytes()).unwrap();
+                let command = read_line(&mut stream).unwrap();
+                commands.send(command).unwrap();
+            };
+            match protocol {
+                StartTls::Smtp => {
+                    exchange("220 mail.example.com ESMTP\r\n");
+                    exchange("250-mail.example.com\r\n250-PIPELINING\r\n250 STARTTLS\r\n");
+                }
+                StartTls::Imap => exchange("* OK [CAPABILITY IMAP4rev1 STARTTLS] ready\r\n"),
+                StartTls::Pop3 => exchange("+OK POP3 ready\r\n"),
+            }
+            stream.write_all(reply.as_bytes()).unwrap();
+            serve_fixture_chain(stream);
+        }
+    }
+
+    #[test]
+    fn test_starttls_exchange() {
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(
+                std::fs::read("./data/certs/root.der").unwrap(),
+            ))
+            .unwrap();
+        for (protocol, reply, expected) in [
+            (
+                StartTls::Smtp,
+                "220 2.0.0 Ready to start TLS\r\n",
+                vec!["EHLO localhost", "STARTTLS"],
+            ),
+            (
+                StartTls::Imap,
+                "* OK still here\r\na001 OK Begin TLS negotiation now\r\n",
+                vec!["a001 STARTTLS"],
+            ),
+            (
+                StartTls::Pop3,
+                "+OK Begin TLS negotiation\r\n",
+                vec!["STLS"],
+            ),
+        ] {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let addr = serve_once(serve_starttls(protocol, reply, tx));
+            let cert_info = probe_cert_info_with(
+                config_tls_with_roots(roots.clone()),
+                "www.example.com",
+                addr,
+                &ProbeOptions {
+                    starttls: Some(protocol),
+                    ..Default::default()
+                },
+                PROBE_TIMEOUT,
+            )
+            .unwrap();
+            assert_eq!(cert_info.organization(), "Webinfo Test", "{:?}", protocol);
+            assert_eq!(rx.iter().collect::<Vec<_>>(), expected);
+        }
+
+        // a server without TLS refuses the upgrade
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let addr = serve_once(serve_starttls(
+            StartTls::Smtp,
+            "454 4.7.0 TLS not available\r\n",
+            tx,
+        ));
+        let error = probe_cert_info_with(
+            config_tls_with_roots(roots),
+            "www.example.com",
+            addr,
+            &ProbeOptions {
+                starttls: Some(StartTls::Smtp),
+                ..Default::default()
+            },
+            PROBE_TIMEOUT,
+        )
+        .unwrap_err()
+        .downcast::<TlsError>()
+        .unwrap();
+        assert!(matches!(error, TlsError::HandshakeFailure(_)), "{}", error);
+        assert!(error.to_string().contains("454 4.7.0"), "{}", error);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "network-tests"), ignore)]
+    fn test_retrive_cert_info_starttls() {
+        use std::net::ToSocketAddrs;
+        let addr = ("smtp.gmail.com", 587)
+            .to_socket_addrs()
+            .unwrap()
+            .find(SocketAddr::is_ipv4)
+            .unwrap();
+        let cert_info = probe_cert_info_with(
+            config_tls(),
+            "smtp.gmail.com",
+            addr,
+            &ProbeOptions {
+                starttls: Some(StartTls::Smtp),
+                ..Default::default()
+            },
+            PROBE_TIMEOUT,
+        )
+        .unwrap();
+        assert!(!cert_info.name_mismatch());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "network-tests"), ignore)]
+    fn test_handshake_ms() {
+        use std::net::ToSocketAddrs;
+        let addr = ("www.google.com", 443)
+            .to_socket_addrs()
+            .unwrap()
+            .find(SocketAddr::is_ipv4)
+            .unwrap();
+        let cert_info = probe_cert_info("www.google.com", addr, &ProbeOptions::default()).unwrap();
+        // a handshake takes at least a round trip, well within the read timeout
+        let handshake_ms = cert_info.handshake_ms().unwrap();
+        assert!(handshake_ms < 30_000, "{}", handshake_ms);
+    }
+
+    fn load_fixture_chain() -> Vec<CertificateDer<'static>> {
+        ["./data/certs/leaf.der", "./data/certs/root.der"]
+            .iter()
+            .map(|path| CertificateDer::from(std::fs::read(path).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_der_subject_alt_names() {
+        let cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        assert_eq!(cert_info.organization(), "Webinfo Test");
+        assert_eq!(cert_info.country(), Some("FR"));
+        assert_eq!(
+            cert_info.subject_alt_names(),
+            ["www.example.com", "example.com", "*.cdn.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_from_der_dn() {
+        let cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        assert_eq!(
+            cert_info.issuer_dn(),
+            "C=FR, O=Webinfo Test, CN=Webinfo Test Root"
+        );
+        assert_eq!(
+            cert_info.subject_dn(),
+            "C=US, O=Example Org, CN=www.example.com"
+        );
+        // C=FR followed by a multi-valued RDN holding CN=a and OU=b
+        let der = [
+            0x30, 0x23, 0x31, 0x0b, 0x30, 0x09, 0x06, 0x03, 0x55, 0x04, 0x06, 0x13, 0x02, 0x46,
+            0x52, 0x31, 0x14, 0x30, 0x08, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x01, 0x61, 0x30,
+            0x08, 0x06, 0x03, 0x55, 0x04, 0x0b, 0x0c, 0x01, 0x62,
+        ];
+        let (_rem, name) = X509Name::from_der(&der).unwrap();
+        assert_eq!(CertificateIssuerInfo::format_dn(&name), "C=FR, CN=a + OU=b");
+    }
+
+    #[test]
+    fn test_from_der_intermediate_issuer_dn() {
+        // leaf issued by the R3 intermediate, itself issued by the ISRG root
+        let chain: Vec<CertificateDer<'static>> = [
+            "./data/certs/le-leaf.der",
+            "./data/certs/le-r3.der",
+            "./data/certs/le-isrg.der",
+        ]
+        .iter()
+        .map(|path| CertificateDer::from(std::fs::read(path).unwrap()))
+        .collect();
+        let cert_info = CertificateIssuerInfo::from_der(&chain).unwrap();
+        assert_eq!(cert_info.issuer_dn(), "C=US, O=Let's Encrypt, CN=R3");
+        assert_eq!(cert_info.subject_dn(), "CN=le.example.com");
+    }
+
+    #[test]
+    fn test_sha256_fingerprint() {
+        let chain = load_fixture_chain();
+        let cert_info = CertificateIssuerInfo::from_der(&chain).unwrap();
+        assert_eq!(
+            cert_info.sha256_fingerprint(),
+            "f843053a83aa4510569b80eb7789a8ecc2bc37332416cd277fdbee8830e0b59a"
+        );
+        assert_eq!(
+            cert_info.root_sha256_fingerprint(),
+            Some("20c7679cf8086cb47a11857473538ee8e6a203d5b718eaf66c62e19f261bc188")
+        );
+        // a single self-signed certificate has no separate root fingerprint
+        let cert_info = CertificateIssuerInfo::from_der(&chain[1..]).unwrap();
+        assert_eq!(
+            cert_info.sha256_fingerprint(),
+            "20c7679cf8086cb47a11857473538ee8e6a203d5b718eaf66c62e19f261bc188"
+        );
+        assert!(cert_info.root_sha256_fingerprint().is_none());
+    }
+
+    #[test]
+    fn test_matches_hostname() {
+        let cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        assert!(cert_info.matches_hostname("www.example.com"));
+        assert!(cert_info.matches_hostname("EXAMPLE.com."));
+        assert!(cert_info.matches_hostname("img.cdn.example.com"));
+        assert!(!cert_info.matches_hostname("cdn.example.com"));
+        assert!(!cert_info.matches_hostname("a.img.cdn.example.com"));
+        assert!(!cert_info.matches_hostname("shop.example.com"));
+    }
+
+    #[test]
+    fn test_check_hostname_mismatch() {
+        let mut cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        cert_info.check_hostname("www.example.com");
+        assert!(!cert_info.name_mismatch());
+        // deliberate mismatch: the CDN certificate does not cover the origin name
+        cert_info.check_hostname("www.example.org");
+        assert!(cert_info.name_mismatch());
+    }
+
+    #[test]
+    fn test_from_der_key_and_expiry() {
+        let cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        assert_eq!(cert_info.key_algorithm(), Some("ec"));
+        assert_eq!(cert_info.key_bits(), Some(256));
+        assert_eq!(cert_info.not_after(), Some("2035-01-01T00:00:00Z"));
+        assert!(cert_info.days_until_expiry().unwrap() > 0);
+        // graded once the connection is known
+        assert!(cert_info.grade().is_none());
+    }
+
+    #[test]
+    fn test_expires_within() {
+        let mut cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        let left = cert_info.days_until_expiry().unwrap();
+        assert_eq!(cert_info.expires_within(30), Some(false));
+        assert_eq!(cert_info.expires_within(left), Some(false));
+        assert_eq!(cert_info.expires_within(left + 1), Some(true));
+        // a certificate near expiry, or already expired
+        cert_info.days_until_expiry = Some(5);
+        assert_eq!(cert_info.expires_within(30), Some(true));
+        assert_eq!(cert_info.expires_within(5), Some(false));
+        cert_info.days_until_expiry = Some(-2);
+        assert_eq!(cert_info.expires_within(0), Some(true));
+        cert_info.days_until_expiry = None;
+        assert_eq!(cert_info.expires_within(30), None);
+    }
+
+    #[test]
+    fn test_compute_grade() {
+        let mut fixture = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        fixture.check_hostname("www.example.com");
+        let grade = |protocol: &str, key: (&str, u32), days: i64, hostname: &str| {
+            let mut cert_info = fixture.clone();
+            cert_info.key_algorithm = Some(key.0.to_string());
+            cert_info.key_bits = Some(key.1);
+            cert_info.days_until_expiry = Some(days);
+            cert_info.check_hostname(hostname);
+            let version = match protocol {
+                "TLSv1.3" => ProtocolVersion::TLSv1_3,
+                "TLSv1.2" => ProtocolVersion::TLSv1_2,
+                _ => ProtocolVersion::TLSv1_1,
+            };
+            cert_info.grade_connection(Some(version));
+            cert_info.grade().unwrap().to_string()
+        };
+        let host = "www.example.com";
+        assert_eq!(grade("TLSv1.3", ("ec", 256), 90, host), "A");
+        assert_eq!(grade("TLSv1.3", ("rsa", 2048), 90, host), "A");
+        assert_eq!(grade("TLSv1.2", ("rsa", 4096), 90, host), "B");
+        assert_eq!(grade("TLSv1.3", ("ec", 256), 20, host), "B");
+        assert_eq!(grade("TLSv1.3", ("rsa", 1024), 90, host), "C");
+        assert_eq!(grade("TLSv1.3", ("ec", 256), 3, host), "C");
+        assert_eq!(grade("TLSv1.1", ("ec", 384), 90, host), "C");
+        assert_eq!(grade("TLSv1.3", ("ec", 256), -1, host), "F");
+        assert_eq!(grade("TLSv1.3", ("ec", 256), 90, "www.example.org"), "F");
+
+        // the unknown inputs are not penalized
+        fixture.key_algorithm = None;
+        fixture.key_bits = None;
+        fixture.days_until_expiry = None;
+        assert_eq!(fixture.compute_grade(), "A");
+    }
+
+    #[test]
+    fn test_certificates_by_ip_all_identical() {
+        let cert_info = CertificateIssuerInfo::from_der(&load_fixture_chain()).unwrap();
+        let ip1 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let ip2 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let by_ip = CertificatesByIp::from_results(vec![
+            (ip1, Ok(cert_info.clone())),
+            (ip2, Ok(cert_info.clone())),
+        ]);
+        assert_eq!(by_ip.certificates.len(), 2);
+        assert!(by_ip.all_identical);
+
+        let mut other = cert_info.clone();
+        other.sha256_fingerprint = "00".repeat(32);
+        let by_ip = CertificatesByIp::from_results(vec![(ip1, Ok(cert_info)), (ip2, Ok(other))]);
+        assert!(!by_ip.all_identical);
+    }
+
+    #[test]
+    fn test_retrive_cert_info_all_ips_unreachable() {
+        // nothing listens on the loopback HTTPS port
+        let ips = [
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+        ];
+        let by_ip = retrive_cert_info_all_ips("www.example.com", &ips, &ProbeOptions::default());
+        assert!(by_ip.certificates.is_empty());
+        assert_eq!(by_ip.errors.len(), 2);
+        assert!(!by_ip.all_identical);
+    }
+
     // #[test]
     // fn test_retrive_cert_info_invalid_domain() {
     //     let domain = "opco.uniformation.fr";
@@ -1,18 +1,36 @@
+use crate::dns::LatencyHistogram;
 use anyhow::Result;
+use flate2::bufread::MultiGzDecoder;
+use futures::future::{BoxFuture, FutureExt, Shared};
 use hickory_proto::{rr::domain::Name, xfer::Protocol};
 use hickory_resolver::{
     Resolver, config::NameServerConfig, config::ResolverConfig,
     name_server::TokioConnectionProvider,
 };
-use ip2asn::{Builder, IpAsnMap};
+use ip_network::IpNetwork;
+use ip2asn::{Builder, IpAsnMap, parser::parse_line, range::range_to_cidrs};
+use publicsuffix2::List;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, HashMap},
     env,
     fs::File,
-    io::{self, BufRead},
+    io::{BufRead, BufReader, Write},
     net::{IpAddr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{Level, event};
 
 fn is_tmp_file_exists(filename: &str) -> bool {
@@ -20,41 +38,353 @@ fn is_tmp_file_exists(filename: &str) -> bool {
     Path::new(dir.join(filename).as_os_str()).exists()
 }
 
-async fn fetch_and_save_asn_db(url: &str, path: &Path) -> Result<()> {
-    let response = reqwest::get(url).await?.bytes().await?;
-    let mut dest = File::create(path)?;
-    io::copy(&mut response.as_ref(), &mut dest)
-        .map_err(|e| anyhow::anyhow!("Failed to save ASN database: {}", e))?;
+/// Overall timeout of the ASN database download
+pub const DEFAULT_ASN_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest wait for the connection of the HTTP client to a server
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP client with the default settings, to be shared by the ASN database
+/// download and the webhook so that they use the same connection pool. A
+/// client configured otherwise, with a proxy for instance, can be used
+/// instead.
+pub fn http_client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .user_agent(concat!("webinfo/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to build the HTTP client: {}", e))
+}
+
+/// Download the ASN database chunk by chunk with `client`, calling `progress` with the
+/// number of bytes downloaded and the total size when the server sends it.
+/// The data is written to a `.part` file renamed once complete, so that a
+/// cancelled or failed download is never mistaken for a cached database.
+/// The download fails once `timeout` elapses, connection included.
+async fn fetch_and_save_asn_db<F>(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    timeout: Duration,
+    cancel: &CancellationToken,
+    mut progress: F,
+) -> Result<()>
+where
+    F: FnMut(u64, Option<u64>),
+{
+    let mut response = client
+        .get(url)
+        .timeout(timeout)
+        .send()
+        .await?
+        .error_for_status()?;
+    let total = response.content_length();
+    let part_path = path.with_extension("part");
+    let mut dest = File::create(&part_path)?;
+    let mut downloaded = 0;
+    loop {
+        let chunk = tokio::select! {
+            _ = cancel.cancelled() => None,
+            chunk = response.chunk() => Some(chunk),
+        };
+        let chunk = match chunk {
+            Some(Ok(Some(chunk))) => chunk,
+            Some(Ok(None)) => break,
+            Some(Err(e)) => {
+                let _ = std::fs::remove_file(&part_path);
+                return Err(e.into());
+            }
+            None => {
+                let _ = std::fs::remove_file(&part_path);
+                return Err(anyhow::anyhow!("ASN database download cancelled"));
+            }
+        };
+        dest.write_all(&chunk)
+            .map_err(|e| anyhow::anyhow!("Failed to save ASN database: {}", e))?;
+        downloaded += chunk.len() as u64;
+        progress(downloaded, total);
+    }
+    std::fs::rename(&part_path, path)?;
     event!(Level::INFO, "Downloaded ASN database to {}", path.display());
     Ok(())
 }
 
 pub async fn open_asn_db() -> Result<IpAsnMap> {
+    open_asn_db_with_progress(
+        &http_client()?,
+        DEFAULT_ASN_DOWNLOAD_TIMEOUT,
+        &CancellationToken::new(),
+        |_, _| {},
+    )
+    .await
+}
+
+/// Open the ASN database, downloading it first with `client` if it is not
+/// cached yet. `progress` is called with the bytes downloaded and the total size when
+/// known, and the download stops with an error when `cancel` is cancelled
+/// or when it takes longer than `timeout`.
+pub async fn open_asn_db_with_progress<F>(
+    client: &reqwest::Client,
+    timeout: Duration,
+    cancel: &CancellationToken,
+    progress: F,
+) -> Result<IpAsnMap>
+where
+    F: FnMut(u64, Option<u64>),
+{
     let filename = "ip2asn-combined.tsv.gz";
     let url = "https://iptoasn.com/data/ip2asn-combined.tsv.gz";
     let dir = env::temp_dir();
     let path = dir.join(filename);
 
     if !is_tmp_file_exists(filename) {
-        fetch_and_save_asn_db(url, &path).await.map_err(|e| {
-            anyhow::anyhow!(
-                "Failed to fetch ASN database from {}: {}",
-                url,
-                e.to_string()
-            )
-        })?;
+        fetch_and_save_asn_db(client, url, &path, timeout, cancel, progress)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to fetch ASN database from {}: {}",
+                    url,
+                    e.to_string()
+                )
+            })?;
         event!(Level::INFO, "ASN database fetched successfully.");
     }
     event!(Level::INFO, "Loading ASN database from {}", path.display());
     // Build the IpAsnMap lookup table
-    let ipasn = Builder::new().from_path(path)?.build()?;
+    load_asn_db(&path, &IptoasnLoader)
+}
+
+/// Loader of an ASN database file in a given format
+pub trait AsnDbLoader {
+    fn load(&self, path: &Path) -> Result<IpAsnMap>;
+
+    /// Refresh the ASN prefix index cached next to the database, for the
+    /// formats it is built from
+    fn refresh_index(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Loader of the iptoasn.com TSV database, gzipped or not
+pub struct IptoasnLoader;
+
+impl AsnDbLoader for IptoasnLoader {
+    fn load(&self, path: &Path) -> Result<IpAsnMap> {
+        Ok(Builder::new().from_path(path)?.build()?)
+    }
+
+    fn refresh_index(&self, path: &Path) -> Result<()> {
+        AsnPrefixIndex::refresh_cached(path)
+    }
+}
+
+/// Loader of a pyasn IPASN database, made of `prefix<TAB>asn` lines.
+/// The format has no organization nor country, the lookups return an empty
+/// organization and the unknown country code `ZZ`.
+pub struct PyasnLoader;
+
+impl AsnDbLoader for PyasnLoader {
+    fn load(&self, path: &Path) -> Result<IpAsnMap> {
+        let file = File::open(path)?;
+        let tsv = pyasn_to_tsv(BufReader::new(file))?;
+        Ok(Builder::new().with_source(tsv.as_bytes())?.build()?)
+    }
+}
+
+/// Convert pyasn lines to the iptoasn TSV format read by `ip2asn`,
+/// skipping blank lines and `;` comments
+fn pyasn_to_tsv<R: BufRead>(reader: R) -> Result<String> {
+    let mut tsv = String::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let mut columns = line.split_whitespace();
+        let (Some(prefix), Some(asn)) = (columns.next(), columns.next()) else {
+            return Err(anyhow::anyhow!("Invalid pyasn line {}: {}", n + 1, line));
+        };
+        let network = IpNetwork::from_str_truncate(prefix)
+            .map_err(|e| anyhow::anyhow!("Invalid prefix on pyasn line {}: {}", n + 1, e))?;
+        let asn: u32 = asn
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid ASN on pyasn line {}: {}", n + 1, e))?;
+        let (start, end) = match network {
+            IpNetwork::V4(net) => (
+                IpAddr::V4(net.network_address()),
+                IpAddr::V4(net.broadcast_address()),
+            ),
+            IpNetwork::V6(net) => (
+                IpAddr::V6(net.network_address()),
+                IpAddr::V6(net.last_address()),
+            ),
+        };
+        tsv.push_str(&format!("{}\t{}\t{}\tNone\t\n", start, end, asn));
+    }
+    Ok(tsv)
+}
+
+/// Load an ASN database from a local file with the given loader. The ASN
+/// prefix index cached next to it is rebuilt when the database is newer.
+pub fn load_asn_db(path: &Path, loader: &dyn AsnDbLoader) -> Result<IpAsnMap> {
+    let ipasn = loader.load(path).map_err(|e| {
+        anyhow::anyhow!("Failed to load ASN database from {}: {}", path.display(), e)
+    })?;
+    event!(Level::INFO, "Loaded ASN database from {}", path.display());
+    // the lookups do not need the index, it is only for the reverse queries
+    if let Err(e) = loader.refresh_index(path) {
+        event!(
+            Level::WARN,
+            "Failed to refresh the ASN index of {}: {}",
+            path.display(),
+            e
+        );
+    }
     Ok(ipasn)
 }
 
+/// Inverse index of an iptoasn.com database, the prefixes announced by each
+/// ASN, for the reverse ASN queries
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AsnPrefixIndex {
+    prefixes: BTreeMap<u32, Vec<IpNetwork>>,
+}
+
+impl AsnPrefixIndex {
+    /// Build the index from the TSV database, gzipped or not, skipping the
+    /// malformed lines and the ranges not routed (ASN 0)
+    pub fn build(source: &Path) -> Result<Self> {
+        let mut index = AsnPrefixIndex::default();
+        for line in open_input(source)?.lines() {
+            let line = line?;
+            let Ok(parsed) = parse_line(&line) else {
+                continue;
+            };
+            if parsed.asn == 0 {
+                continue;
+            }
+            index
+                .prefixes
+                .entry(parsed.asn)
+                .or_default()
+                .extend(range_to_cidrs(parsed.start_ip, parsed.end_ip));
+        }
+        Ok(index)
+    }
+
+    /// Prefixes announced by an ASN
+    pub fn prefixes(&self, asn: u32) -> &[IpNetwork] {
+        self.prefixes.get(&asn).map_or(&[], Vec::as_slice)
+    }
+
+    /// Number of ASNs in the index
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    /// Write the index to a file, through a `.part` file renamed once complete
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let part_path = path.with_extension("part");
+        let file = File::create(&part_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), self)?;
+        std::fs::rename(&part_path, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Open the index cached next to the `source` database, it is rebuilt and
+    /// saved again when the database was modified after it
+    pub fn open_cached(source: &Path) -> Result<Self> {
+        let cache = asn_index_path(source);
+        if is_cache_fresh(source, &cache)? {
+            match AsnPrefixIndex::load(&cache) {
+                Ok(index) => return Ok(index),
+                Err(e) => event!(
+                    Level::WARN,
+                    "Failed to load ASN index {}, rebuilding it: {}",
+                    cache.display(),
+                    e
+                ),
+            }
+        }
+        AsnPrefixIndex::rebuild_cached(source, &cache)
+    }
+
+    /// Rebuild the index cached next to the `source` database when the
+    /// database was modified after it, an up to date cache is reused as is
+    pub fn refresh_cached(source: &Path) -> Result<()> {
+        let cache = asn_index_path(source);
+        if !is_cache_fresh(source, &cache)? {
+            AsnPrefixIndex::rebuild_cached(source, &cache)?;
+        }
+        Ok(())
+    }
+
+    /// Build the index of the `source` database and save it to `cache`
+    fn rebuild_cached(source: &Path, cache: &Path) -> Result<Self> {
+        let index = AsnPrefixIndex::build(source).map_err(|e| {
+            anyhow::anyhow!("Failed to build ASN index from {}: {}", source.display(), e)
+        })?;
+        if let Err(e) = index.save(cache) {
+            event!(
+                Level::WARN,
+                "Failed to save ASN index {}: {}",
+                cache.display(),
+                e
+            );
+        }
+        event!(Level::INFO, "Built ASN index of {}", source.display());
+        Ok(index)
+    }
+}
+
+/// Whether the `cache` derived from `source` was modified after it
+fn is_cache_fresh(source: &Path, cache: &Path) -> Result<bool> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified());
+    let source_modified = modified(source)
+        .map_err(|e| anyhow::anyhow!("Failed to read ASN database {}: {}", source.display(), e))?;
+    Ok(modified(cache).is_ok_and(|cache_modified| cache_modified >= source_modified))
+}
+
+/// Path of the ASN index cached next to an ASN database
+pub fn asn_index_path(source: &Path) -> PathBuf {
+    let mut path = source.as_os_str().to_owned();
+    path.push(".asn-index.json");
+    PathBuf::from(path)
+}
+
+/// Load a public suffix list in the publicsuffix.org format from a file
+pub fn load_psl(path: &Path) -> Result<List> {
+    let list = List::from_file(path).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to load public suffix list from {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    event!(
+        Level::INFO,
+        "Loaded public suffix list from {}",
+        path.display()
+    );
+    Ok(list)
+}
+
+/// Parse a list of IP addresses separated by commas and/or whitespaces
 pub fn parse_ip_list(ip_list: &str) -> Vec<IpAddr> {
     ip_list
-        .split(',')
-        .filter_map(|s| s.trim().parse::<IpAddr>().ok())
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| s.parse::<IpAddr>().ok())
         .collect()
 }
 
@@ -78,34 +408,48 @@ pub fn get_default_dns_config() -> Result<Resolver<TokioConnectionProvider>> {
 }
 
 /// Create a DNS resolver using Cloudflare's DNS server by default
-/// or a custom DNS server if arguments is provided.
-pub fn get_resolver(custom_dns: Option<String>) -> Result<Resolver<TokioConnectionProvider>> {
-    if let Some(custom_dns) = custom_dns {
-        // change to ips_from_str to parse_ip_list
-        let dns_ips = parse_ip_list(&custom_dns);
-        if !dns_ips.is_empty() {
-            event!(
-                Level::INFO,
-                "Resolution using custom DNS servers: {:?}",
-                dns_ips
-            );
-            let dns_config = get_dns_config_from_ips(&dns_ips);
-            let name = Name::from_str("luxbulb.org.")?;
-            let resolver_config = ResolverConfig::from_parts(Some(name), vec![], dns_config);
-            Ok(
-                Resolver::builder_with_config(resolver_config, TokioConnectionProvider::default())
-                    .build(),
-            )
-        } else {
-            // If parsing failed or no valid IPs, fallback to default
-            event!(Level::INFO, "Resolution using default DNS servers: 1.1.1.1");
-            get_default_dns_config()
-        }
-    } else {
-        // Use default Cloudflare DNS configuration
+/// or a custom DNS server if arguments is provided. The queries are sent
+/// from the `bind` source address when given.
+pub fn get_resolver(
+    custom_dns: Option<String>,
+    bind: Option<IpAddr>,
+) -> Result<Resolver<TokioConnectionProvider>> {
+    // change to ips_from_str to parse_ip_list
+    let dns_ips = custom_dns
+        .map(|custom_dns| parse_ip_list(&custom_dns))
+        .unwrap_or_default();
+    let dns_ips = if dns_ips.is_empty() {
+        // If parsing failed or no valid IPs, fallback to default
         event!(Level::INFO, "Resolution using default DNS servers: 1.1.1.1");
-        get_default_dns_config()
+        vec!["1.1.1.1".parse()?]
+    } else {
+        event!(
+            Level::INFO,
+            "Resolution using custom DNS servers: {:?}",
+            dns_ips
+        );
+        dns_ips
+    };
+    // a source address cannot reach the servers of the other family
+    if let Some(bind) = bind
+        && let Some(ip) = dns_ips.iter().find(|ip| ip.is_ipv4() != bind.is_ipv4())
+    {
+        return Err(anyhow::anyhow!(
+            "The source address {} cannot reach the DNS server {} of another family",
+            bind,
+            ip
+        ));
+    }
+    let mut dns_config = get_dns_config_from_ips(&dns_ips);
+    if let Some(bind) = bind {
+        event!(Level::INFO, "Resolution from the source address {}", bind);
+        for name_server in &mut dns_config {
+            name_server.bind_addr = Some(SocketAddr::new(bind, 0));
+        }
     }
+    let name = Name::from_str("luxbulb.org.")?;
+    let resolver_config = ResolverConfig::from_parts(Some(name), vec![], dns_config);
+    Ok(Resolver::builder_with_config(resolver_config, TokioConnectionProvider::default()).build())
 }
 
 /// Break an iterator into chunks of a specified size
@@ -122,10 +466,373 @@ pub fn chunked<I>(
     })
 }
 
+/// Limit the number of concurrent tasks working on the same apex domain, so
+/// that the hosts of a domain with many subdomains do not all query its
+/// authoritative name servers at once
+#[derive(Debug)]
+pub struct ApexLimiter {
+    limit: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ApexLimiter {
+    pub fn new(limit: usize) -> Self {
+        ApexLimiter {
+            limit: limit.max(1),
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for a slot of `apex`, released when the permit is dropped
+    pub async fn acquire(&self, apex: &str) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(apex.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("The apex semaphores are never closed")
+    }
+}
+
+/// ASN database opened in the background, so that the DNS queries start while
+/// it is still loading: the lookups only wait for it once they need it
+#[derive(Clone)]
+pub struct AsnDbPrefetch {
+    map: Shared<BoxFuture<'static, Result<Arc<IpAsnMap>, String>>>,
+}
+
+impl AsnDbPrefetch {
+    /// Start opening the database with `load` in a background task
+    pub fn spawn<F>(load: F) -> Self
+    where
+        F: Future<Output = Result<IpAsnMap>> + Send + 'static,
+    {
+        let task = tokio::spawn(load);
+        let map = async move {
+            let map = match task.await {
+                Ok(map) => map.map(Arc::new).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = &map {
+                event!(Level::ERROR, "Failed to open ASN database: {}", e);
+            }
+            map
+        };
+        AsnDbPrefetch {
+            map: map.boxed().shared(),
+        }
+    }
+
+    /// Database which is already open
+    pub fn ready(map: Arc<IpAsnMap>) -> Self {
+        AsnDbPrefetch {
+            map: futures::future::ready(Ok(map)).boxed().shared(),
+        }
+    }
+
+    /// Wait for the database, none when it could not be opened
+    pub async fn get(&self) -> Option<Arc<IpAsnMap>> {
+        self.map.clone().await.ok()
+    }
+
+    /// Whether the database finished loading, successfully or not
+    pub fn is_done(&self) -> bool {
+        self.map.peek().is_some()
+    }
+
+    /// Error of the database once it failed to open
+    pub fn error(&self) -> Option<String> {
+        self.map.peek().and_then(|map| map.as_ref().err().cloned())
+    }
+}
+
+impl std::fmt::Debug for AsnDbPrefetch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsnDbPrefetch")
+            .field("done", &self.is_done())
+            .finish()
+    }
+}
+
+/// Space the starts of the tasks by a delay plus a random jitter, so that the
+/// queries are spread over time instead of sent in bursts. The jitter is drawn
+/// from a seeded generator so that a run can be reproduced.
+#[derive(Debug)]
+pub struct Pacer {
+    delay: Duration,
+    jitter: Duration,
+    state: Mutex<(StdRng, Option<Instant>)>,
+}
+
+impl Pacer {
+    pub fn new(delay: Duration, jitter: Duration, seed: u64) -> Self {
+        Pacer {
+            delay,
+            jitter,
+            state: Mutex::new((StdRng::seed_from_u64(seed), None)),
+        }
+    }
+
+    /// Instant the next task may start at, the delay plus up to the jitter
+    /// after the start of the previous one
+    pub fn next_start(&self) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let (rng, next) = &mut *state;
+        let start = next.map_or_else(Instant::now, |next| next.max(Instant::now()));
+        let jitter = self.jitter.as_millis() as u64;
+        *next = Some(start + self.delay + Duration::from_millis(rng.random_range(0..=jitter)));
+        start
+    }
+
+    /// Wait for the start of the next task
+    pub async fn wait(&self) {
+        tokio::time::sleep_until(self.next_start()).await;
+    }
+}
+
+/// Open an input file for reading, decompressing it on the fly when it
+/// starts with the gzip magic bytes
+pub fn open_input(path: &Path) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))));
+    }
+    Ok(Box::new(reader))
+}
+
+/// Concurrency limit adapting to the outcome of the tasks, AIMD-style: it
+/// grows by one after a window of successful tasks and halves when tasks fail
+/// with timeouts or SERVFAILs, within the `min` and `max` bounds. A success
+/// much slower than usual leaves the limit as it is.
+#[derive(Debug)]
+pub struct AdaptiveLimiter {
+    semaphore: Arc<Semaphore>,
+    min: usize,
+    max: usize,
+    state: Mutex<AdaptiveState>,
+}
+
+#[derive(Debug)]
+struct AdaptiveState {
+    limit: usize,
+    /// Permits taken out of the semaphore when the running tasks release them
+    excess: usize,
+    /// Successes since the last change of the limit
+    successes: usize,
+    /// Tasks completed since the last decrease of the limit
+    since_decrease: usize,
+    /// Smoothed duration of the successful tasks
+    latency: Option<Duration>,
+}
+
+impl AdaptiveLimiter {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        AdaptiveLimiter {
+            semaphore: Arc::new(Semaphore::new(min)),
+            min,
+            max,
+            state: Mutex::new(AdaptiveState {
+                limit: min,
+                excess: 0,
+                successes: 0,
+                since_decrease: min,
+                latency: None,
+            }),
+        }
+    }
+
+    /// Current concurrency limit
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Wait for a slot, released when the permit is dropped
+    pub async fn acquire(self: &Arc<Self>) -> AdaptivePermit {
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The adaptive semaphore is never closed");
+        AdaptivePermit {
+            permit: Some(permit),
+            limiter: self.clone(),
+        }
+    }
+
+    /// Adapt the limit to the outcome of a task
+    pub fn record(&self, failed: bool, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.since_decrease += 1;
+        if failed {
+            // the tasks started before a decrease fail along, so the limit
+            // is only decreased once per window of tasks
+            if state.since_decrease >= state.limit {
+                let limit = (state.limit / 2).max(self.min);
+                self.set_limit(&mut state, limit);
+                state.since_decrease = 0;
+            }
+            return;
+        }
+        let smoothed = state.latency.map_or(latency, |smoothed| {
+            smoothed.mul_f64(0.9) + latency.mul_f64(0.1)
+        });
+        let slow = state.latency.is_some_and(|previous| latency > previous * 2);
+        state.latency = Some(smoothed);
+        if slow {
+            return;
+        }
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < self.max {
+            let limit = state.limit + 1;
+            self.set_limit(&mut state, limit);
+        }
+    }
+
+    fn set_limit(&self, state: &mut AdaptiveState, limit: usize) {
+        if limit > state.limit {
+            let added = limit - state.limit;
+            let cancelled = added.min(state.excess);
+            state.excess -= cancelled;
+            self.semaphore.add_permits(added - cancelled);
+        } else {
+            let removed = state.limit - limit;
+            let forgotten = self.semaphore.forget_permits(removed);
+            state.excess += removed - forgotten;
+        }
+        if limit != state.limit {
+            event!(
+                Level::INFO,
+                "Concurrency limit {} -> {}",
+                state.limit,
+                limit
+            );
+        }
+        state.limit = limit;
+        state.successes = 0;
+    }
+}
+
+/// Slot of an adaptive limiter, taken out of the limiter when it was lowered
+/// while the task was running
+#[derive(Debug)]
+pub struct AdaptivePermit {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: Arc<AdaptiveLimiter>,
+}
+
+impl Drop for AdaptivePermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let (true, Some(permit)) = (state.excess > 0, self.permit.take()) {
+            state.excess -= 1;
+            permit.forget();
+        }
+    }
+}
+
+/// Progress of a batch run, updated by the tasks processing the records and
+/// readable from any other task, for a health check or a progress display
+#[derive(Debug, Clone, Default)]
+pub struct ProgressHandle {
+    counters: Arc<ProgressCounters>,
+    latency: Arc<LatencyHistogram>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressCounters {
+    processed: AtomicUsize,
+    in_flight: AtomicUsize,
+    errors: AtomicUsize,
+    complete: AtomicBool,
+}
+
+impl ProgressHandle {
+    /// Number of records processed, including the failed ones
+    pub fn processed(&self) -> usize {
+        self.counters.processed.load(Ordering::SeqCst)
+    }
+
+    /// Number of records being processed
+    pub fn in_flight(&self) -> usize {
+        self.counters.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Number of records whose processing failed
+    pub fn errors(&self) -> usize {
+        self.counters.errors.load(Ordering::SeqCst)
+    }
+
+    /// Whether all the records were processed
+    pub fn is_complete(&self) -> bool {
+        self.counters.complete.load(Ordering::SeqCst)
+    }
+
+    /// Count a record as in flight until the returned guard is finished, a
+    /// guard dropped before, by a cancelled task, counts as an error
+    pub fn start(&self) -> InFlight {
+        self.counters.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            progress: self.clone(),
+            finished: false,
+        }
+    }
+
+    /// Latencies of the DNS lookups of the run, for the runners given it
+    pub fn latency(&self) -> &Arc<LatencyHistogram> {
+        &self.latency
+    }
+
+    /// Mark the run as complete
+    pub fn complete(&self) {
+        self.counters.complete.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Record in flight of a `ProgressHandle`
+#[derive(Debug)]
+pub struct InFlight {
+    progress: ProgressHandle,
+    finished: bool,
+}
+
+impl InFlight {
+    /// Count the record as processed, and as an error unless `ok`
+    pub fn finish(mut self, ok: bool) {
+        self.finished = true;
+        self.done(ok);
+    }
+
+    fn done(&self, ok: bool) {
+        let counters = &self.progress.counters;
+        if !ok {
+            counters.errors.fetch_add(1, Ordering::SeqCst);
+        }
+        counters.processed.fetch_add(1, Ordering::SeqCst);
+        counters.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.done(false);
+        }
+    }
+}
+
 /// Count the number of lines in a file
 pub fn count_lines(path: &str) -> Result<usize> {
-    let file = File::open(path).map_err(|e| anyhow::anyhow!("Failed to open CSV file: {}", e))?;
-    let mut lines = std::io::BufReader::new(file).lines();
+    let file = open_input(Path::new(path))
+        .map_err(|e| anyhow::anyhow!("Failed to open CSV file: {}", e))?;
+    let mut lines = file.lines();
     // count lines using try_fold to handle potential errors
     let count = lines.try_fold(0, |acc, line| line.map(|_| acc + 1))?;
     Ok(count)
@@ -153,6 +860,157 @@ mod tests {
         assert!(result_tmp.is_ok());
     }
 
+    #[test]
+    fn test_open_input_gzip() {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Read;
+        let content = "origin,popularity,date,country\nhttps://www.free.fr,1000,2025-08-28,FR\n";
+        let plain = assert_fs::NamedTempFile::new("input.csv").unwrap();
+        std::fs::write(plain.path(), content).unwrap();
+        let gzipped = assert_fs::NamedTempFile::new("input.csv.gz").unwrap();
+        let mut encoder = GzEncoder::new(
+            File::create(gzipped.path()).unwrap(),
+            Compression::default(),
+        );
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let mut from_gzip = String::new();
+        open_input(gzipped.path())
+            .unwrap()
+            .read_to_string(&mut from_gzip)
+            .unwrap();
+        assert_eq!(from_gzip, content);
+        let plain_path = plain.path().to_str().unwrap();
+        let gzipped_path = gzipped.path().to_str().unwrap();
+        assert_eq!(count_lines(gzipped_path).unwrap(), 2);
+        assert_eq!(
+            count_lines(gzipped_path).unwrap(),
+            count_lines(plain_path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_load_asn_db_pyasn() {
+        let file = assert_fs::NamedTempFile::new("ipasn.dat").unwrap();
+        std::fs::write(
+            file.path(),
+            "; IP-ASN32-DAT file\n1.0.0.0/24\t13335\n\n8.8.8.0/24\t15169\n2001:4860::/32\t15169\n",
+        )
+        .unwrap();
+        let map = load_asn_db(file.path(), &PyasnLoader).unwrap();
+        let info = map.lookup_owned("8.8.8.8".parse().unwrap()).unwrap();
+        assert_eq!(info.asn, 15169);
+        assert_eq!(info.organization, "");
+        assert_eq!(info.country_code, "ZZ");
+        assert_eq!(info.network.to_string(), "8.8.8.0/24");
+        let info = map
+            .lookup_owned("2001:4860:4860::8888".parse().unwrap())
+            .unwrap();
+        assert_eq!(info.asn, 15169);
+        assert_eq!(
+            map.lookup_owned("1.0.0.1".parse().unwrap()).unwrap().asn,
+            13335
+        );
+        assert!(map.lookup_owned("9.9.9.9".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_load_asn_db_pyasn_invalid() {
+        let result = pyasn_to_tsv("1.0.0.0/24\tAS13335\n".as_bytes());
+        assert!(result.is_err());
+        let result = pyasn_to_tsv("1.0.0.0\n".as_bytes());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_asn_prefix_index_cache() {
+        use std::time::SystemTime;
+        let dir = assert_fs::TempDir::new().unwrap();
+        let source = dir.path().join("ip2asn-combined.tsv");
+        std::fs::write(
+            &source,
+            "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+             1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+             8.8.8.0\t8.8.8.255\t15169\tUS\tGOOGLE\n\
+             8.8.9.0\t8.8.10.255\t15169\tUS\tGOOGLE\n",
+        )
+        .unwrap();
+        let index = AsnPrefixIndex::open_cached(&source).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index
+                .prefixes(15169)
+                .iter()
+                .map(|network| network.to_string())
+                .collect::<Vec<_>>(),
+            ["8.8.8.0/24", "8.8.9.0/24", "8.8.10.0/24"]
+        );
+        assert!(index.prefixes(0).is_empty());
+        let cache = asn_index_path(&source);
+        assert_eq!(AsnPrefixIndex::load(&cache).unwrap(), index);
+
+        // the cache newer than the source is reused as is
+        let mut stale = AsnPrefixIndex::default();
+        stale.prefixes.insert(64500, vec![]);
+        stale.save(&cache).unwrap();
+        assert_eq!(AsnPrefixIndex::open_cached(&source).unwrap(), stale);
+
+        // a source modified after the cache rebuilds it
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(AsnPrefixIndex::open_cached(&source).unwrap(), index);
+        assert_eq!(AsnPrefixIndex::load(&cache).unwrap(), index);
+    }
+
+    #[test]
+    fn test_load_asn_db_asn_index() {
+        use std::time::SystemTime;
+        let dir = assert_fs::TempDir::new().unwrap();
+        let source = dir.path().join("ip2asn-combined.tsv");
+        std::fs::write(
+            &source,
+            "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+             8.8.8.0\t8.8.8.255\t15169\tUS\tGOOGLE\n",
+        )
+        .unwrap();
+        let cache = asn_index_path(&source);
+        let index = AsnPrefixIndex::build(&source).unwrap();
+
+        // the index is built along the database
+        load_asn_db(&source, &IptoasnLoader).unwrap();
+        assert_eq!(AsnPrefixIndex::load(&cache).unwrap(), index);
+
+        // the cache newer than the source is reused as is
+        let mut stale = AsnPrefixIndex::default();
+        stale.prefixes.insert(64500, vec![]);
+        stale.save(&cache).unwrap();
+        load_asn_db(&source, &IptoasnLoader).unwrap();
+        assert_eq!(AsnPrefixIndex::load(&cache).unwrap(), stale);
+
+        // a source modified after the cache rebuilds it
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&source)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        load_asn_db(&source, &IptoasnLoader).unwrap();
+        assert_eq!(AsnPrefixIndex::load(&cache).unwrap(), index);
+
+        // the pyasn databases have no index
+        let pyasn = dir.path().join("ipasn.dat");
+        std::fs::write(&pyasn, "8.8.8.0/24\t15169\n").unwrap();
+        load_asn_db(&pyasn, &PyasnLoader).unwrap();
+        assert!(!asn_index_path(&pyasn).exists());
+    }
+
     #[tokio::test]
     async fn test_fetch_and_save_asn_db() {
         let filename = "test_ip2asn-combined.tsv.gz";
@@ -163,16 +1021,161 @@ mod tests {
         if is_tmp_file_exists(filename) {
             std::fs::remove_file(&path).unwrap();
         }
-        let result = fetch_and_save_asn_db(url, &path).await;
+        let result = fetch_and_save_asn_db(
+            &http_client().unwrap(),
+            url,
+            &path,
+            DEFAULT_ASN_DOWNLOAD_TIMEOUT,
+            &CancellationToken::new(),
+            |_, _| {},
+        )
+        .await;
         assert!(result.is_ok());
         assert!(is_tmp_file_exists(filename));
         // Clean up
         std::fs::remove_file(&path).unwrap();
     }
 
+    /// Serve `body` over HTTP in two halves, waiting for `resume` before
+    /// sending the second one
+    async fn serve_in_two_halves(body: Vec<u8>, resume: CancellationToken) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            let (first, second) = body.split_at(body.len() / 2);
+            stream.write_all(first).await.unwrap();
+            stream.flush().await.unwrap();
+            resume.cancelled().await;
+            let _ = stream.write_all(second).await;
+        });
+        format!("http://{}/ip2asn.tsv.gz", addr)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_save_asn_db_custom_client() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ip2asn.tsv.gz");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ip2asn.tsv.gz", listener.local_addr().unwrap());
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nasn";
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..n]).to_lowercase()
+        });
+        let client = reqwest::Client::builder()
+            .user_agent("webinfo-test/1.0")
+            .build()
+            .unwrap();
+        fetch_and_save_asn_db(
+            &client,
+            &url,
+            &path,
+            DEFAULT_ASN_DOWNLOAD_TIMEOUT,
+            &CancellationToken::new(),
+            |_, _| {},
+        )
+        .await
+        .unwrap();
+        assert!(
+            request
+                .await
+                .unwrap()
+                .contains("user-agent: webinfo-test/1.0\r\n")
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"asn");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_save_asn_db_progress() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ip2asn.tsv.gz");
+        let resume = CancellationToken::new();
+        let url = serve_in_two_halves(vec![7u8; 1000], resume.clone()).await;
+        let mut reports = Vec::new();
+        let result = fetch_and_save_asn_db(
+            &http_client().unwrap(),
+            &url,
+            &path,
+            DEFAULT_ASN_DOWNLOAD_TIMEOUT,
+            &CancellationToken::new(),
+            |n, t| {
+                reports.push((n, t));
+                resume.cancel();
+            },
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(reports.last(), Some(&(1000, Some(1000))));
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(std::fs::read(&path).unwrap(), vec![7u8; 1000]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_save_asn_db_cancelled() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ip2asn.tsv.gz");
+        // the second half is never sent, the download is cancelled mid-way
+        let url = serve_in_two_halves(vec![7u8; 1000], CancellationToken::new()).await;
+        let cancel = CancellationToken::new();
+        let mut downloaded = 0;
+        let result = fetch_and_save_asn_db(
+            &http_client().unwrap(),
+            &url,
+            &path,
+            DEFAULT_ASN_DOWNLOAD_TIMEOUT,
+            &cancel,
+            |n, _| {
+                downloaded = n;
+                cancel.cancel();
+            },
+        )
+        .await;
+        assert!(result.unwrap_err().to_string().contains("cancelled"));
+        assert!(downloaded > 0 && downloaded < 1000);
+        assert!(!path.exists());
+        assert!(!path.with_extension("part").exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_save_asn_db_timeout() {
+        let dir = assert_fs::TempDir::new().unwrap();
+        let path = dir.path().join("ip2asn.tsv.gz");
+        // the mirror stalls after the first half
+        let stalled = CancellationToken::new();
+        let url = serve_in_two_halves(vec![7u8; 1000], stalled.clone()).await;
+        let start = Instant::now();
+        let result = fetch_and_save_asn_db(
+            &http_client().unwrap(),
+            &url,
+            &path,
+            Duration::from_millis(200),
+            &CancellationToken::new(),
+            |_, _| {},
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!path.exists());
+        assert!(!path.with_extension("part").exists());
+        stalled.cancel();
+    }
+
     #[tokio::test]
     async fn test_get_resolver() {
-        let resolver = get_resolver(None).unwrap();
+        let resolver = get_resolver(None, None).unwrap();
         // Default should be Cloudflare
         assert_eq!(
             resolver.config().name_servers()[0].socket_addr,
@@ -182,6 +1185,26 @@ mod tests {
         assert!(response.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_get_resolver_bind_family() {
+        let resolver = get_resolver(None, Some("127.0.0.1".parse().unwrap())).unwrap();
+        assert_eq!(
+            resolver.config().name_servers()[0].bind_addr,
+            Some(SocketAddr::from(([127, 0, 0, 1], 0)))
+        );
+        // the default server is IPv4
+        let error = get_resolver(None, Some("::1".parse().unwrap())).unwrap_err();
+        assert!(error.to_string().contains("1.1.1.1"), "{}", error);
+        assert!(get_resolver(Some("::1".to_string()), Some("::1".parse().unwrap())).is_ok());
+        assert!(
+            get_resolver(
+                Some("::1, 8.8.8.8".to_string()),
+                Some("::1".parse().unwrap())
+            )
+            .is_err()
+        );
+    }
+
     #[test]
     fn test_parse_ip_list() {
         let ip_list = "1.1.1.1, 8.8.8.8, 8.8.4.4";
@@ -199,6 +1222,194 @@ mod tests {
         assert_eq!(parsed_ips.len(), 0);
     }
 
+    #[test]
+    fn test_parse_ip_list_separators() {
+        let expected = vec![
+            IpAddr::from(Ipv4Addr::new(1, 1, 1, 1)),
+            IpAddr::from(Ipv4Addr::new(8, 8, 8, 8)),
+        ];
+        assert_eq!(parse_ip_list("1.1.1.1 8.8.8.8"), expected);
+        assert_eq!(parse_ip_list("1.1.1.1,8.8.8.8"), expected);
+        assert_eq!(parse_ip_list("1.1.1.1\n8.8.8.8\n"), expected);
+        assert_eq!(parse_ip_list(" 1.1.1.1 ,\t8.8.8.8\r\n"), expected);
+        assert_eq!(parse_ip_list("1.1.1.1,,  \n\n8.8.8.8"), expected);
+        assert_eq!(
+            parse_ip_list("2606:4700:4700::1111 1.1.1.1")[0],
+            "2606:4700:4700::1111".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_load_psl() {
+        let file = assert_fs::NamedTempFile::new("public_suffix_list.dat").unwrap();
+        std::fs::write(
+            file.path(),
+            "// ===BEGIN ICANN DOMAINS===\ncom\nnewtld\n// ===END ICANN DOMAINS===\n",
+        )
+        .unwrap();
+        let list = load_psl(file.path()).unwrap();
+        let strict = publicsuffix2::MatchOpts {
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            list.tld("www.example.newtld", strict).as_deref(),
+            Some("newtld")
+        );
+        assert!(list.tld("www.example.fr", strict).is_none());
+        assert!(load_psl(Path::new("/nonexistent/public_suffix_list.dat")).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_apex_limiter() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let limiter = Arc::new(ApexLimiter::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let other_started = Arc::new(AtomicUsize::new(0));
+        let mut handles = Vec::new();
+        for _ in 0..10 {
+            let (limiter, running, peak) = (limiter.clone(), running.clone(), peak.clone());
+            handles.push(tokio::spawn(async move {
+                let _permit = limiter.acquire("example.com").await;
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+        // another apex is not blocked by the busy one
+        for _ in 0..2 {
+            let (limiter, other_started) = (limiter.clone(), other_started.clone());
+            handles.push(tokio::spawn(async move {
+                let _permit = limiter.acquire("example.org").await;
+                other_started.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(other_started.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pacer() {
+        let delay = Duration::from_millis(100);
+        let jitter = Duration::from_millis(50);
+        let starts = |seed| {
+            let pacer = Pacer::new(delay, jitter, seed);
+            let first = pacer.next_start();
+            (0..5)
+                .map(|_| pacer.next_start() - first)
+                .collect::<Vec<_>>()
+        };
+        let gaps = starts(42);
+        for pair in gaps.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(gap >= delay && gap <= delay + jitter, "{:?}", gap);
+        }
+        // the same seed gives the same schedule
+        assert_eq!(gaps, starts(42));
+
+        // the waits spread the tasks over time
+        let delay = Duration::from_millis(20);
+        let pacer = Pacer::new(delay, Duration::ZERO, 0);
+        let now = Instant::now();
+        for _ in 0..4 {
+            pacer.wait().await;
+        }
+        assert!(now.elapsed() >= delay * 3);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_limiter() {
+        let limiter = Arc::new(AdaptiveLimiter::new(2, 8));
+        let fast = Duration::from_millis(10);
+        assert_eq!(limiter.limit(), 2);
+        // a window of successes raises the limit by one
+        limiter.record(false, fast);
+        limiter.record(false, fast);
+        assert_eq!(limiter.limit(), 3);
+        for _ in 0..100 {
+            limiter.record(false, fast);
+        }
+        assert_eq!(limiter.limit(), 8);
+        assert_eq!(limiter.semaphore.available_permits(), 8);
+        // a slow success does not raise the limit
+        limiter.record(false, Duration::from_secs(1));
+
+        // rising error rate: the running tasks keep their slots
+        let permits = futures::future::join_all((0..6).map(|_| limiter.acquire())).await;
+        limiter.record(true, fast);
+        assert_eq!(limiter.limit(), 4);
+        // the failures of the same window only back off once
+        limiter.record(true, fast);
+        assert_eq!(limiter.limit(), 4);
+        for _ in 0..4 {
+            limiter.record(true, fast);
+        }
+        assert_eq!(limiter.limit(), 2);
+        for _ in 0..10 {
+            limiter.record(true, fast);
+        }
+        assert_eq!(limiter.limit(), 2);
+        // the slots over the limit are taken out as the tasks release them
+        drop(permits);
+        assert_eq!(limiter.semaphore.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_progress_handle() {
+        let progress = ProgressHandle::default();
+        let (release, released) = tokio::sync::watch::channel(0);
+        let tasks: Vec<_> = (0..6)
+            .map(|i| {
+                let in_flight = progress.start();
+                let mut released = released.clone();
+                tokio::spawn(async move {
+                    // the tasks finish in turn as they are released
+                    released.wait_for(|n| *n > i).await.unwrap();
+                    in_flight.finish(i % 3 != 0);
+                })
+            })
+            .collect();
+        assert_eq!((progress.processed(), progress.in_flight()), (0, 6));
+
+        // polled from another task, the counts increase along the run
+        let watcher = progress.clone();
+        let poll = tokio::spawn(async move {
+            let mut seen = vec![watcher.processed()];
+            while !watcher.is_complete() {
+                if watcher.processed() != *seen.last().unwrap() {
+                    seen.push(watcher.processed());
+                }
+                tokio::task::yield_now().await;
+            }
+            seen.push(watcher.processed());
+            seen.dedup();
+            seen
+        });
+        for n in 1..=6 {
+            release.send(n).unwrap();
+            while progress.processed() < n as usize {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(progress.in_flight(), 6 - n as usize);
+        }
+        futures::future::join_all(tasks).await;
+        progress.complete();
+        let seen = poll.await.unwrap();
+        assert!(seen.windows(2).all(|w| w[0] < w[1]), "{:?}", seen);
+        assert_eq!(*seen.last().unwrap(), 6);
+        assert_eq!(progress.errors(), 2);
+
+        // a dropped record counts as an error
+        drop(progress.start());
+        assert_eq!((progress.processed(), progress.errors()), (7, 3));
+        assert_eq!(progress.in_flight(), 0);
+    }
+
     #[test]
     fn test_count_lines() {
         let test_file_path = "./data/test-10k.csv";
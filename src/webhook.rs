@@ -0,0 +1,252 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tracing::{Level, event};
+use url::Url;
+
+/// Delay before the first retry of a failed POST, doubled at each retry
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// HTTP collector receiving the results as JSON arrays, POSTed by batches
+/// with a bounded number of requests in flight
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: Url,
+    batch_size: usize,
+    retries: u32,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Webhook {
+    pub fn new(url: Url) -> Self {
+        Webhook {
+            client: reqwest::Client::new(),
+            url,
+            batch_size: 100,
+            retries: 3,
+            in_flight: Arc::new(Semaphore::new(4)),
+        }
+    }
+
+    /// POST with `client` instead of a client of its own, to share the
+    /// settings and the connection pool of the other HTTP requests
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Number of results sent in a single POST
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Number of retries of a failed POST before the batch is dropped
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Maximum number of POST requests in flight, sending waits beyond
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(max_in_flight.max(1)));
+        self
+    }
+
+    /// POST a batch of results, retrying with an increasing delay on a
+    /// connection error or an error status
+    pub async fn post(&self, batch: &[Value]) -> Result<()> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let response = self
+                .client
+                .post(self.url.clone())
+                .json(batch)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match response {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    event!(
+                        Level::WARN,
+                        "Failed to POST {} results to {}, retrying: {}",
+                        batch.len(),
+                        self.url,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(anyhow::anyhow!(
+                        "Failed to POST {} results to {}: {}",
+                        batch.len(),
+                        self.url,
+                        e
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Batcher sending the results pushed to it to this webhook
+    pub fn batcher(self) -> WebhookBatcher {
+        WebhookBatcher {
+            webhook: self,
+            batch: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Accumulate the results and POST them to the webhook once a batch is full
+pub struct WebhookBatcher {
+    webhook: Webhook,
+    batch: Vec<Value>,
+    pending: Vec<JoinHandle<Result<()>>>,
+}
+
+impl WebhookBatcher {
+    /// Add a result to the current batch, sending the batch in the background
+    /// when it is full. Waits while the maximum of requests are in flight.
+    pub async fn push(&mut self, value: Value) {
+        self.batch.push(value);
+        if self.batch.len() >= self.webhook.batch_size {
+            self.send().await;
+        }
+    }
+
+    async fn send(&mut self) {
+        let batch = std::mem::take(&mut self.batch);
+        let permit = self.webhook.in_flight.clone().acquire_owned().await;
+        let webhook = self.webhook.clone();
+        self.pending.push(tokio::spawn(async move {
+            let _permit = permit;
+            webhook.post(&batch).await
+        }));
+    }
+
+    /// Send the last partial batch and wait for all the requests, returning
+    /// the number of batches which could not be delivered
+    pub async fn finish(mut self) -> usize {
+        if !self.batch.is_empty() {
+            self.send().await;
+        }
+        let mut failed = 0;
+        for handle in self.pending {
+            match handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    event!(Level::ERROR, "{}", e);
+                    failed += 1;
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "Webhook task failed: {}", e);
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Stub collector answering the POSTs with `statuses` in turn, then 200,
+    /// and recording the bodies of the accepted requests
+    async fn stub_collector(statuses: Vec<u16>) -> (Url, Arc<Mutex<Vec<Value>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/results",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let bodies = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (bodies, statuses) = (bodies.clone(), statuses.clone());
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut length = 0;
+                        let mut line = String::new();
+                        loop {
+                            line.clear();
+                            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                            let lower = line.to_ascii_lowercase();
+                            if let Some(value) = lower.strip_prefix("content-length:") {
+                                length = value.trim().parse().unwrap();
+                            }
+                        }
+                        let mut body = vec![0u8; length];
+                        stream.read_exact(&mut body).await.unwrap();
+                        let status = statuses.lock().unwrap().next().unwrap_or(200);
+                        if status == 200 {
+                            bodies
+                                .lock()
+                                .unwrap()
+                                .push(serde_json::from_slice(&body).unwrap());
+                        }
+                        let response =
+                            format!("HTTP/1.1 {} Stub\r\nContent-Length: 0\r\n\r\n", status);
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn test_webhook_batches() {
+        let (url, received) = stub_collector(vec![]).await;
+        let mut batcher = Webhook::new(url).with_batch_size(2).batcher();
+        for i in 0..5 {
+            batcher.push(json!({ "origin": i })).await;
+        }
+        assert_eq!(batcher.finish().await, 0);
+        let mut received = received.lock().unwrap().clone();
+        received.sort_by_key(|batch| batch[0]["origin"].as_u64());
+        assert_eq!(
+            received,
+            vec![
+                json!([{ "origin": 0 }, { "origin": 1 }]),
+                json!([{ "origin": 2 }, { "origin": 3 }]),
+                json!([{ "origin": 4 }]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retry() {
+        let (url, received) = stub_collector(vec![503, 500]).await;
+        let webhook = Webhook::new(url).with_max_in_flight(1);
+        webhook.post(&[json!({ "origin": 1 })]).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![json!([{ "origin": 1 }])]);
+
+        // the batch is dropped once the retries are exhausted
+        let (url, received) = stub_collector(vec![500, 500]).await;
+        let mut batcher = Webhook::new(url).with_retries(1).batcher();
+        batcher.push(json!({ "origin": 2 })).await;
+        assert_eq!(batcher.finish().await, 1);
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
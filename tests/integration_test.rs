@@ -54,3 +54,106 @@ fn process_csv_file_err() -> Result<(), Box<dyn std::error::Error>> {
     ));
     Ok(())
 }
+
+#[test]
+fn process_csv_file_ndjson() -> Result<(), Box<dyn std::error::Error>> {
+    let file = assert_fs::NamedTempFile::new("sample.txt")?;
+    file.write_str(
+        "origin,popularity,date,country\nhttps://www.free.fr,1000,2025-08-28,FR\nhttps://www.google.fr,1000,2025-08-28,FR\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("webinfo")?;
+    cmd.arg("--csv")
+        .arg(file.path())
+        .arg("--output-format")
+        .arg("ndjson");
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output)?;
+    assert!(output.ends_with('\n'));
+    // every line is a standalone JSON object
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    for line in lines {
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        assert!(value["records"]["hostname"].is_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn log_formats() -> Result<(), Box<dyn std::error::Error>> {
+    for format in ["compact", "json", "pretty"] {
+        // the seed of the sample is logged before the input is opened
+        let mut cmd = Command::cargo_bin("webinfo")?;
+        cmd.args([
+            "--csv",
+            "test/file/doesnt/exist",
+            "--sample",
+            "1",
+            "--seed",
+            "42",
+        ])
+        .args(["--log-stderr", "--log-format", format]);
+        let output = cmd.assert().failure().get_output().stderr.clone();
+        let output = String::from_utf8(output)?;
+        assert!(
+            output.contains("Sampling the records and jitter with seed 42"),
+            "{}: {}",
+            format,
+            output
+        );
+        if format == "json" {
+            let line = output.lines().next().unwrap();
+            let value: serde_json::Value = serde_json::from_str(line)?;
+            assert_eq!(value["level"], "INFO");
+            assert_eq!(
+                value["message"],
+                "Sampling the records and jitter with seed 42"
+            );
+        }
+    }
+    // the events above the level are not logged
+    let mut cmd = Command::cargo_bin("webinfo")?;
+    cmd.args([
+        "--csv",
+        "test/file/doesnt/exist",
+        "--sample",
+        "1",
+        "--seed",
+        "42",
+    ])
+    .args(["--log-stderr", "--log-level", "warn"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Sampling").not());
+    Ok(())
+}
+
+#[test]
+fn print_features() -> Result<(), Box<dyn std::error::Error>> {
+    let state = if cfg!(feature = "network-tests") {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    // no input file is needed
+    let mut cmd = Command::cargo_bin("webinfo")?;
+    cmd.arg("--print-features");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "network-tests: {}",
+            state
+        )))
+        .stdout(predicate::str::contains("hickory-resolver 0.25"))
+        .stdout(predicate::str::contains("rustls 0.23"))
+        // the versions are the locked ones, not the manifest requirements
+        .stdout(predicate::str::contains("unknown").not());
+
+    // without it the input file is required
+    let mut cmd = Command::cargo_bin("webinfo")?;
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("<--csv <CSV>|--print-features>"));
+    Ok(())
+}